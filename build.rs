@@ -9,7 +9,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         // "resource",
         // "roles",
         // "saml",
-        // "sessions",
         "users/v1/model",
         "users/v1/user",
         "devices/v1/model",
@@ -20,6 +19,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         "emails/v1/emails",
         "phones/v1/model",
         "phones/v1/phones",
+        "sessions/v1/model",
+        "sessions/v1/sessions",
     ];
 
     let protos: Vec<_> = protos
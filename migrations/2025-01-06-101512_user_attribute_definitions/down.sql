@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX idx_users_metadata;
+DROP TABLE user_attribute_definitions;
+DROP TYPE attribute_visibility_enum;
+DROP TYPE attribute_type_enum;
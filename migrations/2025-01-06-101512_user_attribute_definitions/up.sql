@@ -0,0 +1,31 @@
+CREATE TYPE attribute_type_enum AS ENUM (
+    'string',        -- Free-form text, optionally constrained by a pattern or enum
+    'integer',       -- Whole numbers
+    'number',        -- Floating point numbers
+    'boolean'        -- true / false
+);
+
+CREATE TYPE attribute_visibility_enum AS ENUM (
+    'user',          -- Readable and writable by the user themselves
+    'admin'          -- Only readable and writable by administrators
+);
+
+CREATE TABLE user_attribute_definitions (
+    attribute_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    key VARCHAR(64) UNIQUE NOT NULL,  -- Key of the attribute inside users.metadata
+    attribute_type attribute_type_enum NOT NULL DEFAULT 'string',
+    description TEXT,
+    is_required BOOLEAN NOT NULL DEFAULT FALSE,  -- Whether every user must have a value
+    is_unique BOOLEAN NOT NULL DEFAULT FALSE,  -- Whether a value may only be used by one user
+    is_indexed BOOLEAN NOT NULL DEFAULT FALSE,  -- Whether users can be searched by this attribute
+    visibility attribute_visibility_enum NOT NULL DEFAULT 'admin',
+    enum_values JSONB,  -- Optional list of allowed values
+    pattern TEXT,  -- Optional regular expression string values must match
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP
+);
+
+SELECT diesel_manage_updated_at('user_attribute_definitions');
+
+-- Containment index used to search users by indexed attributes
+CREATE INDEX idx_users_metadata ON users USING GIN (metadata jsonb_path_ops);
@@ -0,0 +1,12 @@
+CREATE TABLE audit_events (
+    event_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,  -- Unique event ID
+    user_uuid UUID REFERENCES users(user_uuid) ON DELETE CASCADE,  -- The user the event is about (if applicable)
+    event_type VARCHAR(100) NOT NULL,  -- Dotted event name (e.g., "user.erased", "email.verified")
+    ip_address INET,  -- IP address that triggered the event (if known)
+    metadata JSONB NOT NULL DEFAULT '{}'::JSONB,  -- Additional event details
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP  -- When the event happened
+);
+
+-- Indexes
+CREATE INDEX idx_audit_events_user_uuid ON audit_events(user_uuid);
+CREATE INDEX idx_audit_events_event_type ON audit_events(event_type);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE user_data_jobs;
+DROP TYPE user_data_job_status_enum;
+DROP TYPE user_data_job_type_enum;
@@ -0,0 +1,27 @@
+CREATE TYPE user_data_job_type_enum AS ENUM (
+    'export',        -- Machine readable export of everything tied to a user
+    'erasure'        -- Anonymization / removal of a user's personal data
+);
+
+CREATE TYPE user_data_job_status_enum AS ENUM (
+    'pending',       -- Job is queued
+    'running',       -- Job is being processed
+    'completed',     -- Job finished, the result is available
+    'failed'         -- Job failed, see error
+);
+
+CREATE TABLE user_data_jobs (
+    job_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,  -- Unique job ID
+    user_uuid UUID NOT NULL REFERENCES users(user_uuid) ON DELETE CASCADE,  -- The user the job is about
+    job_type user_data_job_type_enum NOT NULL,
+    status user_data_job_status_enum NOT NULL DEFAULT 'pending',
+    result JSONB,  -- Export archive or erasure receipt
+    error TEXT,  -- Failure reason (if the job failed)
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    started_at TIMESTAMP WITH TIME ZONE,
+    completed_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Indexes
+CREATE INDEX idx_user_data_jobs_user_uuid ON user_data_jobs(user_uuid);
+CREATE INDEX idx_user_data_jobs_status ON user_data_jobs(status);
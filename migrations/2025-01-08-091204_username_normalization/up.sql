@@ -0,0 +1,8 @@
+-- Usernames are stored case folded; the functional index also keeps out rows
+-- that only differ by case if anything writes around the application.
+CREATE UNIQUE INDEX users_username_lower_idx ON users (lower(username));
+
+-- Confusable skeleton of the username (UTS #39), used to reject look-alikes.
+-- Existing rows are backfilled by the server on startup.
+ALTER TABLE users ADD COLUMN username_skeleton VARCHAR(255);
+CREATE INDEX users_username_skeleton_idx ON users (username_skeleton);
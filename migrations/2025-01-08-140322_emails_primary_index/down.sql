@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS emails_user_uuid_idx;
+DROP INDEX IF EXISTS emails_user_uuid_is_primary_idx;
+CREATE UNIQUE INDEX emails_user_uuid_is_primary_idx ON emails(user_uuid, is_primary);
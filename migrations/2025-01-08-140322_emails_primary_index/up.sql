@@ -0,0 +1,6 @@
+-- The previous index covered (user_uuid, is_primary), which allowed a single
+-- non-primary email per user. Only the primary email has to be unique.
+DROP INDEX IF EXISTS emails_user_uuid_is_primary_idx;
+CREATE UNIQUE INDEX emails_user_uuid_is_primary_idx ON emails(user_uuid) WHERE is_primary;
+
+CREATE INDEX emails_user_uuid_idx ON emails(user_uuid);
@@ -0,0 +1,14 @@
+CREATE TABLE email_verification_tokens (
+    token_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,  -- Unique token ID
+    email_uuid UUID NOT NULL REFERENCES emails(email_uuid) ON DELETE CASCADE,  -- The email being verified
+    code_hash VARCHAR(64) NOT NULL,  -- SHA-256 of the short numeric code (salted with token_uuid)
+    token_hash VARCHAR(64) NOT NULL UNIQUE,  -- SHA-256 of the link token
+    attempts INTEGER NOT NULL DEFAULT 0,  -- Failed code attempts
+    code_expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,  -- Expiry of the link token
+    consumed_at TIMESTAMP WITH TIME ZONE,  -- Set once the token was used or superseded
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Indexes
+CREATE INDEX idx_email_verification_tokens_email_uuid ON email_verification_tokens(email_uuid, created_at);
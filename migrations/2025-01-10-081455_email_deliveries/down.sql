@@ -0,0 +1,2 @@
+DROP TABLE email_deliveries;
+DROP TYPE email_delivery_status_enum;
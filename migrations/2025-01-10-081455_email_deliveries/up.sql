@@ -0,0 +1,22 @@
+CREATE TYPE email_delivery_status_enum AS ENUM (
+    'pending',       -- Waiting for its first or next attempt
+    'sent',          -- Accepted by the transport
+    'failed'         -- Permanently failed or out of attempts
+);
+
+CREATE TABLE email_deliveries (
+    delivery_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,  -- Unique delivery ID
+    recipient VARCHAR(255) NOT NULL,
+    subject TEXT NOT NULL,
+    text_body TEXT NOT NULL,  -- Cleared once sent, bodies can carry one-time codes
+    html_body TEXT NOT NULL,  -- Cleared once sent
+    status email_delivery_status_enum NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_error TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    sent_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Indexes
+CREATE INDEX idx_email_deliveries_due ON email_deliveries(next_attempt_at) WHERE status = 'pending';
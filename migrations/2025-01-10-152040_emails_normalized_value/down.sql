@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS emails_normalized_value_idx;
+ALTER TABLE emails DROP COLUMN IF EXISTS normalized_value;
@@ -0,0 +1,11 @@
+-- Uniqueness key of an address: lowercased, IDNA (ASCII) domain and, when
+-- enabled, Gmail dot/plus folding. Existing rows are keyed by their lowercased
+-- value until the server starts and re-keys them with the same rules as new
+-- addresses (`utils::backfill_email_keys`), IDNA and the EMAIL_FOLD_GMAIL
+-- setting aren't available here. Addresses that only differ by case have to
+-- be merged before running this.
+ALTER TABLE emails ADD COLUMN normalized_value VARCHAR(255);
+UPDATE emails SET normalized_value = lower(value);
+ALTER TABLE emails ALTER COLUMN normalized_value SET NOT NULL;
+
+CREATE UNIQUE INDEX emails_normalized_value_idx ON emails(normalized_value);
@@ -0,0 +1,2 @@
+DROP TABLE email_suppressions;
+DROP TYPE email_suppression_reason_enum;
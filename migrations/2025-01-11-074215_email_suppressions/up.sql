@@ -0,0 +1,14 @@
+CREATE TYPE email_suppression_reason_enum AS ENUM (
+    'hard_bounce',   -- The address permanently bounced
+    'complaint'      -- The recipient marked a message as spam
+);
+
+-- Addresses nothing is sent to anymore. Keyed by the normalized address so
+-- the suppression outlives the email row.
+CREATE TABLE email_suppressions (
+    normalized_value VARCHAR(255) PRIMARY KEY,
+    reason email_suppression_reason_enum NOT NULL,
+    source VARCHAR(50) NOT NULL,  -- Where the feedback came from (e.g. ses, dsn)
+    diagnostic TEXT,  -- Diagnostic reported by the provider
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
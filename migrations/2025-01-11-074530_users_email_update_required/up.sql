@@ -0,0 +1,2 @@
+-- Set when the primary email hard-bounces, so the user can be asked for a new one.
+ALTER TABLE users ADD COLUMN email_update_required BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+DROP TABLE email_change_requests;
+DROP TYPE email_change_status_enum;
@@ -0,0 +1,27 @@
+CREATE TYPE email_change_status_enum AS ENUM (
+    'pending',    -- Waiting for the new address to be verified
+    'completed',  -- The new address became primary
+    'cancelled',  -- Cancelled from the old address before completion
+    'reverted'    -- Undone from the old address after completion
+);
+
+CREATE TABLE email_change_requests (
+    request_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users(user_uuid) ON DELETE CASCADE,
+    old_email_uuid UUID REFERENCES emails(email_uuid) ON DELETE SET NULL,  -- The primary email at the time of the request
+    new_email_uuid UUID REFERENCES emails(email_uuid) ON DELETE SET NULL,  -- Dropped when the change is cancelled or reverted
+    locale VARCHAR(35) NOT NULL,  -- Locale of the notifications sent to the old address
+    status email_change_status_enum NOT NULL DEFAULT 'pending',
+    cancel_token_hash VARCHAR(64) NOT NULL UNIQUE,  -- SHA-256 of the token sent to the old address with the request
+    revert_token_hash VARCHAR(64) UNIQUE,  -- SHA-256 of the token sent to the old address on completion
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,  -- Pending requests expire with the verification link
+    undo_expires_at TIMESTAMP WITH TIME ZONE,  -- End of the undo window, set on completion
+    completed_at TIMESTAMP WITH TIME ZONE,
+    cancelled_at TIMESTAMP WITH TIME ZONE,
+    reverted_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Indexes
+CREATE UNIQUE INDEX idx_email_change_requests_pending_user ON email_change_requests(user_uuid) WHERE status = 'pending';
+CREATE INDEX idx_email_change_requests_new_email_uuid ON email_change_requests(new_email_uuid);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS phones_user_uuid_idx;
+DROP INDEX IF EXISTS phones_user_uuid_is_primary_idx;
+CREATE UNIQUE INDEX phones_user_uuid_is_primary_idx ON phones(user_uuid, is_primary);
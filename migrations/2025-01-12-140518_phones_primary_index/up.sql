@@ -0,0 +1,5 @@
+-- Same fix as for emails: only the primary phone has to be unique per user.
+DROP INDEX IF EXISTS phones_user_uuid_is_primary_idx;
+CREATE UNIQUE INDEX phones_user_uuid_is_primary_idx ON phones(user_uuid) WHERE is_primary;
+
+CREATE INDEX phones_user_uuid_idx ON phones(user_uuid, created_at);
@@ -0,0 +1,23 @@
+CREATE TABLE phone_verification_codes (
+    code_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    phone_uuid UUID NOT NULL REFERENCES phones(phone_uuid) ON DELETE CASCADE,  -- The phone being verified
+    code_hash VARCHAR(64) NOT NULL,  -- SHA-256 of the code (salted with code_uuid)
+    attempts INTEGER NOT NULL DEFAULT 0,  -- Failed attempts
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    consumed_at TIMESTAMP WITH TIME ZONE,  -- Set once the code was used, superseded or burned
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Every SMS sent, used for per-number and per-IP rate limits.
+CREATE TABLE sms_sends (
+    send_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    phone_number VARCHAR(30) NOT NULL,  -- E.164 recipient
+    ip_address INET,  -- IP of the client that asked for the message
+    purpose VARCHAR(50) NOT NULL,  -- e.g. verify_phone
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Indexes
+CREATE INDEX idx_phone_verification_codes_phone_uuid ON phone_verification_codes(phone_uuid, created_at);
+CREATE INDEX idx_sms_sends_phone_number ON sms_sends(phone_number, created_at);
+CREATE INDEX idx_sms_sends_ip_address ON sms_sends(ip_address, created_at);
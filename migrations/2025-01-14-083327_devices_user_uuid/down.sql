@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS devices_user_uuid_idx;
+ALTER TABLE devices DROP COLUMN user_uuid;
@@ -0,0 +1,14 @@
+ALTER TABLE devices ADD COLUMN user_uuid UUID REFERENCES users(user_uuid) ON DELETE CASCADE;
+
+-- Devices registered before this were only linked to users through sessions,
+-- the user of their most recent session becomes their owner.
+UPDATE devices SET user_uuid = latest.user_uuid
+FROM (
+    SELECT DISTINCT ON (device_uuid) device_uuid, user_uuid
+    FROM sessions
+    WHERE device_uuid IS NOT NULL
+    ORDER BY device_uuid, created_at DESC
+) AS latest
+WHERE devices.device_uuid = latest.device_uuid;
+
+CREATE INDEX devices_user_uuid_idx ON devices(user_uuid, created_at);
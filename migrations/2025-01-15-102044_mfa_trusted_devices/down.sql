@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS trusted_devices;
+DROP TABLE IF EXISTS mfa_challenges;
+DROP TYPE IF EXISTS mfa_challenge_status_enum;
+ALTER TABLE users DROP COLUMN mfa_enabled;
@@ -0,0 +1,35 @@
+ALTER TABLE users ADD COLUMN mfa_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TYPE mfa_challenge_status_enum AS ENUM ('pending', 'approved', 'denied', 'expired');
+
+-- Second factor requested after a correct password, the session is only
+-- created once the challenge is approved.
+CREATE TABLE mfa_challenges (
+    challenge_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users(user_uuid) ON DELETE CASCADE,
+    device_uuid UUID NOT NULL REFERENCES devices(device_uuid) ON DELETE CASCADE,
+    ip_address INET NOT NULL,
+    factor VARCHAR(20) NOT NULL,  -- How the challenge is answered, e.g. 'sms'
+    code_hash VARCHAR(64),  -- SHA-256 of the code salted with challenge_uuid, for code factors
+    attempts INTEGER NOT NULL DEFAULT 0,
+    status mfa_challenge_status_enum NOT NULL DEFAULT 'pending',
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    completed_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX mfa_challenges_user_uuid_idx ON mfa_challenges(user_uuid, created_at);
+
+-- Devices on which a user asked to skip the second factor.
+CREATE TABLE trusted_devices (
+    trust_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users(user_uuid) ON DELETE CASCADE,
+    device_uuid UUID NOT NULL REFERENCES devices(device_uuid) ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX trusted_devices_user_uuid_idx ON trusted_devices(user_uuid, created_at);
+CREATE INDEX trusted_devices_device_uuid_idx ON trusted_devices(device_uuid);
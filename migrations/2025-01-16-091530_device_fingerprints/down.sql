@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS devices_user_agent_hash_ip_prefix_idx;
+DROP INDEX IF EXISTS devices_mac_address_idx;
+DROP INDEX IF EXISTS devices_client_device_id_idx;
+ALTER TABLE devices DROP COLUMN ip_prefix;
+ALTER TABLE devices DROP COLUMN user_agent_hash;
+ALTER TABLE devices DROP COLUMN client_device_id;
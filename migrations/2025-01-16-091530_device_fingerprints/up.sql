@@ -0,0 +1,11 @@
+ALTER TABLE devices ADD COLUMN client_device_id VARCHAR(128);
+ALTER TABLE devices ADD COLUMN user_agent_hash VARCHAR(64);
+ALTER TABLE devices ADD COLUMN ip_prefix INET;
+
+-- Same prefix lengths as the matcher: /24 for IPv4, /48 for IPv6.
+UPDATE devices
+SET ip_prefix = network(set_masklen(ip_address, CASE WHEN family(ip_address) = 4 THEN 24 ELSE 48 END))::inet;
+
+CREATE INDEX devices_client_device_id_idx ON devices(client_device_id) WHERE client_device_id IS NOT NULL;
+CREATE INDEX devices_mac_address_idx ON devices(mac_address) WHERE mac_address IS NOT NULL;
+CREATE INDEX devices_user_agent_hash_ip_prefix_idx ON devices(user_agent_hash, ip_prefix) WHERE user_agent_hash IS NOT NULL;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS devices_notification_token_idx;
+ALTER TABLE mfa_challenges DROP COLUMN consumed_at;
+ALTER TABLE mfa_challenges DROP COLUMN responder_device_uuid;
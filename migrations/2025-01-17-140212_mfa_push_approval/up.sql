@@ -0,0 +1,6 @@
+-- Push challenges are approved from another device, then consumed by the
+-- sign-in that started them. Code challenges are consumed as they are approved.
+ALTER TABLE mfa_challenges ADD COLUMN responder_device_uuid UUID REFERENCES devices(device_uuid) ON DELETE SET NULL;
+ALTER TABLE mfa_challenges ADD COLUMN consumed_at TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX devices_notification_token_idx ON devices(notification_token) WHERE notification_token IS NOT NULL;
@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS sessions_active_idle_expires_at_idx;
+DROP INDEX IF EXISTS sessions_active_expires_at_idx;
+ALTER TABLE sessions DROP COLUMN absolute_expires_at;
+ALTER TABLE sessions DROP COLUMN idle_expires_at;
+ALTER TABLE sessions DROP COLUMN sliding_lifetime;
+ALTER TABLE sessions DROP COLUMN idle_timeout;
+DROP TABLE session_policies;
@@ -0,0 +1,26 @@
+-- Session lifetimes stricter than the configured defaults for members of a
+-- role or an organization. Durations are in seconds, NULL keeps the default.
+CREATE TABLE session_policies (
+    policy_uuid UUID DEFAULT uuid_generate_v4() PRIMARY KEY,
+    role_uuid UUID UNIQUE REFERENCES roles(role_uuid) ON DELETE CASCADE,
+    org_uuid UUID UNIQUE REFERENCES organizations(org_uuid) ON DELETE CASCADE,
+    idle_timeout INTEGER CHECK (idle_timeout > 0),
+    sliding_lifetime INTEGER CHECK (sliding_lifetime > 0),
+    absolute_lifetime INTEGER CHECK (absolute_lifetime > 0),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((role_uuid IS NULL) <> (org_uuid IS NULL))
+);
+
+-- The policy a session was started with, so renewals and the reaper don't
+-- have to resolve it again.
+ALTER TABLE sessions ADD COLUMN idle_timeout INTEGER;
+ALTER TABLE sessions ADD COLUMN sliding_lifetime INTEGER;
+ALTER TABLE sessions ADD COLUMN idle_expires_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE sessions ADD COLUMN absolute_expires_at TIMESTAMP WITH TIME ZONE;
+
+-- Earlier sessions keep their fixed expiry.
+UPDATE sessions SET absolute_expires_at = expires_at;
+
+CREATE INDEX sessions_active_expires_at_idx ON sessions(expires_at) WHERE is_active;
+CREATE INDEX sessions_active_idle_expires_at_idx ON sessions(idle_expires_at) WHERE is_active;
@@ -0,0 +1,3 @@
+ALTER TABLE session_policies DROP COLUMN limit_action;
+ALTER TABLE session_policies DROP COLUMN max_sessions_per_device_type;
+ALTER TABLE session_policies DROP COLUMN max_sessions;
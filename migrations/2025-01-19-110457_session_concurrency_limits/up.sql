@@ -0,0 +1,6 @@
+-- Caps on the active sessions of a user, e.g. for tenants licensing per seat.
+-- limit_action is what happens to a login over the cap: 'reject' it or
+-- 'evict_oldest' session to make room.
+ALTER TABLE session_policies ADD COLUMN max_sessions INTEGER CHECK (max_sessions > 0);
+ALTER TABLE session_policies ADD COLUMN max_sessions_per_device_type INTEGER CHECK (max_sessions_per_device_type > 0);
+ALTER TABLE session_policies ADD COLUMN limit_action VARCHAR(20) CHECK (limit_action IN ('reject', 'evict_oldest'));
@@ -0,0 +1,13 @@
+-- Access tokens revoked before they expire, identified by their `jti`.
+-- The cache holds the deny-list, this table is what it is rebuilt from and
+-- what is checked when the cache can't be trusted. Rows are deleted once
+-- the token has expired anyway.
+CREATE TABLE revoked_tokens (
+    jti UUID PRIMARY KEY,
+    user_uuid UUID NOT NULL REFERENCES users(user_uuid) ON DELETE CASCADE,
+    session_uuid UUID,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX revoked_tokens_expires_at_idx ON revoked_tokens(expires_at);
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS users_username_skeleton_idx;
+CREATE INDEX users_username_skeleton_idx ON users (username_skeleton);
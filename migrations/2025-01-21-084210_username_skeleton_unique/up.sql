@@ -0,0 +1,27 @@
+-- Two look-alike usernames registered at the same time could both pass the
+-- read-then-insert check, the database now refuses the second one.
+-- Existing look-alikes have to be renamed by hand first, the migration
+-- stops and lists them rather than picking which account keeps its name.
+DO $$
+DECLARE
+    conflicts TEXT;
+BEGIN
+    SELECT string_agg(username_skeleton || ': ' || usernames, E'\n')
+    INTO conflicts
+    FROM (
+        SELECT username_skeleton, string_agg(username || ' (' || user_uuid || ')', ', ' ORDER BY created_at, user_uuid) AS usernames
+        FROM users
+        WHERE username_skeleton IS NOT NULL
+        GROUP BY username_skeleton
+        HAVING count(*) > 1
+    ) duplicates;
+
+    IF conflicts IS NOT NULL THEN
+        RAISE EXCEPTION 'Usernames that look alike must be renamed before making skeletons unique'
+            USING DETAIL = conflicts;
+    END IF;
+END
+$$;
+
+DROP INDEX IF EXISTS users_username_skeleton_idx;
+CREATE UNIQUE INDEX users_username_skeleton_idx ON users (username_skeleton);
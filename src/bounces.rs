@@ -0,0 +1,467 @@
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::models;
+use crate::repository::{EmailRepository, RepositoryResult};
+use crate::utils::EmailPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackKind {
+    /// The address doesn't exist or permanently rejects mail.
+    HardBounce,
+    /// A temporary failure, e.g. a full mailbox.
+    SoftBounce,
+    /// The recipient marked a message as spam.
+    Complaint,
+}
+
+impl FeedbackKind {
+    /// The audit event recorded for the feedback, the status the email gets
+    /// and why the address is suppressed, if it is.
+    pub(crate) fn effects(self) -> (&'static str, Option<models::EmailStatusEnum>, Option<models::EmailSuppressionReasonEnum>) {
+        match self {
+            FeedbackKind::HardBounce => ("email.bounced", Some(models::EmailStatusEnum::Bounced), Some(models::EmailSuppressionReasonEnum::HardBounce)),
+            FeedbackKind::SoftBounce => ("email.soft_bounced", None, None),
+            FeedbackKind::Complaint => ("email.complained", Some(models::EmailStatusEnum::Spam), Some(models::EmailSuppressionReasonEnum::Complaint)),
+        }
+    }
+}
+
+/// A single bounce or complaint, whatever format it was reported in.
+#[derive(Debug, Clone)]
+pub struct Feedback {
+    pub recipient: String,
+    pub kind: FeedbackKind,
+    pub source: &'static str,
+    pub diagnostic: Option<String>,
+}
+
+/// What ingesting a piece of feedback changed.
+#[derive(Debug, Clone, Serialize)]
+pub struct FeedbackOutcome {
+    pub recipient: String,
+    pub kind: FeedbackKind,
+    /// The matching email, `None` for addresses we don't know.
+    pub email_uuid: Option<Uuid>,
+    pub suppressed: bool,
+}
+
+fn feedback(recipient: &str, kind: FeedbackKind, source: &'static str, diagnostic: Option<&str>) -> Feedback {
+    Feedback {
+        recipient: recipient.trim().to_string(),
+        kind,
+        source,
+        diagnostic: diagnostic.map(str::to_string).filter(|diagnostic| !diagnostic.is_empty()),
+    }
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+/// Parses a notification in one of the supported formats.
+///
+/// JSON bodies from Amazon SES (directly or wrapped in SNS), SendGrid,
+/// Mailgun and Postmark are recognized, as well as a minimal
+/// `{"email": .., "type": "hard_bounce" | "soft_bounce" | "complaint"}` format.
+/// Anything else is parsed as a DSN (RFC 3464) or ARF complaint report.
+pub fn parse_feedback(content_type: &str, payload: &[u8]) -> Result<Vec<Feedback>, String> {
+    let text = String::from_utf8_lossy(payload);
+    let trimmed = text.trim_start();
+
+    if content_type.contains("json") || trimmed.starts_with('{') || trimmed.starts_with('[') {
+        let value: Value = serde_json::from_str(trimmed).map_err(|e| format!("Invalid JSON: {}", e))?;
+        return parse_json(&value);
+    }
+
+    parse_report(&text)
+}
+
+fn parse_json(value: &Value) -> Result<Vec<Feedback>, String> {
+    // SendGrid posts batches of events.
+    if let Value::Array(events) = value {
+        let mut feedback = vec![];
+        for event in events {
+            feedback.extend(parse_json(event)?);
+        }
+        return Ok(feedback);
+    }
+
+    // SNS wraps the SES notification as a JSON string.
+    match str_field(value, "Type") {
+        Some("Notification") => {
+            let message = str_field(value, "Message").ok_or("SNS notification without a message")?;
+            let message: Value = serde_json::from_str(message).map_err(|e| format!("Invalid SNS message: {}", e))?;
+            return parse_json(&message);
+        }
+        Some("SubscriptionConfirmation") => {
+            println!(
+                "SNS subscription confirmation received, confirm it by visiting {}",
+                str_field(value, "SubscribeURL").unwrap_or_default()
+            );
+            return Ok(vec![]);
+        }
+        _ => {}
+    }
+
+    if let Some(kind) = str_field(value, "notificationType").or_else(|| str_field(value, "eventType")) {
+        return parse_ses(kind, value);
+    }
+
+    if let Some(event) = value.get("event-data") {
+        return parse_mailgun(event);
+    }
+
+    if let Some(record_type) = str_field(value, "RecordType") {
+        return parse_postmark(record_type, value);
+    }
+
+    if let (Some(event), Some(email)) = (str_field(value, "event"), str_field(value, "email")) {
+        return Ok(parse_sendgrid(event, email, value).into_iter().collect());
+    }
+
+    if let (Some(kind), Some(email)) = (str_field(value, "type"), str_field(value, "email")) {
+        let kind = match kind {
+            "hard_bounce" | "bounce" => FeedbackKind::HardBounce,
+            "soft_bounce" => FeedbackKind::SoftBounce,
+            "complaint" => FeedbackKind::Complaint,
+            kind => return Err(format!("Unknown feedback type '{}'", kind)),
+        };
+        return Ok(vec![feedback(email, kind, "api", str_field(value, "diagnostic"))]);
+    }
+
+    Err("Unrecognized feedback format".to_string())
+}
+
+fn parse_ses(kind: &str, value: &Value) -> Result<Vec<Feedback>, String> {
+    let recipients = |object: &Value, key: &str| -> Vec<Value> {
+        object.get(key).and_then(Value::as_array).cloned().unwrap_or_default()
+    };
+
+    match kind {
+        "Bounce" => {
+            let bounce = value.get("bounce").ok_or("SES bounce without details")?;
+            let kind = match str_field(bounce, "bounceType") {
+                Some("Permanent") => FeedbackKind::HardBounce,
+                _ => FeedbackKind::SoftBounce,
+            };
+
+            Ok(recipients(bounce, "bouncedRecipients")
+                .iter()
+                .filter_map(|recipient| {
+                    let email = str_field(recipient, "emailAddress")?;
+                    Some(feedback(email, kind, "ses", str_field(recipient, "diagnosticCode")))
+                })
+                .collect())
+        }
+        "Complaint" => {
+            let complaint = value.get("complaint").ok_or("SES complaint without details")?;
+
+            Ok(recipients(complaint, "complainedRecipients")
+                .iter()
+                .filter_map(|recipient| {
+                    let email = str_field(recipient, "emailAddress")?;
+                    Some(feedback(email, FeedbackKind::Complaint, "ses", str_field(complaint, "complaintFeedbackType")))
+                })
+                .collect())
+        }
+        // Deliveries, opens, etc. aren't feedback.
+        _ => Ok(vec![]),
+    }
+}
+
+fn parse_mailgun(event: &Value) -> Result<Vec<Feedback>, String> {
+    let recipient = str_field(event, "recipient").ok_or("Mailgun event without a recipient")?;
+    let diagnostic = event
+        .get("delivery-status")
+        .and_then(|status| str_field(status, "description").or_else(|| str_field(status, "message")));
+
+    let kind = match (str_field(event, "event"), str_field(event, "severity")) {
+        (Some("failed"), Some("permanent")) => FeedbackKind::HardBounce,
+        (Some("failed"), _) => FeedbackKind::SoftBounce,
+        (Some("complained"), _) => FeedbackKind::Complaint,
+        _ => return Ok(vec![]),
+    };
+
+    Ok(vec![feedback(recipient, kind, "mailgun", diagnostic)])
+}
+
+fn parse_postmark(record_type: &str, value: &Value) -> Result<Vec<Feedback>, String> {
+    let email = str_field(value, "Email").ok_or("Postmark record without an email")?;
+    let diagnostic = str_field(value, "Details").or_else(|| str_field(value, "Description"));
+
+    let kind = match (record_type, str_field(value, "Type")) {
+        ("SpamComplaint", _) => FeedbackKind::Complaint,
+        ("Bounce", Some("HardBounce" | "BadEmailAddress" | "ManuallyDeactivated" | "Blocked")) => FeedbackKind::HardBounce,
+        ("Bounce", Some("SpamNotification")) => FeedbackKind::Complaint,
+        ("Bounce", _) => FeedbackKind::SoftBounce,
+        _ => return Ok(vec![]),
+    };
+
+    Ok(vec![feedback(email, kind, "postmark", diagnostic)])
+}
+
+fn parse_sendgrid(event: &str, email: &str, value: &Value) -> Option<Feedback> {
+    let kind = match (event, str_field(value, "type")) {
+        ("bounce", Some("blocked")) => FeedbackKind::SoftBounce,
+        ("bounce", _) => FeedbackKind::HardBounce,
+        ("deferred", _) => FeedbackKind::SoftBounce,
+        ("spamreport", _) => FeedbackKind::Complaint,
+        _ => return None,
+    };
+
+    Some(feedback(email, kind, "sendgrid", str_field(value, "reason")))
+}
+
+/// Parses the per-recipient fields of a DSN (RFC 3464) or an ARF (RFC 5965)
+/// complaint report.
+fn parse_report(text: &str) -> Result<Vec<Feedback>, String> {
+    // Unfold continuation lines so every header fits on one line.
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        match lines.last_mut() {
+            Some(last) if line.starts_with([' ', '\t']) && !last.is_empty() => {
+                last.push(' ');
+                last.push_str(line.trim());
+            }
+            _ => lines.push(line.trim_end().to_string()),
+        }
+    }
+
+    let header = |line: &str| -> Option<(String, String)> {
+        let (name, value) = line.split_once(':')?;
+        Some((name.trim().to_lowercase(), value.trim().to_string()))
+    };
+
+    // Address types are written as `rfc822; user@example.com`.
+    let address = |value: &str| value.rsplit(';').next().unwrap_or_default().trim().trim_matches(['<', '>']).to_string();
+
+    if lines.iter().any(|line| matches!(header(line), Some((name, value)) if name == "feedback-type" && value.eq_ignore_ascii_case("abuse"))) {
+        let recipient = lines
+            .iter()
+            .filter_map(|line| header(line))
+            .find(|(name, _)| name == "original-rcpt-to")
+            .map(|(_, value)| address(&value))
+            .ok_or("Complaint report without an Original-Rcpt-To")?;
+
+        return Ok(vec![feedback(&recipient, FeedbackKind::Complaint, "arf", None)]);
+    }
+
+    struct Recipient {
+        address: String,
+        action: Option<String>,
+        status: Option<String>,
+        diagnostic: Option<String>,
+    }
+
+    let mut recipients: Vec<Recipient> = vec![];
+
+    for (name, value) in lines.iter().filter_map(|line| header(line)) {
+        match name.as_str() {
+            "final-recipient" => recipients.push(Recipient {
+                address: address(&value),
+                action: None,
+                status: None,
+                diagnostic: None,
+            }),
+            "action" => if let Some(recipient) = recipients.last_mut() {
+                recipient.action = Some(value.to_lowercase());
+            },
+            "status" => if let Some(recipient) = recipients.last_mut() {
+                recipient.status = Some(value);
+            },
+            "diagnostic-code" => if let Some(recipient) = recipients.last_mut() {
+                recipient.diagnostic = Some(value);
+            },
+            _ => {}
+        }
+    }
+
+    if recipients.is_empty() {
+        return Err("Unrecognized feedback format".to_string());
+    }
+
+    Ok(recipients
+        .into_iter()
+        .filter_map(|recipient| {
+            let permanent = recipient.status.as_deref().is_some_and(|status| status.starts_with('5'));
+            let kind = match recipient.action.as_deref() {
+                Some("failed") if permanent => FeedbackKind::HardBounce,
+                Some("failed") | Some("delayed") => FeedbackKind::SoftBounce,
+                _ => return None,
+            };
+            let diagnostic = recipient.diagnostic.or(recipient.status);
+
+            Some(feedback(&recipient.address, kind, "dsn", diagnostic.as_deref()))
+        })
+        .collect())
+}
+
+/// Records a bounce or complaint against the matching email.
+///
+/// Hard bounces and complaints suppress the address and change the email's
+/// status. A hard-bouncing primary email flags its user for an email update.
+pub async fn apply_feedback(
+    emails: &dyn EmailRepository,
+    policy: &EmailPolicy,
+    feedback: Feedback,
+) -> RepositoryResult<FeedbackOutcome> {
+    emails.apply_feedback(policy.lookup_key(&feedback.recipient), feedback).await
+}
+
+/// Parses a notification and applies every bounce or complaint it contains.
+///
+/// # Returns
+/// * `Ok(Ok(outcomes))` - What changed, one entry per recipient.
+/// * `Ok(Err(String))` - The payload couldn't be parsed.
+pub async fn ingest_feedback(
+    emails: &dyn EmailRepository,
+    policy: &EmailPolicy,
+    content_type: &str,
+    payload: &[u8],
+) -> RepositoryResult<Result<Vec<FeedbackOutcome>, String>> {
+    let feedback = match parse_feedback(content_type, payload) {
+        Ok(feedback) => feedback,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let mut outcomes = Vec::with_capacity(feedback.len());
+    for feedback in feedback.into_iter().filter(|feedback| !feedback.recipient.is_empty()) {
+        outcomes.push(apply_feedback(emails, policy, feedback).await?);
+    }
+
+    Ok(Ok(outcomes))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const DSN: &str = "From: MAILER-DAEMON@mx.example.com\r
+Subject: Undelivered Mail Returned to Sender\r
+MIME-Version: 1.0\r
+Content-Type: multipart/report; report-type=delivery-status; boundary=\"dsn-boundary\"\r
+\r
+--dsn-boundary\r
+Content-Type: text/plain\r
+\r
+Your message couldn't be delivered.\r
+\r
+--dsn-boundary\r
+Content-Type: message/delivery-status\r
+\r
+Reporting-MTA: dns; mx.example.com\r
+Arrival-Date: Mon, 19 Oct 2026 07:53:02 +0000\r
+\r
+Final-Recipient: rfc822; gone@example.org\r
+Action: failed\r
+Status: 5.1.1\r
+Diagnostic-Code: smtp; 550 5.1.1 <gone@example.org>: Recipient address\r
+ rejected: User unknown\r
+\r
+Final-Recipient: rfc822; <full@example.net>\r
+Action: delayed\r
+Status: 4.2.2\r
+\r
+Final-Recipient: rfc822; fine@example.com\r
+Action: delivered\r
+Status: 2.0.0\r
+\r
+--dsn-boundary--\r
+";
+
+    const ARF: &str = "Content-Type: multipart/report; report-type=feedback-report; boundary=\"arf\"\r
+\r
+--arf\r
+Content-Type: message/feedback-report\r
+\r
+Feedback-Type: abuse\r
+User-Agent: ExampleFBL/1.0\r
+Version: 1\r
+Original-Rcpt-To: <annoyed@example.com>\r
+\r
+--arf--\r
+";
+
+    fn kinds(feedback: &[Feedback]) -> Vec<(&str, FeedbackKind)> {
+        feedback.iter().map(|feedback| (feedback.recipient.as_str(), feedback.kind)).collect()
+    }
+
+    #[test]
+    fn delivery_status_reports_tell_hard_from_soft_bounces() {
+        let feedback = parse_feedback("multipart/report", DSN.as_bytes()).unwrap();
+
+        assert_eq!(kinds(&feedback), vec![
+            ("gone@example.org", FeedbackKind::HardBounce),
+            ("full@example.net", FeedbackKind::SoftBounce),
+        ]);
+        assert_eq!(feedback[0].source, "dsn");
+        assert_eq!(
+            feedback[0].diagnostic.as_deref(),
+            Some("smtp; 550 5.1.1 <gone@example.org>: Recipient address rejected: User unknown")
+        );
+        assert_eq!(feedback[1].diagnostic.as_deref(), Some("4.2.2"));
+    }
+
+    #[test]
+    fn abuse_reports_are_complaints() {
+        let feedback = parse_feedback("multipart/report", ARF.as_bytes()).unwrap();
+
+        assert_eq!(kinds(&feedback), vec![("annoyed@example.com", FeedbackKind::Complaint)]);
+        assert_eq!(feedback[0].source, "arf");
+    }
+
+    #[test]
+    fn provider_notifications_are_recognized() {
+        let ses = json!({
+            "notificationType": "Bounce",
+            "bounce": {
+                "bounceType": "Permanent",
+                "bouncedRecipients": [{ "emailAddress": "gone@example.org", "diagnosticCode": "550 5.1.1 User unknown" }],
+            },
+        });
+        let sns = json!({ "Type": "Notification", "Message": ses.to_string() });
+        let feedback = parse_feedback("text/plain", sns.to_string().as_bytes()).unwrap();
+        assert_eq!(kinds(&feedback), vec![("gone@example.org", FeedbackKind::HardBounce)]);
+        assert_eq!(feedback[0].diagnostic.as_deref(), Some("550 5.1.1 User unknown"));
+
+        let sendgrid = json!([
+            { "event": "deferred", "email": "full@example.net", "reason": "452 Mailbox full" },
+            { "event": "delivered", "email": "fine@example.com" },
+            { "event": "spamreport", "email": "annoyed@example.com" },
+        ]);
+        let feedback = parse_feedback("application/json", sendgrid.to_string().as_bytes()).unwrap();
+        assert_eq!(kinds(&feedback), vec![
+            ("full@example.net", FeedbackKind::SoftBounce),
+            ("annoyed@example.com", FeedbackKind::Complaint),
+        ]);
+
+        let postmark = json!({ "RecordType": "SpamComplaint", "Email": "annoyed@example.com" });
+        let feedback = parse_feedback("application/json", postmark.to_string().as_bytes()).unwrap();
+        assert_eq!(kinds(&feedback), vec![("annoyed@example.com", FeedbackKind::Complaint)]);
+
+        let minimal = json!({ "email": "gone@example.org", "type": "hard_bounce", "diagnostic": "no such user" });
+        let feedback = parse_feedback("application/json", minimal.to_string().as_bytes()).unwrap();
+        assert_eq!(kinds(&feedback), vec![("gone@example.org", FeedbackKind::HardBounce)]);
+        assert_eq!(feedback[0].source, "api");
+    }
+
+    #[test]
+    fn malformed_payloads_are_refused() {
+        for (content_type, payload) in [
+            ("application/json", "{\"email\": \"gone@example.org\""),
+            ("application/json", "{\"hello\": \"world\"}"),
+            ("application/json", "{\"email\": \"gone@example.org\", \"type\": \"bounced_twice\"}"),
+            ("text/plain", "Thanks for your message, I'm out of office until Monday."),
+        ] {
+            assert!(parse_feedback(content_type, payload.as_bytes()).is_err(), "{}", payload);
+        }
+
+        let missing_recipient = ARF.replace("Original-Rcpt-To: <annoyed@example.com>\r\n", "");
+        assert!(parse_feedback("multipart/report", missing_recipient.as_bytes()).is_err());
+    }
+}
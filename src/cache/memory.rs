@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{CacheError, CacheStore};
+
+/// Writes between two sweeps of expired entries.
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Keeps entries in the process, for a single instance, development and tests.
+///
+/// Nothing is shared between instances: a session revoked through one of
+/// them stays cached on the others until it expires.
+pub struct MemoryCacheStore {
+    entries: Mutex<MemoryEntries>,
+}
+
+#[derive(Default)]
+struct MemoryEntries {
+    values: HashMap<String, (String, Instant)>,
+    writes: u64,
+}
+
+impl MemoryCacheStore {
+    pub fn new() -> Self {
+        MemoryCacheStore {
+            entries: Mutex::new(MemoryEntries::default()),
+        }
+    }
+}
+
+impl Default for MemoryCacheStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[tonic::async_trait]
+impl CacheStore for MemoryCacheStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.values.get(key) {
+            Some((value, expires_at)) if *expires_at > Instant::now() => Ok(Some(value.clone())),
+            Some(_) => {
+                entries.values.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<(), CacheError> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        entries.writes += 1;
+        if entries.writes.is_multiple_of(SWEEP_INTERVAL) {
+            entries.values.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        entries.values.insert(key.to_string(), (value.to_string(), now + ttl));
+        Ok(())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), CacheError> {
+        let mut entries = self.entries.lock().unwrap();
+        for key in keys {
+            entries.values.remove(key);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+mod memory;
+mod redis;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub use self::memory::*;
+pub use self::redis::*;
+
+use crate::config::Config;
+use crate::models;
+
+#[derive(Debug)]
+pub enum CacheError {
+    /// The store couldn't be reached or didn't answer in time.
+    Unavailable(String),
+    /// An entry couldn't be read back, e.g. written by another version.
+    Corrupt(String),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Unavailable(message) => write!(f, "Cache unavailable: {}", message),
+            CacheError::Corrupt(message) => write!(f, "Corrupt cache entry: {}", message),
+        }
+    }
+}
+
+/// A key-value store whose entries expire.
+///
+/// Stores answer quickly, or fail quickly when they can't.
+#[tonic::async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError>;
+    async fn set(&self, key: &str, value: &str, ttl: StdDuration) -> Result<(), CacheError>;
+    async fn delete(&self, keys: &[String]) -> Result<(), CacheError>;
+}
+
+/// Builds the store selected by `CACHE_BACKEND`.
+pub fn cache_store_from_config(config: &Config) -> Result<Box<dyn CacheStore>, String> {
+    match config.cache_backend.as_str() {
+        "redis" => Ok(Box::new(RedisCacheStore::new(&config.redis_url)?)),
+        "memory" => Ok(Box::new(MemoryCacheStore::new())),
+        backend => Err(format!("Unsupported cache backend '{}'", backend)),
+    }
+}
+
+/// What `GetUserToken` answers about a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedUser {
+    pub user_uuid: Uuid,
+    pub username: String,
+    pub status: i32,
+    pub email_update_required: bool,
+    pub mfa_enabled: bool,
+}
+
+impl From<&models::User> for CachedUser {
+    fn from(user: &models::User) -> Self {
+        CachedUser {
+            user_uuid: user.user_uuid,
+            username: user.username.clone(),
+            status: user.status.clone() as i32,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
+        }
+    }
+}
+
+/// Saves Postgres round trips when validating access tokens.
+///
+/// Postgres stays the source of truth: a miss or an unavailable store only
+/// costs a query. Sessions and users are kept for a few seconds, changes
+/// that don't invalidate them explicitly show up once they expire.
+///
+/// The deny-list of revoked tokens is different, a missing entry would let
+/// a revoked token through. It is only trusted once it was rebuilt from
+/// Postgres after the last failed write, see `token_revocation`.
+pub struct Cache {
+    store: Box<dyn CacheStore>,
+    session_ttl: StdDuration,
+    user_ttl: StdDuration,
+    /// Bumped whenever the store fails, it may have lost deny-list entries.
+    failures: AtomicU64,
+    /// Failures the deny-list was rebuilt after.
+    synced_through: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Self::with_store(cache_store_from_config(config)?, config))
+    }
+
+    pub fn with_store(store: Box<dyn CacheStore>, config: &Config) -> Self {
+        Cache {
+            store,
+            session_ttl: StdDuration::from_secs(config.cache_session_ttl),
+            user_ttl: StdDuration::from_secs(config.cache_user_ttl),
+            // Nothing is known about the deny-list before the first sync.
+            failures: AtomicU64::new(1),
+            synced_through: AtomicU64::new(0),
+        }
+    }
+
+    fn failed(&self, action: &str, error: CacheError) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        println!("Error {}: {}", action, error);
+    }
+
+    /// Until when the session can be used, if it was cached as live.
+    pub async fn session_live_until(&self, session_uuid: Uuid) -> Option<DateTime<Utc>> {
+        match self.store.get(&session_key(session_uuid)).await {
+            Ok(value) => value
+                .and_then(|value| value.parse::<i64>().ok())
+                .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)),
+            Err(e) => {
+                self.failed("reading session from cache", e);
+                None
+            }
+        }
+    }
+
+    /// Caches a live session until `live_until`, or the session TTL if sooner.
+    pub async fn cache_session(&self, session_uuid: Uuid, live_until: DateTime<Utc>) {
+        let Some(ttl) = ttl_until(live_until).map(|ttl| ttl.min(self.session_ttl)) else {
+            return;
+        };
+
+        if let Err(e) = self.store.set(&session_key(session_uuid), &live_until.timestamp().to_string(), ttl).await {
+            self.failed("caching session", e);
+        }
+    }
+
+    pub async fn forget_sessions(&self, session_uuids: &[Uuid]) {
+        if session_uuids.is_empty() {
+            return;
+        }
+
+        let keys: Vec<String> = session_uuids.iter().copied().map(session_key).collect();
+        if let Err(e) = self.store.delete(&keys).await {
+            self.failed("removing sessions from cache", e);
+        }
+    }
+
+    pub async fn user(&self, user_uuid: Uuid) -> Option<CachedUser> {
+        let value = match self.store.get(&user_key(user_uuid)).await {
+            Ok(value) => value?,
+            Err(e) => {
+                self.failed("reading user from cache", e);
+                return None;
+            }
+        };
+
+        match serde_json::from_str(&value) {
+            Ok(user) => Some(user),
+            Err(e) => {
+                self.failed("reading user from cache", CacheError::Corrupt(e.to_string()));
+                None
+            }
+        }
+    }
+
+    pub async fn cache_user(&self, user: &CachedUser) {
+        let value = match serde_json::to_string(user) {
+            Ok(value) => value,
+            Err(e) => {
+                println!("Error caching user: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.store.set(&user_key(user.user_uuid), &value, self.user_ttl).await {
+            self.failed("caching user", e);
+        }
+    }
+
+    pub async fn forget_user(&self, user_uuid: Uuid) {
+        if let Err(e) = self.store.delete(&[user_key(user_uuid)]).await {
+            self.failed("removing user from cache", e);
+        }
+    }
+
+    /// Adds a token to the deny-list until it expires.
+    pub async fn deny_token(&self, jti: Uuid, expires_at: DateTime<Utc>) {
+        if let Err(e) = self.set_denied(jti, expires_at).await {
+            self.failed("adding token to the deny-list", e);
+        }
+    }
+
+    async fn set_denied(&self, jti: Uuid, expires_at: DateTime<Utc>) -> Result<(), CacheError> {
+        match ttl_until(expires_at) {
+            Some(ttl) => self.store.set(&denied_token_key(jti), "1", ttl).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the token is on the deny-list, `None` when the deny-list
+    /// can't be trusted and Postgres must be asked instead.
+    pub async fn is_token_denied(&self, jti: Uuid) -> Option<bool> {
+        if !self.is_deny_list_synced() {
+            return None;
+        }
+
+        match self.store.get(&denied_token_key(jti)).await {
+            Ok(value) => Some(value.is_some()),
+            Err(e) => {
+                self.failed("reading the deny-list", e);
+                None
+            }
+        }
+    }
+
+    pub fn is_deny_list_synced(&self) -> bool {
+        self.synced_through.load(Ordering::SeqCst) >= self.failures.load(Ordering::SeqCst)
+    }
+
+    /// Where a rebuild of the deny-list starts from, `None` if it is in sync.
+    ///
+    /// Take it before loading the revoked tokens: a write failing meanwhile
+    /// keeps the deny-list untrusted until the next rebuild.
+    pub fn deny_list_sync_point(&self) -> Option<u64> {
+        let failures = self.failures.load(Ordering::SeqCst);
+        (self.synced_through.load(Ordering::SeqCst) < failures).then_some(failures)
+    }
+
+    /// Rebuilds the deny-list from every unexpired revoked token, returns
+    /// whether it can be trusted again.
+    pub async fn restore_deny_list(&self, sync_point: u64, tokens: &[models::RevokedToken]) -> bool {
+        for token in tokens {
+            if let Err(e) = self.set_denied(token.jti, token.expires_at).await {
+                self.failed("rebuilding the deny-list", e);
+                return false;
+            }
+        }
+
+        self.synced_through.fetch_max(sync_point, Ordering::SeqCst);
+        true
+    }
+}
+
+fn session_key(session_uuid: Uuid) -> String {
+    format!("ingot:session:{}", session_uuid)
+}
+
+fn user_key(user_uuid: Uuid) -> String {
+    format!("ingot:user:{}", user_uuid)
+}
+
+fn denied_token_key(jti: Uuid) -> String {
+    format!("ingot:denied_token:{}", jti)
+}
+
+/// Time left until `at`, `None` if it already passed.
+fn ttl_until(at: DateTime<Utc>) -> Option<StdDuration> {
+    (at - Utc::now()).to_std().ok().filter(|ttl| !ttl.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use chrono::Duration;
+    use crate::testing;
+
+    /// An in-process store that can be taken down.
+    struct FlakyStore {
+        inner: MemoryCacheStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl FlakyStore {
+        fn check(&self) -> Result<(), CacheError> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(CacheError::Unavailable("connection refused".to_string())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl CacheStore for FlakyStore {
+        async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+            self.check()?;
+            self.inner.get(key).await
+        }
+
+        async fn set(&self, key: &str, value: &str, ttl: StdDuration) -> Result<(), CacheError> {
+            self.check()?;
+            self.inner.set(key, value, ttl).await
+        }
+
+        async fn delete(&self, keys: &[String]) -> Result<(), CacheError> {
+            self.check()?;
+            self.inner.delete(keys).await
+        }
+    }
+
+    fn flaky_cache(config: &Config) -> (Cache, Arc<AtomicBool>) {
+        let down = Arc::new(AtomicBool::new(false));
+        let store = FlakyStore { inner: MemoryCacheStore::new(), down: down.clone() };
+        (Cache::with_store(Box::new(store), config), down)
+    }
+
+    fn revoked_token(expires_at: DateTime<Utc>) -> models::RevokedToken {
+        models::RevokedToken {
+            jti: Uuid::new_v4(),
+            user_uuid: Uuid::new_v4(),
+            session_uuid: None,
+            expires_at,
+            revoked_at: Utc::now(),
+        }
+    }
+
+    fn user() -> CachedUser {
+        CachedUser {
+            user_uuid: Uuid::new_v4(),
+            username: "alice".to_string(),
+            status: 1,
+            email_update_required: false,
+            mfa_enabled: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_are_cached_until_forgotten() {
+        let cache = Cache::new(&testing::config(&[])).unwrap();
+        let session_uuid = Uuid::new_v4();
+        let live_until = Utc::now() + Duration::hours(1);
+
+        cache.cache_session(session_uuid, live_until).await;
+        assert_eq!(cache.session_live_until(session_uuid).await.map(|at| at.timestamp()), Some(live_until.timestamp()));
+
+        cache.forget_sessions(&[session_uuid]).await;
+        assert_eq!(cache.session_live_until(session_uuid).await, None);
+
+        // Sessions already past their deadline aren't cached at all.
+        cache.cache_session(session_uuid, Utc::now() - Duration::seconds(1)).await;
+        assert_eq!(cache.session_live_until(session_uuid).await, None);
+    }
+
+    #[tokio::test]
+    async fn sessions_and_users_expire_after_their_ttl() {
+        let cache = Cache::new(&testing::config(&[("CACHE_SESSION_TTL", "0"), ("CACHE_USER_TTL", "0")])).unwrap();
+        let session_uuid = Uuid::new_v4();
+        let user = user();
+
+        cache.cache_session(session_uuid, Utc::now() + Duration::hours(1)).await;
+        cache.cache_user(&user).await;
+
+        assert_eq!(cache.session_live_until(session_uuid).await, None);
+        assert!(cache.user(user.user_uuid).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn users_are_cached_until_forgotten() {
+        let cache = Cache::new(&testing::config(&[])).unwrap();
+        let user = user();
+
+        cache.cache_user(&user).await;
+        let cached = cache.user(user.user_uuid).await.unwrap();
+        assert_eq!(cached.username, "alice");
+        assert!(cached.mfa_enabled);
+
+        cache.forget_user(user.user_uuid).await;
+        assert!(cache.user(user.user_uuid).await.is_none());
+
+        // Entries written by another version are a miss.
+        cache.store.set(&user_key(user.user_uuid), "{\"username\": 1}", StdDuration::from_secs(60)).await.unwrap();
+        assert!(cache.user(user.user_uuid).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn the_deny_list_is_only_trusted_once_restored() {
+        let cache = Cache::new(&testing::config(&[])).unwrap();
+        let revoked = revoked_token(Utc::now() + Duration::hours(1));
+
+        assert!(!cache.is_deny_list_synced());
+        assert_eq!(cache.is_token_denied(revoked.jti).await, None);
+
+        let sync_point = cache.deny_list_sync_point().unwrap();
+        assert!(cache.restore_deny_list(sync_point, &[revoked.clone(), revoked_token(Utc::now() - Duration::hours(1))]).await);
+
+        assert_eq!(cache.deny_list_sync_point(), None);
+        assert_eq!(cache.is_token_denied(revoked.jti).await, Some(true));
+        assert_eq!(cache.is_token_denied(Uuid::new_v4()).await, Some(false));
+
+        let jti = Uuid::new_v4();
+        cache.deny_token(jti, Utc::now() + Duration::hours(1)).await;
+        assert_eq!(cache.is_token_denied(jti).await, Some(true));
+    }
+
+    #[tokio::test]
+    async fn a_failed_write_untrusts_the_deny_list_until_the_next_restore() {
+        let (cache, down) = flaky_cache(&testing::config(&[]));
+        cache.restore_deny_list(cache.deny_list_sync_point().unwrap(), &[]).await;
+
+        let jti = Uuid::new_v4();
+        down.store(true, Ordering::SeqCst);
+        cache.deny_token(jti, Utc::now() + Duration::hours(1)).await;
+        down.store(false, Ordering::SeqCst);
+
+        // The entry was lost, answering `false` would let the token through.
+        assert_eq!(cache.is_token_denied(jti).await, None);
+
+        let sync_point = cache.deny_list_sync_point().unwrap();
+        let revoked = models::RevokedToken { jti, ..revoked_token(Utc::now() + Duration::hours(1)) };
+
+        // A failure during the rebuild keeps it untrusted.
+        down.store(true, Ordering::SeqCst);
+        assert!(!cache.restore_deny_list(sync_point, std::slice::from_ref(&revoked)).await);
+        down.store(false, Ordering::SeqCst);
+        assert!(!cache.is_deny_list_synced());
+
+        let sync_point = cache.deny_list_sync_point().unwrap();
+        assert!(cache.restore_deny_list(sync_point, &[revoked]).await);
+        assert_eq!(cache.is_token_denied(jti).await, Some(true));
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::redis::aio::{ConnectionManager, ConnectionManagerConfig};
+
+use super::{CacheError, CacheStore};
+
+/// Redis answers in well under a millisecond, a request shouldn't wait
+/// much longer before falling back to Postgres.
+const TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How long after a failure Redis is left alone before reconnecting, so an
+/// outage doesn't add a connection timeout to every request.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Shares entries between instances through `REDIS_URL`.
+///
+/// Commands from every request are multiplexed on one connection, none of
+/// them waits for another to finish.
+pub struct RedisCacheStore {
+    client: ::redis::Client,
+    connection: Mutex<RedisConnection>,
+}
+
+#[derive(Default)]
+struct RedisConnection {
+    manager: Option<ConnectionManager>,
+    retry_at: Option<Instant>,
+}
+
+impl RedisCacheStore {
+    /// Doesn't connect yet, Redis may come up after the server.
+    pub fn new(url: &str) -> Result<Self, String> {
+        Ok(RedisCacheStore {
+            client: ::redis::Client::open(url).map_err(|e| format!("Invalid Redis URL: {}", e))?,
+            connection: Mutex::new(RedisConnection::default()),
+        })
+    }
+
+    /// Runs a command, connecting first if needed. The connection is dropped
+    /// on any error, its state is unknown after a timeout.
+    async fn query<T: ::redis::FromRedisValue>(&self, command: &::redis::Cmd) -> Result<T, CacheError> {
+        let mut manager = self.manager().await?;
+
+        command.query_async(&mut manager).await.map_err(|e| {
+            self.disconnect();
+            CacheError::Unavailable(e.to_string())
+        })
+    }
+
+    /// The shared connection, connecting unless the last failure is too recent.
+    async fn manager(&self) -> Result<ConnectionManager, CacheError> {
+        {
+            let state = self.connection.lock().unwrap();
+            if let Some(manager) = &state.manager {
+                return Ok(manager.clone());
+            }
+            if state.retry_at.is_some_and(|at| Instant::now() < at) {
+                return Err(CacheError::Unavailable("waiting to reconnect to Redis".to_string()));
+            }
+        }
+
+        let config = ConnectionManagerConfig::new()
+            .set_number_of_retries(0)
+            .set_connection_timeout(TIMEOUT)
+            .set_response_timeout(TIMEOUT);
+
+        match ConnectionManager::new_with_config(self.client.clone(), config).await {
+            Ok(manager) => {
+                let mut state = self.connection.lock().unwrap();
+                state.retry_at = None;
+                // Requests racing to connect all end up on the first connection.
+                Ok(state.manager.get_or_insert(manager).clone())
+            }
+            Err(e) => {
+                self.disconnect();
+                Err(CacheError::Unavailable(e.to_string()))
+            }
+        }
+    }
+
+    fn disconnect(&self) {
+        let mut state = self.connection.lock().unwrap();
+        state.manager = None;
+        state.retry_at = Some(Instant::now() + RECONNECT_BACKOFF);
+    }
+}
+
+#[tonic::async_trait]
+impl CacheStore for RedisCacheStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, CacheError> {
+        self.query(::redis::cmd("GET").arg(key)).await
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<(), CacheError> {
+        // Redis rejects a zero expiry.
+        let ttl = (ttl.as_millis() as u64).max(1);
+        self.query(::redis::cmd("SET").arg(key).arg(value).arg("PX").arg(ttl)).await
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), CacheError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        self.query(::redis::cmd("DEL").arg(keys)).await
+    }
+}
@@ -0,0 +1,186 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use envconfig::Envconfig;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db;
+use crate::import::{self, ImportContext, ImportFormat, RejectedUser};
+use crate::models;
+use crate::session_policy::LimitAction;
+
+const USAGE: &str = "Usage: ingot import-users <file> [--format csv|jsonl] [--batch-size <n>] [--dry-run]
+       ingot set-session-policy --role <id> | --org <id> [--idle-timeout <s>] [--sliding-lifetime <s>] [--absolute-lifetime <s>]
+                                [--max-sessions <n>] [--max-sessions-per-device-type <n>] [--limit-action reject|evict_oldest]";
+
+/// Runs an offline administration command, e.g. `ingot import-users users.csv --dry-run`.
+pub async fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args.first().map(String::as_str) {
+        Some("import-users") => import_users(&args[1..]).await,
+        Some("set-session-policy") => set_session_policy(&args[1..]).await,
+        Some(command) => Err(format!("Unknown command '{}'\n{}", command, USAGE).into()),
+        None => Err(USAGE.into()),
+    }
+}
+
+async fn import_users(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut format = None;
+    let mut batch_size = import::DEFAULT_BATCH_SIZE;
+    let mut dry_run = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--format" => {
+                let value = args.next().ok_or(USAGE)?;
+                format = Some(value.parse::<ImportFormat>()?);
+            }
+            "--batch-size" => {
+                let value = args.next().ok_or(USAGE)?;
+                batch_size = value.parse::<usize>()?.clamp(1, import::MAX_BATCH_SIZE);
+            }
+            value if path.is_none() && !value.starts_with("--") => path = Some(value.to_string()),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+
+    // Fall back to the file extension when no format is given.
+    let format = match format {
+        Some(format) => format,
+        None => Path::new(&path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .ok_or("Unable to detect the file format, pass --format")?
+            .parse::<ImportFormat>()?,
+    };
+
+    let config = Config::init_from_env()?;
+    let mut database = db::connect(&config.database_url).await?;
+    let context = ImportContext::load(&mut database, config.reserved_usernames(), Arc::new(config.email_policy()?)).await?;
+    let mut records = import::read_records(File::open(&path)?, format).enumerate();
+
+    let mut imported = 0;
+    let mut failed = 0;
+
+    loop {
+        let batch: Vec<_> = records
+            .by_ref()
+            .take(batch_size)
+            .map(|(index, record)| {
+                let prepared = match record {
+                    Ok(record) => context.prepare(record),
+                    // The line couldn't be parsed, so there is no username to report.
+                    Err(error) => Err(RejectedUser { username: String::new(), error }),
+                };
+                (index + 1, prepared)
+            })
+            .collect();
+
+        if batch.is_empty() {
+            break;
+        }
+
+        for result in context.insert_batch(&mut database, batch, dry_run).await? {
+            match result.error {
+                Some(_) => failed += 1,
+                None => imported += 1,
+            }
+            println!("{}", serde_json::to_string(&result)?);
+        }
+    }
+
+    eprintln!(
+        "{} {} users, {} failed",
+        if dry_run { "Validated" } else { "Imported" },
+        imported,
+        failed
+    );
+
+    Ok(())
+}
+
+/// Sets the session policy of a role or an organization, removing it when
+/// no duration is given.
+async fn set_session_policy(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut role_uuid = None;
+    let mut org_uuid = None;
+    let mut idle_timeout = None;
+    let mut sliding_lifetime = None;
+    let mut absolute_lifetime = None;
+    let mut max_sessions = None;
+    let mut max_sessions_per_device_type = None;
+    let mut limit_action = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(USAGE)?;
+        match arg.as_str() {
+            "--role" => role_uuid = Some(Uuid::parse_str(value)?),
+            "--org" => org_uuid = Some(Uuid::parse_str(value)?),
+            "--idle-timeout" => idle_timeout = Some(parse_seconds(value)?),
+            "--sliding-lifetime" => sliding_lifetime = Some(parse_seconds(value)?),
+            "--absolute-lifetime" => absolute_lifetime = Some(parse_seconds(value)?),
+            "--max-sessions" => max_sessions = Some(parse_positive(value)?),
+            "--max-sessions-per-device-type" => max_sessions_per_device_type = Some(parse_positive(value)?),
+            "--limit-action" => {
+                value.parse::<LimitAction>()?;
+                limit_action = Some(value.to_string());
+            }
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    if role_uuid.is_some() == org_uuid.is_some() {
+        return Err(USAGE.into());
+    }
+
+    let config = Config::init_from_env()?;
+    let mut database = db::connect(&config.database_url).await?;
+
+    let is_empty = idle_timeout.is_none()
+        && sliding_lifetime.is_none()
+        && absolute_lifetime.is_none()
+        && max_sessions.is_none()
+        && max_sessions_per_device_type.is_none()
+        && limit_action.is_none();
+
+    if is_empty {
+        let deleted = models::SessionPolicy::delete(&mut database, role_uuid, org_uuid).await?;
+        eprintln!("Removed {} session policy", deleted);
+        return Ok(());
+    }
+
+    let policy = models::SessionPolicy::upsert(&mut database, models::NewSessionPolicy {
+        role_uuid,
+        org_uuid,
+        idle_timeout,
+        sliding_lifetime,
+        absolute_lifetime,
+        max_sessions,
+        max_sessions_per_device_type,
+        limit_action,
+    }).await?;
+    eprintln!("Saved session policy {}, it applies to sessions started from now on", policy.policy_uuid);
+
+    Ok(())
+}
+
+fn parse_seconds(value: &str) -> Result<i32, Box<dyn Error>> {
+    match value.parse::<i32>()? {
+        seconds if seconds > 0 => Ok(seconds),
+        _ => Err("Durations must be a positive number of seconds".into()),
+    }
+}
+
+fn parse_positive(value: &str) -> Result<i32, Box<dyn Error>> {
+    match value.parse::<i32>()? {
+        count if count > 0 => Ok(count),
+        _ => Err("Limits must be a positive number".into()),
+    }
+}
@@ -0,0 +1,317 @@
+use envconfig::Envconfig;
+
+use crate::utils;
+
+#[derive(Envconfig, Clone)]
+pub struct Config {
+    #[envconfig(from = "DATABASE_URL")]
+    pub database_url: String,
+
+    /// Connections kept open to Postgres at most.
+    #[envconfig(from = "DATABASE_POOL_SIZE", default = "16")]
+    pub database_pool_size: usize,
+
+    /// Seconds a request waits for a free connection before failing as unavailable.
+    #[envconfig(from = "DATABASE_POOL_WAIT_TIMEOUT", default = "5")]
+    pub database_pool_wait_timeout: u64,
+
+    /// Seconds to open a connection, or to check one before reusing it.
+    #[envconfig(from = "DATABASE_CONNECT_TIMEOUT", default = "5")]
+    pub database_connect_timeout: u64,
+
+    /// Check pooled connections still work before handing them out again.
+    #[envconfig(from = "DATABASE_POOL_HEALTH_CHECK", default = "true")]
+    pub database_pool_health_check: bool,
+
+    /// Comma separated usernames nobody can register, compared after normalization.
+    #[envconfig(
+        from = "RESERVED_USERNAMES",
+        default = "admin,administrator,root,superuser,support,help,system,security,staff,moderator,ingot"
+    )]
+    pub reserved_usernames: String,
+
+    /// Lifetime of an email verification code, in seconds.
+    #[envconfig(from = "EMAIL_VERIFICATION_CODE_TTL", default = "900")]
+    pub email_verification_code_ttl: i64,
+
+    /// Lifetime of an email verification link, in seconds.
+    #[envconfig(from = "EMAIL_VERIFICATION_LINK_TTL", default = "86400")]
+    pub email_verification_link_ttl: i64,
+
+    /// Minimum delay between two verification messages for the same email, in seconds.
+    #[envconfig(from = "EMAIL_VERIFICATION_RESEND_INTERVAL", default = "60")]
+    pub email_verification_resend_interval: i64,
+
+    /// Maximum verification messages sent to the same email per hour.
+    #[envconfig(from = "EMAIL_VERIFICATION_MAX_PER_HOUR", default = "5")]
+    pub email_verification_max_per_hour: usize,
+
+    /// Wrong codes accepted before a verification code is burned.
+    #[envconfig(from = "EMAIL_VERIFICATION_MAX_ATTEMPTS", default = "5")]
+    pub email_verification_max_attempts: i32,
+
+    /// Base URL of the page that completes email verification, `?token=` is appended.
+    #[envconfig(from = "EMAIL_VERIFICATION_URL", default = "http://localhost:3000/verify-email")]
+    pub email_verification_url: String,
+
+    /// Base URL of the page that cancels or undoes an email change, `?token=` is appended.
+    #[envconfig(from = "EMAIL_CHANGE_CANCEL_URL", default = "http://localhost:3000/cancel-email-change")]
+    pub email_change_cancel_url: String,
+
+    /// How long the old address can undo a completed email change, in seconds.
+    #[envconfig(from = "EMAIL_CHANGE_UNDO_WINDOW", default = "604800")]
+    pub email_change_undo_window: i64,
+
+    /// File listing blocked (e.g. disposable) email domains, empty to disable.
+    #[envconfig(from = "EMAIL_BLOCKED_DOMAINS_FILE", default = "data/disposable_domains.txt")]
+    pub email_blocked_domains_file: String,
+
+    /// Fold Gmail dots and `+tags` when checking whether an address is already used.
+    #[envconfig(from = "EMAIL_FOLD_GMAIL", default = "false")]
+    pub email_fold_gmail: bool,
+
+    /// How outbound emails are delivered: `smtp`, `file` or `log`.
+    #[envconfig(from = "EMAIL_TRANSPORT", default = "smtp")]
+    pub email_transport: String,
+
+    #[envconfig(from = "EMAIL_FROM", default = "Ingot <no-reply@localhost>")]
+    pub email_from: String,
+
+    /// Directory the `file` transport writes `.eml` files to.
+    #[envconfig(from = "EMAIL_OUTBOX_DIR", default = "outbox")]
+    pub email_outbox_dir: String,
+
+    #[envconfig(from = "EMAIL_TEMPLATES_DIR", default = "templates/email")]
+    pub email_templates_dir: String,
+
+    #[envconfig(from = "EMAIL_DEFAULT_LOCALE", default = "en")]
+    pub email_default_locale: String,
+
+    /// Attempts before a delivery is given up on.
+    #[envconfig(from = "EMAIL_DELIVERY_MAX_ATTEMPTS", default = "8")]
+    pub email_delivery_max_attempts: i32,
+
+    /// Seconds between two passes over the delivery queue.
+    #[envconfig(from = "EMAIL_DELIVERY_POLL_INTERVAL", default = "10")]
+    pub email_delivery_poll_interval: u64,
+
+    #[envconfig(from = "SMTP_HOST", default = "localhost")]
+    pub smtp_host: String,
+
+    #[envconfig(from = "SMTP_PORT", default = "587")]
+    pub smtp_port: u16,
+
+    /// `tls`, `starttls` or `none`.
+    #[envconfig(from = "SMTP_TLS", default = "starttls")]
+    pub smtp_tls: String,
+
+    #[envconfig(from = "SMTP_USERNAME")]
+    pub smtp_username: Option<String>,
+
+    #[envconfig(from = "SMTP_PASSWORD")]
+    pub smtp_password: Option<String>,
+
+    /// Shared secret bounce and complaint webhooks must present, the webhook
+    /// server only starts when it is set to a non-empty value.
+    #[envconfig(from = "EMAIL_WEBHOOK_SECRET")]
+    pub email_webhook_secret: Option<String>,
+
+    /// Key callers present in the `x-admin-key` metadata to read and write
+    /// admin-only user attributes. Admin views are refused when unset.
+    #[envconfig(from = "ADMIN_API_KEY")]
+    pub admin_api_key: Option<String>,
+
+    /// Address the HTTP webhook server listens on.
+    #[envconfig(from = "WEBHOOK_ADDR", default = "[::1]:8080")]
+    pub webhook_addr: String,
+
+    /// SMS transport: `webhook` or `log`.
+    #[envconfig(from = "SMS_TRANSPORT", default = "log")]
+    pub sms_transport: String,
+
+    /// URL the `webhook` transport POSTs `{"to": .., "body": ..}` to.
+    #[envconfig(from = "SMS_WEBHOOK_URL", default = "http://localhost:8081/sms")]
+    pub sms_webhook_url: String,
+
+    /// Sent as a bearer token to the SMS webhook, if set.
+    #[envconfig(from = "SMS_WEBHOOK_TOKEN")]
+    pub sms_webhook_token: Option<String>,
+
+    #[envconfig(from = "SMS_TEMPLATES_DIR", default = "templates/sms")]
+    pub sms_templates_dir: String,
+
+    #[envconfig(from = "SMS_DEFAULT_LOCALE", default = "en")]
+    pub sms_default_locale: String,
+
+    /// Comma separated ISO 3166 regions SMS can be sent to, empty allows all.
+    #[envconfig(from = "SMS_ALLOWED_COUNTRIES", default = "")]
+    pub sms_allowed_countries: String,
+
+    /// Comma separated ISO 3166 regions SMS are never sent to.
+    #[envconfig(from = "SMS_DENIED_COUNTRIES", default = "")]
+    pub sms_denied_countries: String,
+
+    /// Comma separated `REGION:cost` pairs, e.g. `US:0.008,GB:0.04`.
+    #[envconfig(from = "SMS_COUNTRY_COSTS", default = "")]
+    pub sms_country_costs: String,
+
+    /// Cost assumed for regions missing from `SMS_COUNTRY_COSTS`.
+    #[envconfig(from = "SMS_DEFAULT_COST")]
+    pub sms_default_cost: Option<f64>,
+
+    /// Regions whose cost per message is above this are denied.
+    #[envconfig(from = "SMS_MAX_COST")]
+    pub sms_max_cost: Option<f64>,
+
+    /// Minimum delay between two SMS to the same number, in seconds.
+    #[envconfig(from = "SMS_RESEND_INTERVAL", default = "60")]
+    pub sms_resend_interval: i64,
+
+    #[envconfig(from = "SMS_MAX_PER_NUMBER_PER_HOUR", default = "5")]
+    pub sms_max_per_number_per_hour: i64,
+
+    #[envconfig(from = "SMS_MAX_PER_IP_PER_HOUR", default = "20")]
+    pub sms_max_per_ip_per_hour: i64,
+
+    /// Push notification transport: `webhook` or `log`.
+    #[envconfig(from = "PUSH_TRANSPORT", default = "log")]
+    pub push_transport: String,
+
+    /// URL the `webhook` transport POSTs `{"to": .., "title": .., "body": .., "data": ..}` to.
+    #[envconfig(from = "PUSH_WEBHOOK_URL", default = "http://localhost:8082/push")]
+    pub push_webhook_url: String,
+
+    /// Sent as a bearer token to the push webhook, if set.
+    #[envconfig(from = "PUSH_WEBHOOK_TOKEN")]
+    pub push_webhook_token: Option<String>,
+
+    /// Lifetime of a phone verification code, in seconds.
+    #[envconfig(from = "PHONE_VERIFICATION_CODE_TTL", default = "600")]
+    pub phone_verification_code_ttl: i64,
+
+    /// Wrong codes allowed before a phone verification code is burned.
+    #[envconfig(from = "PHONE_VERIFICATION_MAX_ATTEMPTS", default = "5")]
+    pub phone_verification_max_attempts: i32,
+
+    /// uap-core style regexes used to classify user agents, woothee covers what they miss.
+    #[envconfig(from = "USER_AGENT_REGEXES_FILE", default = "config/regexes.yaml")]
+    pub user_agent_regexes_file: String,
+
+    /// MaxMind-format city database used to locate devices and sessions, empty to disable.
+    #[envconfig(from = "GEOIP_CITY_DATABASE", default = "data/GeoLite2-City.mmdb")]
+    pub geoip_city_database: String,
+
+    /// MaxMind-format ASN database, empty to disable.
+    #[envconfig(from = "GEOIP_ASN_DATABASE", default = "data/GeoLite2-ASN.mmdb")]
+    pub geoip_asn_database: String,
+
+    /// How often the GeoIP databases are checked for changes, in seconds.
+    #[envconfig(from = "GEOIP_RELOAD_INTERVAL", default = "60")]
+    pub geoip_reload_interval: u64,
+
+    /// Seconds without use after which a session ends, 0 for no limit.
+    #[envconfig(from = "SESSION_IDLE_TIMEOUT", default = "1209600")]
+    pub session_idle_timeout: i64,
+
+    /// Seconds a sign-in or refresh keeps the session alive for.
+    #[envconfig(from = "SESSION_SLIDING_LIFETIME", default = "2592000")]
+    pub session_sliding_lifetime: i64,
+
+    /// Seconds after sign-in a session ends, however often it is refreshed.
+    #[envconfig(from = "SESSION_ABSOLUTE_LIFETIME", default = "7776000")]
+    pub session_absolute_lifetime: i64,
+
+    /// Active sessions a user can have at once, 0 for no limit.
+    #[envconfig(from = "SESSION_MAX_PER_USER", default = "0")]
+    pub session_max_per_user: i64,
+
+    /// Active sessions a user can have at once on the same type of device
+    /// (desktop, mobile, ...), 0 for no limit.
+    #[envconfig(from = "SESSION_MAX_PER_DEVICE_TYPE", default = "0")]
+    pub session_max_per_device_type: i64,
+
+    /// What happens to a login over a session limit: `reject` it or
+    /// `evict_oldest` session to make room.
+    #[envconfig(from = "SESSION_LIMIT_ACTION", default = "evict_oldest")]
+    pub session_limit_action: String,
+
+    /// Seconds between two passes of the task invalidating stale sessions.
+    #[envconfig(from = "SESSION_REAPER_INTERVAL", default = "60")]
+    pub session_reaper_interval: u64,
+
+    /// Where sessions, users and the token deny-list are cached: `redis`, or
+    /// `memory` for a single instance.
+    #[envconfig(from = "CACHE_BACKEND", default = "memory")]
+    pub cache_backend: String,
+
+    #[envconfig(from = "REDIS_URL", default = "redis://localhost:6379")]
+    pub redis_url: String,
+
+    /// Seconds a live session is cached for, revocations that don't go
+    /// through the Sessions or Devices services can take this long to apply.
+    #[envconfig(from = "CACHE_SESSION_TTL", default = "30")]
+    pub cache_session_ttl: u64,
+
+    /// Seconds a user is cached for when validating access tokens.
+    #[envconfig(from = "CACHE_USER_TTL", default = "60")]
+    pub cache_user_ttl: u64,
+
+    /// Seconds between two checks that the token deny-list needs rebuilding
+    /// from Postgres, e.g. after Redis was unreachable.
+    #[envconfig(from = "CACHE_SYNC_INTERVAL", default = "30")]
+    pub cache_sync_interval: u64,
+
+    /// Confidence from 0 to 1 a fingerprint needs to be taken for an already
+    /// registered device instead of a new one.
+    #[envconfig(from = "DEVICE_MATCH_THRESHOLD", default = "0.5")]
+    pub device_match_threshold: f64,
+
+    /// Secret signing "remember this device" tokens, devices can't be trusted when unset.
+    #[envconfig(from = "DEVICE_TRUST_SECRET")]
+    pub device_trust_secret: Option<String>,
+
+    /// How long a trusted device skips the second factor, in seconds.
+    #[envconfig(from = "DEVICE_TRUST_TTL", default = "2592000")]
+    pub device_trust_ttl: i64,
+
+    /// Lifetime of a second factor challenge, in seconds.
+    #[envconfig(from = "MFA_CHALLENGE_TTL", default = "300")]
+    pub mfa_challenge_ttl: i64,
+
+    /// Wrong codes accepted before a second factor challenge is denied.
+    #[envconfig(from = "MFA_MAX_ATTEMPTS", default = "5")]
+    pub mfa_max_attempts: i32,
+}
+
+impl Config {
+    /// Loads the email validation policy, including the blocked domains file.
+    pub fn email_policy(&self) -> Result<utils::EmailPolicy, String> {
+        let blocked_domains_file = Some(self.email_blocked_domains_file.as_str()).filter(|path| !path.is_empty());
+        utils::EmailPolicy::load(blocked_domains_file, self.email_fold_gmail)
+    }
+
+    /// The webhook secret, `None` when unset or blank so an empty token can
+    /// never authenticate a request.
+    pub fn webhook_secret(&self) -> Option<String> {
+        self.email_webhook_secret
+            .clone()
+            .filter(|secret| !secret.trim().is_empty())
+    }
+
+    /// The admin API key, `None` when unset or blank.
+    pub fn admin_api_key(&self) -> Option<String> {
+        self.admin_api_key
+            .clone()
+            .filter(|key| !key.trim().is_empty())
+    }
+
+    /// The reserved usernames, normalized the same way as user input.
+    pub fn reserved_usernames(&self) -> Vec<String> {
+        self.reserved_usernames
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(utils::normalize_username)
+            .collect()
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use deadpool::Runtime;
+use diesel_async::pooled_connection::deadpool::{Object, Pool};
+use diesel_async::pooled_connection::{AsyncDieselConnectionManager, ManagerConfig, RecyclingMethod};
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+
+use crate::config::Config;
+
+/// Postgres connections shared by every RPC and background task.
+pub type DbPool = Pool<AsyncPgConnection>;
+
+/// A connection checked out of the pool, returned to it when dropped.
+pub type DbConnection = Object<AsyncPgConnection>;
+
+/// Builds the pool, connections are opened as they are needed.
+///
+/// With health checks on, connections are checked with `SELECT 1` before
+/// being handed out again, so a Postgres restart only fails the requests
+/// that were in flight.
+pub fn build_pool(config: &Config) -> Result<DbPool, String> {
+    let mut manager_config = ManagerConfig::default();
+    manager_config.recycling_method = if config.database_pool_health_check {
+        RecyclingMethod::Verified
+    } else {
+        RecyclingMethod::Fast
+    };
+    let manager = AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_config(
+        &config.database_url,
+        manager_config,
+    );
+
+    let connect_timeout = Duration::from_secs(config.database_connect_timeout);
+
+    Pool::builder(manager)
+        .max_size(config.database_pool_size)
+        .wait_timeout(Some(Duration::from_secs(config.database_pool_wait_timeout)))
+        .create_timeout(Some(connect_timeout))
+        .recycle_timeout(Some(connect_timeout))
+        .runtime(Runtime::Tokio1)
+        .build()
+        .map_err(|e| format!("Error building the database pool: {}", e))
+}
+
+/// A single connection outside the pool, for the command line.
+pub async fn connect(database_url: &str) -> Result<AsyncPgConnection, String> {
+    AsyncPgConnection::establish(database_url)
+        .await
+        .map_err(|e| format!("Error connecting to {}: {}", database_url, e))
+}
@@ -0,0 +1,198 @@
+use chrono::{Duration, Utc};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::mailer::Mailer;
+use crate::models;
+use crate::repository::{EmailRepository, RepositoryError};
+use crate::utils::NormalizedEmail;
+use crate::verification::{self, VerificationError};
+
+pub enum EmailChangeError {
+    /// The user has no primary email to change (or doesn't exist).
+    NoPrimaryEmail,
+    SameEmail,
+    /// The new address belongs to another user.
+    EmailTaken,
+    Verification(VerificationError),
+    /// The cancel/undo token is wrong, or the request was already cancelled or reverted.
+    InvalidToken,
+    UndoExpired,
+    /// The old address was deleted, so there is nothing to revert to.
+    OldEmailMissing,
+    /// A notice to the old address couldn't be rendered.
+    Template(String),
+    Database(RepositoryError),
+}
+
+impl From<RepositoryError> for EmailChangeError {
+    fn from(error: RepositoryError) -> Self {
+        EmailChangeError::Database(error)
+    }
+}
+
+impl From<VerificationError> for EmailChangeError {
+    fn from(error: VerificationError) -> Self {
+        EmailChangeError::Verification(error)
+    }
+}
+
+impl std::fmt::Display for EmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailChangeError::NoPrimaryEmail => write!(f, "User has no primary email"),
+            EmailChangeError::SameEmail => write!(f, "Email is already the primary email"),
+            EmailChangeError::EmailTaken => write!(f, "Email is already in use"),
+            EmailChangeError::Verification(e) => write!(f, "{}", e),
+            EmailChangeError::InvalidToken => write!(f, "Invalid or already used email change token"),
+            EmailChangeError::UndoExpired => write!(f, "The email change can't be undone anymore"),
+            EmailChangeError::OldEmailMissing => write!(f, "The previous email no longer exists"),
+            EmailChangeError::Template(e) => write!(f, "Error rendering email: {}", e),
+            EmailChangeError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+/// Starts changing the primary email of a user to `address`.
+///
+/// The new address gets a verification email and the current primary one a
+/// link to cancel the change. The swap only happens once the new address is
+/// verified, see `complete_email_change`. An address the user already
+/// verified is swapped right away.
+pub async fn request_email_change(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    mailer: &Mailer,
+    user_uuid: Uuid,
+    address: NormalizedEmail,
+    locale: &str,
+) -> Result<models::EmailChangeRequest, EmailChangeError> {
+    let old_email = emails.find_by_user(user_uuid).await?
+        .into_iter()
+        .find(|email| email.is_primary)
+        .ok_or(EmailChangeError::NoPrimaryEmail)?;
+
+    if old_email.normalized_value == address.normalized {
+        return Err(EmailChangeError::SameEmail);
+    }
+
+    let existing = match emails.find_by_value(address.normalized.clone()).await {
+        Ok(email) if email.user_uuid == user_uuid => Some(email),
+        Ok(_) => return Err(EmailChangeError::EmailTaken),
+        Err(RepositoryError::Database(DieselError::NotFound)) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    // Everything that can refuse the change is checked before anything is stored.
+    match &existing {
+        Some(email) if email.is_verified => {}
+        Some(email) => verification::check_sendable(emails, config, email).await?,
+        None => {
+            if emails.is_suppressed(&address.normalized).await? {
+                return Err(VerificationError::Suppressed.into());
+            }
+        }
+    }
+
+    let new_email = match existing {
+        Some(email) => email,
+        None => emails.add_to_user(user_uuid, address).await.map_err(|e| match e {
+            RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => EmailChangeError::EmailTaken,
+            e => EmailChangeError::Database(e),
+        })?,
+    };
+
+    // Only one change can be pending at a time, the latest one wins.
+    let token = verification::generate_token();
+    let request = emails.start_change(models::NewEmailChangeRequest {
+        user_uuid,
+        old_email_uuid: Some(old_email.email_uuid),
+        new_email_uuid: new_email.email_uuid,
+        locale: locale.to_string(),
+        cancel_token_hash: verification::hash_token(&token),
+        expires_at: Utc::now() + Duration::seconds(config.email_verification_link_ttl),
+    }).await?;
+
+    if new_email.is_verified {
+        return Ok(complete_email_change(emails, config, mailer, new_email.email_uuid).await?.unwrap_or(request));
+    }
+
+    let notice = mailer.render_email_change_requested(&old_email.value, locale, &new_email.value, &token)
+        .map_err(EmailChangeError::Template)?;
+    verification::issue_and_send(emails, config, mailer, new_email, locale, vec![notice]).await?;
+
+    Ok(request)
+}
+
+/// Swaps the primary email if a pending change was waiting for `new_email_uuid`
+/// to be verified, and sends the old address a link to undo it.
+///
+/// # Returns
+/// * `Ok(Some(request))` - The change was completed.
+/// * `Ok(None)` - No change was pending for this email.
+pub async fn complete_email_change(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    mailer: &Mailer,
+    new_email_uuid: Uuid,
+) -> Result<Option<models::EmailChangeRequest>, EmailChangeError> {
+    let Some(request) = emails.find_pending_change(new_email_uuid).await? else {
+        return Ok(None);
+    };
+
+    let new_email = emails.find(new_email_uuid).await?;
+    let old_email = match request.old_email_uuid {
+        Some(old_email_uuid) => match emails.find(old_email_uuid).await {
+            Ok(email) => Some(email),
+            Err(RepositoryError::Database(DieselError::NotFound)) => None,
+            Err(e) => return Err(e.into()),
+        },
+        None => None,
+    };
+
+    let token = verification::generate_token();
+    let undo_expires_at = Utc::now() + Duration::seconds(config.email_change_undo_window);
+    let notice = match old_email {
+        Some(old_email) => Some(
+            mailer.render_email_changed(&old_email.value, &request.locale, &new_email.value, &token, &undo_expires_at.to_rfc3339())
+                .map_err(EmailChangeError::Template)?
+        ),
+        None => None,
+    };
+
+    let request = emails.complete_change(request.request_uuid, verification::hash_token(&token), undo_expires_at, notice).await?;
+    Ok(Some(request))
+}
+
+/// Cancels a pending change, or undoes a completed one during the undo
+/// window, with a token sent to the old address.
+///
+/// Undoing makes the old address primary again, drops the new one and
+/// revokes every session of the user, since whoever made the change may
+/// still be signed in. Returns the change with the sessions revoked.
+pub async fn cancel_email_change(
+    emails: &dyn EmailRepository,
+    token: &str,
+) -> Result<(models::EmailChangeRequest, Vec<Uuid>), EmailChangeError> {
+    let request = emails.find_change_by_token_hash(&verification::hash_token(token.trim())).await?
+        .ok_or(EmailChangeError::InvalidToken)?;
+
+    match request.status {
+        models::EmailChangeStatusEnum::Pending => Ok((emails.cancel_change(request.request_uuid).await?, vec![])),
+        models::EmailChangeStatusEnum::Completed => {
+            if !request.undo_expires_at.is_some_and(|undo_expires_at| undo_expires_at > Utc::now()) {
+                return Err(EmailChangeError::UndoExpired);
+            }
+            if request.old_email_uuid.is_none() {
+                return Err(EmailChangeError::OldEmailMissing);
+            }
+
+            emails.revert_change(request.request_uuid).await.map_err(|e| match e {
+                RepositoryError::Database(DieselError::NotFound) => EmailChangeError::OldEmailMissing,
+                e => EmailChangeError::Database(e),
+            })
+        }
+        _ => Err(EmailChangeError::InvalidToken),
+    }
+}
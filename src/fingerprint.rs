@@ -0,0 +1,322 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::models;
+use crate::repository::{DeviceRepository, RepositoryResult};
+use crate::utils::ClientInfo;
+
+/// Addresses are grouped by network: a home or office usually sits in a
+/// single IPv4 /24 or IPv6 /48.
+const IPV4_PREFIX_LENGTH: u8 = 24;
+const IPV6_PREFIX_LENGTH: u8 = 48;
+
+const MAX_CLIENT_DEVICE_ID_LENGTH: usize = 128;
+
+/// Registered devices scored against a fingerprint, most recently used first.
+const MAX_CANDIDATES: i64 = 20;
+
+// How much each signal shared with a registered device adds to the confidence
+// of a match. The user agent and network alone stay under the default
+// threshold, two browsers of the same model behind one NAT look alike.
+const CLIENT_DEVICE_ID_WEIGHT: f64 = 0.6;
+const MAC_ADDRESS_WEIGHT: f64 = 0.5;
+const USER_AGENT_WEIGHT: f64 = 0.25;
+const IP_PREFIX_WEIGHT: f64 = 0.15;
+const OWNER_WEIGHT: f64 = 0.1;
+
+#[derive(Debug)]
+pub enum FingerprintError {
+    InvalidClientDeviceId,
+    InvalidMacAddress,
+}
+
+impl fmt::Display for FingerprintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FingerprintError::InvalidClientDeviceId => write!(
+                f,
+                "Client device ID must be at most {} characters",
+                MAX_CLIENT_DEVICE_ID_LENGTH
+            ),
+            FingerprintError::InvalidMacAddress => write!(f, "Invalid MAC address"),
+        }
+    }
+}
+
+/// What identifies a device across registrations.
+pub struct DeviceFingerprint {
+    /// Stable ID the client generated and kept, e.g. in local storage.
+    pub client_device_id: Option<String>,
+    /// Normalized to `AA:BB:CC:DD:EE:FF`.
+    pub mac_address: Option<String>,
+    /// Hash of the browser, OS and device type, ignoring minor versions.
+    pub user_agent_hash: String,
+    pub ip_prefix: IpNet,
+}
+
+/// A registered device a fingerprint was matched to.
+pub struct DeviceMatch {
+    pub device: models::Device,
+    /// From 0 to 1.
+    pub confidence: f64,
+}
+
+impl DeviceFingerprint {
+    pub fn new(
+        client_device_id: Option<&str>,
+        mac_address: Option<&str>,
+        client: &ClientInfo,
+        ip: IpAddr,
+    ) -> Result<Self, FingerprintError> {
+        let client_device_id = client_device_id.map(str::trim).filter(|id| !id.is_empty());
+        if client_device_id.is_some_and(|id| id.chars().count() > MAX_CLIENT_DEVICE_ID_LENGTH) {
+            return Err(FingerprintError::InvalidClientDeviceId);
+        }
+
+        let mac_address = match mac_address.map(str::trim).filter(|mac| !mac.is_empty()) {
+            Some(mac) => Some(normalize_mac_address(mac).ok_or(FingerprintError::InvalidMacAddress)?),
+            None => None,
+        };
+
+        Ok(Self {
+            client_device_id: client_device_id.map(str::to_string),
+            mac_address,
+            user_agent_hash: user_agent_hash(client),
+            ip_prefix: ip_prefix(ip),
+        })
+    }
+
+    /// The MAC address, unless it is one of the random ones phones use to
+    /// avoid being tracked: those change between networks and identify nothing.
+    pub fn stable_mac_address(&self) -> Option<&str> {
+        self.mac_address.as_deref().filter(|mac| !is_locally_administered(mac))
+    }
+
+    /// How confident we are that the fingerprint was taken from `device`,
+    /// from 0 to 1.
+    ///
+    /// Devices owned by another user, or whose client reported a different
+    /// stable ID, are never the same device.
+    pub fn confidence(&self, device: &models::Device, user_uuid: Option<Uuid>) -> f64 {
+        let mut confidence = 0.0;
+
+        match (user_uuid, device.user_uuid) {
+            (Some(user_uuid), Some(owner)) if user_uuid != owner => return 0.0,
+            (Some(_), Some(_)) => confidence += OWNER_WEIGHT,
+            _ => {}
+        }
+
+        match (&self.client_device_id, &device.client_device_id) {
+            (Some(id), Some(other)) if id != other => return 0.0,
+            (Some(_), Some(_)) => confidence += CLIENT_DEVICE_ID_WEIGHT,
+            _ => {}
+        }
+
+        if self.stable_mac_address().is_some_and(|mac| device.mac_address.as_deref() == Some(mac)) {
+            confidence += MAC_ADDRESS_WEIGHT;
+        }
+
+        if device.user_agent_hash.as_deref() == Some(self.user_agent_hash.as_str()) {
+            confidence += USER_AGENT_WEIGHT;
+        }
+
+        if device.ip_prefix == Some(self.ip_prefix) {
+            confidence += IP_PREFIX_WEIGHT;
+        }
+
+        f64::min(confidence, 1.0)
+    }
+}
+
+/// Finds the registered device the fingerprint most likely belongs to, if
+/// any is at least `threshold` confident.
+pub async fn find_match(
+    devices: &dyn DeviceRepository,
+    fingerprint: &DeviceFingerprint,
+    user_uuid: Option<Uuid>,
+    threshold: f64,
+) -> RepositoryResult<Option<DeviceMatch>> {
+    let candidates = devices.find_fingerprint_candidates(
+        fingerprint.client_device_id.as_deref(),
+        fingerprint.stable_mac_address(),
+        &fingerprint.user_agent_hash,
+        fingerprint.ip_prefix,
+        MAX_CANDIDATES,
+    ).await?;
+
+    // Candidates come most recently used first, which wins ties.
+    let best = candidates
+        .into_iter()
+        .map(|device| {
+            let confidence = fingerprint.confidence(&device, user_uuid);
+            DeviceMatch { device, confidence }
+        })
+        .fold(None, |best: Option<DeviceMatch>, candidate| match best {
+            Some(best) if best.confidence >= candidate.confidence => Some(best),
+            _ => Some(candidate),
+        });
+
+    Ok(best.filter(|best| best.confidence > 0.0 && best.confidence >= threshold))
+}
+
+/// Accepts `aa:bb:cc:dd:ee:ff`, `aa-bb-cc-dd-ee-ff`, `aabb.ccdd.eeff` and
+/// `aabbccddeeff`. Group and broadcast addresses don't belong to a device.
+pub fn normalize_mac_address(mac: &str) -> Option<String> {
+    let digits: String = mac.chars().filter(|c| !matches!(c, ':' | '-' | '.')).collect();
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let octets: Vec<u8> = (0..6)
+        .map(|i| u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    if octets[0] & 0x01 != 0 || octets.iter().all(|octet| *octet == 0) {
+        return None;
+    }
+
+    Some(octets.iter().map(|octet| format!("{:02X}", octet)).collect::<Vec<_>>().join(":"))
+}
+
+fn is_locally_administered(mac: &str) -> bool {
+    u8::from_str_radix(&mac[..2], 16).is_ok_and(|octet| octet & 0x02 != 0)
+}
+
+fn ip_prefix(ip: IpAddr) -> IpNet {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    let prefix_length = match ip {
+        IpAddr::V4(_) => IPV4_PREFIX_LENGTH,
+        IpAddr::V6(_) => IPV6_PREFIX_LENGTH,
+    };
+
+    IpNet::new(ip, prefix_length)
+        .expect("prefix length is valid for the address family")
+        .trunc()
+}
+
+/// Browsers update themselves every few weeks, only major versions are kept
+/// so a device still matches after an update.
+fn user_agent_hash(client: &ClientInfo) -> String {
+    fn major(version: &Option<String>) -> &str {
+        version.as_deref().and_then(|version| version.split('.').next()).unwrap_or("")
+    }
+
+    let normalized = format!(
+        "{:?}|{:?}|{}|{}|{}",
+        client.device_type,
+        client.os,
+        major(&client.os_version),
+        client.browser.as_deref().unwrap_or("").to_lowercase(),
+        major(&client.browser_version),
+    );
+
+    let mut hasher = Sha256::new();
+    hasher.update(normalized.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::models::{DeviceTypeEnum, OsEnum};
+    use crate::repository::MemoryRepository;
+    use crate::testing;
+
+    const THRESHOLD: f64 = 0.5;
+
+    fn chrome(version: &str) -> ClientInfo {
+        ClientInfo {
+            device_type: DeviceTypeEnum::Desktop,
+            os: OsEnum::Windows,
+            os_version: Some("10".to_string()),
+            browser: Some("Chrome".to_string()),
+            browser_version: Some(version.to_string()),
+        }
+    }
+
+    fn fingerprint(client_device_id: Option<&str>, client: &ClientInfo, ip: &str) -> DeviceFingerprint {
+        DeviceFingerprint::new(client_device_id, None, client, ip.parse().unwrap()).unwrap()
+    }
+
+    async fn register(repository: &MemoryRepository, fingerprint: &DeviceFingerprint, user_uuid: Option<Uuid>) -> models::Device {
+        DeviceRepository::create(repository, models::NewDevice {
+            user_uuid,
+            device_type: DeviceTypeEnum::Desktop,
+            os: OsEnum::Windows,
+            os_version: None,
+            browser: None,
+            browser_version: None,
+            ip_address: "203.0.113.7/32".parse().unwrap(),
+            location: None,
+            status: models::DeviceStatusEnum::Active,
+            mac_address: fingerprint.mac_address.clone(),
+            client_device_id: fingerprint.client_device_id.clone(),
+            user_agent_hash: Some(fingerprint.user_agent_hash.clone()),
+            ip_prefix: Some(fingerprint.ip_prefix),
+        }, json!({})).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_same_device_is_matched_with_full_confidence() {
+        let repository = MemoryRepository::new();
+        let user_uuid = Some(testing::create_user(&repository, "alice").await.user_uuid);
+        let registered = fingerprint(Some("install-1"), &chrome("120.0.6099"), "203.0.113.7");
+        let device = register(&repository, &registered, user_uuid).await;
+
+        // Another address of the same network.
+        let seen = fingerprint(Some("install-1"), &chrome("120.0.6099"), "203.0.113.99");
+        let found = find_match(&repository, &seen, user_uuid, THRESHOLD).await.unwrap().unwrap();
+
+        assert_eq!(found.device.device_uuid, device.device_uuid);
+        assert_eq!(found.confidence, 1.0);
+    }
+
+    #[tokio::test]
+    async fn browser_updates_keep_matching() {
+        let repository = MemoryRepository::new();
+        let device = register(&repository, &fingerprint(Some("install-1"), &chrome("120.0.6099"), "203.0.113.7"), None).await;
+
+        // Minor updates leave the user agent hash alone.
+        let patched = fingerprint(Some("install-1"), &chrome("120.0.6167"), "203.0.113.7");
+        assert_eq!(patched.user_agent_hash, device.user_agent_hash.clone().unwrap());
+        let found = find_match(&repository, &patched, None, THRESHOLD).await.unwrap().unwrap();
+        assert_eq!(found.device.device_uuid, device.device_uuid);
+        assert_eq!(found.confidence, 1.0);
+
+        // A major update changes it, the client device ID still carries the match.
+        let upgraded = fingerprint(Some("install-1"), &chrome("121.0.6167"), "203.0.113.7");
+        assert_ne!(upgraded.user_agent_hash, device.user_agent_hash.clone().unwrap());
+        let found = find_match(&repository, &upgraded, None, THRESHOLD).await.unwrap().unwrap();
+        assert_eq!(found.device.device_uuid, device.device_uuid);
+        assert!((found.confidence - (CLIENT_DEVICE_ID_WEIGHT + IP_PREFIX_WEIGHT)).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn unrelated_devices_are_not_matched() {
+        let repository = MemoryRepository::new();
+        let owner = testing::create_user(&repository, "alice").await.user_uuid;
+        register(&repository, &fingerprint(Some("install-1"), &chrome("120.0.6099"), "203.0.113.7"), Some(owner)).await;
+
+        // The same browser model behind the same NAT stays under the threshold.
+        let look_alike = fingerprint(None, &chrome("120.0.6099"), "203.0.113.8");
+        assert!(find_match(&repository, &look_alike, None, THRESHOLD).await.unwrap().is_none());
+
+        // Another stable ID, or another owner, is never the same device.
+        let reinstalled = fingerprint(Some("install-2"), &chrome("120.0.6099"), "203.0.113.7");
+        assert!(find_match(&repository, &reinstalled, Some(owner), THRESHOLD).await.unwrap().is_none());
+
+        let someone_else = fingerprint(Some("install-1"), &chrome("120.0.6099"), "203.0.113.7");
+        assert!(find_match(&repository, &someone_else, Some(Uuid::new_v4()), THRESHOLD).await.unwrap().is_none());
+    }
+}
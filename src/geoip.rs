@@ -0,0 +1,208 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration as StdDuration, SystemTime};
+
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// Where an IP address is, as far as the GeoIP databases know.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GeoLocation {
+    /// ISO 3166 country code, e.g. `FR`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    /// ISO 3166-2 code of the main subdivision, e.g. `IDF`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f64>,
+    /// Radius around the coordinates the address is in, in kilometers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accuracy_radius: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_organization: Option<String>,
+}
+
+impl GeoLocation {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Whether an address can be located at all: private, loopback, link-local
+/// and other reserved ranges never are.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space used by carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Documentation prefix, 2001:db8::/32.
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// One `.mmdb` file, reopened whenever it changes on disk.
+struct GeoDatabase {
+    path: String,
+    reader: RwLock<Option<Arc<Reader<Vec<u8>>>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl GeoDatabase {
+    fn new(path: &str) -> Self {
+        let database = GeoDatabase {
+            path: path.to_string(),
+            reader: RwLock::new(None),
+            modified: Mutex::new(None),
+        };
+        database.reload_if_changed();
+        database
+    }
+
+    fn reader(&self) -> Option<Arc<Reader<Vec<u8>>>> {
+        self.reader.read().unwrap().clone()
+    }
+
+    /// Reopens the file if its modification time changed since it was last
+    /// loaded. A file that is missing or fails to open keeps the previous
+    /// version in use, so a half-written update can't disable lookups.
+    fn reload_if_changed(&self) {
+        if self.path.is_empty() {
+            return;
+        }
+
+        let modified = match std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return,
+        };
+
+        let mut loaded = self.modified.lock().unwrap();
+        if *loaded == Some(modified) {
+            return;
+        }
+
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                println!("Loaded GeoIP database {} ({})", self.path, reader.metadata.database_type);
+                *self.reader.write().unwrap() = Some(Arc::new(reader));
+                *loaded = Some(modified);
+            }
+            Err(e) => println!("Error loading GeoIP database {}: {}", self.path, e),
+        }
+    }
+}
+
+/// Offline IP geolocation backed by a city and an ASN database.
+///
+/// Either database may be missing, lookups then only return what the other
+/// one knows.
+pub struct GeoIp {
+    city: GeoDatabase,
+    asn: GeoDatabase,
+}
+
+impl GeoIp {
+    pub fn from_config(config: &Config) -> Self {
+        GeoIp {
+            city: GeoDatabase::new(&config.geoip_city_database),
+            asn: GeoDatabase::new(&config.geoip_asn_database),
+        }
+    }
+
+    pub fn reload_if_changed(&self) {
+        self.city.reload_if_changed();
+        self.asn.reload_if_changed();
+    }
+
+    /// Locates a public address, `None` for reserved ranges or addresses
+    /// neither database knows.
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        if !is_public_ip(ip) {
+            return None;
+        }
+
+        let mut location = GeoLocation::default();
+
+        if let Some(reader) = self.city.reader() {
+            if let Ok(city) = reader.lookup::<geoip2::City>(ip) {
+                location.country = city.country.and_then(|country| country.iso_code).map(str::to_string);
+                location.region = city.subdivisions
+                    .and_then(|subdivisions| subdivisions.into_iter().next())
+                    .and_then(|subdivision| subdivision.iso_code)
+                    .map(str::to_string);
+                location.city = city.city
+                    .and_then(|city| city.names)
+                    .and_then(|names| names.get("en").copied())
+                    .map(str::to_string);
+
+                if let Some(coordinates) = city.location {
+                    location.latitude = coordinates.latitude;
+                    location.longitude = coordinates.longitude;
+                    location.accuracy_radius = coordinates.accuracy_radius;
+                    location.time_zone = coordinates.time_zone.map(str::to_string);
+                }
+            }
+        }
+
+        if let Some(reader) = self.asn.reader() {
+            if let Ok(asn) = reader.lookup::<geoip2::Asn>(ip) {
+                location.asn = asn.autonomous_system_number;
+                location.as_organization = asn.autonomous_system_organization.map(str::to_string);
+            }
+        }
+
+        (location != GeoLocation::default()).then_some(location)
+    }
+}
+
+/// Picks up updated GeoIP databases (e.g. from `geoipupdate`) without a restart.
+pub fn spawn_geoip_reloader(geoip: Arc<GeoIp>, config: &Config) {
+    let reload_interval = StdDuration::from_secs(config.geoip_reload_interval.max(1));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(reload_interval);
+        // The databases were just loaded.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let geoip = geoip.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || geoip.reload_if_changed()).await {
+                println!("GeoIP reloader panicked: {}", e);
+            }
+        }
+    });
+}
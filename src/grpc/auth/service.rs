@@ -1,21 +1,31 @@
 use serde_json::json;
 use uuid::Uuid;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use diesel::result::Error as DieselError;
 use chrono::{Utc, Duration};
-use diesel::{PgConnection};
 use tonic::{Request, Response, Status};
 use serde::{ Serialize, Deserialize };
 
 use jsonwebtoken::{encode, Header, decode, Validation, DecodingKey, EncodingKey};
 
+use crate::cache::{Cache, CachedUser};
+use crate::config::Config;
+use crate::geoip::GeoIp;
+use crate::mfa::{self, IssuedChallenge, MfaError, PushAnswer, SignInAttempt};
+use crate::push::{PushError, PushGateway};
+use crate::repository::{Repositories, RepositoryError};
+use crate::session_policy;
+use crate::sms::SmsGateway;
+use crate::token_revocation::{self, TokenRevocation};
+use crate::trusted_devices::{self, TrustError};
 use crate::utils;
 use crate::models;
 
-use ipnet::IpNet; // Import ipnet types
+use ipnet::IpNet;
 use std::net::IpAddr;
 
-use super::v1::{AccessToken, AccessTokenTokenRequest, LoginResponse, RefreshToken, Token, TokenAlgorithm, UsernameLoginRequest};
+use super::v1::{AccessToken, AccessTokenTokenRequest, CompleteMfaChallengeRequest, DeviceTrust, LoginResponse, MfaChallenge, RefreshToken, RefreshTokenRequest, RefreshTokenResponse, RespondToMfaChallengeRequest, RespondToMfaChallengeResponse, RevokeTokenRequest, RevokeTokenResponse, SessionLimit, SessionLimitAction, Token, TokenAlgorithm, UsernameLoginRequest};
 use super::v1::auth_server::Auth;
 use crate::grpc::users::v1::UserResponse;
 
@@ -25,7 +35,11 @@ pub struct AccessTokenClaims {
     sub: String,
     iat: i64,
     exp: i64,
-    jti: Uuid
+    jti: Uuid,
+    /// Session the token was issued for, missing from tokens issued before
+    /// sessions were checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<Uuid>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,17 +52,19 @@ pub struct RefreshTokenClaims {
 }
 
 
-async fn signin_at(user: models::User, jti: Uuid) -> Option<AccessToken> {
+async fn signin_at(user: models::User, jti: Uuid, session: &models::Session) -> Option<AccessToken> {
     let now = Utc::now();
     let iat = now.timestamp();
-    let exp = (now + Duration::weeks(1)).timestamp();
+    // Never outlives its session.
+    let exp = (now + Duration::weeks(1)).min(session.expires_at).timestamp();
 
     let claims = AccessTokenClaims { 
         iss: "example-issuer".to_string(),
         sub: user.user_uuid.to_string(),
         iat,
         exp,
-        jti
+        jti,
+        sid: Some(session.session_uuid),
     };
 
     let value = encode(&Header::default(), &claims, &EncodingKey::from_secret("hello world".as_bytes())).unwrap();
@@ -71,10 +87,11 @@ async fn signin_at(user: models::User, jti: Uuid) -> Option<AccessToken> {
 }
 
 
-async fn signin_rt(user: models::User, sid: Uuid) -> Option<RefreshToken> {
+async fn signin_rt(user: models::User, session: &models::Session) -> Option<RefreshToken> {
     let now = Utc::now();
     let iat = now.timestamp();
-    let exp = (now + Duration::days(30)).timestamp();
+    let exp = session.expires_at.timestamp();
+    let sid = session.session_uuid;
 
     let claims = RefreshTokenClaims { 
         iss: "example-issuer".to_string(),
@@ -102,15 +119,206 @@ async fn signin_rt(user: models::User, sid: Uuid) -> Option<RefreshToken> {
 }
 
 pub struct AuthService {
-    database: Arc<Mutex<PgConnection>>
+    repositories: Repositories,
+    config: Config,
+    geoip: Arc<GeoIp>,
+    sms_gateway: Arc<SmsGateway>,
+    push_gateway: Arc<PushGateway>,
+    cache: Arc<Cache>,
 }
 
 impl AuthService {
-    pub fn new(database: Arc<Mutex<PgConnection>>) -> Self {
+    pub fn new(
+        repositories: Repositories,
+        config: &Config,
+        geoip: Arc<GeoIp>,
+        sms_gateway: Arc<SmsGateway>,
+        push_gateway: Arc<PushGateway>,
+        cache: Arc<Cache>,
+    ) -> Self {
         Self {
-            database,
+            repositories,
+            config: config.clone(),
+            geoip,
+            sms_gateway,
+            push_gateway,
+            cache,
         }
     }
+
+    /// Hands the messages of a new challenge to the providers. Push tokens
+    /// the provider no longer knows are forgotten.
+    async fn deliver_challenge(&self, issued: &mut IssuedChallenge) -> Result<(), Status> {
+        if let Some(sms) = issued.sms.take() {
+            self.sms_gateway.send(sms).await
+                .map_err(|e| Status::unavailable(format!("Error sending the second factor code: {}", e)))?;
+        }
+
+        if issued.pushes.is_empty() {
+            return Ok(());
+        }
+
+        let mut delivered = 0;
+        for push in std::mem::take(&mut issued.pushes) {
+            let token = push.token.clone();
+            match self.push_gateway.send(push).await {
+                Ok(()) => delivered += 1,
+                Err(PushError::InvalidToken) => {
+                    self.repositories.devices.clear_notification_token(&token).await
+                        .map_err(|e| device_error("updating", e))?;
+                }
+                Err(e) => println!("Error sending sign-in approval push: {}", e),
+            }
+        }
+
+        if delivered == 0 {
+            return Err(Status::unavailable("No trusted device could be reached, try another second factor"));
+        }
+
+        Ok(())
+    }
+
+    /// Creates the session of a sign-in that passed every factor and issues its tokens.
+    async fn start_session(
+        &self,
+        user: models::User,
+        device_uuid: Uuid,
+        ip_address: IpNet,
+    ) -> Result<LoginResponse, Status> {
+        let location = self.geoip.lookup(ip_address.addr()).map(|location| location.to_json());
+
+        let now = Utc::now();
+
+        let limits = session_policy::resolve_limits(self.repositories.sessions.as_ref(), &self.config, user.user_uuid).await
+            .map_err(|e| session_error("resolving the limits of", e))?;
+        let deadlines = limits.deadlines(now);
+
+        let device = self.repositories.devices.find(device_uuid).await
+            .map_err(|e| device_error("finding", e))?;
+
+        let new_session = models::NewSession{
+            device_uuid: device_uuid,
+            user_uuid: user.user_uuid,
+            ip_address,
+            metadata: match &location {
+                Some(location) => json!({ "location": location }),
+                None => json!({}),
+            },
+            expires_at: deadlines.expires_at,
+            idle_timeout: limits.idle_timeout,
+            sliding_lifetime: Some(limits.sliding_lifetime),
+            idle_expires_at: deadlines.idle_expires_at,
+            absolute_expires_at: Some(deadlines.absolute_expires_at),
+            last_accessed_at: Some(now),
+        };
+
+        let admitted = self.repositories.sessions.start(new_session, &limits, &device.device_type, location).await
+            .map_err(|e| session_error("creating", e))?;
+
+        let session_limit = |action: SessionLimitAction, evicted: Vec<Uuid>, limits: &session_policy::SessionLimits| SessionLimit {
+            action: action as i32,
+            evicted_session_ids: evicted.iter().map(Uuid::to_string).collect(),
+            max_sessions: limits.max_sessions.unwrap_or(0),
+            max_sessions_per_device_type: limits.max_sessions_per_device_type.unwrap_or(0),
+        };
+
+        let Some((session, evicted)) = admitted else {
+            return Ok(LoginResponse {
+                success: false,
+                message: "Too many active sessions, sign out somewhere else first".to_string(),
+                session_limit: Some(session_limit(SessionLimitAction::Rejected, vec![], &limits)),
+                ..Default::default()
+            });
+        };
+
+        self.cache.forget_sessions(&evicted).await;
+
+        let jti = Uuid::new_v4();
+
+        /*
+            - Get Device ✅
+            - Create Session ✅
+            - Assign JWT
+            - Authorization
+         */
+
+        /*
+
+            - login attempts 
+            1. Device
+            2. Store Session
+        
+        */
+
+        Ok(LoginResponse {
+            access_token: signin_at(user.clone(), jti, &session).await,
+            refresh_token: signin_rt(user.clone(), &session).await,
+            message: "".to_string(),
+            success: true,
+            mfa_challenge: None,
+            device_trust: None,
+            session_limit: (!evicted.is_empty()).then(|| session_limit(SessionLimitAction::Evicted, evicted, &limits)),
+        })
+    }
+}
+
+fn user_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("User not found"),
+        e => Status::internal(format!("Error {} user: {}", action, e)),
+    }
+}
+
+fn device_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("Device not found"),
+        e => Status::internal(format!("Error {} device: {}", action, e)),
+    }
+}
+
+/// A session a token points at that is gone can't authenticate anyone.
+fn session_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::unauthenticated("Session not found"),
+        e => Status::internal(format!("Error {} session: {}", action, e)),
+    }
+}
+
+fn token_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        e => Status::internal(format!("Error {} token: {}", action, e)),
+    }
+}
+
+fn mfa_error(error: MfaError) -> Status {
+    match error {
+        MfaError::ChallengeNotFound => Status::not_found("Challenge not found"),
+        MfaError::NoFactor => Status::failed_precondition("MFA is enabled but the user has no second factor available"),
+        MfaError::UnsupportedFactor(factor) => Status::invalid_argument(format!("Unsupported second factor '{}'", factor)),
+        MfaError::CountryNotAllowed(reason) => Status::failed_precondition(reason),
+        MfaError::Throttled { retry_at } => Status::resource_exhausted(format!(
+            "Too many codes requested, retry after {}",
+            retry_at.to_rfc3339()
+        )),
+        MfaError::Invalid => Status::invalid_argument("Invalid or expired code"),
+        MfaError::Pending => Status::failed_precondition("The challenge wasn't approved yet"),
+        MfaError::Denied => Status::permission_denied("The sign-in was denied from a trusted device"),
+        MfaError::NotTrusted => Status::permission_denied("This device can't answer the challenge"),
+        MfaError::Template(e) => Status::internal(format!("Error rendering text message: {}", e)),
+        MfaError::Database(RepositoryError::Unavailable(e)) => Status::unavailable(format!("Database unavailable: {}", e)),
+        MfaError::Database(e) => Status::internal(format!("Error checking second factor: {}", e)),
+    }
+}
+
+fn trust_error(error: TrustError) -> Status {
+    match error {
+        TrustError::Token(e) => Status::internal(format!("Error signing device trust token: {}", e)),
+        TrustError::Database(e) => device_error("trusting", e),
+    }
 }
 
 #[tonic::async_trait]
@@ -122,10 +330,8 @@ impl Auth for AuthService {
 
         let inputs = request.into_inner();
         
-        let user = {
-            let mut database = self.database.lock().unwrap();
-            models::User::find_by_username(&mut database, inputs.username).map_err(|e| Status::internal(format!("Error finding user: {}", e)))?
-        };
+        let user = self.repositories.users.find_by_username(inputs.username).await
+            .map_err(|e| user_error("finding", e))?;
 
         let valid = utils::verify_password(&inputs.password, &user.password_hash);
 
@@ -143,63 +349,153 @@ impl Auth for AuthService {
 
         let device_uuid =  Uuid::parse_str(&inputs.device_id).map_err(|e| Status::invalid_argument(format!("Invalid device ID: {}", e)))?;
 
-        // let device = {
-        //     let mut database = self.database.lock().unwrap();
-        //     models::Device::find_by_uuid(&mut database, device_uuid).map_err(|e| Status::internal(format!("Error finding device: {}", e)))?
-        // };
+        let ip = inputs.ip.trim().parse::<IpAddr>()
+            .map_err(|_| Status::invalid_argument("Invalid IP address"))?;
+        let ip_address = IpNet::from(ip);
 
-        let ip_addr = Some(inputs.ip.parse::<IpAddr>().unwrap()); // Example IP address
+        let challenge = {
+            let device = self.repositories.devices.find(device_uuid).await.map_err(|e| device_error("finding", e))?;
 
-        // Convert the IP address to a network (e.g., /24 for IPv4 or /64 for IPv6)
-        let ip_address: Option<IpNet> = ip_addr.map(|ip| {
-            match ip {
-                // Create a /24 network for IPv4 (common for local networks)
-                IpAddr::V4(ipv4) => IpNet::V4(ipv4.into()),
-                // Create a /64 network for IPv6 (common for local networks)
-                IpAddr::V6(ipv6) => IpNet::V6(ipv6.into())
+            if device.status.is_blocked() {
+                return Err(Status::permission_denied("This device can't be used to sign in"));
             }
-        });
 
-        let now = Utc::now();
-        let exp = now + Duration::days(30);
-        
-        let session = {
-            let mut database = self.database.lock().unwrap();
-        
-            let new_session = models::NewSession{
-                device_uuid: device_uuid,
-                user_uuid: user.user_uuid,
-                ip_address: ip_address.unwrap(),
-                metadata: json!({}),
-                expires_at: exp,
+            // The first user signing in with a device becomes its owner.
+            self.repositories.devices.assign_user(device_uuid, user.user_uuid).await
+                .map_err(|e| device_error("updating", e))?;
+
+            let trusted = user.mfa_enabled && trusted_devices::is_device_trusted(self.repositories.devices.as_ref(), &self.config, &inputs.device_trust_token, user.user_uuid, &device).await
+                .map_err(|e| device_error("checking the trust of", e))?;
+
+            if user.mfa_enabled && !trusted {
+                let attempt = SignInAttempt {
+                    user_uuid: user.user_uuid,
+                    device_uuid,
+                    ip_address,
+                    locale: &inputs.locale,
+                };
+                Some(mfa::start_challenge(&self.repositories, &self.config, &self.sms_gateway, &inputs.mfa_factor, &attempt).await
+                    .map_err(mfa_error)?)
+            } else {
+                None
+            }
+        };
+
+        if let Some(mut issued) = challenge {
+            self.deliver_challenge(&mut issued).await?;
+
+            return Ok(Response::new(LoginResponse {
+                success: false,
+                message: "A second factor is required".to_string(),
+                mfa_challenge: Some(MfaChallenge {
+                    id: issued.challenge.challenge_uuid.to_string(),
+                    factor: issued.challenge.factor,
+                    expires_at: issued.challenge.expires_at.timestamp(),
+                    number_match: issued.number_match.unwrap_or(0),
+                }),
+                ..Default::default()
+            }));
+        }
+
+        Ok(Response::new(self.start_session(user, device_uuid, ip_address).await?))
+    }
+
+    async fn complete_mfa_challenge(
+        &self,
+        request: Request<CompleteMfaChallengeRequest>,
+    ) -> Result<Response<LoginResponse>, Status> {
+        let inputs = request.into_inner();
+        let challenge_uuid = Uuid::parse_str(&inputs.challenge_id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+
+        let (user, challenge, device_trust) = {
+            let challenge = mfa::complete_challenge(self.repositories.mfa.as_ref(), &self.config, challenge_uuid, &inputs.code).await
+                .map_err(mfa_error)?;
+
+            let user = self.repositories.users.find(challenge.user_uuid).await
+                .map_err(|e| user_error("finding", e))?;
+
+            // The device may have been reported lost while the code was on its way.
+            let device = self.repositories.devices.find(challenge.device_uuid).await
+                .map_err(|e| device_error("finding", e))?;
+
+            if device.status.is_blocked() {
+                return Err(Status::permission_denied("This device can't be used to sign in"));
+            }
+
+            let device_trust = if inputs.remember_device {
+                trusted_devices::trust_device(self.repositories.devices.as_ref(), &self.config, user.user_uuid, device.device_uuid).await
+                    .map_err(trust_error)?
+            } else {
+                None
             };
 
-            models::Session::create(&mut database, new_session).map_err(|e| Status::internal(format!("Error creating Session: {}", e)))?
+            (user, challenge, device_trust)
         };
 
+        let mut response = self.start_session(user, challenge.device_uuid, challenge.ip_address).await?;
+        response.device_trust = device_trust.map(|trust| DeviceTrust {
+            token: trust.token,
+            expires_at: trust.expires_at.timestamp(),
+        });
 
-        let jti = Uuid::new_v4();
+        Ok(Response::new(response))
+    }
 
-        /*
-            - Get Device ✅
-            - Create Session ✅
-            - Assign JWT
-            - Authorization
-         */
+    async fn respond_to_mfa_challenge(
+        &self,
+        request: Request<RespondToMfaChallengeRequest>,
+    ) -> Result<Response<RespondToMfaChallengeResponse>, Status> {
+        let inputs = request.into_inner();
+        let challenge_uuid = Uuid::parse_str(&inputs.challenge_id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+        let device_uuid = Uuid::parse_str(&inputs.device_id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+
+        let responder = self.repositories.devices.find(device_uuid).await.map_err(|e| device_error("finding", e))?;
+
+        let challenge = mfa::respond_to_push_challenge(&self.repositories, &self.config, challenge_uuid, PushAnswer {
+            responder: &responder,
+            device_trust_token: &inputs.device_trust_token,
+            approve: inputs.approve,
+            number_match: inputs.number_match,
+        }).await.map_err(mfa_error)?;
+
+        Ok(Response::new(RespondToMfaChallengeResponse {
+            approved: challenge.status == models::MfaChallengeStatusEnum::Approved,
+        }))
+    }
 
-        /*
+    async fn refresh_token(
+        &self,
+        request: Request<RefreshTokenRequest>,
+    ) -> Result<Response<RefreshTokenResponse>, Status> {
+        let inputs = request.into_inner();
 
-            - login attempts 
-            1. Device
-            2. Store Session
-        
-        */
+        let claims = decode::<RefreshTokenClaims>(
+            &inputs.refresh_token,
+            &DecodingKey::from_secret("hello world".as_bytes()),
+            &Validation::default(),
+        ).map_err(|e| Status::unauthenticated(format!("Invalid refresh token: {}", e)))?.claims;
+
+        let session = self.repositories.sessions.find(claims.sid).await
+            .map_err(|e| session_error("finding", e))?;
+
+        if session.user_uuid.to_string() != claims.sub || !session_policy::is_live(&session, Utc::now()) {
+            return Err(Status::unauthenticated("Session has ended, sign in again"));
+        }
+
+        let session = session_policy::renew(self.repositories.sessions.as_ref(), &session).await
+            .map_err(|e| session_error("renewing", e))?;
+
+        let user = self.repositories.users.find(session.user_uuid).await
+            .map_err(|e| user_error("finding", e))?;
 
-        Ok(Response::new(LoginResponse {
-            access_token: signin_at(user.clone(), jti).await,
-            refresh_token: signin_rt(user.clone(), session.session_uuid).await,
+        Ok(Response::new(RefreshTokenResponse {
+            success: true,
+            access_token: signin_at(user.clone(), Uuid::new_v4(), &session).await,
             message: "".to_string(),
-            success: true
+            refresh_token: signin_rt(user, &session).await,
         }))
     }
 
@@ -217,18 +513,362 @@ impl Auth for AuthService {
 
         let user_uuid = Uuid::parse_str(&claims.claims.sub).map_err(|e| Status::invalid_argument(format!("Invalid user UUID: {}", e))).unwrap();
 
-        let user = {
-            let mut database = self.database.lock().unwrap();
-            models::User::find_user_uuid(&mut database, user_uuid)
-                .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?
+        let revoked = match self.cache.is_token_denied(claims.claims.jti).await {
+            Some(denied) => denied,
+            None => self.repositories.sessions.is_token_revoked(claims.claims.jti).await
+                .map_err(|e| token_error("checking", e))?,
+        };
+        if revoked {
+            return Err(Status::unauthenticated("Token has been revoked"));
+        }
+
+        if let Some(session_uuid) = claims.claims.sid {
+            let cached_live = self.cache.session_live_until(session_uuid).await.is_some_and(|until| until > Utc::now());
+
+            if !cached_live {
+                let session = self.repositories.sessions.find(session_uuid).await
+                    .map_err(|e| session_error("finding", e))?;
+
+                if !session_policy::is_live(&session, Utc::now()) {
+                    return Err(Status::unauthenticated("Session has ended, sign in again"));
+                }
+
+                session_policy::touch(self.repositories.sessions.as_ref(), &session).await
+                    .map_err(|e| session_error("updating", e))?;
+
+                self.cache.cache_session(session_uuid, session_policy::live_until(&session)).await;
+            }
+        }
+
+        let user = match self.cache.user(user_uuid).await {
+            Some(user) => user,
+            None => {
+                let user = self.repositories.users.find(user_uuid).await
+                    .map_err(|e| user_error("finding", e))?;
+
+                let user = CachedUser::from(&user);
+                self.cache.cache_user(&user).await;
+                user
+            }
         };
 
         Ok(Response::new(UserResponse {
             id: user.user_uuid.to_string(),
             username: user.username,
-            status: user.status as i32,
+            status: user.status,
             onboarded: true,
             is_verified: true,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
         }))
     }
-}
\ No newline at end of file
+
+    async fn revoke_token(
+        &self,
+        request: Request<RevokeTokenRequest>,
+    ) -> Result<Response<RevokeTokenResponse>, Status> {
+        let inputs = request.into_inner();
+
+        let claims = match decode::<AccessTokenClaims>(
+            &inputs.token,
+            &DecodingKey::from_secret("hello world".as_bytes()),
+            &Validation::default(),
+        ) {
+            Ok(token) => token.claims,
+            // Nothing accepts it anymore.
+            Err(e) if *e.kind() == jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                return Ok(Response::new(RevokeTokenResponse {
+                    success: true,
+                    message: "Token has already expired".to_string(),
+                }));
+            }
+            Err(e) => return Err(Status::invalid_argument(format!("Invalid token: {}", e))),
+        };
+
+        let user_uuid = Uuid::parse_str(&claims.sub)
+            .map_err(|e| Status::invalid_argument(format!("Invalid user UUID: {}", e)))?;
+        let expires_at = chrono::DateTime::from_timestamp(claims.exp, 0)
+            .ok_or_else(|| Status::invalid_argument("Invalid token expiry"))?;
+
+        let revoked = token_revocation::revoke_token(self.repositories.sessions.as_ref(), &self.cache, TokenRevocation {
+            jti: claims.jti,
+            user_uuid,
+            session_uuid: claims.sid,
+            expires_at,
+        }).await.map_err(|e| token_error("revoking", e))?;
+
+        Ok(Response::new(RevokeTokenResponse {
+            success: true,
+            message: if revoked { "" } else { "Token was already revoked" }.to_string(),
+        }))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{DeviceRepository, MemoryRepository, UserRepository};
+    use crate::testing;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    struct Harness {
+        repository: Arc<MemoryRepository>,
+        service: AuthService,
+        sms: Arc<testing::StubSmsSender>,
+        push: Arc<testing::StubPushSender>,
+    }
+
+    fn harness(config: &Config) -> Harness {
+        let repository = Arc::new(MemoryRepository::new());
+        let (sms_gateway, sms) = testing::sms_gateway(config);
+        let (push_gateway, push) = testing::push_gateway();
+
+        let service = AuthService::new(
+            Repositories::new(repository.clone()),
+            config,
+            Arc::new(GeoIp::from_config(config)),
+            sms_gateway,
+            push_gateway,
+            testing::cache(config),
+        );
+
+        Harness { repository, service, sms, push }
+    }
+
+    async fn login(service: &AuthService, username: &str, device_uuid: Uuid) -> LoginResponse {
+        login_with(service, username, device_uuid, "").await.unwrap()
+    }
+
+    async fn login_with(service: &AuthService, username: &str, device_uuid: Uuid, mfa_factor: &str) -> Result<LoginResponse, Status> {
+        service.username_login(Request::new(UsernameLoginRequest {
+            username: username.to_string(),
+            password: PASSWORD.to_string(),
+            device_id: device_uuid.to_string(),
+            ip: "203.0.113.7".to_string(),
+            locale: "en".to_string(),
+            mfa_factor: mfa_factor.to_string(),
+            ..Default::default()
+        })).await.map(Response::into_inner)
+    }
+
+    fn access_token(response: &LoginResponse) -> String {
+        response.access_token.as_ref().unwrap().token.as_ref().unwrap().value.clone()
+    }
+
+    async fn user_of(service: &AuthService, token: &str) -> Result<UserResponse, Status> {
+        service.get_user_token(Request::new(AccessTokenTokenRequest {
+            value: token.to_string(),
+        })).await.map(Response::into_inner)
+    }
+
+    #[tokio::test]
+    async fn a_signed_in_token_resolves_its_user_until_revoked() {
+        let Harness { repository, service, .. } = harness(&testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device = testing::create_device(&repository, None).await;
+
+        let response = login(&service, "Alice", device.device_uuid).await;
+        assert!(response.success);
+
+        let token = access_token(&response);
+        assert_eq!(user_of(&service, &token).await.unwrap().id, user.user_uuid.to_string());
+
+        service.revoke_token(Request::new(RevokeTokenRequest {
+            token: token.clone(),
+        })).await.unwrap();
+
+        let error = user_of(&service, &token).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::Unauthenticated);
+        assert!(repository.audit_events().iter().any(|event| event.event_type == "token.revoked"));
+    }
+
+    #[tokio::test]
+    async fn going_over_the_session_limit_evicts_the_oldest_session() {
+        let Harness { repository, service, .. } = harness(&testing::config(&[("SESSION_MAX_PER_USER", "1")]));
+        testing::create_user(&repository, "alice").await;
+        let device = testing::create_device(&repository, None).await;
+
+        let first = login(&service, "alice", device.device_uuid).await;
+        let second = login(&service, "alice", device.device_uuid).await;
+
+        assert!(second.success);
+        let session_limit = second.session_limit.clone().unwrap();
+        assert_eq!(session_limit.action, SessionLimitAction::Evicted as i32);
+        assert_eq!(session_limit.evicted_session_ids, vec![first.refresh_token.as_ref().unwrap().session_id.clone()]);
+
+        let error = user_of(&service, &access_token(&first)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::Unauthenticated);
+        assert!(user_of(&service, &access_token(&second)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_rejecting_role_policy_refuses_sessions_over_the_limit() {
+        let Harness { repository, service, .. } = harness(&testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device = testing::create_device(&repository, None).await;
+
+        let role_uuid = Uuid::new_v4();
+        repository.assign_role(user.user_uuid, role_uuid).unwrap();
+        repository.insert_session_policy(models::NewSessionPolicy {
+            role_uuid: Some(role_uuid),
+            org_uuid: None,
+            idle_timeout: None,
+            sliding_lifetime: None,
+            absolute_lifetime: None,
+            max_sessions: Some(1),
+            max_sessions_per_device_type: None,
+            limit_action: Some("reject".to_string()),
+        });
+
+        assert!(login(&service, "alice", device.device_uuid).await.success);
+
+        let rejected = login(&service, "alice", device.device_uuid).await;
+        assert!(!rejected.success);
+        assert!(rejected.access_token.is_none());
+        assert_eq!(rejected.session_limit.unwrap().action, SessionLimitAction::Rejected as i32);
+    }
+
+    #[tokio::test]
+    async fn mfa_sign_ins_complete_with_the_texted_code() {
+        let Harness { repository, service, sms, .. } = harness(&testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        repository.set_mfa_enabled(user.user_uuid, true).await.unwrap();
+        testing::create_phone(&repository, user.user_uuid, "+442071838750", true).await;
+        let device = testing::create_device(&repository, None).await;
+
+        let response = login(&service, "alice", device.device_uuid).await;
+        assert!(!response.success);
+        assert!(response.access_token.is_none());
+
+        let challenge = response.mfa_challenge.unwrap();
+        assert_eq!(challenge.factor, mfa::FACTOR_SMS);
+
+        let sent = sms.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "+442071838750");
+
+        let complete = |code: String| service.complete_mfa_challenge(Request::new(CompleteMfaChallengeRequest {
+            challenge_id: challenge.id.clone(),
+            code,
+            remember_device: false,
+        }));
+
+        let error = complete("000000".to_string()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+
+        let response = complete(testing::code_in(&sent[0].body)).await.unwrap().into_inner();
+        assert!(response.success);
+        assert!(user_of(&service, &access_token(&response)).await.is_ok());
+
+        // A challenge completes a single sign-in.
+        assert!(complete(testing::code_in(&sent[0].body)).await.is_err());
+
+        let events: Vec<String> = repository.audit_events().into_iter().map(|event| event.event_type).collect();
+        assert!(events.contains(&"mfa.challenge_started".to_string()));
+        assert!(events.contains(&"mfa.challenge_approved".to_string()));
+    }
+
+    /// A user with a second factor on a trusted phone that receives pushes,
+    /// and the trust token of the phone.
+    async fn user_with_trusted_phone(repository: &MemoryRepository, config: &Config) -> (models::User, models::Device, String) {
+        let user = testing::create_user(repository, "alice").await;
+        repository.set_mfa_enabled(user.user_uuid, true).await.unwrap();
+
+        let phone = testing::create_device(repository, Some(user.user_uuid)).await;
+        let phone = repository.set_notification_token(phone.device_uuid, "fcm-token-1".to_string(), "device.push_token_registered").await.unwrap();
+        let trust = trusted_devices::trust_device(repository, config, user.user_uuid, phone.device_uuid).await.unwrap().unwrap();
+
+        (user, phone, trust.token)
+    }
+
+    #[tokio::test]
+    async fn push_sign_ins_complete_once_the_trusted_device_picks_the_number() {
+        let config = testing::config(&[("DEVICE_TRUST_SECRET", "test-secret")]);
+        let Harness { repository, service, push, .. } = harness(&config);
+        let (_, phone, trust_token) = user_with_trusted_phone(&repository, &config).await;
+        let laptop = testing::create_device(&repository, None).await;
+
+        let challenge = login_with(&service, "alice", laptop.device_uuid, mfa::FACTOR_PUSH).await.unwrap().mfa_challenge.unwrap();
+        assert_eq!(challenge.factor, mfa::FACTOR_PUSH);
+        assert!((10..100).contains(&challenge.number_match));
+
+        let sent = push.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].token, "fcm-token-1");
+        assert_eq!(sent[0].data["challenge_id"], challenge.id);
+
+        let complete = || service.complete_mfa_challenge(Request::new(CompleteMfaChallengeRequest {
+            challenge_id: challenge.id.clone(),
+            code: String::new(),
+            remember_device: false,
+        }));
+
+        assert_eq!(complete().await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        let response = service.respond_to_mfa_challenge(Request::new(RespondToMfaChallengeRequest {
+            challenge_id: challenge.id.clone(),
+            device_id: phone.device_uuid.to_string(),
+            device_trust_token: trust_token,
+            approve: true,
+            number_match: challenge.number_match,
+        })).await.unwrap().into_inner();
+        assert!(response.approved);
+
+        let response = complete().await.unwrap().into_inner();
+        assert!(response.success);
+        assert!(user_of(&service, &access_token(&response)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn picking_the_wrong_number_denies_the_sign_in() {
+        let config = testing::config(&[("DEVICE_TRUST_SECRET", "test-secret")]);
+        let Harness { repository, service, .. } = harness(&config);
+        let (_, phone, trust_token) = user_with_trusted_phone(&repository, &config).await;
+        let laptop = testing::create_device(&repository, None).await;
+
+        let challenge = login_with(&service, "alice", laptop.device_uuid, mfa::FACTOR_PUSH).await.unwrap().mfa_challenge.unwrap();
+
+        let respond = |number_match: i32| service.respond_to_mfa_challenge(Request::new(RespondToMfaChallengeRequest {
+            challenge_id: challenge.id.clone(),
+            device_id: phone.device_uuid.to_string(),
+            device_trust_token: trust_token.clone(),
+            approve: true,
+            number_match,
+        }));
+
+        let wrong = if challenge.number_match == 10 { 11 } else { 10 };
+        assert_eq!(respond(wrong).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        // The right number can't undo the denial.
+        assert!(respond(challenge.number_match).await.is_err());
+
+        let error = service.complete_mfa_challenge(Request::new(CompleteMfaChallengeRequest {
+            challenge_id: challenge.id.clone(),
+            code: String::new(),
+            remember_device: false,
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn push_tokens_the_provider_rejects_are_forgotten() {
+        let config = testing::config(&[("DEVICE_TRUST_SECRET", "test-secret")]);
+        let Harness { repository, service, push, .. } = harness(&config);
+        let (_, phone, _) = user_with_trusted_phone(&repository, &config).await;
+        let laptop = testing::create_device(&repository, None).await;
+
+        push.invalidate("fcm-token-1");
+
+        let error = login_with(&service, "alice", laptop.device_uuid, mfa::FACTOR_PUSH).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::Unavailable);
+        assert!(push.sent().is_empty());
+
+        let phone = DeviceRepository::find(repository.as_ref(), phone.device_uuid).await.unwrap();
+        assert_eq!(phone.notification_token, None);
+        assert_eq!(phone.notification_enabled, Some(false));
+
+        // Without a token left, push is no longer offered.
+        let error = login_with(&service, "alice", laptop.device_uuid, mfa::FACTOR_PUSH).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,104 @@
+use serde_json::Value;
+
+use crate::grpc::users::mapping::metadata_to_map;
+use crate::models;
+
+use super::v1::{Device, DeviceStatus, DeviceType, Os};
+
+impl From<models::DeviceTypeEnum> for DeviceType {
+    fn from(device_type: models::DeviceTypeEnum) -> Self {
+        match device_type {
+            models::DeviceTypeEnum::Desktop => DeviceType::Desktop,
+            models::DeviceTypeEnum::Laptop => DeviceType::Laptop,
+            models::DeviceTypeEnum::Mobile => DeviceType::Mobile,
+            models::DeviceTypeEnum::Tablet => DeviceType::Tablet,
+            models::DeviceTypeEnum::Iot => DeviceType::Iot,
+            models::DeviceTypeEnum::Wearable => DeviceType::Wearable,
+            models::DeviceTypeEnum::GamingConsole => DeviceType::GamingConsole,
+            models::DeviceTypeEnum::SmartTv => DeviceType::SmartTv,
+            models::DeviceTypeEnum::Car => DeviceType::Car,
+            models::DeviceTypeEnum::VrHeadset => DeviceType::VrHeadset,
+            models::DeviceTypeEnum::EReader => DeviceType::EReader,
+            models::DeviceTypeEnum::Server => DeviceType::Server,
+            models::DeviceTypeEnum::Other => DeviceType::Other,
+        }
+    }
+}
+
+impl From<models::OsEnum> for Os {
+    fn from(os: models::OsEnum) -> Self {
+        match os {
+            models::OsEnum::Windows => Os::Windows,
+            models::OsEnum::Macos => Os::Macos,
+            models::OsEnum::Linux => Os::Linux,
+            models::OsEnum::Android => Os::Android,
+            models::OsEnum::Ios => Os::Ios,
+            models::OsEnum::ChromeOs => Os::ChromeOs,
+            models::OsEnum::Tizen => Os::Tizen,
+            models::OsEnum::FireOs => Os::FireOs,
+            models::OsEnum::Watchos => Os::Watchos,
+            models::OsEnum::Webos => Os::Webos,
+            models::OsEnum::Freebsd => Os::Freebsd,
+            models::OsEnum::Other => Os::Other,
+        }
+    }
+}
+
+impl From<models::DeviceStatusEnum> for DeviceStatus {
+    fn from(status: models::DeviceStatusEnum) -> Self {
+        match status {
+            models::DeviceStatusEnum::Active => DeviceStatus::Active,
+            models::DeviceStatusEnum::Inactive => DeviceStatus::Inactive,
+            models::DeviceStatusEnum::Suspended => DeviceStatus::Suspended,
+            models::DeviceStatusEnum::Banned => DeviceStatus::Banned,
+            models::DeviceStatusEnum::Pending => DeviceStatus::Pending,
+            models::DeviceStatusEnum::Retired => DeviceStatus::Retired,
+            models::DeviceStatusEnum::Lost => DeviceStatus::Lost,
+            models::DeviceStatusEnum::Stolen => DeviceStatus::Stolen,
+            models::DeviceStatusEnum::Maintenance => DeviceStatus::Maintenance,
+            models::DeviceStatusEnum::Offline => DeviceStatus::Offline,
+        }
+    }
+}
+
+impl TryFrom<DeviceStatus> for models::DeviceStatusEnum {
+    type Error = ();
+
+    fn try_from(status: DeviceStatus) -> Result<Self, Self::Error> {
+        match status {
+            DeviceStatus::Unspecified => Err(()),
+            DeviceStatus::Active => Ok(models::DeviceStatusEnum::Active),
+            DeviceStatus::Inactive => Ok(models::DeviceStatusEnum::Inactive),
+            DeviceStatus::Suspended => Ok(models::DeviceStatusEnum::Suspended),
+            DeviceStatus::Banned => Ok(models::DeviceStatusEnum::Banned),
+            DeviceStatus::Pending => Ok(models::DeviceStatusEnum::Pending),
+            DeviceStatus::Retired => Ok(models::DeviceStatusEnum::Retired),
+            DeviceStatus::Lost => Ok(models::DeviceStatusEnum::Lost),
+            DeviceStatus::Stolen => Ok(models::DeviceStatusEnum::Stolen),
+            DeviceStatus::Maintenance => Ok(models::DeviceStatusEnum::Maintenance),
+            DeviceStatus::Offline => Ok(models::DeviceStatusEnum::Offline),
+        }
+    }
+}
+
+impl From<models::Device> for Device {
+    fn from(device: models::Device) -> Self {
+        Device {
+            id: device.device_uuid.to_string(),
+            user_id: device.user_uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+            client_device_id: device.client_device_id.unwrap_or_default(),
+            browser: device.browser.unwrap_or_default(),
+            browser_version: device.browser_version.unwrap_or_default(),
+            os: Os::from(device.os) as i32,
+            os_version: device.os_version.unwrap_or_default(),
+            device_type: DeviceType::from(device.device_type) as i32,
+            status: DeviceStatus::from(device.status) as i32,
+            location: metadata_to_map(&device.location.unwrap_or(Value::Null)),
+            ip_address: device.ip_address.addr().to_string(),
+            mac_address: device.mac_address.unwrap_or_default(),
+            metadata: metadata_to_map(&device.metadata),
+            notification_enabled: device.notification_enabled.unwrap_or_default(),
+            notification_token: device.notification_token.unwrap_or_default(),
+        }
+    }
+}
@@ -1,31 +1,68 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use diesel::{PgConnection};
+use std::sync::Arc;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use serde_json::json;
 use tonic::{Request, Response, Status};
+use uuid::Uuid;
 
-use jsonwebtoken::{encode, Header, Algorithm, EncodingKey};
-
-use crate::utils;
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::fingerprint::{self, DeviceFingerprint, FingerprintError};
+use crate::geoip::GeoIp;
+use crate::grpc::errors::invalid_field;
 use crate::models;
+use crate::repository::{DeviceRepository, RepositoryError};
+use crate::utils::UserAgentClassifier;
 
-use uaparser::{Parser, UserAgentParser};
-use ipnet::IpNet; // Import ipnet types
+use ipnet::IpNet;
 use std::net::IpAddr;
 
-
 use super::v1::devices_server::Devices;
-use super::v1::{DeviceResponse, Device, DeviceType, Os, DeviceStatus, CreateDeviceRequest};
+use super::v1::{CreateDeviceRequest, DeleteDeviceRequest, DeleteDeviceResponse, DeviceResponse, DeviceStatus, GetDeviceRequest, GetDeviceResponse, ListDevicesRequest, ListDevicesResponse, ListTrustedDevicesRequest, ListTrustedDevicesResponse, RegisterPushTokenRequest, RegisterPushTokenResponse, RevokeTrustedDeviceRequest, RevokeTrustedDeviceResponse, RotatePushTokenRequest, RotatePushTokenResponse, TrustedDevice, UpdateDeviceRequest, UpdateDeviceResponse};
 
 pub struct DevicesService {
-    database: Arc<Mutex<PgConnection>>,
+    devices: Arc<dyn DeviceRepository>,
+    config: Config,
+    user_agents: Arc<UserAgentClassifier>,
+    geoip: Arc<GeoIp>,
+    cache: Arc<Cache>,
 }
 
 impl DevicesService {
-    pub fn new(database: Arc<Mutex<PgConnection>>) -> Self {
+    pub fn new(devices: Arc<dyn DeviceRepository>, config: &Config, user_agents: Arc<UserAgentClassifier>, geoip: Arc<GeoIp>, cache: Arc<Cache>) -> Self {
         Self {
-            database,
+            devices,
+            config: config.clone(),
+            user_agents,
+            geoip,
+            cache,
+        }
+    }
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::invalid_argument("Invalid UUID format"))
+}
+
+fn device_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("Device not found"),
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            Status::not_found("User not found")
         }
+        e => Status::internal(format!("Error {} device: {}", action, e)),
+    }
+}
+
+/// APNs and FCM tokens are well under this.
+const MAX_PUSH_TOKEN_LENGTH: usize = 4096;
+
+fn validate_push_token(field: &str, token: &str) -> Result<String, Status> {
+    let token = token.trim();
+    if token.is_empty() || token.len() > MAX_PUSH_TOKEN_LENGTH {
+        return Err(invalid_field(field, "Invalid push token"));
     }
+    Ok(token.to_string())
 }
 
 #[tonic::async_trait]
@@ -34,67 +71,583 @@ impl Devices for DevicesService {
         request: Request<CreateDeviceRequest>,
     ) -> Result<Response<DeviceResponse>, Status> {
        let inputs = request.into_inner();
-       
-        // Initialize the parser
-        let parser = UserAgentParser::builder().build_from_yaml("config/regexes.yaml").unwrap();
 
-        let client = parser.parse(&inputs.user_agent);
+        let user_uuid = match &inputs.user_id {
+            Some(user_id) if !user_id.is_empty() => Some(parse_uuid(user_id)?),
+            _ => None,
+        };
+
+        let client = self.user_agents.classify(&inputs.user_agent);
+
+        let ip = inputs.ip.trim().parse::<IpAddr>()
+            .map_err(|_| invalid_field("ip", "Invalid IP address"))?;
 
-        let device_type = models::DeviceTypeEnum::from_device_family(&client.device.family);
-        let os = models::OsEnum::from_device_family(&client.os.family);
+        // Locations sent by the client (e.g. from the device's GPS) are more
+        // precise than GeoIP and take precedence.
+        let mut location = self.geoip.lookup(ip).map(|location| location.to_json()).unwrap_or_else(|| json!({}));
+        for (key, value) in inputs.location {
+            location[key] = json!(value);
+        }
+
+        let fingerprint = DeviceFingerprint::new(
+            inputs.client_device_id.as_deref(),
+            inputs.mac_address.as_deref(),
+            &client,
+            ip,
+        ).map_err(|e| match e {
+            FingerprintError::InvalidClientDeviceId => invalid_field("client_device_id", e.to_string()),
+            FingerprintError::InvalidMacAddress => invalid_field("mac_address", e.to_string()),
+        })?;
+
+        let location = Some(location).filter(|location| location.as_object().is_some_and(|location| !location.is_empty()));
 
-        let database = self.database.lock();
+        let found = fingerprint::find_match(self.devices.as_ref(), &fingerprint, user_uuid, self.config.device_match_threshold).await
+            .map_err(|e| device_error("creating", e))?;
 
-        let ip_addr = Some(inputs.ip.parse::<IpAddr>().unwrap()); // Example IP address
+        let (device, confidence) = match found {
+            Some(found) => {
+                // Keep the signals current so the next registration matches too.
+                let device = self.devices.record_match(found.device.device_uuid, models::DeviceChangeset {
+                    os_version: client.os_version,
+                    browser_version: client.browser_version,
+                    mac_address: fingerprint.mac_address.clone(),
+                    client_device_id: fingerprint.client_device_id.clone(),
+                    user_agent_hash: Some(fingerprint.user_agent_hash.clone()),
+                    ip_prefix: Some(fingerprint.ip_prefix),
+                    ..Default::default()
+                }, IpNet::from(ip), location, user_uuid).await
+                    .map_err(|e| device_error("creating", e))?;
 
-        // Convert the IP address to a network (e.g., /24 for IPv4 or /64 for IPv6)
-        let ip_address: Option<IpNet> = ip_addr.map(|ip| {
-            match ip {
-                // Create a /24 network for IPv4 (common for local networks)
-                IpAddr::V4(ipv4) => IpNet::V4(ipv4.into()),
-                // Create a /64 network for IPv6 (common for local networks)
-                IpAddr::V6(ipv6) => IpNet::V6(ipv6.into())
+                (device, found.confidence)
             }
-        });
-
-        let new_device = models::NewDevice {
-            browser: Some(client.user_agent.family.to_string()),
-            browser_version: Some(client.user_agent.family.to_string()),
-            os_version: Some(client.os.family.to_string()),
-            device_type: device_type,
-            os: os,
-            status: models::DeviceStatusEnum::Active,
-            ip_address: ip_address.unwrap(),
-            location: Some(serde_json::json!({
-                "latitude": 37.7749,
-                "longitude": -122.4194,
-                "city": "San Francisco",
-                "country": "US"
-            }))
-        };
+            None => {
+                let device = self.devices.create(models::NewDevice {
+                    user_uuid,
+                    browser: client.browser,
+                    browser_version: client.browser_version,
+                    os_version: client.os_version,
+                    device_type: client.device_type,
+                    os: client.os,
+                    status: models::DeviceStatusEnum::Active,
+                    ip_address: IpNet::from(ip),
+                    location,
+                    mac_address: fingerprint.mac_address.clone(),
+                    client_device_id: fingerprint.client_device_id.clone(),
+                    user_agent_hash: Some(fingerprint.user_agent_hash.clone()),
+                    ip_prefix: Some(fingerprint.ip_prefix),
+                }, json!({
+                    "ip_address": ip.to_string(),
+                    "client_device_id": fingerprint.client_device_id,
+                })).await.map_err(|e| device_error("creating", e))?;
 
-        let device = models::Device::create(&mut database.unwrap(), new_device)
-        .map_err(|e| Status::internal(format!("Error creating device: {}", e)))?;
+                (device, 0.0)
+            }
+        };
 
         Ok(Response::new(DeviceResponse {
             success: true,
-            device: Some(
-                Device {
-                    id: device.device_uuid.to_string(),
-                    browser: device.browser.unwrap_or_default(),
-                    browser_version: device.browser_version.unwrap_or_default(),
-                    os:  Os::Unspecified.into(),
-                    os_version: device.os_version.unwrap_or_default(),
-                    device_type: DeviceType::Unspecified.into(),
-                    status: DeviceStatus::Active.into(),
-                    location: HashMap::new(),
-                    ip_address: device.ip_address.to_string(),
-                    mac_address: "".to_string(),
-                    metadata: HashMap::new(),
-                    notification_enabled: false,
-                    notification_token: "Fake".to_string()
-                }
-            )
+            device: Some(device.into()),
+            matched: confidence > 0.0,
+            match_confidence: confidence,
+        }))
+    }
+
+    async fn get_device(
+        &self,
+        request: Request<GetDeviceRequest>,
+    ) -> Result<Response<GetDeviceResponse>, Status> {
+        let device_uuid = parse_uuid(&request.into_inner().id)?;
+
+        let device = self.devices.find(device_uuid).await
+            .map_err(|e| device_error("finding", e))?;
+
+        Ok(Response::new(GetDeviceResponse {
+            device: Some(device.into()),
+        }))
+    }
+
+    async fn update_device(
+        &self,
+        request: Request<UpdateDeviceRequest>,
+    ) -> Result<Response<UpdateDeviceResponse>, Status> {
+        let inputs = request.into_inner();
+        let device_uuid = parse_uuid(&inputs.id)?;
+
+        let status = match inputs.status {
+            Some(status) => Some(DeviceStatus::from_i32(status)
+                .and_then(|status| models::DeviceStatusEnum::try_from(status).ok())
+                .ok_or_else(|| invalid_field("status", "Invalid device status"))?),
+            None => None,
+        };
+
+        let device = self.devices.find(device_uuid).await
+            .map_err(|e| device_error("finding", e))?;
+
+        let status = status.filter(|status| *status != device.status);
+        if status.as_ref().is_some_and(|status| !device.status.can_transition_to(status)) {
+            return Err(Status::failed_precondition("Retired devices can't change status"));
+        }
+
+        let notification_token = match inputs.notification_token.as_deref() {
+            Some(token) => Some(validate_push_token("notification_token", token)?),
+            None => None,
+        };
+
+        // Same rules as RegisterPushToken, a blocked device can't re-arm push approvals.
+        let arms_push = notification_token.is_some() || inputs.notification_enabled == Some(true);
+        if arms_push && status.as_ref().unwrap_or(&device.status).is_blocked() {
+            return Err(Status::failed_precondition("Blocked devices can't receive push notifications"));
+        }
+
+        let changes = (notification_token.is_some() || inputs.notification_enabled.is_some())
+            .then(|| models::DeviceChangeset {
+                notification_token,
+                notification_enabled: inputs.notification_enabled,
+                ..Default::default()
+            });
+
+        let metadata = match &status {
+            Some(status) => json!({
+                "from": DeviceStatus::from(device.status.clone()).as_str_name(),
+                "to": DeviceStatus::from(status.clone()).as_str_name(),
+            }),
+            None => json!({}),
+        };
+
+        // A lost or stolen device must go through the second factor again.
+        let (device, sessions_revoked) = self.devices.update(device_uuid, changes, status, metadata).await
+            .map_err(|e| device_error("updating", e))?;
+
+        self.cache.forget_sessions(&sessions_revoked).await;
+
+        Ok(Response::new(UpdateDeviceResponse {
+            device: Some(device.into()),
+            sessions_revoked: sessions_revoked.len() as i32,
+        }))
+    }
+
+    async fn delete_device(
+        &self,
+        request: Request<DeleteDeviceRequest>,
+    ) -> Result<Response<DeleteDeviceResponse>, Status> {
+        let device_uuid = parse_uuid(&request.into_inner().id)?;
+
+        // Sessions only keep a dangling reference to deleted devices, they
+        // can't be trusted anymore.
+        let sessions_revoked = self.devices.delete(device_uuid).await
+            .map_err(|e| device_error("deleting", e))?;
+
+        self.cache.forget_sessions(&sessions_revoked).await;
+
+        Ok(Response::new(DeleteDeviceResponse {
+            sessions_revoked: sessions_revoked.len() as i32,
         }))
     }
-}
\ No newline at end of file
+
+    async fn list_devices(
+        &self,
+        request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+
+        let page_size = match request.page_size {
+            size if size <= 0 => 50,
+            size => size.min(100) as i64,
+        };
+
+        let offset = if request.page_token.is_empty() {
+            0
+        } else {
+            request.page_token.parse::<i64>()
+                .map_err(|_| Status::invalid_argument("Invalid page token"))?
+        };
+
+        let devices = self.devices.find_by_user(user_uuid, page_size, offset).await
+            .map_err(|e| device_error("listing", e))?;
+
+        let next_page_token = if devices.len() as i64 == page_size {
+            (offset + page_size).to_string()
+        } else {
+            "".to_string()
+        };
+
+        Ok(Response::new(ListDevicesResponse {
+            devices: devices.into_iter().map(Into::into).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn list_trusted_devices(
+        &self,
+        request: Request<ListTrustedDevicesRequest>,
+    ) -> Result<Response<ListTrustedDevicesResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+
+        let page_size = match request.page_size {
+            size if size <= 0 => 50,
+            size => size.min(100) as i64,
+        };
+
+        let offset = if request.page_token.is_empty() {
+            0
+        } else {
+            request.page_token.parse::<i64>()
+                .map_err(|_| Status::invalid_argument("Invalid page token"))?
+        };
+
+        let trusted_devices = self.devices.find_trusted_by_user(user_uuid, page_size, offset).await
+            .map_err(|e| device_error("listing trusted", e))?;
+
+        let next_page_token = if trusted_devices.len() as i64 == page_size {
+            (offset + page_size).to_string()
+        } else {
+            "".to_string()
+        };
+
+        Ok(Response::new(ListTrustedDevicesResponse {
+            trusted_devices: trusted_devices.into_iter().map(|(trust, device)| TrustedDevice {
+                id: trust.trust_uuid.to_string(),
+                device: Some(device.into()),
+                expires_at: trust.expires_at.timestamp(),
+                last_used_at: trust.last_used_at.map(|at| at.timestamp()).unwrap_or(0),
+                created_at: trust.created_at.timestamp(),
+            }).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn revoke_trusted_device(
+        &self,
+        request: Request<RevokeTrustedDeviceRequest>,
+    ) -> Result<Response<RevokeTrustedDeviceResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+        let device_uuid = parse_uuid(&request.device_id)?;
+
+        let revoked = self.devices.revoke_trust(user_uuid, device_uuid).await
+            .map_err(|e| device_error("revoking trusted", e))?;
+
+        Ok(Response::new(RevokeTrustedDeviceResponse {
+            revoked,
+        }))
+    }
+
+    async fn register_push_token(
+        &self,
+        request: Request<RegisterPushTokenRequest>,
+    ) -> Result<Response<RegisterPushTokenResponse>, Status> {
+        let request = request.into_inner();
+        let device_uuid = parse_uuid(&request.device_id)?;
+        let token = validate_push_token("token", &request.token)?;
+
+        let device = self.devices.find(device_uuid).await
+            .map_err(|e| device_error("finding", e))?;
+
+        if device.status.is_blocked() {
+            return Err(Status::failed_precondition("Blocked devices can't receive push notifications"));
+        }
+
+        let device = self.devices.set_notification_token(device_uuid, token, "device.push_token_registered").await
+            .map_err(|e| device_error("updating", e))?;
+
+        Ok(Response::new(RegisterPushTokenResponse {
+            device: Some(device.into()),
+        }))
+    }
+
+    async fn rotate_push_token(
+        &self,
+        request: Request<RotatePushTokenRequest>,
+    ) -> Result<Response<RotatePushTokenResponse>, Status> {
+        let request = request.into_inner();
+        let device_uuid = parse_uuid(&request.device_id)?;
+        let previous_token = validate_push_token("previous_token", &request.previous_token)?;
+        let token = validate_push_token("token", &request.token)?;
+
+        let device = self.devices.find(device_uuid).await
+            .map_err(|e| device_error("finding", e))?;
+
+        // Rotating from a stale token would silently undo a newer registration.
+        if device.notification_token.as_deref() != Some(previous_token.as_str()) {
+            return Err(Status::failed_precondition("Previous token isn't the one registered for this device"));
+        }
+
+        if device.status.is_blocked() {
+            return Err(Status::failed_precondition("Blocked devices can't receive push notifications"));
+        }
+
+        let device = self.devices.set_notification_token(device_uuid, token, "device.push_token_rotated").await
+            .map_err(|e| device_error("updating", e))?;
+
+        Ok(Response::new(RotatePushTokenResponse {
+            device: Some(device.into()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{MemoryRepository, SessionRepository};
+    use crate::testing;
+    use crate::trusted_devices;
+
+    const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+    fn service(repository: &Arc<MemoryRepository>, config: &Config) -> DevicesService {
+        DevicesService::new(
+            repository.clone(),
+            config,
+            Arc::new(UserAgentClassifier::load(&config.user_agent_regexes_file).unwrap()),
+            Arc::new(GeoIp::from_config(config)),
+            testing::cache(config),
+        )
+    }
+
+    async fn register(service: &DevicesService, user_uuid: Uuid, client_device_id: &str) -> DeviceResponse {
+        service.create_device(Request::new(CreateDeviceRequest {
+            ip: "203.0.113.7".to_string(),
+            user_agent: USER_AGENT.to_string(),
+            user_id: Some(user_uuid.to_string()),
+            client_device_id: Some(client_device_id.to_string()),
+            ..Default::default()
+        })).await.unwrap().into_inner()
+    }
+
+    fn device_uuid(response: &DeviceResponse) -> Uuid {
+        Uuid::parse_str(&response.device.as_ref().unwrap().id).unwrap()
+    }
+
+    #[tokio::test]
+    async fn registering_the_same_fingerprint_twice_matches_the_first_device() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+
+        let first = register(&service, user.user_uuid, "install-1").await;
+        let second = register(&service, user.user_uuid, "install-1").await;
+        let other = register(&service, user.user_uuid, "install-2").await;
+
+        assert!(!first.matched);
+        assert!(second.matched);
+        assert!(second.match_confidence >= 0.5);
+        assert_eq!(device_uuid(&first), device_uuid(&second));
+        assert!(!other.matched);
+        assert_ne!(device_uuid(&first), device_uuid(&other));
+
+        let new_devices = repository.audit_events().iter().filter(|event| event.event_type == "device.new").count();
+        assert_eq!(new_devices, 2);
+    }
+
+    #[tokio::test]
+    async fn blocking_a_device_revokes_its_sessions_and_trust() {
+        let repository = Arc::new(MemoryRepository::new());
+        let config = testing::config(&[("DEVICE_TRUST_SECRET", "test-secret")]);
+        let service = service(&repository, &config);
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+
+        let session = testing::session(user.user_uuid, device_uuid);
+        repository.insert_session(session.clone()).unwrap();
+        trusted_devices::trust_device(repository.as_ref(), &config, user.user_uuid, device_uuid).await.unwrap().unwrap();
+
+        let response = service.update_device(Request::new(UpdateDeviceRequest {
+            id: device_uuid.to_string(),
+            status: Some(DeviceStatus::Lost as i32),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.sessions_revoked, 1);
+        assert!(SessionRepository::find(repository.as_ref(), session.session_uuid).await.unwrap().revoked_at.is_some());
+
+        let trusted = service.list_trusted_devices(Request::new(ListTrustedDevicesRequest {
+            user_id: user.user_uuid.to_string(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert!(trusted.trusted_devices.is_empty());
+
+        let event = repository.audit_events().into_iter().find(|event| event.event_type == "device.status_changed").unwrap();
+        assert_eq!(event.metadata["to"], "DEVICE_STATUS_LOST");
+        assert_eq!(event.metadata["sessions_revoked"], 1);
+    }
+
+    #[tokio::test]
+    async fn retired_devices_keep_their_status() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+
+        let update = |status: DeviceStatus| service.update_device(Request::new(UpdateDeviceRequest {
+            id: device_uuid.to_string(),
+            status: Some(status as i32),
+            ..Default::default()
+        }));
+
+        update(DeviceStatus::Retired).await.unwrap();
+        let status = update(DeviceStatus::Active).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn deleting_a_device_revokes_its_sessions() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+        repository.insert_session(testing::session(user.user_uuid, device_uuid)).unwrap();
+
+        let response = service.delete_device(Request::new(DeleteDeviceRequest {
+            id: device_uuid.to_string(),
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.sessions_revoked, 1);
+        let status = service.get_device(Request::new(GetDeviceRequest {
+            id: device_uuid.to_string(),
+        })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn push_tokens_move_to_the_device_that_registers_them() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let first = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+        let second = device_uuid(&register(&service, user.user_uuid, "install-2").await);
+
+        let register_token = |device_uuid: Uuid| service.register_push_token(Request::new(RegisterPushTokenRequest {
+            device_id: device_uuid.to_string(),
+            token: " fcm-token-1 ".to_string(),
+        }));
+
+        let device = register_token(first).await.unwrap().into_inner().device.unwrap();
+        assert_eq!(device.notification_token, "fcm-token-1");
+        assert!(device.notification_enabled);
+
+        // An app reinstalled on another device keeps its token.
+        register_token(second).await.unwrap();
+        let device = service.get_device(Request::new(GetDeviceRequest {
+            id: first.to_string(),
+        })).await.unwrap().into_inner().device.unwrap();
+        assert!(device.notification_token.is_empty());
+        assert!(!device.notification_enabled);
+
+        let status = service.register_push_token(Request::new(RegisterPushTokenRequest {
+            device_id: first.to_string(),
+            token: "  ".to_string(),
+        })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        assert!(repository.audit_events().iter().any(|event| event.event_type == "device.push_token_registered"));
+    }
+
+    #[tokio::test]
+    async fn push_tokens_only_rotate_from_the_registered_one() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+
+        service.register_push_token(Request::new(RegisterPushTokenRequest {
+            device_id: device_uuid.to_string(),
+            token: "fcm-token-1".to_string(),
+        })).await.unwrap();
+
+        let rotate = |previous_token: &str, token: &str| service.rotate_push_token(Request::new(RotatePushTokenRequest {
+            device_id: device_uuid.to_string(),
+            previous_token: previous_token.to_string(),
+            token: token.to_string(),
+        }));
+
+        let device = rotate("fcm-token-1", "fcm-token-2").await.unwrap().into_inner().device.unwrap();
+        assert_eq!(device.notification_token, "fcm-token-2");
+
+        // The first token is stale now.
+        let status = rotate("fcm-token-1", "fcm-token-3").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        assert!(repository.audit_events().iter().any(|event| event.event_type == "device.push_token_rotated"));
+    }
+
+    #[tokio::test]
+    async fn blocked_devices_cant_rearm_push_through_updates() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+
+        let update = |request: UpdateDeviceRequest| service.update_device(Request::new(UpdateDeviceRequest {
+            id: device_uuid.to_string(),
+            ..request
+        }));
+
+        let status = update(UpdateDeviceRequest {
+            notification_token: Some(" ".to_string()),
+            ..Default::default()
+        }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // Blocking and re-arming in the same update is refused too.
+        let status = update(UpdateDeviceRequest {
+            status: Some(DeviceStatus::Suspended as i32),
+            notification_token: Some("fcm-token-1".to_string()),
+            ..Default::default()
+        }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        update(UpdateDeviceRequest {
+            status: Some(DeviceStatus::Suspended as i32),
+            ..Default::default()
+        }).await.unwrap();
+
+        for request in [
+            UpdateDeviceRequest { notification_token: Some("fcm-token-1".to_string()), ..Default::default() },
+            UpdateDeviceRequest { notification_enabled: Some(true), ..Default::default() },
+        ] {
+            let status = update(request).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        }
+
+        let device = update(UpdateDeviceRequest {
+            notification_enabled: Some(false),
+            ..Default::default()
+        }).await.unwrap().into_inner().device.unwrap();
+        assert!(!device.notification_enabled);
+    }
+
+    #[tokio::test]
+    async fn blocked_devices_cant_register_push_tokens() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let device_uuid = device_uuid(&register(&service, user.user_uuid, "install-1").await);
+
+        service.register_push_token(Request::new(RegisterPushTokenRequest {
+            device_id: device_uuid.to_string(),
+            token: "fcm-token-1".to_string(),
+        })).await.unwrap();
+
+        service.update_device(Request::new(UpdateDeviceRequest {
+            id: device_uuid.to_string(),
+            status: Some(DeviceStatus::Stolen as i32),
+            ..Default::default()
+        })).await.unwrap();
+
+        let status = service.register_push_token(Request::new(RegisterPushTokenRequest {
+            device_id: device_uuid.to_string(),
+            token: "fcm-token-2".to_string(),
+        })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let status = service.rotate_push_token(Request::new(RotatePushTokenRequest {
+            device_id: device_uuid.to_string(),
+            previous_token: "fcm-token-1".to_string(),
+            token: "fcm-token-2".to_string(),
+        })).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::models;
+
+use super::v1::{Email, EmailChangeStatus, EmailStatus};
+
+impl From<models::EmailStatusEnum> for EmailStatus {
+    fn from(status: models::EmailStatusEnum) -> Self {
+        match status {
+            models::EmailStatusEnum::Unverified => EmailStatus::Unverified,
+            models::EmailStatusEnum::Verified => EmailStatus::Verified,
+            models::EmailStatusEnum::Bounced => EmailStatus::Bounced,
+            models::EmailStatusEnum::Spam => EmailStatus::Spam,
+            models::EmailStatusEnum::Blocked => EmailStatus::Blocked,
+        }
+    }
+}
+
+impl From<EmailStatus> for models::EmailStatusEnum {
+    fn from(status: EmailStatus) -> Self {
+        match status {
+            EmailStatus::Unverified => models::EmailStatusEnum::Unverified,
+            EmailStatus::Verified => models::EmailStatusEnum::Verified,
+            EmailStatus::Bounced => models::EmailStatusEnum::Bounced,
+            EmailStatus::Spam => models::EmailStatusEnum::Spam,
+            EmailStatus::Blocked => models::EmailStatusEnum::Blocked,
+        }
+    }
+}
+
+impl From<models::EmailChangeStatusEnum> for EmailChangeStatus {
+    fn from(status: models::EmailChangeStatusEnum) -> Self {
+        match status {
+            models::EmailChangeStatusEnum::Pending => EmailChangeStatus::Pending,
+            models::EmailChangeStatusEnum::Completed => EmailChangeStatus::Completed,
+            models::EmailChangeStatusEnum::Cancelled => EmailChangeStatus::Cancelled,
+            models::EmailChangeStatusEnum::Reverted => EmailChangeStatus::Reverted,
+        }
+    }
+}
+
+impl From<models::Email> for Email {
+    fn from(email: models::Email) -> Self {
+        Email {
+            id: email.email_uuid.to_string(),
+            email: email.value,
+            status: EmailStatus::from(email.status) as i32,
+            is_primary: email.is_primary,
+            is_verified: email.is_verified,
+        }
+    }
+}
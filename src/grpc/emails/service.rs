@@ -1,32 +1,110 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use diesel::{PgConnection};
-use serde_json::json;
+use std::sync::Arc;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use tonic::{Request, Response, Status};
 
-use jsonwebtoken::{encode, Header, Algorithm, EncodingKey};
-
 use chrono::{DateTime, Utc};
+use crate::bounces::{self, FeedbackKind};
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::email_change::{self, EmailChangeError};
+use crate::mailer::Mailer;
 use crate::models;
+use crate::repository::{EmailRepository, RepositoryError};
+use crate::utils::EmailPolicy;
+use crate::verification::{self, VerificationError};
 
 use prost_types::Timestamp;
 
 use uuid::Uuid;
+use crate::grpc::errors::invalid_field;
 use super::v1::emails_server::Emails;
-use super::v1::{CreateEmailRequest, Email, EmailResponse, EmailStatus};
+use super::v1::{CancelEmailChangeRequest, CancelEmailChangeResponse, CreateEmailRequest, DeleteEmailRequest, Email, EmailChangeStatus, EmailFeedbackResult, EmailFeedbackType, EmailResponse, EmailStatus, GenerateEmailVerificationTokenRequest, GetEmailRequest, IngestEmailFeedbackRequest, IngestEmailFeedbackResponse, IsEmailVerifiedRequest, ListUserEmailsRequest, ListUserEmailsResponse, RequestEmailChangeRequest, RequestEmailChangeResponse, SendEmailVerificationRequest, SendEmailVerificationResponse, SetPrimaryEmailRequest, TokenResponse, UpdateEmailRequest, VerifyEmailRequest, VerifyEmailResponse, VerifyEmailTokenRequest};
 
 pub struct EmailsService {
-    database: Arc<Mutex<PgConnection>>,
+    emails: Arc<dyn EmailRepository>,
+    config: Config,
+    mailer: Arc<Mailer>,
+    email_policy: Arc<EmailPolicy>,
+    cache: Arc<Cache>,
 }
 
 impl EmailsService {
-    pub fn new(database: Arc<Mutex<PgConnection>>) -> Self {
+    pub fn new(emails: Arc<dyn EmailRepository>, config: &Config, mailer: Arc<Mailer>, email_policy: Arc<EmailPolicy>, cache: Arc<Cache>) -> Self {
         Self {
-            database,
+            emails,
+            config: config.clone(),
+            mailer,
+            email_policy,
+            cache,
+        }
+    }
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::invalid_argument("Invalid UUID format"))
+}
+
+fn email_error(action: &str, error: impl Into<RepositoryError>) -> Status {
+    match error.into() {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("Email not found"),
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Status::already_exists("Email is already in use")
+        }
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            Status::not_found("User not found")
         }
+        e => Status::internal(format!("Error {} email: {}", action, e)),
     }
 }
 
+fn verification_error(error: VerificationError) -> Status {
+    match error {
+        VerificationError::EmailNotFound => Status::not_found("Email not found"),
+        VerificationError::AlreadyVerified => Status::failed_precondition("Email is already verified"),
+        VerificationError::NotVerifiable => Status::failed_precondition("Email can't be verified in its current status"),
+        VerificationError::Throttled { retry_at } => Status::resource_exhausted(format!(
+            "Too many verification requests, retry after {}",
+            retry_at.to_rfc3339()
+        )),
+        VerificationError::Suppressed => Status::failed_precondition("Email bounced or was reported as spam, no more emails are sent to it"),
+        VerificationError::Invalid => Status::invalid_argument("Invalid or expired verification code"),
+        VerificationError::Template(e) => Status::internal(format!("Error rendering email: {}", e)),
+        VerificationError::Database(RepositoryError::Unavailable(e)) => Status::unavailable(format!("Database unavailable: {}", e)),
+        VerificationError::Database(e) => Status::internal(format!("Error verifying email: {}", e)),
+    }
+}
+
+fn email_change_error(error: EmailChangeError) -> Status {
+    match error {
+        EmailChangeError::NoPrimaryEmail => Status::failed_precondition("User has no primary email, create one instead"),
+        EmailChangeError::SameEmail => invalid_field("email", "Email is already the primary email"),
+        EmailChangeError::EmailTaken => Status::already_exists("Email is already in use"),
+        EmailChangeError::Verification(e) => verification_error(e),
+        EmailChangeError::InvalidToken => Status::invalid_argument("Invalid or already used email change token"),
+        EmailChangeError::UndoExpired => Status::failed_precondition("The email change can't be undone anymore"),
+        EmailChangeError::OldEmailMissing => Status::failed_precondition("The previous email no longer exists"),
+        EmailChangeError::Template(e) => Status::internal(format!("Error rendering email: {}", e)),
+        EmailChangeError::Database(RepositoryError::Unavailable(e)) => Status::unavailable(format!("Database unavailable: {}", e)),
+        EmailChangeError::Database(e) => Status::internal(format!("Error changing email: {}", e)),
+    }
+}
+
+/// Completes the email change waiting for `email` to be verified, if any.
+///
+/// Returns the email as it is afterwards, primary when a change completed.
+async fn complete_pending_change(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    mailer: &Mailer,
+    email: models::Email,
+) -> Result<models::Email, Status> {
+    match email_change::complete_email_change(emails, config, mailer, email.email_uuid).await {
+        Ok(Some(_)) => emails.find(email.email_uuid).await.map_err(|e| email_error("finding", e)),
+        Ok(None) => Ok(email),
+        Err(e) => Err(email_change_error(e)),
+    }
+}
 
 fn convert_to_prost_timestamp(dt: DateTime<Utc>) -> Timestamp {
     Timestamp {
@@ -40,31 +118,453 @@ impl Emails for EmailsService {
     async fn create_email(&self,
         request: Request<CreateEmailRequest>) -> Result<Response<EmailResponse>, Status> {
         let inputs = request.into_inner();
-        let mut database = self.database.lock().unwrap();    
-        
-        let user_uuid = Uuid::parse_str(&inputs.user_id).unwrap();
-
-        let new_email = models::NewEmail {
-            user_uuid: user_uuid,
-            metadata: json!({}),
-            value: inputs.email,
-            status: models::EmailStatusEnum::Unverified,
-        };
+        let user_uuid = parse_uuid(&inputs.user_id)?;
+
+        let address = self.email_policy.normalize(&inputs.email)
+            .map_err(|e| invalid_field("email", e))?;
+
+        // The first email of a user always becomes their primary email,
+        // replacing an existing one goes through RequestEmailChange.
+        let email = self.emails.add_to_user(user_uuid, address).await
+            .map_err(|e| email_error("creating", e))?;
 
-        let email = models::Email::create(&mut database, new_email)
-            .map_err(|e| Status::internal(e.to_string()))?;
-        
         Ok(Response::new(EmailResponse{
-            email:  Some (Email{
-                id: email.user_uuid.to_string(),
-                email: email.value,
-                is_verified: false,
-                status: EmailStatus::Unverified as i32,
-                is_primary: false,
-            }),
+            email: Some(email.into()),
             success: true,
             message: "".to_string()
         }))
+    }
+
+    async fn delete_email(
+        &self,
+        request: Request<DeleteEmailRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let email_uuid = parse_uuid(&request.into_inner().id)?;
+
+        let email = self.emails.find(email_uuid).await
+            .map_err(|e| email_error("finding", e))?;
+
+        let undo_pending = self.emails.is_undo_pending(email_uuid).await
+            .map_err(|e| email_error("finding", e))?;
+        if undo_pending {
+            return Err(Status::failed_precondition("Email can still undo a recent email change"));
+        }
+
+        if email.is_primary {
+            let emails = self.emails.find_by_user(email.user_uuid).await
+                .map_err(|e| email_error("finding", e))?;
+
+            if emails.len() > 1 {
+                return Err(Status::failed_precondition("Set another primary email before deleting this one"));
+            }
+        }
+
+        let email = self.emails.delete(email_uuid).await
+            .map_err(|e| email_error("deleting", e))?;
+
+        Ok(Response::new(email.into()))
+    }
+
+    async fn get_email(
+        &self,
+        request: Request<GetEmailRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let email_uuid = parse_uuid(&request.into_inner().id)?;
+
+        let email = self.emails.find(email_uuid).await
+            .map_err(|e| email_error("finding", e))?;
+
+        Ok(Response::new(email.into()))
+    }
+
+    async fn is_email_verified(
+        &self,
+        request: Request<IsEmailVerifiedRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let inputs = request.into_inner();
+
+        let email = self.emails.find_by_value(self.email_policy.lookup_key(&inputs.email)).await
+            .map_err(|e| email_error("finding", e))?;
 
+        Ok(Response::new(email.into()))
     }
-}
\ No newline at end of file
+
+    async fn update_email(
+        &self,
+        request: Request<UpdateEmailRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let inputs = request.into_inner();
+        let email_uuid = parse_uuid(&inputs.id)?;
+
+        let status = match inputs.status {
+            Some(status) => Some(EmailStatus::from_i32(status)
+                .ok_or_else(|| Status::invalid_argument("Invalid email status"))?),
+            None => None,
+        };
+
+        // Only the verification flow may verify an address, otherwise it could be
+        // promoted with SetPrimaryEmail without the new address confirming it.
+        if status == Some(EmailStatus::Verified) {
+            return Err(Status::invalid_argument("Emails can only be verified with VerifyEmail or VerifyEmailToken"));
+        }
+
+        let address = match &inputs.email {
+            Some(value) => Some(self.email_policy.normalize(value).map_err(|e| invalid_field("email", e))?),
+            None => None,
+        };
+
+        let email = self.emails.find(email_uuid).await
+            .map_err(|e| email_error("finding", e))?;
+
+        if address.is_some() {
+            if email.is_primary {
+                return Err(Status::failed_precondition("Use RequestEmailChange to change the primary email"));
+            }
+
+            let undo_pending = self.emails.is_undo_pending(email_uuid).await
+                .map_err(|e| email_error("finding", e))?;
+            if undo_pending {
+                return Err(Status::failed_precondition("Email can still undo a recent email change"));
+            }
+        }
+
+        // Changing the address already drops the verification, a status
+        // can't drop it on its own.
+        let value_changes = address.as_ref().is_some_and(|address| address.value != email.value);
+        if email.is_verified && !value_changes && status == Some(EmailStatus::Unverified) {
+            return Err(Status::failed_precondition("A verified email can't be marked unverified, change its address instead"));
+        }
+
+        let email = self.emails.update(email_uuid, address, status.map(Into::into)).await
+            .map_err(|e| email_error("updating", e))?;
+
+        Ok(Response::new(email.into()))
+    }
+
+    async fn set_primary_email(
+        &self,
+        request: Request<SetPrimaryEmailRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let email_uuid = parse_uuid(&request.into_inner().id)?;
+
+        let email = self.emails.find(email_uuid).await
+            .map_err(|e| email_error("finding", e))?;
+        if !email.is_verified {
+            return Err(Status::failed_precondition("Only verified emails can become primary, use RequestEmailChange"));
+        }
+
+        let email = self.emails.set_primary(email_uuid).await
+            .map_err(|e| email_error("updating", e))?;
+
+        Ok(Response::new(email.into()))
+    }
+
+    async fn list_user_emails(
+        &self,
+        request: Request<ListUserEmailsRequest>,
+    ) -> Result<Response<ListUserEmailsResponse>, Status> {
+        let user_uuid = parse_uuid(&request.into_inner().user_id)?;
+
+        let emails = self.emails.find_by_user(user_uuid).await
+            .map_err(|e| email_error("listing", e))?;
+
+        Ok(Response::new(ListUserEmailsResponse {
+            emails: emails.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn verify_email(
+        &self,
+        request: Request<VerifyEmailRequest>,
+    ) -> Result<Response<VerifyEmailResponse>, Status> {
+        let inputs = request.into_inner();
+
+        let email = verification::verify_email_code(self.emails.as_ref(), &self.config, &self.email_policy.lookup_key(&inputs.email), &inputs.code).await
+            .map_err(verification_error)?;
+        let email = complete_pending_change(self.emails.as_ref(), &self.config, &self.mailer, email).await?;
+
+        Ok(Response::new(VerifyEmailResponse {
+            is_verified: email.is_verified,
+            email: Some(email.into()),
+        }))
+    }
+
+    async fn generate_email_verification_token(
+        &self,
+        request: Request<GenerateEmailVerificationTokenRequest>,
+    ) -> Result<Response<TokenResponse>, Status> {
+        let email_uuid = parse_uuid(&request.into_inner().id)?;
+
+        let issued = verification::issue_email_verification(self.emails.as_ref(), &self.config, email_uuid).await
+            .map_err(verification_error)?;
+
+        Ok(Response::new(TokenResponse {
+            value: issued.token,
+            code: issued.code,
+            expiration: issued.expires_at.timestamp(),
+            code_expiration: issued.code_expires_at.timestamp(),
+        }))
+    }
+
+    async fn verify_email_token(
+        &self,
+        request: Request<VerifyEmailTokenRequest>,
+    ) -> Result<Response<Email>, Status> {
+        let inputs = request.into_inner();
+
+        let email = verification::verify_email_token(self.emails.as_ref(), &inputs.token).await
+            .map_err(verification_error)?;
+        let email = complete_pending_change(self.emails.as_ref(), &self.config, &self.mailer, email).await?;
+
+        Ok(Response::new(email.into()))
+    }
+
+    async fn send_email_verification(
+        &self,
+        request: Request<SendEmailVerificationRequest>,
+    ) -> Result<Response<SendEmailVerificationResponse>, Status> {
+        let inputs = request.into_inner();
+        let email_uuid = parse_uuid(&inputs.id)?;
+
+        let issued = verification::send_email_verification(self.emails.as_ref(), &self.config, &self.mailer, email_uuid, &inputs.locale).await
+            .map_err(verification_error)?;
+
+        Ok(Response::new(SendEmailVerificationResponse {
+            expiration: issued.expires_at.timestamp(),
+            code_expiration: issued.code_expires_at.timestamp(),
+        }))
+    }
+
+    async fn request_email_change(
+        &self,
+        request: Request<RequestEmailChangeRequest>,
+    ) -> Result<Response<RequestEmailChangeResponse>, Status> {
+        let inputs = request.into_inner();
+        let user_uuid = parse_uuid(&inputs.user_id)?;
+
+        let address = self.email_policy.normalize(&inputs.email)
+            .map_err(|e| invalid_field("email", e))?;
+
+
+        let change = email_change::request_email_change(self.emails.as_ref(), &self.config, &self.mailer, user_uuid, address, &inputs.locale).await
+            .map_err(email_change_error)?;
+
+        let email = match change.new_email_uuid {
+            Some(email_uuid) => Some(
+                self.emails.find(email_uuid).await
+                    .map_err(|e| email_error("finding", e))?,
+            ),
+            None => None,
+        };
+
+        Ok(Response::new(RequestEmailChangeResponse {
+            request_id: change.request_uuid.to_string(),
+            status: EmailChangeStatus::from(change.status) as i32,
+            email: email.map(Into::into),
+            expiration: change.expires_at.timestamp(),
+        }))
+    }
+
+    async fn cancel_email_change(
+        &self,
+        request: Request<CancelEmailChangeRequest>,
+    ) -> Result<Response<CancelEmailChangeResponse>, Status> {
+        let inputs = request.into_inner();
+
+        let (change, sessions_revoked) = email_change::cancel_email_change(self.emails.as_ref(), &inputs.token).await
+            .map_err(email_change_error)?;
+        self.cache.forget_sessions(&sessions_revoked).await;
+
+        Ok(Response::new(CancelEmailChangeResponse {
+            request_id: change.request_uuid.to_string(),
+            status: EmailChangeStatus::from(change.status) as i32,
+        }))
+    }
+
+    async fn ingest_email_feedback(
+        &self,
+        request: Request<IngestEmailFeedbackRequest>,
+    ) -> Result<Response<IngestEmailFeedbackResponse>, Status> {
+        let inputs = request.into_inner();
+
+        let outcomes = bounces::ingest_feedback(self.emails.as_ref(), &self.email_policy, &inputs.content_type, &inputs.payload).await
+            .map_err(|e| Status::internal(format!("Error ingesting email feedback: {}", e)))?
+            .map_err(|e| invalid_field("payload", e))?;
+
+        Ok(Response::new(IngestEmailFeedbackResponse {
+            results: outcomes.into_iter().map(|outcome| EmailFeedbackResult {
+                recipient: outcome.recipient,
+                r#type: match outcome.kind {
+                    FeedbackKind::HardBounce => EmailFeedbackType::HardBounce,
+                    FeedbackKind::SoftBounce => EmailFeedbackType::SoftBounce,
+                    FeedbackKind::Complaint => EmailFeedbackType::Complaint,
+                } as i32,
+                email_id: outcome.email_uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+                suppressed: outcome.suppressed,
+            }).collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{MemoryRepository, SessionRepository, UserRepository};
+    use crate::testing;
+
+    fn service(repository: &Arc<MemoryRepository>, config: &Config) -> EmailsService {
+        EmailsService::new(repository.clone(), config, testing::mailer(config), Arc::new(config.email_policy().unwrap()), testing::cache(config))
+    }
+
+    #[tokio::test]
+    async fn emails_are_verified_with_the_mailed_code() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let email = testing::create_email(&repository, user.user_uuid, "alice@example.com", false).await;
+
+        service.send_email_verification(Request::new(SendEmailVerificationRequest {
+            id: email.email_uuid.to_string(),
+            locale: "en".to_string(),
+        })).await.unwrap();
+
+        let queued = repository.queued_emails();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].to, "alice@example.com");
+
+        let verify = |code: String| service.verify_email(Request::new(VerifyEmailRequest {
+            email: "alice@example.com".to_string(),
+            code,
+        }));
+
+        let error = verify("000000".to_string()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+
+        let verified = verify(testing::code_in(&queued[0].text)).await.unwrap().into_inner();
+        assert!(verified.is_verified);
+        assert!(UserRepository::find(repository.as_ref(), user.user_uuid).await.unwrap().is_verified);
+        assert!(repository.audit_events().iter().any(|event| event.event_type == "email.verified"));
+
+        let error = service.send_email_verification(Request::new(SendEmailVerificationRequest {
+            id: email.email_uuid.to_string(),
+            locale: "en".to_string(),
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn verification_emails_are_throttled() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let email = testing::create_email(&repository, user.user_uuid, "alice@example.com", false).await;
+
+        let send = || service.send_email_verification(Request::new(SendEmailVerificationRequest {
+            id: email.email_uuid.to_string(),
+            locale: "en".to_string(),
+        }));
+
+        send().await.unwrap();
+        let error = send().await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(repository.queued_emails().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn email_changes_complete_on_verification_and_can_be_undone() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let old_email = testing::create_email(&repository, user.user_uuid, "alice@example.com", true).await;
+        let device = testing::create_device(&repository, Some(user.user_uuid)).await;
+        let session = testing::session(user.user_uuid, device.device_uuid);
+        repository.insert_session(session.clone()).unwrap();
+        service.cache.cache_session(session.session_uuid, Utc::now() + chrono::Duration::hours(1)).await;
+
+        let change = service.request_email_change(Request::new(RequestEmailChangeRequest {
+            user_id: user.user_uuid.to_string(),
+            email: "alice@example.org".to_string(),
+            locale: "en".to_string(),
+        })).await.unwrap().into_inner();
+        assert_eq!(change.status, EmailChangeStatus::Pending as i32);
+
+        // The new address gets the verification, the old one a way to cancel.
+        let queued = repository.queued_emails();
+        assert_eq!(queued.iter().map(|email| email.to.as_str()).collect::<Vec<_>>(), ["alice@example.org", "alice@example.com"]);
+
+        let new_email = service.verify_email_token(Request::new(VerifyEmailTokenRequest {
+            token: testing::token_in(&queued[0].text),
+        })).await.unwrap().into_inner();
+        assert!(new_email.is_primary);
+
+        let queued = repository.queued_emails();
+        assert_eq!(queued.len(), 3);
+        assert_eq!(queued[2].to, "alice@example.com");
+
+        let undone = service.cancel_email_change(Request::new(CancelEmailChangeRequest {
+            token: testing::token_in(&queued[2].text),
+        })).await.unwrap().into_inner();
+        assert_eq!(undone.status, EmailChangeStatus::Reverted as i32);
+
+        let emails = EmailRepository::find_by_user(repository.as_ref(), user.user_uuid).await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert!(emails[0].email_uuid == old_email.email_uuid && emails[0].is_primary);
+        assert!(SessionRepository::find(repository.as_ref(), session.session_uuid).await.unwrap().revoked_at.is_some());
+        assert_eq!(service.cache.session_live_until(session.session_uuid).await, None);
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_pending_change_drops_the_new_address() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        testing::create_email(&repository, user.user_uuid, "alice@example.com", true).await;
+
+        service.request_email_change(Request::new(RequestEmailChangeRequest {
+            user_id: user.user_uuid.to_string(),
+            email: "alice@example.org".to_string(),
+            locale: "en".to_string(),
+        })).await.unwrap();
+
+        let cancel = || service.cancel_email_change(Request::new(CancelEmailChangeRequest {
+            token: testing::token_in(&repository.queued_emails()[1].text),
+        }));
+
+        let cancelled = cancel().await.unwrap().into_inner();
+        assert_eq!(cancelled.status, EmailChangeStatus::Cancelled as i32);
+        assert_eq!(EmailRepository::find_by_user(repository.as_ref(), user.user_uuid).await.unwrap().len(), 1);
+
+        let error = cancel().await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn hard_bounces_suppress_the_address() {
+        let repository = Arc::new(MemoryRepository::new());
+        let service = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+        let email = testing::create_email(&repository, user.user_uuid, "alice@example.com", true).await;
+        let bob = testing::create_user(&repository, "bob").await;
+        testing::create_email(&repository, bob.user_uuid, "bob@example.com", true).await;
+
+        let outcome = service.ingest_email_feedback(Request::new(IngestEmailFeedbackRequest {
+            content_type: "application/json".to_string(),
+            payload: br#"{"email": "alice@example.com", "type": "hard_bounce"}"#.to_vec(),
+        })).await.unwrap().into_inner();
+
+        assert_eq!(outcome.results.len(), 1);
+        assert!(outcome.results[0].suppressed);
+        assert_eq!(outcome.results[0].email_id, email.email_uuid.to_string());
+        assert_eq!(EmailRepository::find(repository.as_ref(), email.email_uuid).await.unwrap().status, models::EmailStatusEnum::Bounced);
+        assert!(UserRepository::find(repository.as_ref(), user.user_uuid).await.unwrap().email_update_required);
+
+        // Nobody can move onto the address, it wouldn't receive the verification.
+        EmailRepository::delete(repository.as_ref(), email.email_uuid).await.unwrap();
+        let error = service.request_email_change(Request::new(RequestEmailChangeRequest {
+            user_id: bob.user_uuid.to_string(),
+            email: "alice@example.com".to_string(),
+            locale: "en".to_string(),
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+    }
+}
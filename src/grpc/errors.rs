@@ -0,0 +1,62 @@
+use prost::Message;
+use tonic::codegen::Bytes;
+use tonic::{Code, Status};
+
+// Minimal copies of the `google.rpc` error model, encoded by hand so clients
+// using the standard error details (e.g. `tonic-types`) can decode them.
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldViolation {
+    #[prost(string, tag = "1")]
+    field: String,
+    #[prost(string, tag = "2")]
+    description: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    field_violations: Vec<FieldViolation>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Any {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<Any>,
+}
+
+/// An `invalid_argument` status carrying a `google.rpc.BadRequest` detail
+/// that names the offending field.
+pub fn invalid_field(field: &str, description: impl Into<String>) -> Status {
+    let description = description.into();
+
+    let bad_request = BadRequest {
+        field_violations: vec![FieldViolation {
+            field: field.to_string(),
+            description: description.clone(),
+        }],
+    };
+
+    let status = RpcStatus {
+        code: Code::InvalidArgument as i32,
+        message: description.clone(),
+        details: vec![Any {
+            type_url: "type.googleapis.com/google.rpc.BadRequest".to_string(),
+            value: bad_request.encode_to_vec(),
+        }],
+    };
+
+    Status::with_details(Code::InvalidArgument, description, Bytes::from(status.encode_to_vec()))
+}
@@ -3,6 +3,11 @@ pub mod device;
 pub mod auth;
 pub mod emails;
 pub mod phones;
+pub mod sessions;
+pub mod errors;
+
+// Generated code refers to other packages by their proto name.
+pub use device as devices;
 
 // pub mod auth {
 //     pub mod v1 {
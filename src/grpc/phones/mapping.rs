@@ -0,0 +1,43 @@
+use crate::models;
+
+use super::v1::{Phone, PhoneStatus};
+
+impl From<models::PhoneStatusEnum> for PhoneStatus {
+    fn from(status: models::PhoneStatusEnum) -> Self {
+        match status {
+            models::PhoneStatusEnum::Unverified => PhoneStatus::Unverified,
+            models::PhoneStatusEnum::Verified => PhoneStatus::Verified,
+            models::PhoneStatusEnum::Bounced => PhoneStatus::Bounced,
+            models::PhoneStatusEnum::Spam => PhoneStatus::Spam,
+            models::PhoneStatusEnum::Blocked => PhoneStatus::Blocked,
+        }
+    }
+}
+
+impl From<PhoneStatus> for models::PhoneStatusEnum {
+    fn from(status: PhoneStatus) -> Self {
+        match status {
+            PhoneStatus::Unverified => models::PhoneStatusEnum::Unverified,
+            PhoneStatus::Verified => models::PhoneStatusEnum::Verified,
+            PhoneStatus::Bounced => models::PhoneStatusEnum::Bounced,
+            PhoneStatus::Spam => models::PhoneStatusEnum::Spam,
+            PhoneStatus::Blocked => models::PhoneStatusEnum::Blocked,
+        }
+    }
+}
+
+impl From<models::Phone> for Phone {
+    fn from(phone: models::Phone) -> Self {
+        Phone {
+            phone_id: phone.phone_uuid.to_string(),
+            user_id: phone.user_uuid.to_string(),
+            country_code: phone.country_code,
+            number: phone.number,
+            full_number: phone.full_number.unwrap_or_default(),
+            r#type: phone.type_.unwrap_or_default(),
+            status: PhoneStatus::from(phone.status) as i32,
+            is_primary: phone.is_primary,
+            is_verified: phone.is_verified,
+        }
+    }
+}
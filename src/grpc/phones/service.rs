@@ -1,61 +1,314 @@
-use std::sync::{Arc, Mutex};
-use diesel::{PgConnection};
-use serde_json::json;
+use std::sync::Arc;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use tonic::{Request, Response, Status};
 
+use crate::config::Config;
+use crate::grpc::errors::invalid_field;
 use crate::models;
+use crate::phone_verification::{self, PhoneVerificationError};
+use crate::repository::{PhoneRepository, RepositoryError};
+use crate::sms::SmsGateway;
+use crate::utils;
 
+use ipnet::IpNet;
 use uuid::Uuid;
 use super::v1::phones_server::Phones;
-use super::v1::{CreatePhoneRequest, Phone, PhoneStatus};
+use super::v1::get_phone_request::Identifier;
+use super::v1::{CreatePhoneRequest, DeletePhoneRequest, GetPhoneRequest, ListPhonesByUserRequest, ListPhonesResponse, MarkPhoneAsBouncedRequest, Phone, PhoneStatus, SendPhoneVerificationRequest, SendPhoneVerificationResponse, SetPrimaryPhoneRequest, UpdatePhoneRequest, VerifyPhoneRequest};
 
 pub struct PhonesService {
-    database: Arc<Mutex<PgConnection>>,
+    phones: Arc<dyn PhoneRepository>,
+    config: Config,
+    sms_gateway: Arc<SmsGateway>,
 }
 
 impl PhonesService {
-    pub fn new(database: Arc<Mutex<PgConnection>>) -> Self {
+    pub fn new(phones: Arc<dyn PhoneRepository>, config: &Config, sms_gateway: Arc<SmsGateway>) -> Self {
         Self {
-            database,
+            phones,
+            config: config.clone(),
+            sms_gateway,
         }
     }
 }
 
+fn parse_uuid(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::invalid_argument("Invalid UUID format"))
+}
+
+fn phone_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("Phone not found"),
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Status::already_exists("Phone number is already in use")
+        }
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+            Status::not_found("User not found")
+        }
+        e => Status::internal(format!("Error {} phone: {}", action, e)),
+    }
+}
+
+fn phone_verification_error(error: PhoneVerificationError) -> Status {
+    match error {
+        PhoneVerificationError::PhoneNotFound => Status::not_found("Phone not found"),
+        PhoneVerificationError::AlreadyVerified => Status::failed_precondition("Phone is already verified"),
+        PhoneVerificationError::NotVerifiable => Status::failed_precondition("Phone can't be verified in its current status"),
+        PhoneVerificationError::CountryNotAllowed(reason) => Status::failed_precondition(reason),
+        PhoneVerificationError::Throttled { retry_at } => Status::resource_exhausted(format!(
+            "Too many verification requests, retry after {}",
+            retry_at.to_rfc3339()
+        )),
+        PhoneVerificationError::Invalid => Status::invalid_argument("Invalid or expired verification code"),
+        PhoneVerificationError::Template(e) => Status::internal(format!("Error rendering text message: {}", e)),
+        PhoneVerificationError::Database(RepositoryError::Unavailable(e)) => Status::unavailable(format!("Database unavailable: {}", e)),
+        PhoneVerificationError::Database(e) => Status::internal(format!("Error verifying phone: {}", e)),
+    }
+}
+
 #[tonic::async_trait]
 impl Phones for PhonesService {
     async fn create_phone(&self,
         request: Request<CreatePhoneRequest>) -> Result<Response<Phone>, Status> {
         let inputs = request.into_inner();
-        let mut database = self.database.lock().unwrap();    
-        
-        let user_uuid = Uuid::parse_str(&inputs.user_id).unwrap();
-
-        let new_phone = models::NewPhone {
-            user_uuid: user_uuid,
-            country_code: inputs.country_code,
-            number: inputs.number,
-            type_: inputs.r#type,
-            status: models::PhoneStatusEnum::Unverified,
-            metadata: json!({}),
+        let user_uuid = parse_uuid(&inputs.user_id)?;
+
+        let number = utils::normalize_phone(&inputs.country_code, &inputs.number)
+            .map_err(|e| invalid_field("number", e))?;
+
+        // The first phone of a user becomes their primary phone.
+        let phone = self.phones.add_to_user(user_uuid, number).await
+            .map_err(|e| phone_error("creating", e))?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn get_phone(
+        &self,
+        request: Request<GetPhoneRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let identifier = request.into_inner().identifier
+            .ok_or_else(|| Status::invalid_argument("A phone_id or full_number is required"))?;
+
+        let phone = match identifier {
+            Identifier::PhoneId(id) => self.phones.find(parse_uuid(&id)?).await,
+            Identifier::FullNumber(full_number) => {
+                let number = utils::normalize_full_phone(&full_number)
+                    .map_err(|e| invalid_field("full_number", e))?;
+                self.phones.find_by_number(number.e164).await
+            }
+        }.map_err(|e| phone_error("finding", e))?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn update_phone(
+        &self,
+        request: Request<UpdatePhoneRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let inputs = request.into_inner();
+        let phone_uuid = parse_uuid(&inputs.phone_id)?;
+
+        let status = match inputs.status {
+            Some(status) => Some(PhoneStatus::from_i32(status)
+                .ok_or_else(|| Status::invalid_argument("Invalid phone status"))?),
+            None => None,
+        };
+
+        if let Some(type_) = &inputs.r#type {
+            if type_.trim().is_empty() || type_.len() > 20 {
+                return Err(invalid_field("type", "Type must be between 1 and 20 characters"));
+            }
+        }
+
+        let type_ = inputs.r#type.map(|type_| type_.trim().to_string());
+
+        let phone = self.phones.update(phone_uuid, type_, status.map(Into::into)).await
+            .map_err(|e| phone_error("updating", e))?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn delete_phone(
+        &self,
+        request: Request<DeletePhoneRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let phone_uuid = parse_uuid(&request.into_inner().phone_id)?;
+
+        let phone = self.phones.find(phone_uuid).await
+            .map_err(|e| phone_error("finding", e))?;
+
+        if phone.is_primary {
+            let phones = self.phones.count_for_user(phone.user_uuid).await
+                .map_err(|e| phone_error("finding", e))?;
+
+            if phones > 1 {
+                return Err(Status::failed_precondition("Set another primary phone before deleting this one"));
+            }
+        }
+
+        let phone = self.phones.delete(phone_uuid).await
+            .map_err(|e| phone_error("deleting", e))?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn set_primary_phone(
+        &self,
+        request: Request<SetPrimaryPhoneRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let inputs = request.into_inner();
+        let user_uuid = parse_uuid(&inputs.user_id)?;
+        let phone_uuid = parse_uuid(&inputs.phone_id)?;
+
+        let phone = self.phones.find(phone_uuid).await
+            .map_err(|e| phone_error("finding", e))?;
+
+        if phone.user_uuid != user_uuid {
+            return Err(Status::not_found("Phone not found"));
+        }
+
+        let phone = self.phones.set_primary(phone_uuid).await
+            .map_err(|e| phone_error("updating", e))?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn send_phone_verification(
+        &self,
+        request: Request<SendPhoneVerificationRequest>,
+    ) -> Result<Response<SendPhoneVerificationResponse>, Status> {
+        // Services sit behind a gateway, so the end user's IP is passed in and
+        // the peer address is only a fallback.
+        let remote_addr = request.remote_addr();
+        let inputs = request.into_inner();
+        let phone_uuid = parse_uuid(&inputs.phone_id)?;
+
+        let ip_address = if inputs.ip.trim().is_empty() {
+            remote_addr.map(|addr| IpNet::from(addr.ip()))
+        } else {
+            let ip = inputs.ip.trim().parse::<std::net::IpAddr>()
+                .map_err(|_| invalid_field("ip", "Invalid IP address"))?;
+            Some(IpNet::from(ip))
         };
 
-        let phone = models::Phone::create(&mut database, new_phone)
-            .map_err(|e| Status::internal(e.to_string()))?;
-        
-
-        let full_number = phone.full_number.map(|n| n.to_string());
-
-        Ok(Response::new(Phone{
-            user_id: phone.user_uuid.to_string(),
-            phone_id: phone.phone_uuid.to_string(),
-            country_code: phone.country_code,
-            number: phone.number.to_string(),
-            full_number: full_number.unwrap(),
-            r#type: phone.type_.unwrap_or_default().to_string(),
-            is_primary: false,
-            is_verified: false,
-            status: PhoneStatus::Unverified as i32,
+        let issued = phone_verification::issue_phone_verification(self.phones.as_ref(), &self.config, &self.sms_gateway, phone_uuid, ip_address, &inputs.locale).await
+            .map_err(phone_verification_error)?;
+
+        self.sms_gateway.send(issued.sms).await
+            .map_err(|e| Status::unavailable(format!("Error sending verification code: {}", e)))?;
+
+        Ok(Response::new(SendPhoneVerificationResponse {
+            expiration: issued.expires_at.timestamp(),
         }))
+    }
+
+    async fn verify_phone(
+        &self,
+        request: Request<VerifyPhoneRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let inputs = request.into_inner();
+        let phone_uuid = parse_uuid(&inputs.phone_id)?;
 
+        if inputs.code.trim().is_empty() {
+            return Err(invalid_field("code", "A verification code is required"));
+        }
+
+        let phone = phone_verification::verify_phone_code(self.phones.as_ref(), &self.config, phone_uuid, &inputs.code).await
+            .map_err(phone_verification_error)?;
+
+        Ok(Response::new(phone.into()))
+    }
+
+    async fn mark_phone_as_bounced(
+        &self,
+        request: Request<MarkPhoneAsBouncedRequest>,
+    ) -> Result<Response<Phone>, Status> {
+        let phone_uuid = parse_uuid(&request.into_inner().phone_id)?;
+
+        let phone = self.phones.update(phone_uuid, None, Some(models::PhoneStatusEnum::Bounced)).await
+            .map_err(|e| phone_error("updating", e))?;
+
+        Ok(Response::new(phone.into()))
     }
-}
\ No newline at end of file
+
+    async fn list_phones_by_user(
+        &self,
+        request: Request<ListPhonesByUserRequest>,
+    ) -> Result<Response<ListPhonesResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+
+        let page_size = match request.page_size {
+            size if size <= 0 => 50,
+            size => size.min(100) as i64,
+        };
+
+        let offset = if request.page_token.is_empty() {
+            0
+        } else {
+            request.page_token.parse::<i64>()
+                .map_err(|_| Status::invalid_argument("Invalid page token"))?
+        };
+
+        let phones = self.phones.find_by_user(user_uuid, page_size, offset).await
+            .map_err(|e| phone_error("listing", e))?;
+
+        let next_page_token = if phones.len() as i64 == page_size {
+            (offset + page_size).to_string()
+        } else {
+            "".to_string()
+        };
+
+        Ok(Response::new(ListPhonesResponse {
+            phones: phones.into_iter().map(Into::into).collect(),
+            next_page_token,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryRepository;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn phones_are_verified_with_the_texted_code() {
+        let config = testing::config(&[]);
+        let repository = Arc::new(MemoryRepository::new());
+        let (sms_gateway, sms) = testing::sms_gateway(&config);
+        let service = PhonesService::new(repository.clone(), &config, sms_gateway);
+
+        let user = testing::create_user(&repository, "alice").await;
+        let phone = testing::create_phone(&repository, user.user_uuid, "+442071838750", false).await;
+        let phone_id = phone.phone_uuid.to_string();
+
+        service.send_phone_verification(Request::new(SendPhoneVerificationRequest {
+            phone_id: phone_id.clone(),
+            locale: "en".to_string(),
+            ip: "203.0.113.7".to_string(),
+        })).await.unwrap();
+
+        let sent = sms.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "+442071838750");
+
+        let verify = |code: String| service.verify_phone(Request::new(VerifyPhoneRequest {
+            phone_id: phone_id.clone(),
+            code,
+        }));
+
+        let error = verify("000000".to_string()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+
+        let verified = verify(testing::code_in(&sent[0].body)).await.unwrap().into_inner();
+        assert!(verified.is_verified);
+        assert_eq!(verified.status, PhoneStatus::Verified as i32);
+
+        let error = verify(testing::code_in(&sent[0].body)).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+        assert!(repository.audit_events().iter().any(|event| event.event_type == "phone.verified"));
+    }
+}
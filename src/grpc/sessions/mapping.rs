@@ -0,0 +1,29 @@
+use chrono::Utc;
+use serde_json::Value;
+
+use crate::grpc::users::mapping::metadata_to_map;
+use crate::models;
+
+use super::v1::Session;
+
+impl From<(models::Session, Option<models::Device>)> for Session {
+    fn from((session, device): (models::Session, Option<models::Device>)) -> Self {
+        let is_active = session.is_active && session.revoked_at.is_none() && session.expires_at > Utc::now();
+
+        Session {
+            session_id: session.session_uuid.to_string(),
+            user_id: session.user_uuid.to_string(),
+            device_id: session.device_uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+            ip_address: session.ip_address.addr().to_string(),
+            // Located with GeoIP at sign-in.
+            location: metadata_to_map(session.metadata.get("location").unwrap_or(&Value::Null)),
+            is_active,
+            is_current: false,
+            created_at: session.created_at.map(|at| at.timestamp()).unwrap_or(0),
+            last_accessed_at: session.last_accessed_at.map(|at| at.timestamp()).unwrap_or(0),
+            expires_at: session.expires_at.timestamp(),
+            revoked_at: session.revoked_at.map(|at| at.timestamp()).unwrap_or(0),
+            device: device.map(Into::into),
+        }
+    }
+}
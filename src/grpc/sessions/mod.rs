@@ -0,0 +1,6 @@
+pub mod v1 {
+    tonic::include_proto!("ingot.api.sessions.v1");
+}
+
+pub mod service;
+pub mod mapping;
@@ -0,0 +1,286 @@
+use std::sync::Arc;
+use diesel::result::Error as DieselError;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::cache::Cache;
+use crate::repository::{RepositoryError, SessionRepository};
+
+use super::v1::sessions_server::Sessions;
+use super::v1::{GetSessionRequest, GetSessionResponse, ListUserSessionsRequest, ListUserSessionsResponse, RevokeDeviceSessionsRequest, RevokeDeviceSessionsResponse, RevokeOtherSessionsRequest, RevokeOtherSessionsResponse, RevokeSessionRequest, RevokeSessionResponse, Session};
+
+pub struct SessionsService {
+    sessions: Arc<dyn SessionRepository>,
+    cache: Arc<Cache>,
+}
+
+impl SessionsService {
+    pub fn new(sessions: Arc<dyn SessionRepository>, cache: Arc<Cache>) -> Self {
+        Self {
+            sessions,
+            cache,
+        }
+    }
+}
+
+fn parse_uuid(id: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::invalid_argument("Invalid UUID format"))
+}
+
+fn session_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found("Session not found"),
+        e => Status::internal(format!("Error {} session: {}", action, e)),
+    }
+}
+
+#[tonic::async_trait]
+impl Sessions for SessionsService {
+    async fn get_session(
+        &self,
+        request: Request<GetSessionRequest>,
+    ) -> Result<Response<GetSessionResponse>, Status> {
+        let session_uuid = parse_uuid(&request.into_inner().session_id)?;
+        let session = self.sessions.find_with_device(session_uuid).await
+            .map_err(|e| session_error("finding", e))?;
+
+        Ok(Response::new(GetSessionResponse {
+            session: Some(session.into()),
+        }))
+    }
+
+    async fn list_user_sessions(
+        &self,
+        request: Request<ListUserSessionsRequest>,
+    ) -> Result<Response<ListUserSessionsResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+
+        let current_session_uuid = match request.current_session_id.as_str() {
+            "" => None,
+            id => Some(parse_uuid(id)?),
+        };
+
+        let page_size = match request.page_size {
+            size if size <= 0 => 50,
+            size => size.min(100) as i64,
+        };
+
+        let offset = if request.page_token.is_empty() {
+            0
+        } else {
+            request.page_token.parse::<i64>()
+                .map_err(|_| Status::invalid_argument("Invalid page token"))?
+        };
+
+        let sessions = self.sessions.find_by_user_with_devices(user_uuid, request.include_inactive, page_size, offset).await
+            .map_err(|e| session_error("listing", e))?;
+
+        let next_page_token = if sessions.len() as i64 == page_size {
+            (offset + page_size).to_string()
+        } else {
+            "".to_string()
+        };
+
+        let sessions = sessions.into_iter().map(|session| {
+            let is_current = Some(session.0.session_uuid) == current_session_uuid;
+            Session {
+                is_current,
+                ..session.into()
+            }
+        }).collect();
+
+        Ok(Response::new(ListUserSessionsResponse {
+            sessions,
+            next_page_token,
+        }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<RevokeSessionRequest>,
+    ) -> Result<Response<RevokeSessionResponse>, Status> {
+        let session_uuid = parse_uuid(&request.into_inner().session_id)?;
+        let session = self.sessions.find(session_uuid).await
+            .map_err(|e| session_error("finding", e))?;
+
+        let revoked = self.sessions.revoke(&session).await
+            .map_err(|e| session_error("revoking", e))?;
+
+        self.cache.forget_sessions(&[session_uuid]).await;
+
+        Ok(Response::new(RevokeSessionResponse {
+            revoked,
+        }))
+    }
+
+    async fn revoke_other_sessions(
+        &self,
+        request: Request<RevokeOtherSessionsRequest>,
+    ) -> Result<Response<RevokeOtherSessionsResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+        let current_session_uuid = parse_uuid(&request.current_session_id)?;
+
+        // Keeping a session of someone else would sign this user out everywhere.
+        let current_session = self.sessions.find(current_session_uuid).await
+            .map_err(|e| session_error("finding", e))?;
+        if current_session.user_uuid != user_uuid {
+            return Err(Status::invalid_argument("Current session belongs to another user"));
+        }
+
+        let sessions_revoked = self.sessions.revoke_others_for_user(user_uuid, current_session_uuid).await
+            .map_err(|e| session_error("revoking", e))?;
+
+        self.cache.forget_sessions(&sessions_revoked).await;
+
+        Ok(Response::new(RevokeOtherSessionsResponse {
+            sessions_revoked: sessions_revoked.len() as i32,
+        }))
+    }
+
+    async fn revoke_device_sessions(
+        &self,
+        request: Request<RevokeDeviceSessionsRequest>,
+    ) -> Result<Response<RevokeDeviceSessionsResponse>, Status> {
+        let request = request.into_inner();
+        let user_uuid = parse_uuid(&request.user_id)?;
+        let device_uuid = parse_uuid(&request.device_id)?;
+
+        let sessions_revoked = self.sessions.revoke_for_user_device(user_uuid, device_uuid).await
+            .map_err(|e| session_error("revoking", e))?;
+
+        self.cache.forget_sessions(&sessions_revoked).await;
+
+        Ok(Response::new(RevokeDeviceSessionsResponse {
+            sessions_revoked: sessions_revoked.len() as i32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+    use crate::models;
+    use crate::repository::MemoryRepository;
+    use crate::testing;
+
+    fn service(repository: &Arc<MemoryRepository>) -> (SessionsService, Arc<Cache>) {
+        let cache = testing::cache(&testing::config(&[]));
+        (SessionsService::new(repository.clone(), cache.clone()), cache)
+    }
+
+    fn start(repository: &MemoryRepository, user: &models::User, device: &models::Device) -> Uuid {
+        let session = testing::session(user.user_uuid, device.device_uuid);
+        repository.insert_session(session.clone()).unwrap();
+        session.session_uuid
+    }
+
+    async fn is_revoked(repository: &MemoryRepository, session_uuid: Uuid) -> bool {
+        SessionRepository::find(repository, session_uuid).await.unwrap().revoked_at.is_some()
+    }
+
+    #[tokio::test]
+    async fn other_sessions_are_revoked_but_not_the_current_one() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, cache) = service(&repository);
+        let alice = testing::create_user(&repository, "alice").await;
+        let bob = testing::create_user(&repository, "bob").await;
+        let laptop = testing::create_device(&repository, Some(alice.user_uuid)).await;
+        let phone = testing::create_device(&repository, Some(alice.user_uuid)).await;
+
+        let current = start(&repository, &alice, &laptop);
+        let other = start(&repository, &alice, &phone);
+        let bobs = start(&repository, &bob, &phone);
+        cache.cache_session(other, Utc::now() + Duration::hours(1)).await;
+
+        let response = service.revoke_other_sessions(Request::new(RevokeOtherSessionsRequest {
+            user_id: alice.user_uuid.to_string(),
+            current_session_id: current.to_string(),
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.sessions_revoked, 1);
+        assert!(!is_revoked(&repository, current).await);
+        assert!(is_revoked(&repository, other).await);
+        assert!(!is_revoked(&repository, bobs).await);
+        assert!(cache.session_live_until(other).await.is_none());
+
+        let listed = service.list_user_sessions(Request::new(ListUserSessionsRequest {
+            user_id: alice.user_uuid.to_string(),
+            current_session_id: current.to_string(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(listed.sessions.len(), 1);
+        assert_eq!(listed.sessions[0].session_id, current.to_string());
+        assert!(listed.sessions[0].is_current);
+    }
+
+    #[tokio::test]
+    async fn the_current_session_must_belong_to_the_user() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository);
+        let alice = testing::create_user(&repository, "alice").await;
+        let bob = testing::create_user(&repository, "bob").await;
+        let device = testing::create_device(&repository, None).await;
+
+        let alices = start(&repository, &alice, &device);
+        let bobs = start(&repository, &bob, &device);
+
+        let status = service.revoke_other_sessions(Request::new(RevokeOtherSessionsRequest {
+            user_id: alice.user_uuid.to_string(),
+            current_session_id: bobs.to_string(),
+        })).await.unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(!is_revoked(&repository, alices).await);
+        assert!(!is_revoked(&repository, bobs).await);
+    }
+
+    #[tokio::test]
+    async fn revoking_the_current_session_signs_out_only_that_session() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, cache) = service(&repository);
+        let alice = testing::create_user(&repository, "alice").await;
+        let device = testing::create_device(&repository, Some(alice.user_uuid)).await;
+
+        let current = start(&repository, &alice, &device);
+        let other = start(&repository, &alice, &device);
+        cache.cache_session(current, Utc::now() + Duration::hours(1)).await;
+
+        let revoke = || service.revoke_session(Request::new(RevokeSessionRequest {
+            session_id: current.to_string(),
+        }));
+
+        assert!(revoke().await.unwrap().into_inner().revoked);
+        assert!(is_revoked(&repository, current).await);
+        assert!(!is_revoked(&repository, other).await);
+        assert!(cache.session_live_until(current).await.is_none());
+
+        // Signing out twice isn't an error, there's just nothing left to revoke.
+        assert!(!revoke().await.unwrap().into_inner().revoked);
+    }
+
+    #[tokio::test]
+    async fn device_sessions_of_other_users_stay_signed_in() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository);
+        let alice = testing::create_user(&repository, "alice").await;
+        let bob = testing::create_user(&repository, "bob").await;
+        let shared = testing::create_device(&repository, None).await;
+
+        let alices = start(&repository, &alice, &shared);
+        let bobs = start(&repository, &bob, &shared);
+
+        let response = service.revoke_device_sessions(Request::new(RevokeDeviceSessionsRequest {
+            user_id: alice.user_uuid.to_string(),
+            device_id: shared.device_uuid.to_string(),
+        })).await.unwrap().into_inner();
+
+        assert_eq!(response.sessions_revoked, 1);
+        assert!(is_revoked(&repository, alices).await);
+        assert!(!is_revoked(&repository, bobs).await);
+    }
+}
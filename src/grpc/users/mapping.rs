@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use serde_json::Value;
+
+use crate::import;
+use crate::models;
+
+use super::v1::{AttributeDefinition, AttributeType, AttributeVisibility, ImportUserRecord, ImportUserResult, UserDataJob, UserDataJobStatus, UserDataJobType, UserResponse};
+
+impl From<models::User> for UserResponse {
+    fn from(user: models::User) -> Self {
+        UserResponse {
+            id: user.user_uuid.to_string(),
+            username: user.username,
+            status: user.status as i32,
+            is_verified: user.is_verified,
+            onboarded: user.onboarded,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
+        }
+    }
+}
+
+/// Flattens the JSONB `metadata` column into the string map exposed over gRPC.
+///
+/// Non-string values are rendered as their JSON representation.
+pub fn metadata_to_map(metadata: &Value) -> HashMap<String, String> {
+    match metadata.as_object() {
+        Some(object) => object
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect(),
+        None => HashMap::new(),
+    }
+}
+
+impl From<models::AttributeTypeEnum> for AttributeType {
+    fn from(attribute_type: models::AttributeTypeEnum) -> Self {
+        match attribute_type {
+            models::AttributeTypeEnum::String => AttributeType::String,
+            models::AttributeTypeEnum::Integer => AttributeType::Integer,
+            models::AttributeTypeEnum::Number => AttributeType::Number,
+            models::AttributeTypeEnum::Boolean => AttributeType::Boolean,
+        }
+    }
+}
+
+impl From<AttributeType> for models::AttributeTypeEnum {
+    fn from(attribute_type: AttributeType) -> Self {
+        match attribute_type {
+            AttributeType::String => models::AttributeTypeEnum::String,
+            AttributeType::Integer => models::AttributeTypeEnum::Integer,
+            AttributeType::Number => models::AttributeTypeEnum::Number,
+            AttributeType::Boolean => models::AttributeTypeEnum::Boolean,
+        }
+    }
+}
+
+impl From<models::AttributeVisibilityEnum> for AttributeVisibility {
+    fn from(visibility: models::AttributeVisibilityEnum) -> Self {
+        match visibility {
+            models::AttributeVisibilityEnum::User => AttributeVisibility::User,
+            models::AttributeVisibilityEnum::Admin => AttributeVisibility::Admin,
+        }
+    }
+}
+
+impl From<AttributeVisibility> for models::AttributeVisibilityEnum {
+    fn from(visibility: AttributeVisibility) -> Self {
+        match visibility {
+            AttributeVisibility::User => models::AttributeVisibilityEnum::User,
+            AttributeVisibility::Admin => models::AttributeVisibilityEnum::Admin,
+        }
+    }
+}
+
+impl From<models::AttributeDefinition> for AttributeDefinition {
+    fn from(definition: models::AttributeDefinition) -> Self {
+        let enum_values = match definition.enum_values {
+            Some(Value::Array(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        AttributeDefinition {
+            key: definition.key,
+            r#type: AttributeType::from(definition.attribute_type) as i32,
+            description: definition.description.unwrap_or_default(),
+            required: definition.is_required,
+            unique: definition.is_unique,
+            indexed: definition.is_indexed,
+            visibility: AttributeVisibility::from(definition.visibility) as i32,
+            enum_values,
+            pattern: definition.pattern.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ImportUserRecord> for import::ImportRecord {
+    fn from(record: ImportUserRecord) -> Self {
+        import::ImportRecord {
+            username: record.username,
+            password: Some(record.password).filter(|password| !password.is_empty()),
+            password_hash: Some(record.password_hash).filter(|hash| !hash.is_empty()),
+            emails: record.emails,
+            phones: record.phones
+                .into_iter()
+                .map(|phone| import::ImportPhone {
+                    country_code: phone.country_code,
+                    number: phone.number,
+                })
+                .collect(),
+            roles: record.roles,
+            metadata: record.metadata
+                .into_iter()
+                .map(|(key, value)| (key, Value::String(value)))
+                .collect(),
+        }
+    }
+}
+
+impl From<import::ImportRowResult> for ImportUserResult {
+    fn from(result: import::ImportRowResult) -> Self {
+        ImportUserResult {
+            row: result.row as i64,
+            success: result.error.is_none(),
+            username: result.username,
+            user_id: result.user_uuid.map(|uuid| uuid.to_string()).unwrap_or_default(),
+            message: result.error.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<models::UserDataJob> for UserDataJob {
+    fn from(job: models::UserDataJob) -> Self {
+        let job_type = match job.job_type {
+            models::UserDataJobTypeEnum::Export => UserDataJobType::Export,
+            models::UserDataJobTypeEnum::Erasure => UserDataJobType::Erasure,
+        };
+
+        let status = match job.status {
+            models::UserDataJobStatusEnum::Pending => UserDataJobStatus::Pending,
+            models::UserDataJobStatusEnum::Running => UserDataJobStatus::Running,
+            models::UserDataJobStatusEnum::Completed => UserDataJobStatus::Completed,
+            models::UserDataJobStatusEnum::Failed => UserDataJobStatus::Failed,
+        };
+
+        UserDataJob {
+            id: job.job_uuid.to_string(),
+            user_id: job.user_uuid.to_string(),
+            r#type: job_type as i32,
+            status: status as i32,
+            result: job.result.map(|result| result.to_string()).unwrap_or_default(),
+            error: job.error.unwrap_or_default(),
+            created_at: job.created_at.timestamp(),
+            completed_at: job.completed_at.map(|completed_at| completed_at.timestamp()).unwrap_or_default(),
+        }
+    }
+}
@@ -2,4 +2,5 @@ pub mod v1 {
     tonic::include_proto!("ingot.api.users.v1");
 }
 
-pub mod service;
\ No newline at end of file
+pub mod service;
+pub mod mapping;
\ No newline at end of file
@@ -1,84 +1,208 @@
-use argon2::password_hash::Salt;
-use serde_json::json;
+use serde_json::{json, Map, Value};
 use regex::Regex;
 use uuid::Uuid;
-use std::borrow::Borrow;
-use std::sync::{Arc, Mutex};
-use diesel::PgConnection;
-use tonic::{Request, Response, Status};
+use std::sync::Arc;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use tonic::{Request, Response, Status, Streaming};
 
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::import;
+use crate::mailer::Mailer;
+use crate::mfa;
+use crate::privacy::JobRunner;
+use crate::repository::{DeviceRepository, EmailRepository, PhoneRepository, Repositories, RepositoryError, UserRepository};
 use crate::utils;
+use crate::verification;
 use crate::models;
 
-use super::v1::{ChangePasswordRequest, UpdateUserRequest, GetUserByUsernameRequest, CheckPasswordRequest, CheckPasswordResponse, CreateUserRequest, DeleteUserRequest, GetUserByIdRequest, UserResponse};
+use crate::grpc::errors::invalid_field;
+use super::mapping::metadata_to_map;
+use super::v1::{AttributeDefinition, AttributeView, ChangePasswordRequest, UpdateUserRequest, GetUserByUsernameRequest, CheckPasswordRequest, CheckPasswordResponse, CreateUserRequest, DefineAttributeRequest, DeleteAttributeDefinitionRequest, DeleteUserRequest, EraseUserDataRequest, ExportUserDataRequest, GetUserAttributesRequest, GetUserByIdRequest, GetUserDataJobRequest, ImportUsersOptions, ImportUsersRequest, ImportUsersResponse, ListAttributeDefinitionsRequest, ListAttributeDefinitionsResponse, ListUsersResponse, RegisterUserRequest, RegisterUserResponse, SearchUsersByAttributeRequest, UpdateUserAttributesRequest, UserAttributesResponse, UserDataJob, UserResponse};
+use super::v1::import_users_request::Payload;
 use super::v1::users_server::Users;
 
 pub struct UsersService {
-    database: Arc<Mutex<PgConnection>>,
+    users: Arc<dyn UserRepository>,
+    emails: Arc<dyn EmailRepository>,
+    phones: Arc<dyn PhoneRepository>,
+    devices: Arc<dyn DeviceRepository>,
+    config: Config,
+    mailer: Arc<Mailer>,
+    email_policy: Arc<utils::EmailPolicy>,
+    reserved_usernames: Vec<String>,
+    cache: Arc<Cache>,
+    data_jobs: Arc<dyn JobRunner>,
 }
 
 impl UsersService {
-    pub fn new(database: Arc<Mutex<PgConnection>>) -> Self {
+    pub fn new(
+        repositories: Repositories,
+        config: &Config,
+        mailer: Arc<Mailer>,
+        email_policy: Arc<utils::EmailPolicy>,
+        cache: Arc<Cache>,
+        data_jobs: Arc<dyn JobRunner>,
+    ) -> Self {
         Self {
-            database,
+            users: repositories.users,
+            emails: repositories.emails,
+            phones: repositories.phones,
+            devices: repositories.devices,
+            config: config.clone(),
+            mailer,
+            email_policy,
+            reserved_usernames: config.reserved_usernames(),
+            cache,
+            data_jobs,
         }
     }
-}
 
-#[tonic::async_trait]
-impl Users for UsersService {
-    async fn create_user(
+    async fn queue_user_data_job(
         &self,
-        request: Request<CreateUserRequest>,
-    ) -> Result<Response<UserResponse>, Status> {
-        let user = request.into_inner();
-        let password = user.password.borrow();
+        user_id: &str,
+        job_type: models::UserDataJobTypeEnum,
+    ) -> Result<UserDataJob, Status> {
+        let user_uuid = Uuid::parse_str(user_id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
 
-        let username_regex = Regex::new(r"^[a-zA-Z0-9_]+$").unwrap();
+        self.users.find(user_uuid).await
+            .map_err(|e| not_found_or("User not found", "finding user", e))?;
 
-        if !username_regex.is_match(&user.username) {
-            return Err(Status::invalid_argument("Username must contain only letters, numbers, and underscores"));
-        }
+        let job = self.users.create_data_job(models::NewUserDataJob { user_uuid, job_type }).await
+            .map_err(|e| internal_error("creating job", e))?;
 
-        if user.username.len() < 3 {
-            return Err(Status::invalid_argument("Username must be at least 3 characters"));
-        }
+        self.data_jobs.spawn(job.job_uuid);
 
-        if user.username.len() > 32 {
-            return Err(Status::invalid_argument("Username must be less than 32 characters"));
-        }
+        Ok(job.into())
+    }
 
-        if user.password.len() < 8 {
-            return Err(Status::invalid_argument("Password must be at least 8 characters"));
-        }
+    async fn import_batch(
+        &self,
+        context: &Arc<import::ImportContext>,
+        batch: Vec<(usize, import::ImportRecord)>,
+        dry_run: bool,
+    ) -> Result<Vec<import::ImportRowResult>, Status> {
+        // Password hashing is the slow part, so it happens on the blocking pool
+        // before anything is written.
+        let prepare_context = context.clone();
+        let prepared = tokio::task::spawn_blocking(move || {
+            batch
+                .into_iter()
+                .map(|(row, record)| (row, prepare_context.prepare(record)))
+                .collect()
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Error preparing users: {}", e)))?;
 
-        if user.password.len() > 64 {
-            return Err(Status::invalid_argument("Password must be less than 64 characters"));
-        }
+        self.users.import_batch(context, prepared, dry_run).await
+            .map_err(|e| internal_error("importing users", e))
+    }
+}
 
-        let has_uppercase = Regex::new(r"[A-Z]").unwrap().is_match(password);
+fn user_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        e => Status::internal(format!("Error {} user: {}", action, e)),
+    }
+}
 
-        if !has_uppercase {
-            return Err(Status::invalid_argument("Password must contain at least one uppercase letter"));
-        }
+/// Maps a repository failure, `action` completes "Error ...".
+fn internal_error(action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Unavailable(e) => Status::unavailable(format!("Database unavailable: {}", e)),
+        e => Status::internal(format!("Error {}: {}", action, e)),
+    }
+}
 
-        let has_lowercase = Regex::new(r"[a-z]").unwrap().is_match(password);
+fn not_found_or(message: &str, action: &str, error: RepositoryError) -> Status {
+    match error {
+        RepositoryError::Database(DieselError::NotFound) => Status::not_found(message),
+        e => internal_error(action, e),
+    }
+}
 
-        if !has_lowercase {
-            return Err(Status::invalid_argument("Password must contain at least one lowercase letter"));
+fn registration_error(error: impl Into<RepositoryError>) -> Status {
+    match error.into() {
+        // Lost a race against a look-alike registered after the confusable check.
+        RepositoryError::Database(e) if utils::is_username_conflict(&e) => {
+            Status::already_exists("Username is already taken")
+        }
+        RepositoryError::Database(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+            Status::already_exists(format!("Already registered: {}", info.message()))
         }
+        e => user_error("registering", e),
+    }
+}
 
-        let has_number = Regex::new(r"[0-9]").unwrap().is_match(password);
+/// Metadata key carrying the admin API key.
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
 
-        if !has_number {
-            return Err(Status::invalid_argument("Password must contain at least one number"));
-        }
+/// Whether a request asking for `view` is served with the admin view of
+/// custom attributes: it must present the admin API key, without one
+/// admin-only attributes stay out of the response.
+fn admin_view<T>(config: &Config, request: &Request<T>, view: i32) -> bool {
+    if view != AttributeView::Admin as i32 {
+        return false;
+    }
+
+    let Some(key) = config.admin_api_key() else {
+        return false;
+    };
+
+    request.metadata().get(ADMIN_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|presented| utils::constant_time_eq(presented.as_bytes(), key.as_bytes()))
+}
+
+/// Rejects a username that looks like one already in use by another user.
+async fn check_confusable_username(
+    users: &dyn UserRepository,
+    skeleton: &str,
+    user_uuid: Option<Uuid>,
+) -> Result<(), Status> {
+    let existing = users.find_confusable(skeleton, user_uuid).await
+        .map_err(|e| user_error("checking", e))?;
 
-        let has_special = Regex::new(r"[!@#$%^&*(),.?:{}|<>]").unwrap().is_match(password);
+    if existing.is_some() {
+        return Err(Status::already_exists("Username is already taken"));
+    }
 
-        if !has_special {
-            return Err(Status::invalid_argument("Password must contain at least one special character"));
+    Ok(())
+}
+
+async fn check_unique_attributes(
+    users: &dyn UserRepository,
+    definitions: &[models::AttributeDefinition],
+    attributes: &Map<String, Value>,
+    user_uuid: Option<Uuid>,
+) -> Result<(), Status> {
+    for definition in definitions.iter().filter(|definition| definition.is_unique) {
+        if let Some(value) = attributes.get(&definition.key) {
+            let taken = users.attribute_value_taken(&definition.key, value.clone(), user_uuid).await
+                .map_err(|e| internal_error("checking attribute", e))?;
+
+            if taken {
+                return Err(Status::already_exists(format!("Attribute '{}' is already in use", definition.key)));
+            }
         }
+    }
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Users for UsersService {
+    async fn create_user(
+        &self,
+        request: Request<CreateUserRequest>,
+    ) -> Result<Response<UserResponse>, Status> {
+        let user = request.into_inner();
+
+        let username = utils::canonicalize_username(&user.username, &self.reserved_usernames)
+            .map_err(Status::invalid_argument)?;
+        let username_skeleton = utils::username_skeleton(&username);
+        utils::validate_password(&user.password).map_err(Status::invalid_argument)?;
 
         // if user.password != user.confirm_password {
         //     return Err(Status::invalid_argument("Passwords do not match"));
@@ -88,20 +212,20 @@ impl Users for UsersService {
         //     return Err(Status::invalid_argument("Email is required"));
         // }
 
-        let salt_str = "YmFkIHNhbHQh";
-        let salt: Salt = salt_str.try_into().unwrap();
-        let hashed_password = utils::hash_password(&user.password, salt).unwrap();
+        let hashed_password = utils::hash_new_password(&user.password)
+            .map_err(|e| Status::internal(format!("Error hashing password: {}", e)))?;
 
         let user = models::NewUser {
-            username: user.username.to_lowercase(),
+            username,
+            username_skeleton,
             password_hash: hashed_password,
             metadata: json!({}),
         };
 
-        let database = self.database.lock();
-        
-        let user = models::User::create(&mut database.unwrap(), user)
-            .map_err(|e| Status::internal(format!("Error creating user: {}", e)))?;
+        check_confusable_username(self.users.as_ref(), &user.username_skeleton, None).await?;
+
+        let user = self.users.create(user).await
+            .map_err(registration_error)?;
 
         Ok(Response::new(UserResponse {
             id: user.user_uuid.to_string(),
@@ -109,6 +233,8 @@ impl Users for UsersService {
             status: user.status as i32,
             is_verified: user.is_verified,
             onboarded: user.onboarded,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
         }))
 
         // timezone: user.timezone,
@@ -119,15 +245,153 @@ impl Users for UsersService {
         // updated_at: user.updated_at,
     }
 
+    async fn register_user(
+        &self,
+        request: Request<RegisterUserRequest>,
+    ) -> Result<Response<RegisterUserResponse>, Status> {
+        let inputs = request.into_inner();
+
+        let username = utils::canonicalize_username(&inputs.username, &self.reserved_usernames)
+            .map_err(Status::invalid_argument)?;
+        let username_skeleton = utils::username_skeleton(&username);
+        utils::validate_password(&inputs.password).map_err(Status::invalid_argument)?;
+
+        let address = self.email_policy.normalize(&inputs.email)
+            .map_err(|e| invalid_field("email", e))?;
+
+        let phone_number = match &inputs.phone {
+            Some(phone) => Some(utils::normalize_phone(&phone.country_code, &phone.number)
+                .map_err(|e| invalid_field("phone", e))?),
+            None => None,
+        };
+
+        let hashed_password = utils::hash_new_password(&inputs.password)
+            .map_err(|e| Status::internal(format!("Error hashing password: {}", e)))?;
+
+        let mut role_names = inputs.roles.clone();
+        role_names.sort();
+        role_names.dedup();
+
+        check_confusable_username(self.users.as_ref(), &username_skeleton, None).await?;
+
+        let roles = self.users.find_roles_by_names(&role_names).await
+            .map_err(|e| internal_error("finding roles", e))?;
+
+        let unknown_roles: Vec<&str> = role_names
+            .iter()
+            .filter(|name| !roles.iter().any(|role| &role.role_name == *name))
+            .map(|name| name.as_str())
+            .collect();
+
+        if !unknown_roles.is_empty() {
+            return Err(Status::invalid_argument(format!("Unknown roles: {}", unknown_roles.join(", "))));
+        }
+
+        let role_uuids: Vec<Uuid> = roles.iter().map(|role| role.role_uuid).collect();
+
+        let definitions = self.users.list_attribute_definitions().await
+            .map_err(|e| internal_error("finding attribute definitions", e))?;
+
+        // Registration is open, admin-only attributes are set afterwards.
+        let attributes = utils::coerce_attributes(&definitions, &inputs.metadata, false)
+            .map_err(Status::invalid_argument)?;
+        utils::check_required_attributes(&definitions, &attributes).map_err(Status::invalid_argument)?;
+        check_unique_attributes(self.users.as_ref(), &definitions, &attributes, None).await?;
+
+        // Registered all at once so a failure never leaves a user without its
+        // primary email.
+        let new_user = models::NewUser {
+            username,
+            username_skeleton,
+            password_hash: hashed_password,
+            metadata: Value::Object(attributes),
+        };
+
+        let (user, email, phone) = self.users.register(new_user, address, phone_number, role_uuids).await
+            .map_err(registration_error)?;
+
+        // The user exists at this point, a failure to queue the email shouldn't
+        // fail the registration, verification can be requested again.
+        if inputs.send_verification_email {
+            if let Err(e) = verification::send_email_verification(self.emails.as_ref(), &self.config, &self.mailer, email.email_uuid, &inputs.locale).await {
+                println!("Error sending verification email for user {}: {}", user.user_uuid, e);
+            }
+        }
+
+        Ok(Response::new(RegisterUserResponse {
+            metadata: metadata_to_map(&utils::visible_attributes(&definitions, &user.metadata, false)),
+            user: Some(user.into()),
+            email: Some(email.into()),
+            phone: phone.map(Into::into),
+            roles: roles.into_iter().map(|role| role.role_name).collect(),
+        }))
+    }
+
+    async fn import_users(
+        &self,
+        request: Request<Streaming<ImportUsersRequest>>,
+    ) -> Result<Response<ImportUsersResponse>, Status> {
+        let mut stream = request.into_inner();
+
+        let definitions = self.users.list_attribute_definitions().await
+            .map_err(|e| internal_error("loading import context", e))?;
+        let roles = self.users.list_roles().await
+            .map_err(|e| internal_error("loading import context", e))?;
+        let context = Arc::new(import::ImportContext::new(definitions, roles, self.reserved_usernames.clone(), self.email_policy.clone()));
+
+        let mut options = ImportUsersOptions::default();
+        let mut received = 0usize;
+        let mut rows = 0usize;
+        let mut batch = vec![];
+        let mut results = vec![];
+
+        while let Some(message) = stream.message().await? {
+            match message.payload {
+                Some(Payload::Options(value)) if received == 0 => options = value,
+                Some(Payload::Options(_)) => {
+                    return Err(Status::invalid_argument("Import options must be the first message"));
+                }
+                Some(Payload::Record(record)) => {
+                    rows += 1;
+                    batch.push((rows, record.into()));
+                }
+                None => {}
+            }
+
+            received += 1;
+
+            let batch_size = match options.batch_size {
+                size if size <= 0 => import::DEFAULT_BATCH_SIZE,
+                size => (size as usize).min(import::MAX_BATCH_SIZE),
+            };
+
+            if batch.len() >= batch_size {
+                results.extend(self.import_batch(&context, std::mem::take(&mut batch), options.dry_run).await?);
+            }
+        }
+
+        if !batch.is_empty() {
+            results.extend(self.import_batch(&context, batch, options.dry_run).await?);
+        }
+
+        let failed = results.iter().filter(|result| result.error.is_some()).count() as i64;
+
+        Ok(Response::new(ImportUsersResponse {
+            imported: results.len() as i64 - failed,
+            failed,
+            dry_run: options.dry_run,
+            results: results.into_iter().map(Into::into).collect(),
+        }))
+    }
+
     async fn check_user_password(
         &self,
         request: Request<CheckPasswordRequest>
     ) -> Result<Response<CheckPasswordResponse>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
         
-        let user = models::User::find_user_uuid(&mut database, Uuid::parse_str(&request.id).unwrap())
-            .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?;
+        let user = self.users.find(Uuid::parse_str(&request.id).unwrap()).await
+            .map_err(|e| user_error("finding", e))?;
   
         let valid = utils::verify_password(&request.password, &user.password_hash);
 
@@ -145,10 +409,9 @@ impl Users for UsersService {
         request: Request<GetUserByIdRequest>
     ) -> Result<Response<UserResponse>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
         
-        let user = models::User::find_user_uuid(&mut database, Uuid::parse_str(&request.id).unwrap())
-            .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?;
+        let user = self.users.find(Uuid::parse_str(&request.id).unwrap()).await
+            .map_err(|e| user_error("finding", e))?;
 
         Ok(Response::new(UserResponse {
             id: user.user_uuid.to_string(),
@@ -156,6 +419,8 @@ impl Users for UsersService {
             status: user.status as i32,
             is_verified: user.is_verified,
             onboarded: user.onboarded,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
         }))
     }
 
@@ -164,12 +429,13 @@ impl Users for UsersService {
         request: Request<DeleteUserRequest>,
     ) -> Result<Response<()>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
 
         // Check if user Exist 
+        let user_uuid = Uuid::parse_str(&request.id).unwrap();
         
-        models::User::delete_user(&mut database, Uuid::parse_str(&request.id).unwrap())
-            .map_err(|e| Status::internal(format!("Error deleting user: {}", e)))?;
+        self.users.delete(user_uuid).await
+            .map_err(|e| user_error("deleting", e))?;
+        self.cache.forget_user(user_uuid).await;
 
         Ok(Response::new(()))
     }
@@ -180,41 +446,12 @@ impl Users for UsersService {
         request: Request<ChangePasswordRequest>,
     ) -> Result<Response<()>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
 
         // Validate new password requirements
-        let password = request.new_password.borrow();
+        utils::validate_password(&request.new_password).map_err(Status::invalid_argument)?;
 
-        if request.new_password.len() < 8 {
-            return Err(Status::invalid_argument("Password must be at least 8 characters"));
-        }
-
-        if request.new_password.len() > 64 {
-            return Err(Status::invalid_argument("Password must be less than 64 characters"));
-        }
-
-        let has_uppercase = Regex::new(r"[A-Z]").unwrap().is_match(password);
-        if !has_uppercase {
-            return Err(Status::invalid_argument("Password must contain at least one uppercase letter"));
-        }
-
-        let has_lowercase = Regex::new(r"[a-z]").unwrap().is_match(password);
-        if !has_lowercase {
-            return Err(Status::invalid_argument("Password must contain at least one lowercase letter"));
-        }
-
-        let has_number = Regex::new(r"[0-9]").unwrap().is_match(password);
-        if !has_number {
-            return Err(Status::invalid_argument("Password must contain at least one number"));
-        }
-
-        let has_special = Regex::new(r"[!@#$%^&*(),.?:{}|<>]").unwrap().is_match(password);
-        if !has_special {
-            return Err(Status::invalid_argument("Password must contain at least one special character"));
-        }
-
-        let user = models::User::find_user_uuid(&mut database, Uuid::parse_str(&request.id).unwrap())
-            .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?;
+        let user = self.users.find(Uuid::parse_str(&request.id).unwrap()).await
+            .map_err(|e| user_error("finding", e))?;
 
         // Verify old password
         let is_valid = utils::verify_password(&request.current_password, &user.password_hash)
@@ -224,12 +461,11 @@ impl Users for UsersService {
         }
 
         // Hash and update new password
-        let salt_str = "YmFkIHNhbHQh";
-        let salt: Salt = salt_str.try_into().unwrap();
-        let new_hash = utils::hash_password(&request.new_password, salt).unwrap();
+        let new_hash = utils::hash_new_password(&request.new_password)
+            .map_err(|e| Status::internal(format!("Error hashing password: {}", e)))?;
 
-        models::User::update_password(&mut database, user.user_uuid, new_hash)
-            .map_err(|e| Status::internal(format!("Error updating password: {}", e)))?;
+        self.users.update_password(user.user_uuid, new_hash).await
+            .map_err(|e| user_error("updating", e))?;
 
         Ok(Response::new(()))
     }
@@ -240,10 +476,9 @@ impl Users for UsersService {
         request: Request<GetUserByUsernameRequest>,
     ) -> Result<Response<UserResponse>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
         
-        let user = models::User::find_by_username(&mut database, request.username)
-            .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?;
+        let user = self.users.find_by_username(request.username).await
+            .map_err(|e| user_error("finding", e))?;
 
         Ok(Response::new(UserResponse {
             id: user.user_uuid.to_string(),
@@ -251,6 +486,8 @@ impl Users for UsersService {
             status: user.status as i32,
             is_verified: user.is_verified,
             onboarded: user.onboarded,
+            email_update_required: user.email_update_required,
+            mfa_enabled: user.mfa_enabled,
         }))
     }
 
@@ -260,18 +497,51 @@ impl Users for UsersService {
         request: Request<UpdateUserRequest>,
     ) -> Result<Response<UserResponse>, Status> {
         let request = request.into_inner();
-        let mut database = self.database.lock().unwrap();
 
         // Check if user exists
         let user_uuid = Uuid::parse_str(&request.id)
             .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
 
-        let user = models::User::find_user_uuid(&mut database, user_uuid)
-            .map_err(|e| Status::internal(format!("Error finding user: {}", e)))?;
+        let user = self.users.find(user_uuid).await
+            .map_err(|e| user_error("finding", e))?;
+
+        let mut updated_user = user;
+
+        if let Some(mfa_enabled) = request.mfa_enabled {
+            if mfa_enabled {
+                // The second factor is a code texted to a verified phone or a
+                // push approved on a trusted device, either one will do.
+                let has_verified_phone = self.phones.find_by_user(user_uuid, 100, 0).await
+                    .map_err(|e| Status::internal(format!("Error finding phones: {}", e)))?
+                    .iter()
+                    .any(|phone| phone.is_verified);
+                let has_factor = has_verified_phone || mfa::has_push_device(self.devices.as_ref(), user_uuid).await
+                    .map_err(|e| Status::internal(format!("Error finding devices: {}", e)))?;
+
+                if !has_factor {
+                    return Err(Status::failed_precondition("A verified phone or a push enabled trusted device is required to enable MFA"));
+                }
+            }
+
+            updated_user = self.users.set_mfa_enabled(user_uuid, mfa_enabled).await
+                .map_err(|e| user_error("updating", e))?;
+            self.cache.forget_user(user_uuid).await;
+        }
+
+        if request.username.is_empty() {
+            return Ok(Response::new(updated_user.into()));
+        }
+
+        let username = utils::canonicalize_username(&request.username, &self.reserved_usernames)
+            .map_err(Status::invalid_argument)?;
+        let username_skeleton = utils::username_skeleton(&username);
+
+        check_confusable_username(self.users.as_ref(), &username_skeleton, Some(user_uuid)).await?;
 
         // Save the updated user
-        let updated_user = models::User::update(&mut database, user)
-            .map_err(|e| Status::internal(format!("Error updating user: {}", e)))?;
+        let updated_user = self.users.update_username(user_uuid, username, username_skeleton).await
+            .map_err(registration_error)?;
+        self.cache.forget_user(user_uuid).await;
 
         Ok(Response::new(UserResponse {
             id: updated_user.user_uuid.to_string(),
@@ -279,6 +549,513 @@ impl Users for UsersService {
             status: updated_user.status as i32,
             is_verified: updated_user.is_verified,
             onboarded: updated_user.onboarded,
+            email_update_required: updated_user.email_update_required,
+            mfa_enabled: updated_user.mfa_enabled,
+        }))
+    }
+
+    async fn define_attribute(
+        &self,
+        request: Request<DefineAttributeRequest>,
+    ) -> Result<Response<AttributeDefinition>, Status> {
+        let definition = request.into_inner().definition
+            .ok_or_else(|| Status::invalid_argument("Definition is required"))?;
+
+        let key_regex = Regex::new(r"^[a-zA-Z0-9_]{1,64}$").unwrap();
+
+        if !key_regex.is_match(&definition.key) {
+            return Err(Status::invalid_argument("Attribute key must be 1 to 64 letters, numbers, or underscores"));
+        }
+
+        let attribute_type: models::AttributeTypeEnum = definition.r#type().into();
+
+        if !definition.pattern.is_empty() {
+            if attribute_type != models::AttributeTypeEnum::String {
+                return Err(Status::invalid_argument("Patterns are only supported on string attributes"));
+            }
+
+            Regex::new(&definition.pattern)
+                .map_err(|e| Status::invalid_argument(format!("Invalid pattern: {}", e)))?;
+        }
+
+        let enum_values = if definition.enum_values.is_empty() {
+            None
+        } else {
+            let values = definition.enum_values
+                .iter()
+                .map(|value| utils::parse_attribute_value(&definition.key, &attribute_type, value))
+                .collect::<Result<Vec<Value>, String>>()
+                .map_err(Status::invalid_argument)?;
+            Some(Value::Array(values))
+        };
+
+        let new_definition = models::NewAttributeDefinition {
+            key: definition.key.clone(),
+            attribute_type,
+            description: Some(definition.description.clone()).filter(|description| !description.is_empty()),
+            is_required: definition.required,
+            is_unique: definition.unique,
+            is_indexed: definition.indexed,
+            visibility: definition.visibility().into(),
+            enum_values,
+            pattern: Some(definition.pattern.clone()).filter(|pattern| !pattern.is_empty()),
+        };
+
+        let definition = self.users.upsert_attribute_definition(new_definition).await
+            .map_err(|e| internal_error("saving attribute definition", e))?;
+
+        Ok(Response::new(definition.into()))
+    }
+
+    async fn delete_attribute_definition(
+        &self,
+        request: Request<DeleteAttributeDefinitionRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
+        let deleted = self.users.delete_attribute_definition(&request.key).await
+            .map_err(|e| internal_error("deleting attribute definition", e))?;
+
+        if !deleted {
+            return Err(Status::not_found(format!("Attribute '{}' is not defined", request.key)));
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn list_attribute_definitions(
+        &self,
+        _request: Request<ListAttributeDefinitionsRequest>,
+    ) -> Result<Response<ListAttributeDefinitionsResponse>, Status> {
+        let definitions = self.users.list_attribute_definitions().await
+            .map_err(|e| internal_error("finding attribute definitions", e))?;
+
+        Ok(Response::new(ListAttributeDefinitionsResponse {
+            definitions: definitions.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_user_attributes(
+        &self,
+        request: Request<GetUserAttributesRequest>,
+    ) -> Result<Response<UserAttributesResponse>, Status> {
+        let admin = admin_view(&self.config, &request, request.get_ref().view);
+        let request = request.into_inner();
+
+        let user_uuid = Uuid::parse_str(&request.id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+
+        let user = self.users.find(user_uuid).await
+            .map_err(|e| user_error("finding", e))?;
+
+        let definitions = self.users.list_attribute_definitions().await
+            .map_err(|e| internal_error("finding attribute definitions", e))?;
+
+        let attributes = utils::visible_attributes(&definitions, &user.metadata, admin);
+
+        Ok(Response::new(UserAttributesResponse {
+            id: user.user_uuid.to_string(),
+            attributes: metadata_to_map(&attributes),
         }))
     }
-}
\ No newline at end of file
+
+    async fn update_user_attributes(
+        &self,
+        request: Request<UpdateUserAttributesRequest>,
+    ) -> Result<Response<UserAttributesResponse>, Status> {
+        let admin = admin_view(&self.config, &request, request.get_ref().view);
+        let request = request.into_inner();
+
+        let user_uuid = Uuid::parse_str(&request.id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+
+        let user = self.users.find(user_uuid).await
+            .map_err(|e| user_error("finding", e))?;
+
+        let definitions = self.users.list_attribute_definitions().await
+            .map_err(|e| internal_error("finding attribute definitions", e))?;
+
+        let updates = utils::coerce_attributes(&definitions, &request.attributes, admin)
+            .map_err(Status::invalid_argument)?;
+
+        let mut metadata = user.metadata.as_object().cloned().unwrap_or_default();
+
+        for key in &request.remove {
+            let user_visible = definitions.iter().any(|definition| {
+                &definition.key == key && definition.visibility == models::AttributeVisibilityEnum::User
+            });
+
+            if !admin && !user_visible {
+                return Err(Status::permission_denied(format!("Attribute '{}' can only be changed by an administrator", key)));
+            }
+
+            metadata.remove(key);
+        }
+
+        check_unique_attributes(self.users.as_ref(), &definitions, &updates, Some(user_uuid)).await?;
+        metadata.extend(updates);
+        utils::check_required_attributes(&definitions, &metadata).map_err(Status::invalid_argument)?;
+
+        let user = self.users.update_metadata(user_uuid, Value::Object(metadata)).await
+            .map_err(|e| user_error("updating", e))?;
+
+        let attributes = utils::visible_attributes(&definitions, &user.metadata, admin);
+
+        Ok(Response::new(UserAttributesResponse {
+            id: user.user_uuid.to_string(),
+            attributes: metadata_to_map(&attributes),
+        }))
+    }
+
+    async fn search_users_by_attribute(
+        &self,
+        request: Request<SearchUsersByAttributeRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let request = request.into_inner();
+
+        let page_size = match request.page_size {
+            size if size <= 0 => 50,
+            size => size.min(100) as i64,
+        };
+
+        let offset = if request.page_token.is_empty() {
+            0
+        } else {
+            request.page_token.parse::<i64>()
+                .map_err(|_| Status::invalid_argument("Invalid page token"))?
+        };
+
+        let definition = self.users.find_attribute_definition(&request.key).await
+            .map_err(|e| not_found_or(&format!("Attribute '{}' is not defined", request.key), "finding attribute definition", e))?;
+
+        if !definition.is_indexed {
+            return Err(Status::failed_precondition(format!("Attribute '{}' is not indexed", request.key)));
+        }
+
+        // Matching on an admin-only attribute would reveal its values.
+        if definition.visibility == models::AttributeVisibilityEnum::Admin {
+            return Err(Status::permission_denied(format!("Attribute '{}' can only be searched by an administrator", request.key)));
+        }
+
+        let value = utils::parse_attribute_value(&definition.key, &definition.attribute_type, &request.value)
+            .map_err(Status::invalid_argument)?;
+
+        let users = self.users.find_by_attribute(&definition.key, value, page_size, offset).await
+            .map_err(|e| internal_error("finding users", e))?;
+
+        let next_page_token = if users.len() as i64 == page_size {
+            (offset + page_size).to_string()
+        } else {
+            "".to_string()
+        };
+
+        Ok(Response::new(ListUsersResponse {
+            users: users.into_iter().map(Into::into).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn export_user_data(
+        &self,
+        request: Request<ExportUserDataRequest>,
+    ) -> Result<Response<UserDataJob>, Status> {
+        let request = request.into_inner();
+        let job = self.queue_user_data_job(&request.id, models::UserDataJobTypeEnum::Export).await?;
+        Ok(Response::new(job))
+    }
+
+    async fn erase_user_data(
+        &self,
+        request: Request<EraseUserDataRequest>,
+    ) -> Result<Response<UserDataJob>, Status> {
+        let request = request.into_inner();
+        let job = self.queue_user_data_job(&request.id, models::UserDataJobTypeEnum::Erasure).await?;
+        Ok(Response::new(job))
+    }
+
+    async fn get_user_data_job(
+        &self,
+        request: Request<GetUserDataJobRequest>,
+    ) -> Result<Response<UserDataJob>, Status> {
+        let request = request.into_inner();
+
+        let job_uuid = Uuid::parse_str(&request.id)
+            .map_err(|_| Status::invalid_argument("Invalid UUID format"))?;
+
+        let job = self.users.find_data_job(job_uuid).await
+            .map_err(|e| not_found_or("Job not found", "finding job", e))?;
+
+        Ok(Response::new(job.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::repository::MemoryRepository;
+    use crate::testing;
+    use crate::trusted_devices;
+    use super::super::v1::{AttributeType, AttributeVisibility, RegisterUserPhone};
+
+    fn service(repository: &Arc<MemoryRepository>, config: &Config) -> (UsersService, Arc<testing::StubJobRunner>) {
+        let data_jobs = Arc::new(testing::StubJobRunner::default());
+        let service = UsersService::new(
+            Repositories::new(repository.clone()),
+            config,
+            testing::mailer(config),
+            Arc::new(config.email_policy().unwrap()),
+            testing::cache(config),
+            data_jobs.clone(),
+        );
+
+        (service, data_jobs)
+    }
+
+    fn registration(username: &str, email: &str) -> RegisterUserRequest {
+        RegisterUserRequest {
+            username: username.to_string(),
+            password: "Correct-horse-battery-1!".to_string(),
+            email: email.to_string(),
+            locale: "en".to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn define(service: &UsersService, key: &str, unique: bool) {
+        service.define_attribute(Request::new(DefineAttributeRequest {
+            definition: Some(AttributeDefinition {
+                key: key.to_string(),
+                r#type: AttributeType::String as i32,
+                unique,
+                indexed: true,
+                visibility: AttributeVisibility::User as i32,
+                ..Default::default()
+            }),
+        })).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn registering_creates_the_primary_contacts_and_roles() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository, &testing::config(&[]));
+        repository.insert_role("support");
+
+        let registered = service.register_user(Request::new(RegisterUserRequest {
+            phone: Some(RegisterUserPhone { country_code: "+1".to_string(), number: "4155550100".to_string() }),
+            roles: vec!["support".to_string()],
+            send_verification_email: true,
+            ..registration("alice", "alice@example.com")
+        })).await.unwrap().into_inner();
+
+        assert!(registered.email.unwrap().is_primary);
+        assert!(registered.phone.unwrap().is_primary);
+        assert_eq!(registered.roles, vec!["support"]);
+
+        let queued = repository.queued_emails();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].to, "alice@example.com");
+
+        let error = service.register_user(Request::new(RegisterUserRequest {
+            roles: vec!["admin".to_string()],
+            ..registration("bob", "bob@example.com")
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn a_failed_registration_leaves_nothing_behind() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository, &testing::config(&[]));
+
+        service.register_user(Request::new(registration("alice", "alice@example.com"))).await.unwrap();
+
+        let error = service.register_user(Request::new(registration("bob", "alice@example.com"))).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::AlreadyExists);
+
+        let found = UserRepository::find_by_username(repository.as_ref(), "bob".to_string()).await;
+        assert!(matches!(found, Err(RepositoryError::Database(DieselError::NotFound))));
+    }
+
+    #[tokio::test]
+    async fn unique_attributes_are_refused_and_indexed_ones_searchable() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository, &testing::config(&[]));
+        define(&service, "employee_id", true).await;
+
+        let attributes = HashMap::from([("employee_id".to_string(), "E1".to_string())]);
+
+        service.register_user(Request::new(RegisterUserRequest {
+            metadata: attributes.clone(),
+            ..registration("alice", "alice@example.com")
+        })).await.unwrap();
+
+        let error = service.register_user(Request::new(RegisterUserRequest {
+            metadata: attributes,
+            ..registration("bob", "bob@example.com")
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::AlreadyExists);
+
+        let found = service.search_users_by_attribute(Request::new(SearchUsersByAttributeRequest {
+            key: "employee_id".to_string(),
+            value: "E1".to_string(),
+            ..Default::default()
+        })).await.unwrap().into_inner();
+        assert_eq!(found.users.len(), 1);
+        assert_eq!(found.users[0].username, "alice");
+
+        service.delete_attribute_definition(Request::new(DeleteAttributeDefinitionRequest {
+            key: "employee_id".to_string(),
+        })).await.unwrap();
+
+        let error = service.search_users_by_attribute(Request::new(SearchUsersByAttributeRequest {
+            key: "employee_id".to_string(),
+            value: "E1".to_string(),
+            ..Default::default()
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn admin_only_attributes_need_the_admin_key() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, _) = service(&repository, &testing::config(&[("ADMIN_API_KEY", "admin-key")]));
+        define(&service, "team", false).await;
+        service.define_attribute(Request::new(DefineAttributeRequest {
+            definition: Some(AttributeDefinition {
+                key: "risk_score".to_string(),
+                r#type: AttributeType::String as i32,
+                visibility: AttributeVisibility::Admin as i32,
+                ..Default::default()
+            }),
+        })).await.unwrap();
+
+        // Registration is open, it can't set admin-only or undefined attributes.
+        for key in ["risk_score", "undefined"] {
+            let error = service.register_user(Request::new(RegisterUserRequest {
+                metadata: HashMap::from([(key.to_string(), "high".to_string())]),
+                ..registration("mallory", "mallory@example.com")
+            })).await.unwrap_err();
+            assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        }
+
+        let registered = service.register_user(Request::new(RegisterUserRequest {
+            metadata: HashMap::from([("team".to_string(), "blue".to_string())]),
+            ..registration("alice", "alice@example.com")
+        })).await.unwrap().into_inner();
+        let user_id = registered.user.unwrap().id;
+
+        let with_key = |mut request: Request<UpdateUserAttributesRequest>, key: &str| {
+            request.metadata_mut().insert(ADMIN_KEY_HEADER, key.parse().unwrap());
+            request
+        };
+        let update = || Request::new(UpdateUserAttributesRequest {
+            id: user_id.clone(),
+            attributes: HashMap::from([("risk_score".to_string(), "low".to_string())]),
+            view: AttributeView::Admin as i32,
+            ..Default::default()
+        });
+
+        let error = service.update_user_attributes(update()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+        let error = service.update_user_attributes(with_key(update(), "wrong-key")).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+
+        let updated = service.update_user_attributes(with_key(update(), "admin-key")).await.unwrap().into_inner();
+        assert_eq!(updated.attributes.get("risk_score").map(String::as_str), Some("low"));
+
+        let read = |view: AttributeView, key: Option<&str>| {
+            let mut request = Request::new(GetUserAttributesRequest {
+                id: user_id.clone(),
+                view: view as i32,
+            });
+            if let Some(key) = key {
+                request.metadata_mut().insert(ADMIN_KEY_HEADER, key.parse().unwrap());
+            }
+            service.get_user_attributes(request)
+        };
+
+        let admin = read(AttributeView::Admin, Some("admin-key")).await.unwrap().into_inner();
+        assert_eq!(admin.attributes.len(), 2);
+
+        for (view, key) in [(AttributeView::User, Some("admin-key")), (AttributeView::Admin, None), (AttributeView::Admin, Some("wrong-key"))] {
+            let attributes = read(view, key).await.unwrap().into_inner().attributes;
+            assert_eq!(attributes.keys().collect::<Vec<_>>(), ["team"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn mfa_needs_a_verified_phone_or_a_push_device() {
+        let repository = Arc::new(MemoryRepository::new());
+        let config = testing::config(&[("DEVICE_TRUST_SECRET", "test-secret")]);
+        let (service, _) = service(&repository, &config);
+        let user = testing::create_user(&repository, "alice").await;
+
+        let enable = || service.update_user(Request::new(UpdateUserRequest {
+            id: user.user_uuid.to_string(),
+            mfa_enabled: Some(true),
+            ..Default::default()
+        }));
+
+        assert_eq!(enable().await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        // Trusting a device isn't enough while it can't receive pushes.
+        let device = testing::create_device(&repository, Some(user.user_uuid)).await;
+        trusted_devices::trust_device(repository.as_ref(), &config, user.user_uuid, device.device_uuid).await.unwrap().unwrap();
+        assert_eq!(enable().await.unwrap_err().code(), tonic::Code::FailedPrecondition);
+
+        DeviceRepository::set_notification_token(repository.as_ref(), device.device_uuid, "push-token-1".to_string(), "device.push_token_registered").await.unwrap();
+        let response = enable().await.unwrap().into_inner();
+        assert!(response.mfa_enabled);
+    }
+
+    #[tokio::test]
+    async fn data_jobs_are_queued_for_existing_users() {
+        let repository = Arc::new(MemoryRepository::new());
+        let (service, data_jobs) = service(&repository, &testing::config(&[]));
+        let user = testing::create_user(&repository, "alice").await;
+
+        let job = service.export_user_data(Request::new(ExportUserDataRequest {
+            id: user.user_uuid.to_string(),
+        })).await.unwrap().into_inner();
+        assert_eq!(data_jobs.spawned(), vec![Uuid::parse_str(&job.id).unwrap()]);
+
+        let polled = service.get_user_data_job(Request::new(GetUserDataJobRequest { id: job.id })).await.unwrap().into_inner();
+        assert_eq!(polled.user_id, user.user_uuid.to_string());
+
+        let error = service.erase_user_data(Request::new(EraseUserDataRequest {
+            id: Uuid::new_v4().to_string(),
+        })).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::NotFound);
+        assert_eq!(data_jobs.spawned().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn imports_roll_back_bad_rows_and_dry_runs() {
+        let repository = Arc::new(MemoryRepository::new());
+        let config = testing::config(&[]);
+        let (service, _) = service(&repository, &config);
+        let context = Arc::new(import::ImportContext::new(vec![], vec![], vec![], Arc::new(config.email_policy().unwrap())));
+
+        let record = |username: &str, email: &str| import::ImportRecord {
+            username: username.to_string(),
+            password: Some("Correct-horse-battery-1!".to_string()),
+            emails: vec![email.to_string()],
+            ..Default::default()
+        };
+
+        let results = service.import_batch(&context, vec![(1, record("alice", "alice@example.com"))], true).await.unwrap();
+        assert!(results[0].error.is_none());
+        assert!(UserRepository::find_by_username(repository.as_ref(), "alice".to_string()).await.is_err());
+
+        let results = service.import_batch(&context, vec![
+            (1, record("alice", "alice@example.com")),
+            (2, record("bob", "alice@example.com")),
+        ], false).await.unwrap();
+        assert!(results[0].user_uuid.is_some());
+        assert!(results[1].error.is_some());
+        assert!(UserRepository::find_by_username(repository.as_ref(), "alice".to_string()).await.is_ok());
+        assert!(UserRepository::find_by_username(repository.as_ref(), "bob".to_string()).await.is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::str::FromStr;
+use std::sync::Arc;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::{AsyncConnection, AsyncPgConnection};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::models;
+use crate::utils;
+
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+pub const MAX_BATCH_SIZE: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "csv" => Ok(ImportFormat::Csv),
+            "jsonl" | "ndjson" => Ok(ImportFormat::Jsonl),
+            format => Err(format!("Unsupported import format '{}'", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImportPhone {
+    pub country_code: String,
+    pub number: String,
+}
+
+/// A single user to import, as read from a JSONL line or a CSV row.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportRecord {
+    pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+    #[serde(default)]
+    pub phones: Vec<ImportPhone>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, Value>,
+}
+
+/// CSV layout of an `ImportRecord`.
+///
+/// `emails`, `phones` and `roles` are `;` separated lists, phones are written
+/// as `<country code> <number>` and `metadata` is a JSON object.
+#[derive(Debug, Deserialize)]
+struct CsvRecord {
+    username: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    password_hash: String,
+    #[serde(default)]
+    emails: String,
+    #[serde(default)]
+    phones: String,
+    #[serde(default)]
+    roles: String,
+    #[serde(default)]
+    metadata: String,
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl TryFrom<CsvRecord> for ImportRecord {
+    type Error = String;
+
+    fn try_from(record: CsvRecord) -> Result<Self, Self::Error> {
+        let phones = split_list(&record.phones)
+            .into_iter()
+            .map(|phone| match phone.split_once(' ') {
+                Some((country_code, number)) => Ok(ImportPhone {
+                    country_code: country_code.trim().to_string(),
+                    number: number.trim().to_string(),
+                }),
+                None => Err(format!("Phone '{}' must be written as '<country code> <number>'", phone)),
+            })
+            .collect::<Result<Vec<ImportPhone>, String>>()?;
+
+        let metadata = if record.metadata.trim().is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(&record.metadata)
+                .map_err(|e| format!("Invalid metadata: {}", e))?
+        };
+
+        Ok(ImportRecord {
+            username: record.username,
+            password: Some(record.password).filter(|password| !password.is_empty()),
+            password_hash: Some(record.password_hash).filter(|hash| !hash.is_empty()),
+            emails: split_list(&record.emails),
+            phones,
+            roles: split_list(&record.roles),
+            metadata,
+        })
+    }
+}
+
+/// Reads import records lazily so large files never have to fit in memory.
+pub fn read_records<'a, R: Read + 'a>(
+    reader: R,
+    format: ImportFormat,
+) -> Box<dyn Iterator<Item = Result<ImportRecord, String>> + 'a> {
+    match format {
+        ImportFormat::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize::<CsvRecord>()
+                .map(|record| record.map_err(|e| e.to_string()).and_then(ImportRecord::try_from)),
+        ),
+        ImportFormat::Jsonl => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| {
+                    let line = line.map_err(|e| e.to_string())?;
+                    serde_json::from_str::<ImportRecord>(&line).map_err(|e| e.to_string())
+                }),
+        ),
+    }
+}
+
+/// Outcome of importing a single row.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRowResult {
+    pub row: usize,
+    pub username: String,
+    pub user_uuid: Option<Uuid>,
+    pub error: Option<String>,
+}
+
+/// A record that passed validation and is ready to be inserted.
+pub struct PreparedUser {
+    pub(crate) username: String,
+    pub(crate) username_skeleton: String,
+    pub(crate) password_hash: String,
+    pub(crate) emails: Vec<utils::NormalizedEmail>,
+    pub(crate) phones: Vec<utils::NormalizedPhone>,
+    pub(crate) role_uuids: Vec<Uuid>,
+    pub(crate) attributes: Map<String, Value>,
+}
+
+/// A record that failed validation, reported under the username it was submitted with.
+#[derive(Debug, Clone)]
+pub struct RejectedUser {
+    pub username: String,
+    pub error: String,
+}
+
+/// Reference data shared by every row of an import.
+pub struct ImportContext {
+    definitions: Vec<models::AttributeDefinition>,
+    roles: Vec<models::Role>,
+    reserved_usernames: Vec<String>,
+    email_policy: Arc<utils::EmailPolicy>,
+}
+
+impl ImportContext {
+    pub fn new(
+        definitions: Vec<models::AttributeDefinition>,
+        roles: Vec<models::Role>,
+        reserved_usernames: Vec<String>,
+        email_policy: Arc<utils::EmailPolicy>,
+    ) -> Self {
+        ImportContext { definitions, roles, reserved_usernames, email_policy }
+    }
+
+    pub async fn load(
+        conn: &mut AsyncPgConnection,
+        reserved_usernames: Vec<String>,
+        email_policy: Arc<utils::EmailPolicy>,
+    ) -> Result<Self, DieselError> {
+        Ok(ImportContext::new(
+            models::AttributeDefinition::list(conn).await?,
+            models::Role::list(conn).await?,
+            reserved_usernames,
+            email_policy,
+        ))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn definitions(&self) -> &[models::AttributeDefinition] {
+        &self.definitions
+    }
+
+    /// Validates a record with the same rules as `CreateUser` and hashes its password.
+    ///
+    /// This is CPU heavy and doesn't touch the database, so callers should run it
+    /// without holding a connection.
+    pub fn prepare(&self, record: ImportRecord) -> Result<PreparedUser, RejectedUser> {
+        let username = record.username.clone();
+        self.validate(record).map_err(|error| RejectedUser { username, error })
+    }
+
+    fn validate(&self, record: ImportRecord) -> Result<PreparedUser, String> {
+        let username = utils::canonicalize_username(&record.username, &self.reserved_usernames)?;
+
+        let password = record.password.filter(|password| !password.is_empty());
+        let password_hash = record.password_hash.filter(|hash| !hash.is_empty());
+
+        let password_hash = match (password, password_hash) {
+            (Some(_), Some(_)) => return Err("Provide either a password or a password hash, not both".to_string()),
+            (Some(password), None) => {
+                utils::validate_password(&password)?;
+                utils::hash_new_password(&password).map_err(|e| format!("Error hashing password: {}", e))?
+            }
+            (None, Some(hash)) => {
+                if !utils::is_supported_password_hash(&hash) {
+                    return Err("Password hash must be an Argon2 PHC string".to_string());
+                }
+                hash
+            }
+            (None, None) => return Err("A password or password hash is required".to_string()),
+        };
+
+        let emails = record.emails
+            .iter()
+            .map(|email| self.email_policy.normalize(email).map_err(|e| format!("Email '{}': {}", email, e)))
+            .collect::<Result<Vec<utils::NormalizedEmail>, String>>()?;
+
+        let phones = record.phones
+            .iter()
+            .map(|phone| {
+                utils::normalize_phone(&phone.country_code, &phone.number)
+                    .map_err(|e| format!("Phone '{} {}': {}", phone.country_code, phone.number, e))
+            })
+            .collect::<Result<Vec<utils::NormalizedPhone>, String>>()?;
+
+        let mut role_uuids = Vec::with_capacity(record.roles.len());
+        let mut unknown_roles = vec![];
+
+        for name in &record.roles {
+            match self.roles.iter().find(|role| &role.role_name == name) {
+                Some(role) => role_uuids.push(role.role_uuid),
+                None => unknown_roles.push(name.as_str()),
+            }
+        }
+
+        if !unknown_roles.is_empty() {
+            return Err(format!("Unknown roles: {}", unknown_roles.join(", ")));
+        }
+
+        role_uuids.sort();
+        role_uuids.dedup();
+
+        let raw_metadata: HashMap<String, String> = record.metadata
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect();
+
+        let attributes = utils::coerce_attributes(&self.definitions, &raw_metadata, true)?;
+        utils::check_required_attributes(&self.definitions, &attributes)?;
+
+        Ok(PreparedUser {
+            username_skeleton: utils::username_skeleton(&username),
+            username,
+            password_hash,
+            emails,
+            phones,
+            role_uuids,
+            attributes,
+        })
+    }
+
+    /// Inserts a batch of prepared rows in a single transaction.
+    ///
+    /// Every row is written inside its own savepoint so one bad row doesn't
+    /// abort the rest of the batch. With `dry_run` the whole batch is rolled
+    /// back once every row has been checked against the database.
+    pub async fn insert_batch(
+        &self,
+        conn: &mut AsyncPgConnection,
+        batch: Vec<(usize, Result<PreparedUser, RejectedUser>)>,
+        dry_run: bool,
+    ) -> Result<Vec<ImportRowResult>, DieselError> {
+        let mut results = Vec::with_capacity(batch.len());
+        let rows = &mut results;
+
+        let outcome = conn.transaction::<_, DieselError, _>(|conn| async move {
+            for (row, prepared) in batch {
+                let result = match prepared {
+                    Ok(user) => {
+                        let username = user.username.clone();
+                        match conn.transaction::<_, RowError, _>(|conn| self.insert_user(conn, user).scope_boxed()).await {
+                            Ok(user_uuid) => ImportRowResult { row, username, user_uuid: Some(user_uuid), error: None },
+                            Err(error) => ImportRowResult { row, username, user_uuid: None, error: Some(error.to_string()) },
+                        }
+                    }
+                    Err(rejected) => ImportRowResult {
+                        row,
+                        username: rejected.username,
+                        user_uuid: None,
+                        error: Some(rejected.error),
+                    },
+                };
+
+                rows.push(result);
+            }
+
+            if dry_run {
+                return Err(DieselError::RollbackTransaction);
+            }
+
+            Ok(())
+        }.scope_boxed()).await;
+
+        match outcome {
+            Ok(()) => Ok(results),
+            Err(DieselError::RollbackTransaction) if dry_run => {
+                // Nothing was written, so there are no identifiers to report.
+                for result in results.iter_mut() {
+                    result.user_uuid = None;
+                }
+                Ok(results)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn insert_user(&self, conn: &mut AsyncPgConnection, user: PreparedUser) -> Result<Uuid, RowError> {
+        if models::User::find_confusable(conn, &user.username_skeleton, None).await?.is_some() {
+            return Err(RowError::Invalid("Username is already taken".to_string()));
+        }
+
+        for definition in self.definitions.iter().filter(|definition| definition.is_unique) {
+            if let Some(value) = user.attributes.get(&definition.key) {
+                if models::User::attribute_value_taken(conn, &definition.key, value.clone(), None).await? {
+                    return Err(RowError::Invalid(format!("Attribute '{}' is already in use", definition.key)));
+                }
+            }
+        }
+
+        let created = models::User::create(conn, models::NewUser {
+            username: user.username,
+            username_skeleton: user.username_skeleton,
+            password_hash: user.password_hash,
+            metadata: Value::Object(user.attributes),
+        }).await?;
+
+        for (index, email) in user.emails.into_iter().enumerate() {
+            models::Email::create(conn, models::NewEmail {
+                user_uuid: created.user_uuid,
+                value: email.value,
+                normalized_value: email.normalized,
+                status: models::EmailStatusEnum::Unverified,
+                is_primary: index == 0,
+                metadata: json!({}),
+            }).await?;
+        }
+
+        for (index, phone) in user.phones.into_iter().enumerate() {
+            models::Phone::create(conn, models::NewPhone {
+                user_uuid: created.user_uuid,
+                country_code: phone.country_code,
+                number: phone.number,
+                type_: phone.number_type.to_string(),
+                status: models::PhoneStatusEnum::Unverified,
+                is_primary: index == 0,
+                metadata: json!({ "region": phone.region }),
+            }).await?;
+        }
+
+        models::Role::assign_to_user(conn, created.user_uuid, &user.role_uuids).await?;
+
+        Ok(created.user_uuid)
+    }
+}
+
+/// Why a row couldn't be inserted.
+pub(crate) enum RowError {
+    Invalid(String),
+    Database(DieselError),
+}
+
+impl From<DieselError> for RowError {
+    fn from(error: DieselError) -> Self {
+        RowError::Database(error)
+    }
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RowError::Invalid(message) => write!(f, "{}", message),
+            RowError::Database(e) if utils::is_username_conflict(e) => write!(f, "Username is already taken"),
+            RowError::Database(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+                write!(f, "Already exists: {}", info.message())
+            }
+            RowError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::PathBuf;
+use chrono::Utc;
+use lettre::message::Mailbox;
+use uuid::Uuid;
+
+use super::{build_message, EmailSender, MailError, OutgoingEmail};
+use crate::config::Config;
+
+/// Writes every email as an `.eml` file into `EMAIL_OUTBOX_DIR`, for development.
+pub struct FileSender {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileSender {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let dir = PathBuf::from(&config.email_outbox_dir);
+        fs::create_dir_all(&dir).map_err(|e| format!("Error creating {}: {}", dir.display(), e))?;
+
+        Ok(FileSender {
+            dir,
+            from: config.email_from.parse().map_err(|e| format!("Invalid EMAIL_FROM: {}", e))?,
+        })
+    }
+}
+
+impl EmailSender for FileSender {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        let path = self.dir.join(format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S"), Uuid::new_v4().simple()));
+
+        fs::write(&path, message.formatted())
+            .map_err(|e| MailError::Transient(format!("Error writing {}: {}", path.display(), e)))
+    }
+}
+
+/// Prints emails to stdout instead of sending them, for development.
+pub struct LogSender;
+
+impl EmailSender for LogSender {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), MailError> {
+        println!("Email to {}: {}\n{}", email.to, email.subject, email.text);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn emails_are_written_to_the_outbox() {
+        let dir = std::env::temp_dir().join(format!("ingot-outbox-{}", Uuid::new_v4().simple()));
+        let config = testing::config(&[("EMAIL_OUTBOX_DIR", dir.to_str().unwrap())]);
+        let sender = FileSender::new(&config).unwrap();
+
+        sender.send(&OutgoingEmail {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            text: "Plain body".to_string(),
+            html: "<p>HTML body</p>".to_string(),
+        }).unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+
+        let message = fs::read_to_string(&files[0]).unwrap();
+        assert!(message.contains("To: alice@example.com"));
+        assert!(message.contains("Subject: Hello"));
+        assert!(message.contains("Plain body"));
+
+        let error = sender.send(&OutgoingEmail {
+            to: "not an address".to_string(),
+            subject: "Hello".to_string(),
+            text: "Plain body".to_string(),
+            html: "<p>HTML body</p>".to_string(),
+        }).unwrap_err();
+        assert!(matches!(error, MailError::Permanent(_)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+mod file;
+mod queue;
+mod smtp;
+mod templates;
+
+use std::sync::Arc;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+
+pub use file::*;
+pub use queue::*;
+pub use smtp::*;
+pub use templates::*;
+
+use crate::config::Config;
+
+/// A rendered email, ready to be handed to a transport.
+#[derive(Debug, Clone)]
+pub struct OutgoingEmail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    /// The delivery may succeed later, e.g. the server was unreachable.
+    Transient(String),
+    /// Retrying won't help, e.g. the recipient was rejected.
+    Permanent(String),
+}
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MailError::Transient(message) => write!(f, "Transient delivery error: {}", message),
+            MailError::Permanent(message) => write!(f, "Permanent delivery error: {}", message),
+        }
+    }
+}
+
+/// An outbound email transport.
+///
+/// Senders are blocking, deliveries run on the blocking thread pool.
+pub trait EmailSender: Send + Sync {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), MailError>;
+}
+
+/// Builds the transport selected by `EMAIL_TRANSPORT`.
+pub fn sender_from_config(config: &Config) -> Result<Arc<dyn EmailSender>, String> {
+    match config.email_transport.as_str() {
+        "smtp" => Ok(Arc::new(SmtpSender::new(config)?)),
+        "file" => Ok(Arc::new(FileSender::new(config)?)),
+        "log" => Ok(Arc::new(LogSender)),
+        transport => Err(format!("Unsupported email transport '{}'", transport)),
+    }
+}
+
+/// Builds a multipart (text + HTML) message.
+fn build_message(from: &Mailbox, email: &OutgoingEmail) -> Result<Message, MailError> {
+    let to: Mailbox = email.to
+        .parse()
+        .map_err(|e| MailError::Permanent(format!("Invalid recipient '{}': {}", email.to, e)))?;
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject.clone())
+        .multipart(MultiPart::alternative_plain_html(email.text.clone(), email.html.clone()))
+        .map_err(|e| MailError::Permanent(format!("Error building message: {}", e)))
+}
+
+/// Renders templates into emails, which are queued for delivery with the
+/// changes they belong to, see `EmailRepository`.
+pub struct Mailer {
+    templates: Templates,
+    verification_url: String,
+    email_change_cancel_url: String,
+}
+
+/// Appends `token` as a query parameter.
+fn link_with_token(url: &str, token: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", url, separator, token)
+}
+
+impl Mailer {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Mailer {
+            templates: Templates::load(&config.email_templates_dir, &config.email_default_locale)?,
+            verification_url: config.email_verification_url.clone(),
+            email_change_cancel_url: config.email_change_cancel_url.clone(),
+        })
+    }
+
+    /// Renders `template` for `locale` into an email for `to`.
+    pub fn render(&self, to: &str, template: &str, locale: &str, variables: &[(&str, &str)]) -> Result<OutgoingEmail, String> {
+        let rendered = self.templates.render(template, locale, variables)?;
+
+        Ok(OutgoingEmail {
+            to: to.to_string(),
+            subject: rendered.subject,
+            text: rendered.text,
+            html: rendered.html,
+        })
+    }
+
+    /// The verification email, with both the code and the link.
+    pub fn render_verification(&self, to: &str, locale: &str, code: &str, token: &str) -> Result<OutgoingEmail, String> {
+        let link = link_with_token(&self.verification_url, token);
+
+        self.render(to, "verify_email", locale, &[("email", to), ("code", code), ("link", &link)])
+    }
+
+    /// Tells the old address about a requested email change, with a link to cancel it.
+    pub fn render_email_change_requested(&self, to: &str, locale: &str, new_email: &str, token: &str) -> Result<OutgoingEmail, String> {
+        let link = link_with_token(&self.email_change_cancel_url, token);
+
+        self.render(to, "email_change_requested", locale, &[("email", to), ("new_email", new_email), ("link", &link)])
+    }
+
+    /// Tells the old address the change went through, with a link to undo it.
+    pub fn render_email_changed(&self, to: &str, locale: &str, new_email: &str, token: &str, undo_until: &str) -> Result<OutgoingEmail, String> {
+        let link = link_with_token(&self.email_change_cancel_url, token);
+
+        self.render(to, "email_changed", locale, &[("email", to), ("new_email", new_email), ("link", &link), ("undo_until", undo_until)])
+    }
+}
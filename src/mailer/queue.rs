@@ -0,0 +1,132 @@
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use chrono::{Duration, Utc};
+use diesel_async::AsyncPgConnection;
+
+use super::{EmailSender, MailError, OutgoingEmail};
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::models;
+use crate::utils::EmailPolicy;
+
+/// Deliveries handled per pass of the worker.
+const BATCH_SIZE: i64 = 50;
+
+/// How long a worker has to send the deliveries it claimed before another
+/// one may pick them up.
+const CLAIM_LEASE_MINUTES: i64 = 10;
+
+/// Adds an email to the persistent delivery queue.
+///
+/// Enqueueing inside a transaction means the email is only sent if the
+/// transaction commits.
+pub async fn enqueue(
+    conn: &mut AsyncPgConnection,
+    email: OutgoingEmail,
+) -> Result<models::EmailDelivery, diesel::result::Error> {
+    models::EmailDelivery::create(conn, models::NewEmailDelivery {
+        recipient: email.to,
+        subject: email.subject,
+        text_body: email.text,
+        html_body: email.html,
+    }).await
+}
+
+/// Exponential backoff: 30 seconds after the first failure, capped at 6 hours.
+fn retry_delay(attempts: i32) -> Duration {
+    // Clamped well past the cap so it is reached, and `pow` can't overflow.
+    let exponent = attempts.clamp(1, 20) as u32 - 1;
+    Duration::seconds((30 * 2i64.pow(exponent)).min(6 * 60 * 60))
+}
+
+/// Sends every due delivery once, failing those to suppressed addresses.
+///
+/// Deliveries are claimed first, so several instances can run the worker
+/// without sending an email twice. Senders are blocking, emails are sent on
+/// the blocking thread pool.
+async fn deliver_due(
+    conn: &mut AsyncPgConnection,
+    sender: &Arc<dyn EmailSender>,
+    email_policy: &EmailPolicy,
+    max_attempts: i32,
+) -> Result<usize, diesel::result::Error> {
+    let deliveries = models::EmailDelivery::claim_due(conn, BATCH_SIZE, Duration::minutes(CLAIM_LEASE_MINUTES)).await?;
+
+    for delivery in &deliveries {
+        if models::EmailSuppression::is_suppressed(conn, &email_policy.lookup_key(&delivery.recipient)).await? {
+            models::EmailDelivery::mark_failed(conn, delivery.delivery_uuid, "Recipient is suppressed".to_string()).await?;
+            continue;
+        }
+
+        let email = OutgoingEmail {
+            to: delivery.recipient.clone(),
+            subject: delivery.subject.clone(),
+            text: delivery.text_body.clone(),
+            html: delivery.html_body.clone(),
+        };
+        let sender = sender.clone();
+        let outcome = tokio::task::spawn_blocking(move || sender.send(&email))
+            .await
+            .unwrap_or_else(|e| Err(MailError::Transient(format!("Email sender panicked: {}", e))));
+
+        let attempts = delivery.attempts + 1;
+
+        match outcome {
+            Ok(()) => models::EmailDelivery::mark_sent(conn, delivery.delivery_uuid).await?,
+            Err(MailError::Transient(error)) if attempts < max_attempts => {
+                models::EmailDelivery::mark_retry(conn, delivery.delivery_uuid, error, Utc::now() + retry_delay(attempts)).await?
+            }
+            Err(error) => {
+                println!("Giving up on email delivery {}: {}", delivery.delivery_uuid, error);
+                models::EmailDelivery::mark_failed(conn, delivery.delivery_uuid, error.to_string()).await?
+            }
+        };
+    }
+
+    Ok(deliveries.len())
+}
+
+/// Drains the delivery queue in the background for as long as the server runs.
+pub fn spawn_delivery_worker(
+    database: DbPool,
+    sender: Arc<dyn EmailSender>,
+    email_policy: Arc<EmailPolicy>,
+    config: &Config,
+) {
+    let max_attempts = config.email_delivery_max_attempts;
+    let poll_interval = StdDuration::from_secs(config.email_delivery_poll_interval);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(poll_interval);
+
+        loop {
+            interval.tick().await;
+
+            let outcome = match database.get().await {
+                Ok(mut conn) => deliver_due(&mut conn, &sender, &email_policy, max_attempts).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            if let Err(e) = outcome {
+                println!("Error delivering emails: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off_exponentially_up_to_six_hours() {
+        let delays: Vec<i64> = (1..=6).map(|attempts| retry_delay(attempts).num_seconds()).collect();
+
+        assert_eq!(delays, [30, 60, 120, 240, 480, 960]);
+        assert_eq!(retry_delay(10).num_seconds(), 15360);
+        assert_eq!(retry_delay(11).num_seconds(), 6 * 60 * 60);
+        assert_eq!(retry_delay(i32::MAX).num_seconds(), 6 * 60 * 60);
+        // Attempts are only counted from one, a zero is treated as the first failure.
+        assert_eq!(retry_delay(0).num_seconds(), 30);
+    }
+}
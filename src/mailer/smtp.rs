@@ -0,0 +1,172 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{SmtpTransport, Transport};
+
+use super::{build_message, EmailSender, MailError, OutgoingEmail};
+use crate::config::Config;
+
+/// Delivers emails through an SMTP relay.
+///
+/// `SMTP_TLS` selects `tls` (implicit TLS), `starttls` or `none`, the last one
+/// being meant for local SMTP sinks.
+pub struct SmtpSender {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpSender {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let host = config.smtp_host.as_str();
+        let tls_parameters = || TlsParameters::new(host.to_string())
+            .map_err(|e| format!("Invalid SMTP TLS configuration: {}", e));
+
+        let tls = match config.smtp_tls.as_str() {
+            "tls" => Tls::Wrapper(tls_parameters()?),
+            "starttls" => Tls::Required(tls_parameters()?),
+            "none" => Tls::None,
+            tls => return Err(format!("Unsupported SMTP TLS mode '{}'", tls)),
+        };
+
+        let mut builder = SmtpTransport::builder_dangerous(host)
+            .port(config.smtp_port)
+            .tls(tls);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(SmtpSender {
+            transport: builder.build(),
+            from: config.email_from.parse().map_err(|e| format!("Invalid EMAIL_FROM: {}", e))?,
+        })
+    }
+}
+
+impl EmailSender for SmtpSender {
+    fn send(&self, email: &OutgoingEmail) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+
+        self.transport.send(&message).map(|_| ()).map_err(|e| {
+            if e.is_permanent() {
+                MailError::Permanent(e.to_string())
+            } else {
+                MailError::Transient(e.to_string())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::testing;
+
+    /// What a sink received: the envelope commands and the message data.
+    struct Received {
+        commands: Vec<String>,
+        data: String,
+    }
+
+    /// Accepts a single plain-text SMTP session, answering `RCPT TO` with
+    /// `rcpt_reply`.
+    fn smtp_sink(rcpt_reply: &'static str) -> (u16, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let sink = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Received { commands: vec![], data: String::new() };
+
+            writer.write_all(b"220 localhost ESMTP sink\r\n").unwrap();
+
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                let verb = command.split([' ', ':']).next().unwrap_or_default().to_ascii_uppercase();
+                line.clear();
+
+                let reply = match verb.as_str() {
+                    "EHLO" | "HELO" | "MAIL" | "RSET" | "NOOP" => "250 OK\r\n",
+                    "RCPT" => rcpt_reply,
+                    "DATA" => {
+                        writer.write_all(b"354 Go ahead\r\n").unwrap();
+                        while reader.read_line(&mut line).unwrap() > 0 && line != ".\r\n" {
+                            received.data.push_str(&line);
+                            line.clear();
+                        }
+                        line.clear();
+                        "250 Queued\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 Bye\r\n").unwrap();
+                        received.commands.push(command);
+                        break;
+                    }
+                    _ => "502 Unsupported\r\n",
+                };
+
+                received.commands.push(command);
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+
+            received
+        });
+
+        (port, sink)
+    }
+
+    fn sender(port: u16) -> SmtpSender {
+        let port = port.to_string();
+        SmtpSender::new(&testing::config(&[
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "none"),
+            ("EMAIL_FROM", "Ingot <no-reply@example.com>"),
+        ])).unwrap()
+    }
+
+    fn email() -> OutgoingEmail {
+        OutgoingEmail {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            text: "Plain body".to_string(),
+            html: "<p>HTML body</p>".to_string(),
+        }
+    }
+
+    #[test]
+    fn emails_are_relayed_to_the_smtp_server() {
+        let (port, sink) = smtp_sink("250 OK\r\n");
+        let sender = sender(port);
+
+        sender.send(&email()).unwrap();
+        drop(sender);
+
+        let received = sink.join().unwrap();
+        assert!(received.commands.contains(&"MAIL FROM:<no-reply@example.com>".to_string()));
+        assert!(received.commands.contains(&"RCPT TO:<alice@example.com>".to_string()));
+        assert!(received.data.contains("To: alice@example.com"));
+        assert!(received.data.contains("Subject: Hello"));
+        assert!(received.data.contains("Plain body"));
+        assert!(received.data.contains("<p>HTML body</p>"));
+    }
+
+    #[test]
+    fn rejected_recipients_are_permanent_failures() {
+        let (port, sink) = smtp_sink("550 No such user\r\n");
+        let sender = sender(port);
+
+        let error = sender.send(&email()).unwrap_err();
+        assert!(matches!(error, MailError::Permanent(_)));
+
+        drop(sender);
+        assert!(sink.join().unwrap().data.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Templates the server sends, they must exist for the default locale.
+pub const REQUIRED_TEMPLATES: &[&str] = &["verify_email", "email_change_requested", "email_changed"];
+
+/// A subject, text and HTML body with `{{ name }}` placeholders.
+#[derive(Debug, Clone)]
+pub struct RenderedTemplate {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Per-locale email templates loaded from disk.
+///
+/// Templates live in `<dir>/<locale>/<name>.subject.txt`, `<name>.txt` and
+/// `<name>.html`. Lookups fall back from `fr-CA` to `fr` and then to the
+/// default locale.
+pub struct Templates {
+    templates: HashMap<(String, String), RenderedTemplate>,
+    default_locale: String,
+}
+
+fn read_part(dir: &Path, file: &str) -> Result<String, String> {
+    let path = dir.join(file);
+    fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Replaces `{{ name }}` placeholders in a single pass, so a value holding a
+/// placeholder is never expanded itself. Unknown placeholders are kept.
+pub(crate) fn substitute(template: &str, variables: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end) else {
+            break;
+        };
+
+        let name = rest[start + 2..end].trim();
+        rendered.push_str(&rest[..start]);
+        match variables.iter().find(|(variable, _)| *variable == name) {
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+impl Templates {
+    pub fn load(dir: &str, default_locale: &str) -> Result<Self, String> {
+        let mut templates = HashMap::new();
+        let locales = fs::read_dir(dir).map_err(|e| format!("Error reading templates from {}: {}", dir, e))?;
+
+        for locale in locales {
+            let locale = locale.map_err(|e| e.to_string())?.path();
+            if !locale.is_dir() {
+                continue;
+            }
+
+            let locale_name = locale.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+
+            for file in fs::read_dir(&locale).map_err(|e| e.to_string())? {
+                let file_name = file.map_err(|e| e.to_string())?.file_name().to_string_lossy().to_string();
+                let Some(name) = file_name.strip_suffix(".subject.txt") else {
+                    continue;
+                };
+
+                templates.insert((locale_name.clone(), name.to_string()), RenderedTemplate {
+                    subject: read_part(&locale, &file_name)?.trim().to_string(),
+                    text: read_part(&locale, &format!("{}.txt", name))?,
+                    html: read_part(&locale, &format!("{}.html", name))?,
+                });
+            }
+        }
+
+        let default_locale = default_locale.to_lowercase();
+
+        for name in REQUIRED_TEMPLATES {
+            if !templates.contains_key(&(default_locale.clone(), name.to_string())) {
+                return Err(format!("Email template '{}' is missing for locale '{}'", name, default_locale));
+            }
+        }
+
+        Ok(Templates { templates, default_locale })
+    }
+
+    /// Renders a template, escaping the variables in the HTML body.
+    ///
+    /// Only `REQUIRED_TEMPLATES` are sure to exist, any other template may
+    /// be missing for every locale.
+    pub fn render(&self, name: &str, locale: &str, variables: &[(&str, &str)]) -> Result<RenderedTemplate, String> {
+        let locale = locale.to_lowercase().replace('_', "-");
+        let language = locale.split('-').next().unwrap_or_default().to_string();
+
+        let template = [locale, language, self.default_locale.clone()]
+            .into_iter()
+            .find_map(|locale| self.templates.get(&(locale, name.to_string())))
+            .ok_or_else(|| format!("Email template '{}' is missing", name))?;
+
+        let plain: Vec<(&str, String)> = variables.iter().map(|(name, value)| (*name, value.to_string())).collect();
+        let escaped: Vec<(&str, String)> = variables.iter().map(|(name, value)| (*name, escape_html(value))).collect();
+
+        Ok(RenderedTemplate {
+            subject: substitute(&template.subject, &plain),
+            text: substitute(&template.text, &plain),
+            html: substitute(&template.html, &escaped),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates() -> Templates {
+        Templates::load("templates/email", "en").unwrap()
+    }
+
+    #[test]
+    fn locales_fall_back_to_the_language_then_the_default() {
+        let templates = templates();
+        let variables = [("email", "alice@example.com"), ("code", "123456"), ("link", "https://example.com")];
+
+        let french = templates.render("verify_email", "fr_CA", &variables).unwrap();
+        assert_eq!(french.subject, "Vérifiez votre adresse e-mail");
+
+        let english = templates.render("verify_email", "de-DE", &variables).unwrap();
+        assert_eq!(english.subject, "Verify your email address");
+        assert!(english.text.contains("123456"));
+    }
+
+    #[test]
+    fn variables_are_escaped_in_the_html_body_only() {
+        let rendered = templates().render("verify_email", "en", &[
+            ("email", "<b>alice</b>@example.com"),
+            ("code", "123456"),
+            ("link", "https://example.com/?a=1&b=2"),
+        ]).unwrap();
+
+        assert!(rendered.text.contains("<b>alice</b>@example.com"));
+        assert!(rendered.html.contains("&lt;b&gt;alice&lt;/b&gt;@example.com"));
+        assert!(rendered.html.contains("https://example.com/?a=1&amp;b=2"));
+    }
+
+    #[test]
+    fn values_holding_placeholders_are_not_expanded() {
+        let variables = [("email", "{{ code }}".to_string()), ("code", "123456".to_string())];
+
+        assert_eq!(substitute("{{ email }} / {{code}} / {{ unknown }}", &variables), "{{ code }} / 123456 / {{ unknown }}");
+    }
+
+    #[test]
+    fn missing_templates_are_an_error() {
+        let error = templates().render("welcome", "en", &[]).unwrap_err();
+        assert_eq!(error, "Email template 'welcome' is missing");
+    }
+}
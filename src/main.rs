@@ -1,35 +1,88 @@
+mod config;
+mod db;
 mod models;
+mod repository;
 mod schema;
 mod grpc;
 mod utils;
+mod import;
+mod mailer;
+mod privacy;
+mod verification;
+mod email_change;
+mod bounces;
+mod webhooks;
+mod geoip;
+mod fingerprint;
+mod mfa;
+mod trusted_devices;
+mod sms;
+mod push;
+mod phone_verification;
+mod session_policy;
+mod cache;
+mod token_revocation;
+mod cli;
+#[cfg(test)]
+mod testing;
 
 use std::env;
 use dotenvy::dotenv;
-use diesel::{PgConnection, Connection};
+use envconfig::Envconfig;
 use tonic::transport::Server;
 
 // use crate::grpc::users::v1::;
 
-pub fn connect_db() -> PgConnection {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-    .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
-    let database = std::sync::Arc::new(std::sync::Mutex::new(connect_db()));
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args).await;
+    }
+
+    let config = config::Config::init_from_env()?;
+    config.session_limit_action.parse::<session_policy::LimitAction>()?;
+    let database = db::build_pool(&config)?;
+    let repository = std::sync::Arc::new(repository::PgRepository::new(database.clone()));
     
     println!("Starting server...");
     
     let addr = "[::1]:50051".parse()?;
     
-    let users_service = grpc::users::service::UsersService::new(database.clone());
-    let auth_service = grpc::auth::service::AuthService::new(database.clone());
-    let device_service = grpc::device::service::DevicesService::new(database.clone());
-    let emails_service = grpc::emails::service::EmailsService::new(database.clone());
-    let phone_service = grpc::phones::service::PhonesService::new(database.clone());
+    let mailer = std::sync::Arc::new(mailer::Mailer::new(&config)?);
+    let email_sender = mailer::sender_from_config(&config)?;
+    let sms_gateway = std::sync::Arc::new(sms::SmsGateway::new(&config)?);
+    let push_gateway = std::sync::Arc::new(push::PushGateway::new(&config)?);
+    let email_policy = std::sync::Arc::new(config.email_policy()?);
+    let user_agents = std::sync::Arc::new(utils::UserAgentClassifier::load(&config.user_agent_regexes_file)?);
+    let geoip = std::sync::Arc::new(geoip::GeoIp::from_config(&config));
+    let cache = std::sync::Arc::new(cache::Cache::new(&config)?);
+
+    let data_jobs = std::sync::Arc::new(privacy::PoolJobRunner::new(database.clone(), cache.clone()));
+
+    let users_service = grpc::users::service::UsersService::new(repository::Repositories::new(repository.clone()), &config, mailer.clone(), email_policy.clone(), cache.clone(), data_jobs);
+    let auth_service = grpc::auth::service::AuthService::new(repository::Repositories::new(repository.clone()), &config, geoip.clone(), sms_gateway.clone(), push_gateway, cache.clone());
+    let device_service = grpc::device::service::DevicesService::new(repository.clone(), &config, user_agents, geoip.clone(), cache.clone());
+    let emails_service = grpc::emails::service::EmailsService::new(repository.clone(), &config, mailer.clone(), email_policy.clone(), cache.clone());
+    let phone_service = grpc::phones::service::PhonesService::new(repository.clone(), &config, sms_gateway.clone());
+    let sessions_service = grpc::sessions::service::SessionsService::new(repository.clone(), cache.clone());
+
+    {
+        let mut conn = database.get().await?;
+        utils::backfill_username_skeletons(&mut conn).await?;
+        utils::backfill_email_keys(&mut conn, &email_policy).await?;
+        privacy::resume_unfinished_jobs(&mut conn, &database, &cache).await?;
+    }
+    mailer::spawn_delivery_worker(database.clone(), email_sender, email_policy.clone(), &config);
+    geoip::spawn_geoip_reloader(geoip, &config);
+    session_policy::spawn_session_reaper(database.clone(), cache.clone(), &config);
+    token_revocation::spawn_deny_list_sync(database.clone(), cache, &config);
+
+    if let Some(secret) = config.webhook_secret() {
+        webhooks::spawn_webhook_server(repository, email_policy.clone(), secret, config.webhook_addr.parse()?);
+    }
 
     Server::builder()
     .add_service(grpc::users::v1::users_server::UsersServer::new(users_service))
@@ -37,6 +90,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .add_service(grpc::device::v1::devices_server::DevicesServer::new(device_service))
     .add_service(grpc::emails::v1::emails_server::EmailsServer::new(emails_service))
     .add_service(grpc::phones::v1::phones_server::PhonesServer::new(phone_service))
+    .add_service(grpc::sessions::v1::sessions_server::SessionsServer::new(sessions_service))
     .serve(addr)
     .await?;
 
@@ -0,0 +1,368 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::result::Error as DieselError;
+use ipnet::IpNet;
+use rand::Rng;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::models;
+use crate::phone_verification::check_send_limits;
+use crate::push::PushNotification;
+use crate::repository::{DeviceRepository, MfaRepository, Repositories, RepositoryError, RepositoryResult};
+use crate::sms::{OutgoingSms, SmsGateway};
+use crate::trusted_devices;
+use crate::utils;
+use crate::verification::hash_secret;
+
+/// Second factor answered with a code texted to the user's verified phone.
+pub const FACTOR_SMS: &str = "sms";
+
+/// Second factor approved from one of the user's trusted devices, which has
+/// to pick the number shown on the signing-in screen.
+pub const FACTOR_PUSH: &str = "push";
+
+/// Pushes to more trusted devices than this would be spam.
+const MAX_PUSH_DEVICES: i64 = 10;
+
+/// A sign-in whose password was correct and that now needs a second factor.
+pub struct SignInAttempt<'a> {
+    pub user_uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub ip_address: IpNet,
+    /// Locale of the text message, for the SMS factor.
+    pub locale: &'a str,
+}
+
+/// A challenge that was started, with the messages carrying it to hand to
+/// the SMS or push provider.
+pub struct IssuedChallenge {
+    pub challenge: models::MfaChallenge,
+    pub sms: Option<OutgoingSms>,
+    pub pushes: Vec<PushNotification>,
+    /// For the push factor, the number to show on the signing-in screen.
+    pub number_match: Option<i32>,
+}
+
+pub enum MfaError {
+    ChallengeNotFound,
+    /// The user has no verified phone, or no trusted device push
+    /// notifications can reach, for the requested factor.
+    NoFactor,
+    UnsupportedFactor(String),
+    /// The SMS policy doesn't allow sending to the phone's country.
+    CountryNotAllowed(String),
+    Throttled { retry_at: DateTime<Utc> },
+    /// The code is wrong, or the challenge expired or was already settled.
+    Invalid,
+    /// The push challenge wasn't answered yet.
+    Pending,
+    /// The push challenge was rejected from a trusted device.
+    Denied,
+    /// The device answering a push challenge isn't one of the user's trusted devices.
+    NotTrusted,
+    /// The text message carrying the code couldn't be rendered.
+    Template(String),
+    Database(RepositoryError),
+}
+
+impl From<RepositoryError> for MfaError {
+    fn from(error: RepositoryError) -> Self {
+        MfaError::Database(error)
+    }
+}
+
+impl std::fmt::Display for MfaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MfaError::ChallengeNotFound => write!(f, "Challenge not found"),
+            MfaError::NoFactor => write!(f, "User has no second factor available"),
+            MfaError::UnsupportedFactor(factor) => write!(f, "Unsupported second factor '{}'", factor),
+            MfaError::CountryNotAllowed(reason) => write!(f, "{}", reason),
+            MfaError::Throttled { retry_at } => write!(f, "Too many codes requested, retry after {}", retry_at.to_rfc3339()),
+            MfaError::Invalid => write!(f, "Invalid or expired code"),
+            MfaError::Pending => write!(f, "Challenge wasn't answered yet"),
+            MfaError::Denied => write!(f, "Sign-in was denied from a trusted device"),
+            MfaError::NotTrusted => write!(f, "Device isn't trusted to answer this challenge"),
+            MfaError::Template(e) => write!(f, "Error rendering text message: {}", e),
+            MfaError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+/// An answer to a push challenge from another device of the user.
+pub struct PushAnswer<'a> {
+    pub responder: &'a models::Device,
+    pub device_trust_token: &'a str,
+    pub approve: bool,
+    pub number_match: i32,
+}
+
+fn find_error(error: RepositoryError) -> MfaError {
+    match error {
+        RepositoryError::Database(DieselError::NotFound) => MfaError::ChallengeNotFound,
+        e => MfaError::Database(e),
+    }
+}
+
+/// Settling fails with `NotFound` when someone else settled it first.
+fn settle_error(error: RepositoryError) -> MfaError {
+    match error {
+        RepositoryError::Database(DieselError::NotFound) => MfaError::Invalid,
+        e => MfaError::Database(e),
+    }
+}
+
+/// Starts a second factor challenge with `factor`, or when it's empty with
+/// a push to the user's trusted devices, falling back to SMS.
+pub async fn start_challenge(
+    repositories: &Repositories,
+    config: &Config,
+    gateway: &SmsGateway,
+    factor: &str,
+    attempt: &SignInAttempt<'_>,
+) -> Result<IssuedChallenge, MfaError> {
+    match factor {
+        FACTOR_SMS => start_sms_challenge(repositories, config, gateway, attempt).await,
+        FACTOR_PUSH => start_push_challenge(repositories, config, attempt).await,
+        "" => match start_push_challenge(repositories, config, attempt).await {
+            Err(MfaError::NoFactor) => start_sms_challenge(repositories, config, gateway, attempt).await,
+            issued => issued,
+        },
+        factor => Err(MfaError::UnsupportedFactor(factor.to_string())),
+    }
+}
+
+/// Texts a code to the user's primary phone, or another verified one. It
+/// counts against the same SMS limits as phone verification.
+async fn start_sms_challenge(
+    repositories: &Repositories,
+    config: &Config,
+    gateway: &SmsGateway,
+    attempt: &SignInAttempt<'_>,
+) -> Result<IssuedChallenge, MfaError> {
+    let ip_address = attempt.ip_address;
+
+    let mut phones: Vec<models::Phone> = repositories.phones.find_by_user(attempt.user_uuid, 100, 0).await?
+        .into_iter()
+        .filter(|phone| phone.is_verified)
+        .collect();
+    phones.sort_by_key(|phone| !phone.is_primary);
+    let phone = phones.into_iter().next().ok_or(MfaError::NoFactor)?;
+
+    let e164 = format!("{}{}", phone.country_code, phone.number);
+    let region = utils::normalize_full_phone(&e164).ok().and_then(|number| number.region);
+    gateway.policy.check(region.as_deref()).map_err(MfaError::CountryNotAllowed)?;
+
+    if let Some(retry_at) = check_send_limits(repositories.phones.as_ref(), config, &e164, Some(ip_address)).await? {
+        return Err(MfaError::Throttled { retry_at });
+    }
+
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let minutes = (config.mfa_challenge_ttl / 60).max(1).to_string();
+    let body = gateway.templates.render("mfa_code", attempt.locale, &[
+        ("code", code.as_str()),
+        ("minutes", minutes.as_str()),
+    ]).map_err(MfaError::Template)?;
+
+    let challenge_uuid = Uuid::new_v4();
+    let challenge = repositories.mfa.create_challenge(models::NewMfaChallenge {
+        challenge_uuid,
+        user_uuid: attempt.user_uuid,
+        device_uuid: attempt.device_uuid,
+        ip_address,
+        factor: FACTOR_SMS.to_string(),
+        code_hash: Some(hash_secret(&challenge_uuid, &code)),
+        expires_at: Utc::now() + Duration::seconds(config.mfa_challenge_ttl),
+    }, Some(models::NewSmsSend {
+        phone_number: e164.clone(),
+        ip_address: Some(ip_address),
+        purpose: "mfa".to_string(),
+    })).await?;
+
+    Ok(IssuedChallenge {
+        challenge,
+        sms: Some(OutgoingSms { to: e164, body }),
+        pushes: vec![],
+        number_match: None,
+    })
+}
+
+fn receives_push(device: &models::Device) -> bool {
+    !device.status.is_blocked() && device.notification_enabled == Some(true) && device.notification_token.is_some()
+}
+
+/// Whether one of the user's trusted devices can approve sign-ins by push.
+pub async fn has_push_device(devices: &dyn DeviceRepository, user_uuid: Uuid) -> RepositoryResult<bool> {
+    Ok(devices.find_trusted_by_user(user_uuid, MAX_PUSH_DEVICES, 0).await?
+        .iter()
+        .any(|(_, device)| receives_push(device)))
+}
+
+/// Asks the user's trusted devices that registered a push token to approve
+/// the sign-in. The device signing in never approves itself.
+async fn start_push_challenge(
+    repositories: &Repositories,
+    config: &Config,
+    attempt: &SignInAttempt<'_>,
+) -> Result<IssuedChallenge, MfaError> {
+    let tokens: Vec<String> = repositories.devices.find_trusted_by_user(attempt.user_uuid, MAX_PUSH_DEVICES, 0).await?
+        .into_iter()
+        .map(|(_, device)| device)
+        .filter(|device| device.device_uuid != attempt.device_uuid && receives_push(device))
+        .filter_map(|device| device.notification_token)
+        .collect();
+
+    if tokens.is_empty() {
+        return Err(MfaError::NoFactor);
+    }
+
+    // Two digits are enough: the number is only there so the user
+    // checks which sign-in they approve, guessing denies the challenge.
+    let number = rand::thread_rng().gen_range(10..100);
+    let challenge_uuid = Uuid::new_v4();
+    let challenge = repositories.mfa.create_challenge(models::NewMfaChallenge {
+        challenge_uuid,
+        user_uuid: attempt.user_uuid,
+        device_uuid: attempt.device_uuid,
+        ip_address: attempt.ip_address,
+        factor: FACTOR_PUSH.to_string(),
+        code_hash: Some(hash_secret(&challenge_uuid, &number.to_string())),
+        expires_at: Utc::now() + Duration::seconds(config.mfa_challenge_ttl),
+    }, None).await?;
+
+    let data = json!({
+        "type": "mfa_challenge",
+        "challenge_id": challenge.challenge_uuid,
+        "ip_address": attempt.ip_address.addr().to_string(),
+        "expires_at": challenge.expires_at.timestamp(),
+    });
+
+    let pushes = tokens.into_iter().map(|token| PushNotification {
+        token,
+        title: "Is this you signing in?".to_string(),
+        body: format!(
+            "Someone is signing in to your account from {}. Pick the number shown on their screen to approve.",
+            attempt.ip_address.addr()
+        ),
+        data: data.clone(),
+    }).collect();
+
+    Ok(IssuedChallenge {
+        challenge,
+        sms: None,
+        pushes,
+        number_match: Some(number),
+    })
+}
+
+/// Answers a push challenge from one of the user's trusted devices,
+/// returning the settled challenge.
+///
+/// Picking the wrong number denies the challenge right away: it most likely
+/// means the user is approving a sign-in that isn't theirs.
+pub async fn respond_to_push_challenge(
+    repositories: &Repositories,
+    config: &Config,
+    challenge_uuid: Uuid,
+    answer: PushAnswer<'_>,
+) -> Result<models::MfaChallenge, MfaError> {
+    let challenge = repositories.mfa.find_challenge(challenge_uuid).await.map_err(find_error)?;
+
+    if challenge.factor != FACTOR_PUSH || challenge.status != models::MfaChallengeStatusEnum::Pending {
+        return Err(MfaError::Invalid);
+    }
+
+    if challenge.expires_at < Utc::now() {
+        repositories.mfa.settle(challenge_uuid, models::MfaChallengeStatusEnum::Expired, None).await?;
+        return Err(MfaError::Invalid);
+    }
+
+    let responder = answer.responder;
+    if responder.device_uuid == challenge.device_uuid
+        || !trusted_devices::is_device_trusted(repositories.devices.as_ref(), config, answer.device_trust_token, challenge.user_uuid, responder).await?
+    {
+        return Err(MfaError::NotTrusted);
+    }
+
+    let number_matches = challenge.code_hash.as_deref() == Some(hash_secret(&challenge_uuid, &answer.number_match.to_string()).as_str());
+    let status = if answer.approve && number_matches {
+        models::MfaChallengeStatusEnum::Approved
+    } else {
+        models::MfaChallengeStatusEnum::Denied
+    };
+
+    // Another device may have answered first.
+    let challenge = repositories.mfa.settle(challenge_uuid, status, Some(responder.device_uuid)).await.map_err(settle_error)?;
+
+    if answer.approve && !number_matches {
+        return Err(MfaError::Invalid);
+    }
+
+    Ok(challenge)
+}
+
+/// Completes the sign-in of a challenge: code challenges are answered with
+/// `code`, push challenges must have been approved from a trusted device.
+///
+/// A challenge completes a single sign-in.
+pub async fn complete_challenge(
+    mfa: &dyn MfaRepository,
+    config: &Config,
+    challenge_uuid: Uuid,
+    code: &str,
+) -> Result<models::MfaChallenge, MfaError> {
+    let challenge = mfa.find_challenge(challenge_uuid).await.map_err(find_error)?;
+
+    if challenge.factor == FACTOR_PUSH {
+        match challenge.status {
+            models::MfaChallengeStatusEnum::Pending if challenge.expires_at < Utc::now() => {
+                mfa.settle(challenge_uuid, models::MfaChallengeStatusEnum::Expired, None).await?;
+                return Err(MfaError::Invalid);
+            }
+            models::MfaChallengeStatusEnum::Pending => return Err(MfaError::Pending),
+            models::MfaChallengeStatusEnum::Denied => return Err(MfaError::Denied),
+            _ => {}
+        }
+    } else {
+        verify_challenge_code(mfa, config, challenge, code).await?;
+    }
+
+    mfa.consume(challenge_uuid).await.map_err(settle_error)
+}
+
+/// Answers a code challenge, returning it once approved.
+///
+/// Every try counts as an attempt, once the limit is reached the challenge
+/// is denied and the sign-in has to start over.
+async fn verify_challenge_code(
+    mfa: &dyn MfaRepository,
+    config: &Config,
+    challenge: models::MfaChallenge,
+    code: &str,
+) -> Result<models::MfaChallenge, MfaError> {
+    let challenge_uuid = challenge.challenge_uuid;
+
+    let Some(code_hash) = challenge.code_hash.as_deref().filter(|_| challenge.status == models::MfaChallengeStatusEnum::Pending) else {
+        return Err(MfaError::Invalid);
+    };
+
+    if challenge.expires_at < Utc::now() {
+        mfa.settle(challenge_uuid, models::MfaChallengeStatusEnum::Expired, None).await?;
+        return Err(MfaError::Invalid);
+    }
+
+    // The attempt is counted before the code is compared, so parallel guesses
+    // can't all slip under the limit.
+    let claimed = mfa.claim_attempt(challenge_uuid, config.mfa_max_attempts).await?
+        .ok_or(MfaError::Invalid)?;
+
+    if hash_secret(&challenge_uuid, code.trim()) != code_hash {
+        if claimed.attempts >= config.mfa_max_attempts {
+            mfa.settle(challenge_uuid, models::MfaChallengeStatusEnum::Denied, None).await.map_err(settle_error)?;
+        }
+        return Err(MfaError::Invalid);
+    }
+
+    mfa.settle(challenge_uuid, models::MfaChallengeStatusEnum::Approved, None).await.map_err(settle_error)
+}
@@ -1,8 +1,10 @@
 use diesel::{
-    ExpressionMethods, Insertable, PgConnection, QueryDsl, Queryable, RunQueryDsl, Selectable, SelectableHelper
+    AsChangeset, BoolExpressionMethods, JoinOnDsl, ExpressionMethods, Insertable, NullableExpressionMethods, OptionalExtension, PgJsonbExpressionMethods, PgSortExpressionMethods, QueryDsl, Queryable, Selectable, SelectableHelper
 };
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
 use uuid::Uuid;
-use crate::schema::{users, emails, phones, devices, sessions};
+use crate::schema::{users, emails, email_change_requests, email_deliveries, email_suppressions, email_verification_tokens, mfa_challenges, phone_verification_codes, phones, devices, revoked_tokens, session_policies, sessions, sms_sends, trusted_devices, roles, user_roles, user_attribute_definitions, audit_events, user_data_jobs, membership};
 use serde_json::Value;
 use ipnet::IpNet;
 
@@ -72,19 +74,25 @@ pub enum OsEnum {
 }
 
 impl OsEnum {
+    /// Maps an OS family as reported by uaparser (`Mac OS X`) or woothee
+    /// (`Mac OSX`, `Windows 10`), case-insensitively.
     pub fn from_device_family(family: &str) -> Self {
-        match family.to_lowercase().as_str() {
-            "Windows" => OsEnum::Windows,
-            "Mac OS X" => OsEnum::Macos,
-            "Linux" => OsEnum::Linux,
-            "Android" => OsEnum::Android,
-            "iOS" => OsEnum::Ios,
-            "Chrome OS" => OsEnum::ChromeOs,
-            "Tizen" => OsEnum::Tizen,
-            "Fire OS" => OsEnum::FireOs,
-            "watchOS" => OsEnum::Watchos,
-            "webOS" => OsEnum::Webos,
-            "FreeBSD" => OsEnum::Freebsd,
+        let family = family.trim().to_lowercase();
+
+        match family.as_str() {
+            "mac os x" | "mac osx" | "macos" | "mac os" => OsEnum::Macos,
+            "ios" | "iphone" | "ipad" | "ipod" | "ipados" => OsEnum::Ios,
+            "android" => OsEnum::Android,
+            "chrome os" | "chromeos" => OsEnum::ChromeOs,
+            "tizen" => OsEnum::Tizen,
+            "fire os" | "fireos" => OsEnum::FireOs,
+            "watchos" => OsEnum::Watchos,
+            "webos" => OsEnum::Webos,
+            "freebsd" | "bsd" => OsEnum::Freebsd,
+            // Windows Phone has no variant, it mustn't be mistaken for desktop Windows.
+            "windows phone" | "windows phone os" => OsEnum::Other,
+            family if family.starts_with("windows") => OsEnum::Windows,
+            "linux" | "ubuntu" | "debian" | "fedora" | "red hat" | "suse" | "arch linux" | "linux mint" | "gentoo" => OsEnum::Linux,
             _ => OsEnum::Other,
         }
     }
@@ -105,6 +113,26 @@ pub enum DeviceStatusEnum {
     Offline
 }
 
+impl DeviceStatusEnum {
+    /// Statuses in which a device can't be used to sign in. Moving a device
+    /// into one of them revokes its sessions.
+    pub fn is_blocked(&self) -> bool {
+        matches!(
+            self,
+            DeviceStatusEnum::Suspended
+                | DeviceStatusEnum::Banned
+                | DeviceStatusEnum::Retired
+                | DeviceStatusEnum::Lost
+                | DeviceStatusEnum::Stolen
+        )
+    }
+
+    /// Retired devices are kept for history only and can't come back.
+    pub fn can_transition_to(&self, status: &DeviceStatusEnum) -> bool {
+        self == status || *self != DeviceStatusEnum::Retired
+    }
+}
+
 
 #[derive(Queryable, Selectable, Clone)]
 #[diesel(table_name = devices)]
@@ -125,12 +153,17 @@ pub struct Device {
     pub mac_address: Option<String>,
     pub notification_token: Option<String>,
     pub notification_enabled: Option<bool>,
-    pub metadata: Value
+    pub metadata: Value,
+    pub user_uuid: Option<Uuid>,
+    pub client_device_id: Option<String>,
+    pub user_agent_hash: Option<String>,
+    pub ip_prefix: Option<IpNet>,
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = devices)]
 pub struct NewDevice {
+    pub user_uuid: Option<Uuid>,
     pub device_type: DeviceTypeEnum,
     pub os: OsEnum,
     pub os_version: Option<String>,
@@ -139,31 +172,256 @@ pub struct NewDevice {
     // #[diesel(sql_type = diesel::sql_types::Inet)]
     pub ip_address: IpNet,
     pub location: Option<Value>,
-    pub status: DeviceStatusEnum
+    pub status: DeviceStatusEnum,
+    pub mac_address: Option<String>,
+    pub client_device_id: Option<String>,
+    pub user_agent_hash: Option<String>,
+    pub ip_prefix: Option<IpNet>,
+}
+
+/// Fields of a device that can be changed after it was registered, `None`
+/// leaves a field as is.
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = devices)]
+pub struct DeviceChangeset {
+    pub notification_token: Option<String>,
+    pub notification_enabled: Option<bool>,
+    pub os_version: Option<String>,
+    pub browser_version: Option<String>,
+    pub mac_address: Option<String>,
+    pub client_device_id: Option<String>,
+    pub user_agent_hash: Option<String>,
+    pub ip_prefix: Option<IpNet>,
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Device {
-    pub fn create(
-        conn: &mut PgConnection,
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
         new_device: NewDevice,
     ) -> Result<Device, diesel::result::Error> {
         diesel::insert_into(devices::table)
             .values(new_device)
             .returning(Device::as_returning())
-            .get_result(conn)   
+            .get_result(conn).await   
     }
 
-    pub fn find_by_uuid(
-        conn: &mut PgConnection,
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
         device_uuid: Uuid,
     ) -> Result<Device, diesel::result::Error> {
         devices::table
             .filter(devices::device_uuid.eq(device_uuid))
             .select(Device::as_select())
-            .first(conn)   
+            .first(conn).await   
+    }
+
+    pub async fn find_by_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Device>, diesel::result::Error> {
+        devices::table
+            .filter(devices::user_uuid.eq(user_uuid))
+            .order((devices::created_at.asc(), devices::device_uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .select(Device::as_select())
+            .load(conn).await
+    }
+
+    /// Devices that share at least one fingerprint signal: the client's stable
+    /// ID, the MAC address, or the user agent from the same network. Retired
+    /// devices never come back.
+    pub async fn find_fingerprint_candidates(
+        conn: &mut AsyncPgConnection,
+        client_device_id: Option<&str>,
+        mac_address: Option<&str>,
+        user_agent_hash: &str,
+        ip_prefix: IpNet,
+        limit: i64,
+    ) -> Result<Vec<Device>, diesel::result::Error> {
+        // Comparing with NULL never matches, so missing signals simply drop out.
+        devices::table
+            .filter(devices::status.ne(DeviceStatusEnum::Retired))
+            .filter(
+                devices::client_device_id.eq(client_device_id)
+                    .or(devices::mac_address.eq(mac_address))
+                    .or(devices::user_agent_hash.eq(user_agent_hash).and(devices::ip_prefix.eq(ip_prefix)))
+            )
+            .order((devices::last_used_at.desc().nulls_last(), devices::created_at.desc()))
+            .limit(limit)
+            .select(Device::as_select())
+            .load(conn).await
+    }
+
+    pub async fn update(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+        changes: DeviceChangeset,
+    ) -> Result<Device, diesel::result::Error> {
+        diesel::update(devices::table)
+            .filter(devices::device_uuid.eq(device_uuid))
+            .set(DeviceChangeset {
+                updated_at: Some(chrono::Utc::now()),
+                ..changes
+            })
+            .returning(Device::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Sets the token push notifications are sent to, enabling them.
+    ///
+    /// A token identifies an app install, so it is taken away from any other
+    /// device it was registered for, e.g. before the app data was cleared.
+    pub async fn set_notification_token(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+        token: String,
+    ) -> Result<Device, diesel::result::Error> {
+        let now = Some(chrono::Utc::now());
+
+        diesel::update(devices::table)
+            .filter(devices::notification_token.eq(&token))
+            .filter(devices::device_uuid.ne(device_uuid))
+            .set((
+                devices::notification_token.eq(None::<String>),
+                devices::notification_enabled.eq(Some(false)),
+                devices::updated_at.eq(now),
+            ))
+            .execute(conn).await?;
+
+        diesel::update(devices::table)
+            .filter(devices::device_uuid.eq(device_uuid))
+            .set((
+                devices::notification_token.eq(Some(token)),
+                devices::notification_enabled.eq(Some(true)),
+                devices::updated_at.eq(now),
+            ))
+            .returning(Device::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Forgets a token the push provider reported as no longer valid.
+    pub async fn clear_notification_token(
+        conn: &mut AsyncPgConnection,
+        token: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(devices::table)
+            .filter(devices::notification_token.eq(token))
+            .set((
+                devices::notification_token.eq(None::<String>),
+                devices::notification_enabled.eq(Some(false)),
+                devices::updated_at.eq(Some(chrono::Utc::now())),
+            ))
+            .execute(conn).await
+    }
+
+    pub async fn update_status(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+        status: DeviceStatusEnum,
+    ) -> Result<Device, diesel::result::Error> {
+        diesel::update(devices::table)
+            .filter(devices::device_uuid.eq(device_uuid))
+            .set((
+                devices::status.eq(status),
+                devices::updated_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(Device::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Records a sign-in from the device, keeping its last known address and
+    /// location.
+    pub async fn record_use(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+        ip_address: IpNet,
+        location: Option<Value>,
+    ) -> Result<usize, diesel::result::Error> {
+        let target = devices::table.filter(devices::device_uuid.eq(device_uuid));
+        let now = Some(chrono::Utc::now());
+
+        match location {
+            Some(location) => diesel::update(target)
+                .set((
+                    devices::ip_address.eq(ip_address),
+                    devices::location.eq(Some(location)),
+                    devices::last_used_at.eq(now),
+                ))
+                .execute(conn).await,
+            None => diesel::update(target)
+                .set((
+                    devices::ip_address.eq(ip_address),
+                    devices::last_used_at.eq(now),
+                ))
+                .execute(conn).await,
+        }
+    }
+
+    /// Makes the user the owner of a device that has none yet.
+    pub async fn assign_user(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(devices::table)
+            .filter(devices::device_uuid.eq(device_uuid))
+            .filter(devices::user_uuid.is_null())
+            .set(devices::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+    ) -> Result<Device, diesel::result::Error> {
+        diesel::delete(devices::table)
+            .filter(devices::device_uuid.eq(device_uuid))
+            .returning(Device::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Strips network identifiers, location and push tokens from every device
+    /// the user owns or has signed in from. Devices other users signed in
+    /// from are left alone, they still identify those users' sessions.
+    pub async fn anonymize_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        let user_devices = sessions::table
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .filter(sessions::device_uuid.is_not_null())
+            .select(sessions::device_uuid.assume_not_null());
+        let shared_devices = sessions::table
+            .filter(sessions::user_uuid.ne(user_uuid))
+            .filter(sessions::device_uuid.is_not_null())
+            .select(sessions::device_uuid.assume_not_null());
+
+        diesel::update(devices::table)
+            .filter(devices::user_uuid.eq(user_uuid).or(devices::device_uuid.eq_any(user_devices)))
+            .filter(diesel::dsl::not(devices::device_uuid.eq_any(shared_devices)))
+            .set((
+                devices::ip_address.eq(anonymous_ip()),
+                devices::mac_address.eq(None::<String>),
+                devices::location.eq(None::<Value>),
+                devices::notification_token.eq(None::<String>),
+                devices::metadata.eq(serde_json::json!({})),
+                devices::client_device_id.eq(None::<String>),
+                devices::user_agent_hash.eq(None::<String>),
+                devices::ip_prefix.eq(None::<IpNet>),
+            ))
+            .execute(conn).await
     }
 }
 
+/// Placeholder address written over IP addresses during erasure.
+fn anonymous_ip() -> IpNet {
+    "0.0.0.0/32".parse().unwrap()
+}
+
 #[derive(Queryable, Selectable, Clone)]
 #[diesel(table_name = users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -189,83 +447,260 @@ pub struct User {
     // #[prost(message, tag = "7")]
     // #[diesel(sql_type = Jsonb)]
     pub metadata: Value, // JSONB field
+
+    pub email_update_required: bool,
+
+    pub mfa_enabled: bool,
 }
 
 #[derive(Insertable)]
 #[diesel(table_name = users)]
 pub struct NewUser {
     pub username: String,
+    pub username_skeleton: String,
     pub password_hash: String,
     pub metadata: Value,
 }
 
+diesel::define_sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
 
 impl User {
-    pub fn create(
-        conn: &mut PgConnection,
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
         new_user: NewUser,
     ) -> Result<User, diesel::result::Error> {
         diesel::insert_into(users::table)
             .values(new_user)
             .returning(User::as_returning())
-            .get_result(conn)   
+            .get_result(conn).await   
     }
 
 
-    pub fn find_user_uuid(
-        conn: &mut PgConnection,
+    pub async fn find_user_uuid(
+        conn: &mut AsyncPgConnection,
         user_uuid: Uuid,
     ) -> Result<User, diesel::result::Error> {
         users::table
             .filter(users::user_uuid.eq(user_uuid))
             .select(User::as_select())
-            .first(conn)
+            .first(conn).await
+    }
+
+    /// Locks the user row until the end of the current transaction, so
+    /// concurrent changes to the user's sessions run one at a time.
+    pub async fn lock(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<(), diesel::result::Error> {
+        users::table
+            .filter(users::user_uuid.eq(user_uuid))
+            .select(users::user_uuid)
+            .for_update()
+            .first::<Uuid>(conn).await?;
+        Ok(())
     }
 
 
-    pub fn delete_user(
-        conn: &mut PgConnection,
+    pub async fn delete_user(
+        conn: &mut AsyncPgConnection,
         user_uuid: Uuid,
     ) -> Result<bool, diesel::result::Error> {
         let deleted = diesel::delete(users::table)
             .filter(users::user_uuid.eq(user_uuid))
-            .execute(conn)?;
+            .execute(conn).await?;
         Ok(deleted > 0)
     }
 
-    pub fn update_password(
-        conn: &mut PgConnection,
+    pub async fn update_password(
+        conn: &mut AsyncPgConnection,
         user_uuid: Uuid,
         hashed_password: String
     ) -> Result<bool, diesel::result::Error> {
         let updated = diesel::update(users::table)
             .filter(users::user_uuid.eq(user_uuid))
             .set(users::password_hash.eq(hashed_password))
-            .execute(conn)?;
+            .execute(conn).await?;
         Ok(updated > 0)
     }
 
-    pub fn find_by_username(
-        conn:  &mut PgConnection,
+    /// Looks a user up by username, ignoring case and compatibility variants.
+    pub async fn find_by_username(
+        conn:  &mut AsyncPgConnection,
         username: String
     ) -> Result<User, diesel::result::Error> {
         users::table
-            .filter(users::username.eq(username))
+            .filter(lower(users::username).eq(crate::utils::normalize_username(&username)))
+            .select(User::as_select())
+            .first(conn).await
+    }
+
+    pub async fn set_email_update_required(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        required: bool,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .set(users::email_update_required.eq(required))
+            .execute(conn).await?;
+        Ok(updated > 0)
+    }
+
+    pub async fn mark_verified(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .filter(users::is_verified.eq(false))
+            .set(users::is_verified.eq(true))
+            .execute(conn).await?;
+        Ok(updated > 0)
+    }
+
+    pub async fn set_mfa_enabled(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        enabled: bool,
+    ) -> Result<User, diesel::result::Error> {
+        diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .set(users::mfa_enabled.eq(enabled))
+            .returning(User::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Finds a user whose username looks like one with the given skeleton.
+    pub async fn find_confusable(
+        conn: &mut AsyncPgConnection,
+        skeleton: &str,
+        exclude: Option<Uuid>,
+    ) -> Result<Option<User>, diesel::result::Error> {
+        let mut query = users::table
+            .filter(users::username_skeleton.eq(skeleton))
             .select(User::as_select())
-            .first(conn)
+            .into_boxed();
+
+        if let Some(user_uuid) = exclude {
+            query = query.filter(users::user_uuid.ne(user_uuid));
+        }
+
+        query.first(conn).await.optional()
+    }
+
+    pub async fn update_username(
+        conn:  &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        username: String,
+        skeleton: String,
+    ) -> Result<User, diesel::result::Error> {
+        diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .set((
+                users::username.eq(username),
+                users::username_skeleton.eq(skeleton),
+            ))
+            .returning(User::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Returns the users created before username skeletons were tracked.
+    pub async fn find_missing_skeletons(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<(Uuid, String)>, diesel::result::Error> {
+        users::table
+            .filter(users::username_skeleton.is_null())
+            .filter(users::archived_at.is_null())
+            .select((users::user_uuid, users::username))
+            .load(conn).await
+    }
+
+    pub async fn set_skeleton(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        skeleton: String,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .set(users::username_skeleton.eq(skeleton))
+            .execute(conn).await?;
+        Ok(updated > 0)
     }
 
-    pub fn update(
-        conn:  &mut PgConnection,
-        user: User,
+    pub async fn update_metadata(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        metadata: Value,
+    ) -> Result<User, diesel::result::Error> {
+        diesel::update(users::table)
+            .filter(users::user_uuid.eq(user_uuid))
+            .set(users::metadata.eq(metadata))
+            .returning(User::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Replaces every piece of personal data on the user row while keeping the
+    /// row itself so foreign keys stay valid.
+    pub async fn anonymize(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
     ) -> Result<User, diesel::result::Error> {
         diesel::update(users::table)
-            .filter(users::user_uuid.eq(user.user_uuid))
+            .filter(users::user_uuid.eq(user_uuid))
             .set((
-                users::username.eq(user.username),
+                users::username.eq(format!("erased_{}", user_uuid.simple())),
+                users::username_skeleton.eq(None::<String>),
+                users::password_hash.eq(""),
+                users::status.eq(UserStatusEnum::Deleted),
+                users::is_verified.eq(false),
+                users::metadata.eq(serde_json::json!({})),
+                users::archived_at.eq(diesel::dsl::now),
             ))
             .returning(User::as_returning())
-            .get_result(conn)
+            .get_result(conn).await
+    }
+
+    /// Finds users whose metadata contains `key` with exactly `value`.
+    pub async fn find_by_attribute(
+        conn: &mut AsyncPgConnection,
+        key: &str,
+        value: Value,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<User>, diesel::result::Error> {
+        let mut filter = serde_json::Map::new();
+        filter.insert(key.to_string(), value);
+
+        users::table
+            .filter(users::metadata.contains(Value::Object(filter)))
+            .order(users::created_at.asc())
+            .limit(limit)
+            .offset(offset)
+            .select(User::as_select())
+            .load(conn).await
+    }
+
+    /// Checks whether any user other than `user_uuid` already uses `value` for `key`.
+    pub async fn attribute_value_taken(
+        conn: &mut AsyncPgConnection,
+        key: &str,
+        value: Value,
+        user_uuid: Option<Uuid>,
+    ) -> Result<bool, diesel::result::Error> {
+        let mut filter = serde_json::Map::new();
+        filter.insert(key.to_string(), value);
+
+        let mut query = users::table
+            .filter(users::metadata.contains(Value::Object(filter)))
+            .into_boxed();
+
+        if let Some(user_uuid) = user_uuid {
+            query = query.filter(users::user_uuid.ne(user_uuid));
+        }
+
+        let count: i64 = query.count().get_result(conn).await?;
+        Ok(count > 0)
     }
 }
 
@@ -284,7 +719,14 @@ pub struct Session {
     pub expires_at: chrono::DateTime<chrono::Utc>,
     pub invalidated_at: Option<chrono::DateTime<chrono::Utc>>,
     pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
-    pub created_at: Option<chrono::DateTime<chrono::Utc>>
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Seconds without use after which the session ends, `None` for no limit.
+    pub idle_timeout: Option<i32>,
+    /// Seconds a refresh extends the session by, `None` for a fixed expiry.
+    pub sliding_lifetime: Option<i32>,
+    pub idle_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Refreshes never extend the session past this.
+    pub absolute_expires_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Insertable)]
@@ -295,48 +737,382 @@ pub struct NewSession {
     pub expires_at: chrono::DateTime<chrono::Utc>,
     pub ip_address: IpNet,
     pub metadata: Value,
+    pub idle_timeout: Option<i32>,
+    pub sliding_lifetime: Option<i32>,
+    pub idle_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub absolute_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_accessed_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 impl Session {
-    pub fn create(
-        conn: &mut PgConnection,
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
         new_session: NewSession,
     ) -> Result<Session, diesel::result::Error> {
         diesel::insert_into(sessions::table)
             .values(new_session)
             .returning(Session::as_returning())
-            .get_result(conn)
+            .get_result(conn).await
     }
 
-    pub fn find_by_uuid(
-        conn: &mut PgConnection,
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
         session_uuid: Uuid,
     ) -> Result<Session, diesel::result::Error> {
         sessions::table
             .filter(sessions::session_uuid.eq(session_uuid))
             .select(Session::as_select())
-            .first(conn)
+            .first(conn).await
     }
 
-    pub fn delete(
-        conn: &mut PgConnection,
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
         session_uuid: Uuid,
     ) -> Result<bool, diesel::result::Error> {
         let deleted = diesel::delete(sessions::table)
             .filter(sessions::session_uuid.eq(session_uuid))
-            .execute(conn)?;
+            .execute(conn).await?;
         Ok(deleted > 0)
     }
+
+    /// Revokes every active session of the user, returning them.
+    pub async fn revoke_all_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+
+    /// Revokes every active session bound to the device, returning them.
+    pub async fn revoke_all_for_device(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::device_uuid.eq(device_uuid))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+
+    /// A session with the device it was started from, `None` if the device
+    /// was deleted since.
+    pub async fn find_with_device(
+        conn: &mut AsyncPgConnection,
+        session_uuid: Uuid,
+    ) -> Result<(Session, Option<Device>), diesel::result::Error> {
+        sessions::table
+            .left_join(devices::table.on(sessions::device_uuid.eq(devices::device_uuid.nullable())))
+            .filter(sessions::session_uuid.eq(session_uuid))
+            .select((Session::as_select(), Option::<Device>::as_select()))
+            .first(conn).await
+    }
+
+    /// Sessions of a user with their devices, most recently used first.
+    /// Revoked and expired sessions are left out unless `include_inactive`.
+    pub async fn find_by_user_with_devices(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        include_inactive: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<(Session, Option<Device>)>, diesel::result::Error> {
+        let mut query = sessions::table
+            .left_join(devices::table.on(sessions::device_uuid.eq(devices::device_uuid.nullable())))
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .into_boxed();
+
+        if !include_inactive {
+            query = query
+                .filter(sessions::is_active.eq(true))
+                .filter(sessions::revoked_at.is_null())
+                .filter(sessions::expires_at.gt(chrono::Utc::now()));
+        }
+
+        query
+            .order((
+                sessions::last_accessed_at.desc().nulls_last(),
+                sessions::created_at.desc().nulls_last(),
+                sessions::session_uuid.asc(),
+            ))
+            .limit(limit)
+            .offset(offset)
+            .select((Session::as_select(), Option::<Device>::as_select()))
+            .load(conn).await
+    }
+
+    /// Revokes a session, returns 0 if it was already revoked.
+    pub async fn revoke(
+        conn: &mut AsyncPgConnection,
+        session_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::session_uuid.eq(session_uuid))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn).await
+    }
+
+    /// Revokes every active session of the user but one, returning them.
+    pub async fn revoke_others_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        kept_session_uuid: Uuid,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .filter(sessions::session_uuid.ne(kept_session_uuid))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+
+    /// Revokes the active sessions of the user on one device, returning them.
+    pub async fn revoke_for_user_device(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        device_uuid: Uuid,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .filter(sessions::device_uuid.eq(device_uuid))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+
+    /// Records a use of the session, pushing back its idle deadline.
+    pub async fn touch(
+        conn: &mut AsyncPgConnection,
+        session_uuid: Uuid,
+        idle_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::session_uuid.eq(session_uuid))
+            .set((
+                sessions::last_accessed_at.eq(Some(chrono::Utc::now())),
+                sessions::idle_expires_at.eq(idle_expires_at),
+            ))
+            .execute(conn).await
+    }
+
+    /// Extends a session on refresh.
+    pub async fn renew(
+        conn: &mut AsyncPgConnection,
+        session_uuid: Uuid,
+        expires_at: chrono::DateTime<chrono::Utc>,
+        idle_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Session, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::session_uuid.eq(session_uuid))
+            .set((
+                sessions::expires_at.eq(expires_at),
+                sessions::idle_expires_at.eq(idle_expires_at),
+                sessions::last_accessed_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(Session::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Marks active sessions past their expiry, idle or absolute deadline as
+    /// inactive, returning them.
+    pub async fn invalidate_stale(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        let now = chrono::Utc::now();
+
+        diesel::update(sessions::table)
+            .filter(sessions::is_active.eq(true))
+            .filter(
+                sessions::expires_at.le(now).nullable()
+                    .or(sessions::idle_expires_at.le(now))
+                    .or(sessions::absolute_expires_at.le(now))
+            )
+            .set((
+                sessions::is_active.eq(false),
+                sessions::invalidated_at.eq(Some(now)),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+
+    /// Revokes sessions, keeping why in their metadata as `revocation_reason`.
+    pub async fn revoke_with_reason(
+        conn: &mut AsyncPgConnection,
+        session_uuids: &[Uuid],
+        reason: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::session_uuid.eq_any(session_uuids))
+            .filter(sessions::revoked_at.is_null())
+            .set((
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+                sessions::metadata.eq(sessions::metadata.concat(serde_json::json!({ "revocation_reason": reason }))),
+            ))
+            .execute(conn).await
+    }
+
+    /// Revokes every session of the user and strips their IP address and
+    /// metadata, returning them.
+    pub async fn anonymize_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<Vec<Uuid>, diesel::result::Error> {
+        diesel::update(sessions::table)
+            .filter(sessions::user_uuid.eq(user_uuid))
+            .set((
+                sessions::ip_address.eq(anonymous_ip()),
+                sessions::metadata.eq(serde_json::json!({})),
+                sessions::is_active.eq(false),
+                sessions::revoked_at.eq(diesel::dsl::now),
+            ))
+            .returning(sessions::session_uuid)
+            .get_results(conn).await
+    }
+}
+
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = session_policies)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SessionPolicy {
+    pub policy_uuid: Uuid,
+    pub role_uuid: Option<Uuid>,
+    pub org_uuid: Option<Uuid>,
+    pub idle_timeout: Option<i32>,
+    pub sliding_lifetime: Option<i32>,
+    pub absolute_lifetime: Option<i32>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    pub max_sessions: Option<i32>,
+    pub max_sessions_per_device_type: Option<i32>,
+    /// `reject` or `evict_oldest`.
+    pub limit_action: Option<String>,
+}
+
+/// A policy for exactly one of a role or an organization.
+#[derive(Insertable)]
+#[diesel(table_name = session_policies)]
+pub struct NewSessionPolicy {
+    pub role_uuid: Option<Uuid>,
+    pub org_uuid: Option<Uuid>,
+    pub idle_timeout: Option<i32>,
+    pub sliding_lifetime: Option<i32>,
+    pub absolute_lifetime: Option<i32>,
+    pub max_sessions: Option<i32>,
+    pub max_sessions_per_device_type: Option<i32>,
+    pub limit_action: Option<String>,
+}
+
+impl SessionPolicy {
+    /// Policies of the user's roles, and of the organizations they are an
+    /// active member of with the role they have there.
+    pub async fn find_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<Vec<SessionPolicy>, diesel::result::Error> {
+        let role_uuids = user_roles::table
+            .filter(user_roles::user_uuid.eq(user_uuid))
+            .select(user_roles::role_uuid)
+            .load::<Uuid>(conn).await?;
+
+        let memberships = membership::table
+            .filter(membership::user_uuid.eq(user_uuid))
+            .filter(membership::status.eq(MembershipStatusEnum::Active))
+            .select((membership::org_uuid, membership::role_uuid))
+            .load::<(Uuid, Uuid)>(conn).await?;
+
+        let (org_uuids, member_role_uuids): (Vec<Uuid>, Vec<Uuid>) = memberships.into_iter().unzip();
+        let role_uuids: Vec<Uuid> = role_uuids.into_iter().chain(member_role_uuids).collect();
+
+        session_policies::table
+            .filter(
+                session_policies::role_uuid.eq_any(role_uuids)
+                    .or(session_policies::org_uuid.eq_any(org_uuids))
+            )
+            .select(SessionPolicy::as_select())
+            .load(conn).await
+    }
+
+    /// Creates or replaces the policy of a role or an organization.
+    pub async fn upsert(
+        conn: &mut AsyncPgConnection,
+        new_policy: NewSessionPolicy,
+    ) -> Result<SessionPolicy, diesel::result::Error> {
+        conn.transaction(|conn| async move {
+            diesel::delete(session_policies::table)
+                .filter(
+                    session_policies::role_uuid.eq(new_policy.role_uuid)
+                        .or(session_policies::org_uuid.eq(new_policy.org_uuid))
+                )
+                .execute(conn).await?;
+
+            diesel::insert_into(session_policies::table)
+                .values(new_policy)
+                .returning(SessionPolicy::as_returning())
+                .get_result(conn).await
+        }.scope_boxed()).await
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        role_uuid: Option<Uuid>,
+        org_uuid: Option<Uuid>,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(session_policies::table)
+            .filter(
+                session_policies::role_uuid.eq(role_uuid)
+                    .or(session_policies::org_uuid.eq(org_uuid))
+            )
+            .execute(conn).await
+    }
 }
 
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::MembershipStatusEnum"]
+pub enum MembershipStatusEnum {
+    Active,
+    Inactive,
+    Suspended,
+    Pending,
+    Expired,
+    Revoked,
+    Left,
+    Banned,
+}
 
 #[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
 #[ExistingTypePath = "crate::schema::sql_types::EmailStatusEnum"]
 pub enum EmailStatusEnum {
-    Unverified,
     Verified,
+    Unverified,
     Bounced,
-    Disabled
+    Spam,
+    Blocked
 }
 
 #[derive(Queryable, Selectable, Clone)]
@@ -354,6 +1130,7 @@ pub struct Email {
     pub metadata: Value,
     // pub created_at: Option<chrono::DateTime<chrono::Utc>>,
     // pub updated_at: Option<chrono::DateTime<chrono::Utc>>
+    pub normalized_value: String,
 }
 
 #[derive(Insertable)]
@@ -361,49 +1138,665 @@ pub struct Email {
 pub struct NewEmail {
     pub user_uuid: Uuid,
     pub value: String,
+    pub normalized_value: String,
     pub status: EmailStatusEnum,
+    pub is_primary: bool,
     pub metadata: Value,
 }
 
 impl Email {
-    pub fn create(
-        conn: &mut PgConnection,
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
         new_email: NewEmail,
     ) -> Result<Email, diesel::result::Error> {
         diesel::insert_into(emails::table)
             .values(new_email)
             .returning(Email::as_returning())
-            .get_result(conn)
+            .get_result(conn).await
     }
 
-    pub fn find_by_uuid(
-        conn: &mut PgConnection,
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
         email_uuid: Uuid,
     ) -> Result<Email, diesel::result::Error> {
         emails::table
             .filter(emails::email_uuid.eq(email_uuid))
             .select(Email::as_select())
-            .first(conn)
+            .first(conn).await
     }
 
-    pub fn find_by_value(
-        conn: &mut PgConnection,
-        email: String,
+    /// Looks an email up by its normalized value, see `utils::EmailPolicy`.
+    pub async fn find_by_value(
+        conn: &mut AsyncPgConnection,
+        normalized_value: String,
     ) -> Result<Email, diesel::result::Error> {
         emails::table
-            .filter(emails::value.eq(email))
+            .filter(emails::normalized_value.eq(normalized_value))
             .select(Email::as_select())
-            .first(conn)
+            .first(conn).await
+    }
+
+    /// Returns every address with the key it's currently stored under.
+    pub async fn find_keys(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<(Uuid, String, String)>, diesel::result::Error> {
+        emails::table
+            .select((emails::email_uuid, emails::value, emails::normalized_value))
+            .load(conn).await
+    }
+
+    pub async fn set_normalized_value(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+        normalized_value: String,
+    ) -> Result<bool, diesel::result::Error> {
+        let updated = diesel::update(emails::table)
+            .filter(emails::email_uuid.eq(email_uuid))
+            .set(emails::normalized_value.eq(normalized_value))
+            .execute(conn).await?;
+        Ok(updated > 0)
+    }
+
+    pub async fn find_by_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<Vec<Email>, diesel::result::Error> {
+        emails::table
+            .filter(emails::user_uuid.eq(user_uuid))
+            .order((emails::is_primary.desc(), emails::created_at.asc()))
+            .select(Email::as_select())
+            .load(conn).await
+    }
+
+    /// Replaces the address of an email, which has to be verified again.
+    pub async fn update_value(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+        value: String,
+        normalized_value: String,
+    ) -> Result<Email, diesel::result::Error> {
+        diesel::update(emails::table)
+            .filter(emails::email_uuid.eq(email_uuid))
+            .set((
+                emails::value.eq(value),
+                emails::normalized_value.eq(normalized_value),
+                emails::status.eq(EmailStatusEnum::Unverified),
+                emails::is_verified.eq(false),
+                emails::verified_at.eq(None::<chrono::NaiveDateTime>),
+                emails::bounced_at.eq(None::<chrono::NaiveDateTime>),
+            ))
+            .returning(Email::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Changes the status of an email, keeping `is_verified` and the
+    /// `verified_at`/`bounced_at` timestamps in sync with it.
+    pub async fn update_status(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+        status: EmailStatusEnum,
+    ) -> Result<Email, diesel::result::Error> {
+        let target = emails::table.filter(emails::email_uuid.eq(email_uuid));
+
+        match status {
+            EmailStatusEnum::Verified => diesel::update(target)
+                .set((
+                    emails::status.eq(status),
+                    emails::is_verified.eq(true),
+                    emails::verified_at.eq(diesel::dsl::now.nullable()),
+                ))
+                .returning(Email::as_returning())
+                .get_result(conn).await,
+            EmailStatusEnum::Unverified => diesel::update(target)
+                .set((
+                    emails::status.eq(status),
+                    emails::is_verified.eq(false),
+                    emails::verified_at.eq(None::<chrono::NaiveDateTime>),
+                ))
+                .returning(Email::as_returning())
+                .get_result(conn).await,
+            EmailStatusEnum::Bounced => diesel::update(target)
+                .set((
+                    emails::status.eq(status),
+                    emails::bounced_at.eq(diesel::dsl::now.nullable()),
+                ))
+                .returning(Email::as_returning())
+                .get_result(conn).await,
+            EmailStatusEnum::Spam | EmailStatusEnum::Blocked => diesel::update(target)
+                .set(emails::status.eq(status))
+                .returning(Email::as_returning())
+                .get_result(conn).await,
+        }
+    }
+
+    /// Makes `email_uuid` the only primary email of its user.
+    ///
+    /// The current primary is demoted first so the partial unique index on
+    /// primary emails is never violated.
+    pub async fn set_primary(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+    ) -> Result<Email, diesel::result::Error> {
+        conn.transaction(|conn| async move {
+            let email = Email::find_by_uuid(conn, email_uuid).await?;
+
+            diesel::update(emails::table)
+                .filter(emails::user_uuid.eq(email.user_uuid))
+                .filter(emails::is_primary.eq(true))
+                .filter(emails::email_uuid.ne(email_uuid))
+                .set(emails::is_primary.eq(false))
+                .execute(conn).await?;
+
+            diesel::update(emails::table)
+                .filter(emails::email_uuid.eq(email_uuid))
+                .set(emails::is_primary.eq(true))
+                .returning(Email::as_returning())
+                .get_result(conn).await
+        }.scope_boxed()).await
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+    ) -> Result<Email, diesel::result::Error> {
+        diesel::delete(emails::table)
+            .filter(emails::email_uuid.eq(email_uuid))
+            .returning(Email::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(emails::table)
+            .filter(emails::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = email_verification_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailVerificationToken {
+    pub token_uuid: Uuid,
+    pub email_uuid: Uuid,
+    pub code_hash: String,
+    pub token_hash: String,
+    pub attempts: i32,
+    pub code_expires_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub consumed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = email_verification_tokens)]
+pub struct NewEmailVerificationToken {
+    pub token_uuid: Uuid,
+    pub email_uuid: Uuid,
+    pub code_hash: String,
+    pub token_hash: String,
+    pub code_expires_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl EmailVerificationToken {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_token: NewEmailVerificationToken,
+    ) -> Result<EmailVerificationToken, diesel::result::Error> {
+        diesel::insert_into(email_verification_tokens::table)
+            .values(new_token)
+            .returning(EmailVerificationToken::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Returns the most recently issued token of an email that wasn't used yet.
+    pub async fn find_latest_active(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+    ) -> Result<Option<EmailVerificationToken>, diesel::result::Error> {
+        email_verification_tokens::table
+            .filter(email_verification_tokens::email_uuid.eq(email_uuid))
+            .filter(email_verification_tokens::consumed_at.is_null())
+            .order(email_verification_tokens::created_at.desc())
+            .select(EmailVerificationToken::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    pub async fn find_by_token_hash(
+        conn: &mut AsyncPgConnection,
+        token_hash: &str,
+    ) -> Result<Option<EmailVerificationToken>, diesel::result::Error> {
+        email_verification_tokens::table
+            .filter(email_verification_tokens::token_hash.eq(token_hash))
+            .select(EmailVerificationToken::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    /// Lists the tokens issued for an email since `since`, newest first.
+    pub async fn find_issued_since(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<EmailVerificationToken>, diesel::result::Error> {
+        email_verification_tokens::table
+            .filter(email_verification_tokens::email_uuid.eq(email_uuid))
+            .filter(email_verification_tokens::created_at.ge(since))
+            .order(email_verification_tokens::created_at.desc())
+            .select(EmailVerificationToken::as_select())
+            .load(conn).await
+    }
+
+    /// Counts an attempt against an unused token, `None` once `max_attempts` were made.
+    ///
+    /// The increment and the limit check are a single statement, so concurrent
+    /// guesses can't all pass the check with the same stale count.
+    pub async fn claim_attempt(
+        conn: &mut AsyncPgConnection,
+        token_uuid: Uuid,
+        max_attempts: i32,
+    ) -> Result<Option<EmailVerificationToken>, diesel::result::Error> {
+        diesel::update(email_verification_tokens::table)
+            .filter(email_verification_tokens::token_uuid.eq(token_uuid))
+            .filter(email_verification_tokens::consumed_at.is_null())
+            .filter(email_verification_tokens::attempts.lt(max_attempts))
+            .set(email_verification_tokens::attempts.eq(email_verification_tokens::attempts + 1))
+            .returning(EmailVerificationToken::as_returning())
+            .get_result(conn).await
+            .optional()
+    }
+
+    /// Marks every outstanding token of an email as used.
+    pub async fn consume_all_for_email(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(email_verification_tokens::table)
+            .filter(email_verification_tokens::email_uuid.eq(email_uuid))
+            .filter(email_verification_tokens::consumed_at.is_null())
+            .set(email_verification_tokens::consumed_at.eq(Some(chrono::Utc::now())))
+            .execute(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::EmailChangeStatusEnum"]
+pub enum EmailChangeStatusEnum {
+    Pending,
+    Completed,
+    Cancelled,
+    Reverted
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = email_change_requests)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailChangeRequest {
+    pub request_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub old_email_uuid: Option<Uuid>,
+    pub new_email_uuid: Option<Uuid>,
+    pub locale: String,
+    pub status: EmailChangeStatusEnum,
+    pub cancel_token_hash: String,
+    pub revert_token_hash: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub undo_expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub completed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub cancelled_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub reverted_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = email_change_requests)]
+pub struct NewEmailChangeRequest {
+    pub user_uuid: Uuid,
+    pub old_email_uuid: Option<Uuid>,
+    pub new_email_uuid: Uuid,
+    pub locale: String,
+    pub cancel_token_hash: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl EmailChangeRequest {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_request: NewEmailChangeRequest,
+    ) -> Result<EmailChangeRequest, diesel::result::Error> {
+        diesel::insert_into(email_change_requests::table)
+            .values(new_request)
+            .returning(EmailChangeRequest::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(email_change_requests::table)
+            .filter(email_change_requests::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+
+    /// Returns the unexpired pending request waiting for `new_email_uuid` to be verified.
+    pub async fn find_pending_for_email(
+        conn: &mut AsyncPgConnection,
+        new_email_uuid: Uuid,
+    ) -> Result<Option<EmailChangeRequest>, diesel::result::Error> {
+        email_change_requests::table
+            .filter(email_change_requests::new_email_uuid.eq(new_email_uuid))
+            .filter(email_change_requests::status.eq(EmailChangeStatusEnum::Pending))
+            .filter(email_change_requests::expires_at.gt(diesel::dsl::now))
+            .select(EmailChangeRequest::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    /// Finds a request by the hash of either token sent to the old address.
+    pub async fn find_by_token_hash(
+        conn: &mut AsyncPgConnection,
+        token_hash: &str,
+    ) -> Result<Option<EmailChangeRequest>, diesel::result::Error> {
+        email_change_requests::table
+            .filter(
+                email_change_requests::cancel_token_hash.eq(token_hash)
+                    .or(email_change_requests::revert_token_hash.eq(token_hash)),
+            )
+            .select(EmailChangeRequest::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    /// Cancels the pending request of a user, if any, so a new one can be made.
+    pub async fn cancel_pending_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(email_change_requests::table)
+            .filter(email_change_requests::user_uuid.eq(user_uuid))
+            .filter(email_change_requests::status.eq(EmailChangeStatusEnum::Pending))
+            .set((
+                email_change_requests::status.eq(EmailChangeStatusEnum::Cancelled),
+                email_change_requests::cancelled_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn).await
+    }
+
+    /// Whether `email_uuid` is the old address of a change that can still be undone.
+    pub async fn is_undo_pending_for_old_email(
+        conn: &mut AsyncPgConnection,
+        email_uuid: Uuid,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            email_change_requests::table
+                .filter(email_change_requests::old_email_uuid.eq(email_uuid))
+                .filter(email_change_requests::status.eq(EmailChangeStatusEnum::Completed))
+                .filter(email_change_requests::undo_expires_at.gt(diesel::dsl::now)),
+        ))
+        .get_result(conn).await
+    }
+
+    pub async fn mark_completed(
+        conn: &mut AsyncPgConnection,
+        request_uuid: Uuid,
+        revert_token_hash: String,
+        undo_expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<EmailChangeRequest, diesel::result::Error> {
+        diesel::update(email_change_requests::table)
+            .filter(email_change_requests::request_uuid.eq(request_uuid))
+            .set((
+                email_change_requests::status.eq(EmailChangeStatusEnum::Completed),
+                email_change_requests::revert_token_hash.eq(Some(revert_token_hash)),
+                email_change_requests::completed_at.eq(diesel::dsl::now),
+                email_change_requests::undo_expires_at.eq(Some(undo_expires_at)),
+            ))
+            .returning(EmailChangeRequest::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_cancelled(
+        conn: &mut AsyncPgConnection,
+        request_uuid: Uuid,
+    ) -> Result<EmailChangeRequest, diesel::result::Error> {
+        diesel::update(email_change_requests::table)
+            .filter(email_change_requests::request_uuid.eq(request_uuid))
+            .set((
+                email_change_requests::status.eq(EmailChangeStatusEnum::Cancelled),
+                email_change_requests::cancelled_at.eq(diesel::dsl::now),
+            ))
+            .returning(EmailChangeRequest::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_reverted(
+        conn: &mut AsyncPgConnection,
+        request_uuid: Uuid,
+    ) -> Result<EmailChangeRequest, diesel::result::Error> {
+        diesel::update(email_change_requests::table)
+            .filter(email_change_requests::request_uuid.eq(request_uuid))
+            .set((
+                email_change_requests::status.eq(EmailChangeStatusEnum::Reverted),
+                email_change_requests::reverted_at.eq(diesel::dsl::now),
+            ))
+            .returning(EmailChangeRequest::as_returning())
+            .get_result(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::EmailDeliveryStatusEnum"]
+pub enum EmailDeliveryStatusEnum {
+    Pending,
+    Sent,
+    Failed
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = email_deliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct EmailDelivery {
+    pub delivery_uuid: Uuid,
+    pub recipient: String,
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: String,
+    pub status: EmailDeliveryStatusEnum,
+    pub attempts: i32,
+    pub next_attempt_at: chrono::DateTime<chrono::Utc>,
+    pub last_error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub sent_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = email_deliveries)]
+pub struct NewEmailDelivery {
+    pub recipient: String,
+    pub subject: String,
+    pub text_body: String,
+    pub html_body: String,
+}
+
+impl EmailDelivery {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_delivery: NewEmailDelivery,
+    ) -> Result<EmailDelivery, diesel::result::Error> {
+        diesel::insert_into(email_deliveries::table)
+            .values(new_delivery)
+            .returning(EmailDelivery::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Deletes the messages sent to any address of a user, sent or not.
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        let addresses = emails::table
+            .filter(emails::user_uuid.eq(user_uuid))
+            .select(emails::value);
+
+        diesel::delete(email_deliveries::table)
+            .filter(email_deliveries::recipient.eq_any(addresses))
+            .execute(conn).await
+    }
+
+    /// Claims pending deliveries whose next attempt is due, oldest first.
+    ///
+    /// Claimed deliveries have their next attempt pushed back by `lease`, so
+    /// other workers skip them. Rows another worker is claiming are skipped
+    /// rather than waited on. If the worker dies before settling them, they
+    /// are due again once the lease runs out.
+    pub async fn claim_due(
+        conn: &mut AsyncPgConnection,
+        limit: i64,
+        lease: chrono::Duration,
+    ) -> Result<Vec<EmailDelivery>, diesel::result::Error> {
+        conn.transaction(|conn| async move {
+            let due: Vec<Uuid> = email_deliveries::table
+                .filter(email_deliveries::status.eq(EmailDeliveryStatusEnum::Pending))
+                .filter(email_deliveries::next_attempt_at.le(diesel::dsl::now))
+                .order(email_deliveries::next_attempt_at.asc())
+                .limit(limit)
+                .select(email_deliveries::delivery_uuid)
+                .for_update()
+                .skip_locked()
+                .load(conn).await?;
+
+            diesel::update(email_deliveries::table)
+                .filter(email_deliveries::delivery_uuid.eq_any(&due))
+                .set(email_deliveries::next_attempt_at.eq(chrono::Utc::now() + lease))
+                .returning(EmailDelivery::as_returning())
+                .load(conn).await
+        }.scope_boxed()).await
+    }
+
+    /// Marks a delivery as sent and clears its bodies.
+    pub async fn mark_sent(
+        conn: &mut AsyncPgConnection,
+        delivery_uuid: Uuid,
+    ) -> Result<EmailDelivery, diesel::result::Error> {
+        diesel::update(email_deliveries::table)
+            .filter(email_deliveries::delivery_uuid.eq(delivery_uuid))
+            .set((
+                email_deliveries::status.eq(EmailDeliveryStatusEnum::Sent),
+                email_deliveries::attempts.eq(email_deliveries::attempts + 1),
+                email_deliveries::text_body.eq(""),
+                email_deliveries::html_body.eq(""),
+                email_deliveries::last_error.eq(None::<String>),
+                email_deliveries::sent_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(EmailDelivery::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_retry(
+        conn: &mut AsyncPgConnection,
+        delivery_uuid: Uuid,
+        error: String,
+        next_attempt_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<EmailDelivery, diesel::result::Error> {
+        diesel::update(email_deliveries::table)
+            .filter(email_deliveries::delivery_uuid.eq(delivery_uuid))
+            .set((
+                email_deliveries::attempts.eq(email_deliveries::attempts + 1),
+                email_deliveries::last_error.eq(Some(error)),
+                email_deliveries::next_attempt_at.eq(next_attempt_at),
+            ))
+            .returning(EmailDelivery::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_failed(
+        conn: &mut AsyncPgConnection,
+        delivery_uuid: Uuid,
+        error: String,
+    ) -> Result<EmailDelivery, diesel::result::Error> {
+        diesel::update(email_deliveries::table)
+            .filter(email_deliveries::delivery_uuid.eq(delivery_uuid))
+            .set((
+                email_deliveries::status.eq(EmailDeliveryStatusEnum::Failed),
+                email_deliveries::attempts.eq(email_deliveries::attempts + 1),
+                email_deliveries::text_body.eq(""),
+                email_deliveries::html_body.eq(""),
+                email_deliveries::last_error.eq(Some(error)),
+            ))
+            .returning(EmailDelivery::as_returning())
+            .get_result(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::EmailSuppressionReasonEnum"]
+pub enum EmailSuppressionReasonEnum {
+    HardBounce,
+    Complaint
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = email_suppressions)]
+pub struct NewEmailSuppression {
+    pub normalized_value: String,
+    pub reason: EmailSuppressionReasonEnum,
+    pub source: String,
+    pub diagnostic: Option<String>,
+}
+
+/// Addresses that bounced or complained and must not be sent to anymore.
+pub struct EmailSuppression;
+
+impl EmailSuppression {
+    /// Suppresses an address, keeping the first recorded reason.
+    pub async fn suppress(
+        conn: &mut AsyncPgConnection,
+        new_suppression: NewEmailSuppression,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(email_suppressions::table)
+            .values(new_suppression)
+            .on_conflict(email_suppressions::normalized_value)
+            .do_nothing()
+            .execute(conn).await
+    }
+
+    pub async fn is_suppressed(
+        conn: &mut AsyncPgConnection,
+        normalized_value: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            email_suppressions::table.filter(email_suppressions::normalized_value.eq(normalized_value)),
+        ))
+        .get_result(conn).await
+    }
+
+    /// Lifts the suppressions of every address of a user.
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        let addresses = emails::table
+            .filter(emails::user_uuid.eq(user_uuid))
+            .select(emails::normalized_value);
+
+        diesel::delete(email_suppressions::table)
+            .filter(email_suppressions::normalized_value.eq_any(addresses))
+            .execute(conn).await
     }
 }
 
 #[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
 #[ExistingTypePath = "crate::schema::sql_types::PhoneStatusEnum"]
 pub enum PhoneStatusEnum {
-    Unverified,
     Verified,
+    Unverified,
     Bounced,
-    Disabled
+    Spam,
+    Blocked
 }
 
 #[derive(Queryable, Selectable, Clone)]
@@ -432,37 +1825,984 @@ pub struct NewPhone {
     pub number: String,
     pub type_: String,
     pub status: PhoneStatusEnum,
+    pub is_primary: bool,
     pub metadata: Value,
 }
 
 impl Phone {
-    pub fn create(
-        conn: &mut PgConnection,
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
         new_phone: NewPhone,
     ) -> Result<Phone, diesel::result::Error> {
         diesel::insert_into(phones::table)
             .values(new_phone)
             .returning(Phone::as_returning())
-            .get_result(conn)
+            .get_result(conn).await
     }
 
-    pub fn find_by_uuid(
-        conn: &mut PgConnection,
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
         phone_uuid: Uuid,
     ) -> Result<Phone, diesel::result::Error> {
         phones::table
             .filter(phones::phone_uuid.eq(phone_uuid))
             .select(Phone::as_select())
-            .first(conn)
+            .first(conn).await
     }
 
-    pub fn find_by_number(
-        conn: &mut PgConnection,
+    /// Looks a phone up by its E.164 number, see `utils::normalize_phone`.
+    pub async fn find_by_number(
+        conn: &mut AsyncPgConnection,
         full_number: String,
     ) -> Result<Phone, diesel::result::Error> {
         phones::table
             .filter(phones::full_number.eq(full_number))
             .select(Phone::as_select())
-            .first(conn)
+            .first(conn).await
+    }
+
+    /// Returns a page of the user's phones, primary first, then oldest first.
+    pub async fn find_by_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Phone>, diesel::result::Error> {
+        phones::table
+            .filter(phones::user_uuid.eq(user_uuid))
+            .order((phones::is_primary.desc(), phones::created_at.asc(), phones::phone_uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .select(Phone::as_select())
+            .load(conn).await
+    }
+
+    pub async fn count_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<i64, diesel::result::Error> {
+        phones::table
+            .filter(phones::user_uuid.eq(user_uuid))
+            .count()
+            .get_result(conn).await
+    }
+
+    pub async fn update_type(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+        type_: String,
+    ) -> Result<Phone, diesel::result::Error> {
+        diesel::update(phones::table)
+            .filter(phones::phone_uuid.eq(phone_uuid))
+            .set((
+                phones::type_.eq(Some(type_)),
+                phones::updated_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(Phone::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Changes the status of a phone, keeping `is_verified` and the
+    /// `verified_at`/`bounced_at` timestamps in sync with it.
+    pub async fn update_status(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+        status: PhoneStatusEnum,
+    ) -> Result<Phone, diesel::result::Error> {
+        let target = phones::table.filter(phones::phone_uuid.eq(phone_uuid));
+
+        match status {
+            PhoneStatusEnum::Verified => diesel::update(target)
+                .set((
+                    phones::status.eq(status),
+                    phones::is_verified.eq(true),
+                    phones::verified_at.eq(diesel::dsl::now.nullable()),
+                    phones::updated_at.eq(Some(chrono::Utc::now())),
+                ))
+                .returning(Phone::as_returning())
+                .get_result(conn).await,
+            PhoneStatusEnum::Unverified => diesel::update(target)
+                .set((
+                    phones::status.eq(status),
+                    phones::is_verified.eq(false),
+                    phones::verified_at.eq(None::<chrono::NaiveDateTime>),
+                    phones::updated_at.eq(Some(chrono::Utc::now())),
+                ))
+                .returning(Phone::as_returning())
+                .get_result(conn).await,
+            PhoneStatusEnum::Bounced => diesel::update(target)
+                .set((
+                    phones::status.eq(status),
+                    phones::bounced_at.eq(diesel::dsl::now.nullable()),
+                    phones::updated_at.eq(Some(chrono::Utc::now())),
+                ))
+                .returning(Phone::as_returning())
+                .get_result(conn).await,
+            PhoneStatusEnum::Spam | PhoneStatusEnum::Blocked => diesel::update(target)
+                .set((
+                    phones::status.eq(status),
+                    phones::updated_at.eq(Some(chrono::Utc::now())),
+                ))
+                .returning(Phone::as_returning())
+                .get_result(conn).await,
+        }
+    }
+
+    /// Makes a phone its user's primary phone, demoting the previous one.
+    pub async fn set_primary(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+    ) -> Result<Phone, diesel::result::Error> {
+        conn.transaction(|conn| async move {
+            let phone = Phone::find_by_uuid(conn, phone_uuid).await?;
+
+            diesel::update(phones::table)
+                .filter(phones::user_uuid.eq(phone.user_uuid))
+                .filter(phones::is_primary.eq(true))
+                .filter(phones::phone_uuid.ne(phone_uuid))
+                .set(phones::is_primary.eq(false))
+                .execute(conn).await?;
+
+            diesel::update(phones::table)
+                .filter(phones::phone_uuid.eq(phone_uuid))
+                .set(phones::is_primary.eq(true))
+                .returning(Phone::as_returning())
+                .get_result(conn).await
+        }.scope_boxed()).await
+    }
+
+    pub async fn delete(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+    ) -> Result<Phone, diesel::result::Error> {
+        diesel::delete(phones::table)
+            .filter(phones::phone_uuid.eq(phone_uuid))
+            .returning(Phone::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(phones::table)
+            .filter(phones::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = phone_verification_codes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PhoneVerificationCode {
+    pub code_uuid: Uuid,
+    pub phone_uuid: Uuid,
+    pub code_hash: String,
+    pub attempts: i32,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub consumed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = phone_verification_codes)]
+pub struct NewPhoneVerificationCode {
+    pub code_uuid: Uuid,
+    pub phone_uuid: Uuid,
+    pub code_hash: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl PhoneVerificationCode {
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        let user_phones = phones::table
+            .filter(phones::user_uuid.eq(user_uuid))
+            .select(phones::phone_uuid);
+
+        diesel::delete(phone_verification_codes::table)
+            .filter(phone_verification_codes::phone_uuid.eq_any(user_phones))
+            .execute(conn).await
+    }
+
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_code: NewPhoneVerificationCode,
+    ) -> Result<PhoneVerificationCode, diesel::result::Error> {
+        diesel::insert_into(phone_verification_codes::table)
+            .values(new_code)
+            .returning(PhoneVerificationCode::as_returning())
+            .get_result(conn).await
     }
-}
\ No newline at end of file
+
+    /// Returns the most recently issued code of a phone that wasn't used yet.
+    pub async fn find_latest_active(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+    ) -> Result<Option<PhoneVerificationCode>, diesel::result::Error> {
+        phone_verification_codes::table
+            .filter(phone_verification_codes::phone_uuid.eq(phone_uuid))
+            .filter(phone_verification_codes::consumed_at.is_null())
+            .order(phone_verification_codes::created_at.desc())
+            .select(PhoneVerificationCode::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    /// Counts an attempt against an unused code, `None` once `max_attempts` were made.
+    pub async fn claim_attempt(
+        conn: &mut AsyncPgConnection,
+        code_uuid: Uuid,
+        max_attempts: i32,
+    ) -> Result<Option<PhoneVerificationCode>, diesel::result::Error> {
+        diesel::update(phone_verification_codes::table)
+            .filter(phone_verification_codes::code_uuid.eq(code_uuid))
+            .filter(phone_verification_codes::consumed_at.is_null())
+            .filter(phone_verification_codes::attempts.lt(max_attempts))
+            .set(phone_verification_codes::attempts.eq(phone_verification_codes::attempts + 1))
+            .returning(PhoneVerificationCode::as_returning())
+            .get_result(conn).await
+            .optional()
+    }
+
+    /// Marks every outstanding code of a phone as used.
+    pub async fn consume_all_for_phone(
+        conn: &mut AsyncPgConnection,
+        phone_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(phone_verification_codes::table)
+            .filter(phone_verification_codes::phone_uuid.eq(phone_uuid))
+            .filter(phone_verification_codes::consumed_at.is_null())
+            .set(phone_verification_codes::consumed_at.eq(Some(chrono::Utc::now())))
+            .execute(conn).await
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sms_sends)]
+pub struct NewSmsSend {
+    pub phone_number: String,
+    pub ip_address: Option<IpNet>,
+    pub purpose: String,
+}
+
+/// Log of sent text messages, used for rate limiting.
+pub struct SmsSend;
+
+impl SmsSend {
+    pub async fn record(
+        conn: &mut AsyncPgConnection,
+        new_send: NewSmsSend,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(sms_sends::table)
+            .values(new_send)
+            .execute(conn).await
+    }
+
+    /// Deletes the log of messages sent to any number of a user.
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        let numbers = phones::table
+            .filter(phones::user_uuid.eq(user_uuid))
+            .filter(phones::full_number.is_not_null())
+            .select(phones::full_number.assume_not_null());
+
+        diesel::delete(sms_sends::table)
+            .filter(sms_sends::phone_number.eq_any(numbers))
+            .execute(conn).await
+    }
+
+    /// Times of the messages sent to a number since `since`, newest first.
+    pub async fn find_sent_to_number_since(
+        conn: &mut AsyncPgConnection,
+        phone_number: &str,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<chrono::DateTime<chrono::Utc>>, diesel::result::Error> {
+        sms_sends::table
+            .filter(sms_sends::phone_number.eq(phone_number))
+            .filter(sms_sends::created_at.ge(since))
+            .order(sms_sends::created_at.desc())
+            .select(sms_sends::created_at)
+            .load(conn).await
+    }
+
+    /// Times of the messages requested from an IP since `since`, newest first.
+    pub async fn find_sent_for_ip_since(
+        conn: &mut AsyncPgConnection,
+        ip_address: IpNet,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<chrono::DateTime<chrono::Utc>>, diesel::result::Error> {
+        sms_sends::table
+            .filter(sms_sends::ip_address.eq(ip_address))
+            .filter(sms_sends::created_at.ge(since))
+            .order(sms_sends::created_at.desc())
+            .select(sms_sends::created_at)
+            .load(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = roles)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Role {
+    pub role_uuid: Uuid,
+    pub role_name: String,
+    pub description: Option<String>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_roles)]
+pub struct NewUserRole {
+    pub user_uuid: Uuid,
+    pub role_uuid: Uuid,
+}
+
+impl Role {
+    pub async fn list(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<Role>, diesel::result::Error> {
+        roles::table
+            .order(roles::role_name.asc())
+            .select(Role::as_select())
+            .load(conn).await
+    }
+
+    pub async fn find_by_names(
+        conn: &mut AsyncPgConnection,
+        role_names: &[String],
+    ) -> Result<Vec<Role>, diesel::result::Error> {
+        roles::table
+            .filter(roles::role_name.eq_any(role_names))
+            .select(Role::as_select())
+            .load(conn).await
+    }
+
+    pub async fn assign_to_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        role_uuids: &[Uuid],
+    ) -> Result<usize, diesel::result::Error> {
+        let new_user_roles: Vec<NewUserRole> = role_uuids
+            .iter()
+            .map(|role_uuid| NewUserRole { user_uuid, role_uuid: *role_uuid })
+            .collect();
+
+        diesel::insert_into(user_roles::table)
+            .values(new_user_roles)
+            .on_conflict_do_nothing()
+            .execute(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::AttributeTypeEnum"]
+pub enum AttributeTypeEnum {
+    String,
+    Integer,
+    Number,
+    Boolean
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::AttributeVisibilityEnum"]
+pub enum AttributeVisibilityEnum {
+    User,
+    Admin
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = user_attribute_definitions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AttributeDefinition {
+    pub attribute_uuid: Uuid,
+    pub key: String,
+    pub attribute_type: AttributeTypeEnum,
+    pub description: Option<String>,
+    pub is_required: bool,
+    pub is_unique: bool,
+    pub is_indexed: bool,
+    pub visibility: AttributeVisibilityEnum,
+    pub enum_values: Option<Value>,
+    pub pattern: Option<String>,
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_attribute_definitions)]
+pub struct NewAttributeDefinition {
+    pub key: String,
+    pub attribute_type: AttributeTypeEnum,
+    pub description: Option<String>,
+    pub is_required: bool,
+    pub is_unique: bool,
+    pub is_indexed: bool,
+    pub visibility: AttributeVisibilityEnum,
+    pub enum_values: Option<Value>,
+    pub pattern: Option<String>,
+}
+
+impl AttributeDefinition {
+    /// Creates the definition, replacing any existing definition with the same key.
+    pub async fn upsert(
+        conn: &mut AsyncPgConnection,
+        definition: NewAttributeDefinition,
+    ) -> Result<AttributeDefinition, diesel::result::Error> {
+        diesel::insert_into(user_attribute_definitions::table)
+            .values(&definition)
+            .on_conflict(user_attribute_definitions::key)
+            .do_update()
+            .set((
+                user_attribute_definitions::attribute_type.eq(definition.attribute_type.clone()),
+                user_attribute_definitions::description.eq(definition.description.clone()),
+                user_attribute_definitions::is_required.eq(definition.is_required),
+                user_attribute_definitions::is_unique.eq(definition.is_unique),
+                user_attribute_definitions::is_indexed.eq(definition.is_indexed),
+                user_attribute_definitions::visibility.eq(definition.visibility.clone()),
+                user_attribute_definitions::enum_values.eq(definition.enum_values.clone()),
+                user_attribute_definitions::pattern.eq(definition.pattern.clone()),
+            ))
+            .returning(AttributeDefinition::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn list(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<AttributeDefinition>, diesel::result::Error> {
+        user_attribute_definitions::table
+            .order(user_attribute_definitions::key.asc())
+            .select(AttributeDefinition::as_select())
+            .load(conn).await
+    }
+
+    pub async fn find_by_key(
+        conn: &mut AsyncPgConnection,
+        key: &str,
+    ) -> Result<AttributeDefinition, diesel::result::Error> {
+        user_attribute_definitions::table
+            .filter(user_attribute_definitions::key.eq(key))
+            .select(AttributeDefinition::as_select())
+            .first(conn).await
+    }
+
+    pub async fn delete_by_key(
+        conn: &mut AsyncPgConnection,
+        key: &str,
+    ) -> Result<bool, diesel::result::Error> {
+        let deleted = diesel::delete(user_attribute_definitions::table)
+            .filter(user_attribute_definitions::key.eq(key))
+            .execute(conn).await?;
+        Ok(deleted > 0)
+    }
+}
+
+pub struct Membership;
+
+impl Membership {
+    /// Clears the free-form metadata of every membership of the user.
+    pub async fn anonymize_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(membership::table)
+            .filter(membership::user_uuid.eq(user_uuid))
+            .set(membership::metadata.eq(None::<Value>))
+            .execute(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = audit_events)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEvent {
+    pub event_uuid: Uuid,
+    pub user_uuid: Option<Uuid>,
+    pub event_type: String,
+    pub ip_address: Option<IpNet>,
+    pub metadata: Value,
+    pub created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = audit_events)]
+pub struct NewAuditEvent {
+    pub user_uuid: Option<Uuid>,
+    pub event_type: String,
+    pub ip_address: Option<IpNet>,
+    pub metadata: Value,
+}
+
+impl AuditEvent {
+    pub async fn record(
+        conn: &mut AsyncPgConnection,
+        new_event: NewAuditEvent,
+    ) -> Result<AuditEvent, diesel::result::Error> {
+        diesel::insert_into(audit_events::table)
+            .values(new_event)
+            .returning(AuditEvent::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn anonymize_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(audit_events::table)
+            .filter(audit_events::user_uuid.eq(user_uuid))
+            .set((
+                audit_events::ip_address.eq(None::<IpNet>),
+                audit_events::metadata.eq(serde_json::json!({})),
+            ))
+            .execute(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::UserDataJobTypeEnum"]
+pub enum UserDataJobTypeEnum {
+    Export,
+    Erasure
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::UserDataJobStatusEnum"]
+pub enum UserDataJobStatusEnum {
+    Pending,
+    Running,
+    Completed,
+    Failed
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = user_data_jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserDataJob {
+    pub job_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub job_type: UserDataJobTypeEnum,
+    pub status: UserDataJobStatusEnum,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub completed_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = user_data_jobs)]
+pub struct NewUserDataJob {
+    pub user_uuid: Uuid,
+    pub job_type: UserDataJobTypeEnum,
+}
+
+impl UserDataJob {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_job: NewUserDataJob,
+    ) -> Result<UserDataJob, diesel::result::Error> {
+        diesel::insert_into(user_data_jobs::table)
+            .values(new_job)
+            .returning(UserDataJob::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
+        job_uuid: Uuid,
+    ) -> Result<UserDataJob, diesel::result::Error> {
+        user_data_jobs::table
+            .filter(user_data_jobs::job_uuid.eq(job_uuid))
+            .select(UserDataJob::as_select())
+            .first(conn).await
+    }
+
+    /// Jobs that were queued or interrupted, e.g. by a restart.
+    pub async fn find_unfinished(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<UserDataJob>, diesel::result::Error> {
+        user_data_jobs::table
+            .filter(user_data_jobs::status.eq_any(vec![UserDataJobStatusEnum::Pending, UserDataJobStatusEnum::Running]))
+            .order(user_data_jobs::created_at.asc())
+            .select(UserDataJob::as_select())
+            .load(conn).await
+    }
+
+    pub async fn mark_running(
+        conn: &mut AsyncPgConnection,
+        job_uuid: Uuid,
+    ) -> Result<UserDataJob, diesel::result::Error> {
+        diesel::update(user_data_jobs::table)
+            .filter(user_data_jobs::job_uuid.eq(job_uuid))
+            .set((
+                user_data_jobs::status.eq(UserDataJobStatusEnum::Running),
+                user_data_jobs::started_at.eq(diesel::dsl::now),
+            ))
+            .returning(UserDataJob::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_completed(
+        conn: &mut AsyncPgConnection,
+        job_uuid: Uuid,
+        result: Value,
+    ) -> Result<UserDataJob, diesel::result::Error> {
+        diesel::update(user_data_jobs::table)
+            .filter(user_data_jobs::job_uuid.eq(job_uuid))
+            .set((
+                user_data_jobs::status.eq(UserDataJobStatusEnum::Completed),
+                user_data_jobs::result.eq(Some(result)),
+                user_data_jobs::completed_at.eq(diesel::dsl::now),
+            ))
+            .returning(UserDataJob::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn mark_failed(
+        conn: &mut AsyncPgConnection,
+        job_uuid: Uuid,
+        error: String,
+    ) -> Result<UserDataJob, diesel::result::Error> {
+        diesel::update(user_data_jobs::table)
+            .filter(user_data_jobs::job_uuid.eq(job_uuid))
+            .set((
+                user_data_jobs::status.eq(UserDataJobStatusEnum::Failed),
+                user_data_jobs::error.eq(Some(error)),
+                user_data_jobs::completed_at.eq(diesel::dsl::now),
+            ))
+            .returning(UserDataJob::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Drops the archives kept by the export jobs of a user.
+    pub async fn clear_export_results(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(user_data_jobs::table)
+            .filter(user_data_jobs::user_uuid.eq(user_uuid))
+            .filter(user_data_jobs::job_type.eq(UserDataJobTypeEnum::Export))
+            .filter(user_data_jobs::result.is_not_null())
+            .set(user_data_jobs::result.eq(None::<Value>))
+            .execute(conn).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq, diesel_derive_enum::DbEnum)]
+#[ExistingTypePath = "crate::schema::sql_types::MfaChallengeStatusEnum"]
+pub enum MfaChallengeStatusEnum {
+    Pending,
+    Approved,
+    Denied,
+    Expired,
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = mfa_challenges)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct MfaChallenge {
+    pub challenge_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub ip_address: IpNet,
+    pub factor: String,
+    pub code_hash: Option<String>,
+    pub attempts: i32,
+    pub status: MfaChallengeStatusEnum,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub completed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub responder_device_uuid: Option<Uuid>,
+    pub consumed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = mfa_challenges)]
+pub struct NewMfaChallenge {
+    pub challenge_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub ip_address: IpNet,
+    pub factor: String,
+    pub code_hash: Option<String>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl MfaChallenge {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_challenge: NewMfaChallenge,
+    ) -> Result<MfaChallenge, diesel::result::Error> {
+        diesel::insert_into(mfa_challenges::table)
+            .values(new_challenge)
+            .returning(MfaChallenge::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(mfa_challenges::table)
+            .filter(mfa_challenges::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+
+    pub async fn find_by_uuid(
+        conn: &mut AsyncPgConnection,
+        challenge_uuid: Uuid,
+    ) -> Result<MfaChallenge, diesel::result::Error> {
+        mfa_challenges::table
+            .filter(mfa_challenges::challenge_uuid.eq(challenge_uuid))
+            .select(MfaChallenge::as_select())
+            .first(conn).await
+    }
+
+    /// Counts an attempt against a pending challenge, `None` once it was
+    /// settled or `max_attempts` were made.
+    pub async fn claim_attempt(
+        conn: &mut AsyncPgConnection,
+        challenge_uuid: Uuid,
+        max_attempts: i32,
+    ) -> Result<Option<MfaChallenge>, diesel::result::Error> {
+        diesel::update(mfa_challenges::table)
+            .filter(mfa_challenges::challenge_uuid.eq(challenge_uuid))
+            .filter(mfa_challenges::status.eq(MfaChallengeStatusEnum::Pending))
+            .filter(mfa_challenges::attempts.lt(max_attempts))
+            .set(mfa_challenges::attempts.eq(mfa_challenges::attempts + 1))
+            .returning(MfaChallenge::as_returning())
+            .get_result(conn).await
+            .optional()
+    }
+
+    /// Settles a pending challenge, `NotFound` if it was already settled.
+    pub async fn complete(
+        conn: &mut AsyncPgConnection,
+        challenge_uuid: Uuid,
+        status: MfaChallengeStatusEnum,
+    ) -> Result<MfaChallenge, diesel::result::Error> {
+        diesel::update(mfa_challenges::table)
+            .filter(mfa_challenges::challenge_uuid.eq(challenge_uuid))
+            .filter(mfa_challenges::status.eq(MfaChallengeStatusEnum::Pending))
+            .set((
+                mfa_challenges::status.eq(status),
+                mfa_challenges::completed_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(MfaChallenge::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Settles a pending push challenge with the answer of another device,
+    /// `NotFound` if it was already settled.
+    pub async fn respond(
+        conn: &mut AsyncPgConnection,
+        challenge_uuid: Uuid,
+        status: MfaChallengeStatusEnum,
+        responder_device_uuid: Uuid,
+    ) -> Result<MfaChallenge, diesel::result::Error> {
+        diesel::update(mfa_challenges::table)
+            .filter(mfa_challenges::challenge_uuid.eq(challenge_uuid))
+            .filter(mfa_challenges::status.eq(MfaChallengeStatusEnum::Pending))
+            .set((
+                mfa_challenges::status.eq(status),
+                mfa_challenges::responder_device_uuid.eq(Some(responder_device_uuid)),
+                mfa_challenges::completed_at.eq(Some(chrono::Utc::now())),
+            ))
+            .returning(MfaChallenge::as_returning())
+            .get_result(conn).await
+    }
+
+    /// Marks an approved challenge as used by its sign-in, `NotFound` if it
+    /// isn't approved or was already used.
+    pub async fn consume(
+        conn: &mut AsyncPgConnection,
+        challenge_uuid: Uuid,
+    ) -> Result<MfaChallenge, diesel::result::Error> {
+        diesel::update(mfa_challenges::table)
+            .filter(mfa_challenges::challenge_uuid.eq(challenge_uuid))
+            .filter(mfa_challenges::status.eq(MfaChallengeStatusEnum::Approved))
+            .filter(mfa_challenges::consumed_at.is_null())
+            .set(mfa_challenges::consumed_at.eq(Some(chrono::Utc::now())))
+            .returning(MfaChallenge::as_returning())
+            .get_result(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = trusted_devices)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct TrustedDevice {
+    pub trust_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = trusted_devices)]
+pub struct NewTrustedDevice {
+    pub trust_uuid: Uuid,
+    pub user_uuid: Uuid,
+    pub device_uuid: Uuid,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl TrustedDevice {
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_trust: NewTrustedDevice,
+    ) -> Result<TrustedDevice, diesel::result::Error> {
+        diesel::insert_into(trusted_devices::table)
+            .values(new_trust)
+            .returning(TrustedDevice::as_returning())
+            .get_result(conn).await
+    }
+
+    pub async fn delete_for_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(trusted_devices::table)
+            .filter(trusted_devices::user_uuid.eq(user_uuid))
+            .execute(conn).await
+    }
+
+    /// Finds a trust that is neither revoked nor expired.
+    pub async fn find_active(
+        conn: &mut AsyncPgConnection,
+        trust_uuid: Uuid,
+    ) -> Result<Option<TrustedDevice>, diesel::result::Error> {
+        trusted_devices::table
+            .filter(trusted_devices::trust_uuid.eq(trust_uuid))
+            .filter(trusted_devices::revoked_at.is_null())
+            .filter(trusted_devices::expires_at.gt(chrono::Utc::now()))
+            .select(TrustedDevice::as_select())
+            .first(conn).await
+            .optional()
+    }
+
+    /// Active trusts of a user with the device each one is bound to, newest first.
+    pub async fn find_active_by_user(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<(TrustedDevice, Device)>, diesel::result::Error> {
+        trusted_devices::table
+            .inner_join(devices::table)
+            .filter(trusted_devices::user_uuid.eq(user_uuid))
+            .filter(trusted_devices::revoked_at.is_null())
+            .filter(trusted_devices::expires_at.gt(chrono::Utc::now()))
+            .order((trusted_devices::created_at.desc(), trusted_devices::trust_uuid.asc()))
+            .limit(limit)
+            .offset(offset)
+            .select((TrustedDevice::as_select(), Device::as_select()))
+            .load(conn).await
+    }
+
+    pub async fn touch(
+        conn: &mut AsyncPgConnection,
+        trust_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(trusted_devices::table)
+            .filter(trusted_devices::trust_uuid.eq(trust_uuid))
+            .set(trusted_devices::last_used_at.eq(Some(chrono::Utc::now())))
+            .execute(conn).await
+    }
+
+    /// Revokes the trust a user gave to a device.
+    pub async fn revoke_for_user_device(
+        conn: &mut AsyncPgConnection,
+        user_uuid: Uuid,
+        device_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(trusted_devices::table)
+            .filter(trusted_devices::user_uuid.eq(user_uuid))
+            .filter(trusted_devices::device_uuid.eq(device_uuid))
+            .filter(trusted_devices::revoked_at.is_null())
+            .set(trusted_devices::revoked_at.eq(Some(chrono::Utc::now())))
+            .execute(conn).await
+    }
+
+    /// Revokes every trust given to a device, whoever gave it.
+    pub async fn revoke_all_for_device(
+        conn: &mut AsyncPgConnection,
+        device_uuid: Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::update(trusted_devices::table)
+            .filter(trusted_devices::device_uuid.eq(device_uuid))
+            .filter(trusted_devices::revoked_at.is_null())
+            .set(trusted_devices::revoked_at.eq(Some(chrono::Utc::now())))
+            .execute(conn).await
+    }
+}
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = revoked_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RevokedToken {
+    pub jti: Uuid,
+    pub user_uuid: Uuid,
+    pub session_uuid: Option<Uuid>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = revoked_tokens)]
+pub struct NewRevokedToken {
+    pub jti: Uuid,
+    pub user_uuid: Uuid,
+    pub session_uuid: Option<Uuid>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl RevokedToken {
+    /// Revokes a token, revoking it again changes nothing.
+    pub async fn create(
+        conn: &mut AsyncPgConnection,
+        new_token: NewRevokedToken,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::insert_into(revoked_tokens::table)
+            .values(new_token)
+            .on_conflict(revoked_tokens::jti)
+            .do_nothing()
+            .execute(conn).await
+    }
+
+    pub async fn is_revoked(
+        conn: &mut AsyncPgConnection,
+        jti: Uuid,
+    ) -> Result<bool, diesel::result::Error> {
+        diesel::select(diesel::dsl::exists(
+            revoked_tokens::table.filter(revoked_tokens::jti.eq(jti))
+        ))
+        .get_result(conn).await
+    }
+
+    /// Revoked tokens that haven't expired yet, i.e. the whole deny-list.
+    pub async fn find_unexpired(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<Vec<RevokedToken>, diesel::result::Error> {
+        revoked_tokens::table
+            .filter(revoked_tokens::expires_at.gt(chrono::Utc::now()))
+            .select(RevokedToken::as_select())
+            .load(conn).await
+    }
+
+    /// Forgets tokens that would be rejected as expired anyway.
+    pub async fn delete_expired(
+        conn: &mut AsyncPgConnection,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::delete(revoked_tokens::table.filter(revoked_tokens::expires_at.le(chrono::Utc::now())))
+            .execute(conn).await
+    }
+}
@@ -0,0 +1,300 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::result::Error as DieselError;
+use ipnet::IpNet;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::models;
+use crate::repository::{PhoneRepository, RepositoryError, RepositoryResult};
+use crate::sms::{OutgoingSms, SmsGateway};
+use crate::utils;
+use crate::verification::hash_secret;
+
+/// A code that was issued and the text carrying it, to be handed to the
+/// SMS provider.
+pub struct IssuedPhoneVerification {
+    pub sms: OutgoingSms,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum PhoneVerificationError {
+    PhoneNotFound,
+    AlreadyVerified,
+    /// The phone is bounced, flagged as spam or blocked.
+    NotVerifiable,
+    /// The SMS policy doesn't allow sending to the number's country.
+    CountryNotAllowed(String),
+    Throttled { retry_at: DateTime<Utc> },
+    /// The code is wrong, expired, used or burned.
+    Invalid,
+    /// The text message carrying the code couldn't be rendered.
+    Template(String),
+    Database(RepositoryError),
+}
+
+impl From<RepositoryError> for PhoneVerificationError {
+    fn from(error: RepositoryError) -> Self {
+        PhoneVerificationError::Database(error)
+    }
+}
+
+impl std::fmt::Display for PhoneVerificationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PhoneVerificationError::PhoneNotFound => write!(f, "Phone not found"),
+            PhoneVerificationError::AlreadyVerified => write!(f, "Phone is already verified"),
+            PhoneVerificationError::NotVerifiable => write!(f, "Phone can't be verified in its current status"),
+            PhoneVerificationError::CountryNotAllowed(reason) => write!(f, "{}", reason),
+            PhoneVerificationError::Throttled { retry_at } => write!(f, "Too many verification requests, retry after {}", retry_at.to_rfc3339()),
+            PhoneVerificationError::Invalid => write!(f, "Invalid or expired verification code"),
+            PhoneVerificationError::Template(e) => write!(f, "Error rendering text message: {}", e),
+            PhoneVerificationError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+async fn find_phone(phones: &dyn PhoneRepository, phone_uuid: Uuid) -> Result<models::Phone, PhoneVerificationError> {
+    let phone = phones.find(phone_uuid).await.map_err(|e| match e {
+        RepositoryError::Database(DieselError::NotFound) => PhoneVerificationError::PhoneNotFound,
+        e => PhoneVerificationError::Database(e),
+    })?;
+
+    match phone.status {
+        models::PhoneStatusEnum::Unverified => Ok(phone),
+        models::PhoneStatusEnum::Verified => Err(PhoneVerificationError::AlreadyVerified),
+        _ => Err(PhoneVerificationError::NotVerifiable),
+    }
+}
+
+/// The time the next message is allowed if one of the limits is hit.
+///
+/// `sent` are the send times within the last hour, newest first.
+fn retry_at(
+    sent: &[DateTime<Utc>],
+    resend_interval: Option<i64>,
+    max_per_hour: i64,
+) -> Option<DateTime<Utc>> {
+    if let (Some(latest), Some(resend_interval)) = (sent.first(), resend_interval) {
+        let resend_at = *latest + Duration::seconds(resend_interval);
+        if resend_at > Utc::now() {
+            return Some(resend_at);
+        }
+    }
+
+    if sent.len() as i64 >= max_per_hour {
+        return sent.last().map(|oldest| *oldest + Duration::hours(1));
+    }
+
+    None
+}
+
+/// Checks the per-number and per-IP SMS limits, returning when sending is
+/// allowed again if one is hit.
+pub(crate) async fn check_send_limits(
+    phones: &dyn PhoneRepository,
+    config: &Config,
+    phone_number: &str,
+    ip_address: Option<IpNet>,
+) -> RepositoryResult<Option<DateTime<Utc>>> {
+    let since = Utc::now() - Duration::hours(1);
+
+    let sent = phones.find_sms_sent_to_number_since(phone_number, since).await?;
+    if let Some(retry_at) = retry_at(&sent, Some(config.sms_resend_interval), config.sms_max_per_number_per_hour) {
+        return Ok(Some(retry_at));
+    }
+
+    if let Some(ip_address) = ip_address {
+        let sent = phones.find_sms_sent_for_ip_since(ip_address, since).await?;
+        return Ok(retry_at(&sent, None, config.sms_max_per_ip_per_hour));
+    }
+
+    Ok(None)
+}
+
+/// Issues a new code for a phone, superseding older ones, and renders the
+/// text carrying it.
+///
+/// Sending is limited per number (one message per resend interval and a
+/// fixed number per hour) and per requesting IP, and only to countries the
+/// SMS policy allows. The send is recorded here, so callers only have to
+/// hand `sms` to the gateway.
+pub async fn issue_phone_verification(
+    phones: &dyn PhoneRepository,
+    config: &Config,
+    gateway: &SmsGateway,
+    phone_uuid: Uuid,
+    ip_address: Option<IpNet>,
+    locale: &str,
+) -> Result<IssuedPhoneVerification, PhoneVerificationError> {
+    let phone = find_phone(phones, phone_uuid).await?;
+    let e164 = format!("{}{}", phone.country_code, phone.number);
+
+    let region = utils::normalize_full_phone(&e164).ok().and_then(|number| number.region);
+    gateway.policy.check(region.as_deref()).map_err(PhoneVerificationError::CountryNotAllowed)?;
+
+    if let Some(retry_at) = check_send_limits(phones, config, &e164, ip_address).await? {
+        return Err(PhoneVerificationError::Throttled { retry_at });
+    }
+
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let minutes = (config.phone_verification_code_ttl / 60).max(1).to_string();
+    let body = gateway.templates.render("verify_phone", locale, &[
+        ("code", code.as_str()),
+        ("minutes", minutes.as_str()),
+    ]).map_err(PhoneVerificationError::Template)?;
+
+    let code_uuid = Uuid::new_v4();
+    let created = phones.replace_verification_code(models::NewPhoneVerificationCode {
+        code_uuid,
+        phone_uuid,
+        code_hash: hash_secret(&code_uuid, &code),
+        expires_at: Utc::now() + Duration::seconds(config.phone_verification_code_ttl),
+    }, models::NewSmsSend {
+        phone_number: e164.clone(),
+        ip_address,
+        purpose: "verify_phone".to_string(),
+    }).await?;
+
+    Ok(IssuedPhoneVerification {
+        sms: OutgoingSms { to: e164, body },
+        expires_at: created.expires_at,
+    })
+}
+
+/// Verifies a phone with the code that was texted to it.
+///
+/// Every try counts as an attempt, once the limit is reached the code is
+/// burned and a new one has to be requested.
+pub async fn verify_phone_code(
+    phones: &dyn PhoneRepository,
+    config: &Config,
+    phone_uuid: Uuid,
+    code: &str,
+) -> Result<models::Phone, PhoneVerificationError> {
+    find_phone(phones, phone_uuid).await?;
+
+    let issued = phones.find_latest_verification_code(phone_uuid).await?
+        .ok_or(PhoneVerificationError::Invalid)?;
+
+    if issued.expires_at < Utc::now() {
+        return Err(PhoneVerificationError::Invalid);
+    }
+
+    // The attempt is counted before the code is compared, so parallel guesses
+    // can't all slip under the limit.
+    let issued = phones.claim_verification_attempt(issued.code_uuid, config.phone_verification_max_attempts).await?
+        .ok_or(PhoneVerificationError::Invalid)?;
+
+    if hash_secret(&issued.code_uuid, code.trim()) != issued.code_hash {
+        if issued.attempts >= config.phone_verification_max_attempts {
+            phones.burn_verification_codes(phone_uuid).await?;
+        }
+        return Err(PhoneVerificationError::Invalid);
+    }
+
+    Ok(phones.mark_verified(phone_uuid).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryRepository;
+    use crate::testing;
+
+    async fn issue(
+        repository: &MemoryRepository,
+        config: &Config,
+        gateway: &SmsGateway,
+        phone: &models::Phone,
+        ip_address: &str,
+    ) -> Result<IssuedPhoneVerification, PhoneVerificationError> {
+        let issued = issue_phone_verification(repository, config, gateway, phone.phone_uuid, Some(ip_address.parse().unwrap()), "en").await?;
+        gateway.send(issued.sms.clone()).await.unwrap();
+        Ok(issued)
+    }
+
+    #[tokio::test]
+    async fn codes_are_burned_after_too_many_wrong_guesses() {
+        let config = testing::config(&[("PHONE_VERIFICATION_MAX_ATTEMPTS", "2")]);
+        let repository = MemoryRepository::new();
+        let (gateway, sms) = testing::sms_gateway(&config);
+        let user = testing::create_user(&repository, "alice").await;
+        let phone = testing::create_phone(&repository, user.user_uuid, "+442071838750", false).await;
+
+        issue(&repository, &config, &gateway, &phone, "203.0.113.7/32").await.unwrap();
+        let code = testing::code_in(&sms.sent()[0].body);
+
+        for _ in 0..2 {
+            let error = verify_phone_code(&repository, &config, phone.phone_uuid, "000000").await.err().unwrap();
+            assert!(matches!(error, PhoneVerificationError::Invalid));
+        }
+
+        let error = verify_phone_code(&repository, &config, phone.phone_uuid, &code).await.err().unwrap();
+        assert!(matches!(error, PhoneVerificationError::Invalid));
+    }
+
+    #[tokio::test]
+    async fn texts_wait_for_the_resend_interval() {
+        let config = testing::config(&[]);
+        let repository = MemoryRepository::new();
+        let (gateway, sms) = testing::sms_gateway(&config);
+        let user = testing::create_user(&repository, "alice").await;
+        let phone = testing::create_phone(&repository, user.user_uuid, "+442071838750", false).await;
+
+        issue(&repository, &config, &gateway, &phone, "203.0.113.7/32").await.unwrap();
+
+        let error = issue(&repository, &config, &gateway, &phone, "203.0.113.7/32").await.err().unwrap();
+        let PhoneVerificationError::Throttled { retry_at } = error else {
+            panic!("expected the resend to be throttled, got {}", error);
+        };
+        assert!(retry_at > Utc::now() + Duration::seconds(50));
+        assert_eq!(sms.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn texts_are_limited_per_number_and_per_ip() {
+        let config = testing::config(&[
+            ("SMS_RESEND_INTERVAL", "0"),
+            ("SMS_MAX_PER_NUMBER_PER_HOUR", "2"),
+            ("SMS_MAX_PER_IP_PER_HOUR", "3"),
+        ]);
+        let repository = MemoryRepository::new();
+        let (gateway, sms) = testing::sms_gateway(&config);
+        let user = testing::create_user(&repository, "alice").await;
+        let first = testing::create_phone(&repository, user.user_uuid, "+442071838750", false).await;
+        let second = testing::create_phone(&repository, user.user_uuid, "+442071838751", false).await;
+
+        for _ in 0..2 {
+            issue(&repository, &config, &gateway, &first, "203.0.113.7/32").await.unwrap();
+        }
+        let error = issue(&repository, &config, &gateway, &first, "198.51.100.1/32").await.err().unwrap();
+        assert!(matches!(error, PhoneVerificationError::Throttled { .. }));
+
+        issue(&repository, &config, &gateway, &second, "203.0.113.7/32").await.unwrap();
+        let error = issue(&repository, &config, &gateway, &second, "203.0.113.7/32").await.err().unwrap();
+        assert!(matches!(error, PhoneVerificationError::Throttled { .. }));
+
+        // Another IP still gets through while the number is under its limit.
+        issue(&repository, &config, &gateway, &second, "198.51.100.1/32").await.unwrap();
+        assert_eq!(sms.sent().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn texts_are_only_sent_to_allowed_countries() {
+        let config = testing::config(&[("SMS_DENIED_COUNTRIES", "gb")]);
+        let repository = MemoryRepository::new();
+        let (gateway, sms) = testing::sms_gateway(&config);
+        let user = testing::create_user(&repository, "alice").await;
+        let phone = testing::create_phone(&repository, user.user_uuid, "+442071838750", false).await;
+
+        let error = issue(&repository, &config, &gateway, &phone, "203.0.113.7/32").await.err().unwrap();
+        assert!(matches!(error, PhoneVerificationError::CountryNotAllowed(_)));
+        assert!(sms.sent().is_empty());
+
+        // Nothing was recorded, so the number isn't throttled either.
+        let sent = repository.find_sms_sent_to_number_since("+442071838750", Utc::now() - Duration::hours(1)).await.unwrap();
+        assert!(sent.is_empty());
+    }
+}
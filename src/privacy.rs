@@ -0,0 +1,213 @@
+use chrono::Utc;
+use diesel::QueryableByName;
+use diesel::sql_types::{Jsonb, Uuid as SqlUuid};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::cache::Cache;
+use crate::db::DbPool;
+use crate::models;
+
+#[derive(QueryableByName)]
+struct JsonRow {
+    #[diesel(sql_type = Jsonb)]
+    row: Value,
+}
+
+/// Queries included in a data export, each selecting one JSON `row` per record
+/// tied to the user bound as `$1`.
+const EXPORT_QUERIES: &[(&str, &str)] = &[
+    ("user", "SELECT to_jsonb(u) - 'password_hash' AS row FROM users u WHERE u.user_uuid = $1"),
+    ("emails", "SELECT to_jsonb(e) AS row FROM emails e WHERE e.user_uuid = $1 ORDER BY e.created_at"),
+    ("phones", "SELECT to_jsonb(p) AS row FROM phones p WHERE p.user_uuid = $1 ORDER BY p.created_at"),
+    ("sessions", "SELECT to_jsonb(s) AS row FROM sessions s WHERE s.user_uuid = $1 ORDER BY s.created_at"),
+    ("devices", "SELECT to_jsonb(d) AS row FROM devices d WHERE d.user_uuid = $1 OR d.device_uuid IN (SELECT s.device_uuid FROM sessions s WHERE s.user_uuid = $1) ORDER BY d.created_at"),
+    ("memberships", "SELECT to_jsonb(m) AS row FROM membership m WHERE m.user_uuid = $1 ORDER BY m.joined_at"),
+    ("roles", "SELECT jsonb_build_object('role_uuid', r.role_uuid, 'role_name', r.role_name, 'assigned_at', ur.assigned_at) AS row FROM user_roles ur JOIN roles r ON r.role_uuid = ur.role_uuid WHERE ur.user_uuid = $1 ORDER BY ur.assigned_at"),
+    ("audit_events", "SELECT to_jsonb(a) AS row FROM audit_events a WHERE a.user_uuid = $1 ORDER BY a.created_at"),
+    ("email_verification_tokens", "SELECT to_jsonb(t) - 'code_hash' - 'token_hash' AS row FROM email_verification_tokens t WHERE t.email_uuid IN (SELECT e.email_uuid FROM emails e WHERE e.user_uuid = $1) ORDER BY t.created_at"),
+    ("email_suppressions", "SELECT to_jsonb(s) AS row FROM email_suppressions s WHERE s.normalized_value IN (SELECT e.normalized_value FROM emails e WHERE e.user_uuid = $1) ORDER BY s.created_at"),
+    ("email_change_requests", "SELECT to_jsonb(r) - 'cancel_token_hash' - 'revert_token_hash' AS row FROM email_change_requests r WHERE r.user_uuid = $1 ORDER BY r.created_at"),
+    // Bodies are left out, they carry verification codes and links.
+    ("email_deliveries", "SELECT to_jsonb(d) - 'text_body' - 'html_body' AS row FROM email_deliveries d WHERE d.recipient IN (SELECT e.value FROM emails e WHERE e.user_uuid = $1) ORDER BY d.created_at"),
+    ("phone_verification_codes", "SELECT to_jsonb(c) - 'code_hash' AS row FROM phone_verification_codes c WHERE c.phone_uuid IN (SELECT p.phone_uuid FROM phones p WHERE p.user_uuid = $1) ORDER BY c.created_at"),
+    ("sms_sends", "SELECT to_jsonb(s) AS row FROM sms_sends s WHERE s.phone_number IN (SELECT p.full_number FROM phones p WHERE p.user_uuid = $1) ORDER BY s.created_at"),
+    ("mfa_challenges", "SELECT to_jsonb(c) - 'code_hash' AS row FROM mfa_challenges c WHERE c.user_uuid = $1 ORDER BY c.created_at"),
+    ("trusted_devices", "SELECT to_jsonb(t) AS row FROM trusted_devices t WHERE t.user_uuid = $1 ORDER BY t.created_at"),
+    ("revoked_tokens", "SELECT to_jsonb(t) AS row FROM revoked_tokens t WHERE t.user_uuid = $1 ORDER BY t.revoked_at"),
+];
+
+/// Gathers everything tied to a user into a single machine readable archive.
+pub async fn export_user_data(
+    conn: &mut AsyncPgConnection,
+    user_uuid: Uuid,
+) -> Result<Value, diesel::result::Error> {
+    let mut archive = serde_json::Map::new();
+    archive.insert("format".to_string(), json!("ingot.user-export.v1"));
+    archive.insert("user_id".to_string(), json!(user_uuid));
+    archive.insert("exported_at".to_string(), json!(Utc::now().to_rfc3339()));
+
+    conn.transaction(|conn| async move {
+        for (name, query) in EXPORT_QUERIES {
+            let rows: Vec<Value> = diesel::sql_query(*query)
+                .bind::<SqlUuid, _>(user_uuid)
+                .load::<JsonRow>(conn).await?
+                .into_iter()
+                .map(|row| row.row)
+                .collect();
+
+            let value = match *name {
+                "user" => rows.into_iter().next().unwrap_or(Value::Null),
+                _ => Value::Array(rows),
+            };
+
+            archive.insert(name.to_string(), value);
+        }
+
+        models::AuditEvent::record(conn, models::NewAuditEvent {
+            user_uuid: Some(user_uuid),
+            event_type: "user.data_exported".to_string(),
+            ip_address: None,
+            metadata: json!({}),
+        }).await?;
+
+        Ok(Value::Object(archive))
+    }.scope_boxed()).await
+}
+
+/// Anonymizes or removes every piece of personal data tied to a user.
+///
+/// The user row is kept (with its PII replaced) so memberships, roles, jobs and
+/// audit events keep pointing at a valid user. Archives of earlier exports are
+/// dropped from their jobs. Returns the erasure receipt.
+///
+/// The user and their sessions are dropped from the cache once erased, the
+/// tokens they still hold stop working right away.
+pub async fn erase_user_data(
+    conn: &mut AsyncPgConnection,
+    cache: &Cache,
+    user_uuid: Uuid,
+) -> Result<Value, diesel::result::Error> {
+    let (receipt, sessions_anonymized) = conn.transaction(|conn| async move {
+        let devices_anonymized = models::Device::anonymize_for_user(conn, user_uuid).await?;
+        let sessions_anonymized = models::Session::anonymize_for_user(conn, user_uuid).await?;
+        // Deliveries, suppressions and SMS logs are found through the user's
+        // addresses and numbers, so they go before the emails and phones.
+        // Suppressions would otherwise keep the addresses around.
+        let email_deliveries_deleted = models::EmailDelivery::delete_for_user(conn, user_uuid).await?;
+        let email_suppressions_deleted = models::EmailSuppression::delete_for_user(conn, user_uuid).await?;
+        let email_change_requests_deleted = models::EmailChangeRequest::delete_for_user(conn, user_uuid).await?;
+        let sms_sends_deleted = models::SmsSend::delete_for_user(conn, user_uuid).await?;
+        let phone_verification_codes_deleted = models::PhoneVerificationCode::delete_for_user(conn, user_uuid).await?;
+        let emails_deleted = models::Email::delete_for_user(conn, user_uuid).await?;
+        let phones_deleted = models::Phone::delete_for_user(conn, user_uuid).await?;
+        let mfa_challenges_deleted = models::MfaChallenge::delete_for_user(conn, user_uuid).await?;
+        let trusted_devices_deleted = models::TrustedDevice::delete_for_user(conn, user_uuid).await?;
+        let exports_cleared = models::UserDataJob::clear_export_results(conn, user_uuid).await?;
+        let memberships_anonymized = models::Membership::anonymize_for_user(conn, user_uuid).await?;
+        let audit_events_anonymized = models::AuditEvent::anonymize_for_user(conn, user_uuid).await?;
+        let user = models::User::anonymize(conn, user_uuid).await?;
+
+        let receipt = json!({
+            "format": "ingot.erasure-receipt.v1",
+            "user_id": user_uuid,
+            "erased_at": Utc::now().to_rfc3339(),
+            "username": user.username,
+            "emails_deleted": emails_deleted,
+            "phones_deleted": phones_deleted,
+            "email_deliveries_deleted": email_deliveries_deleted,
+            "email_suppressions_deleted": email_suppressions_deleted,
+            "email_change_requests_deleted": email_change_requests_deleted,
+            "sms_sends_deleted": sms_sends_deleted,
+            "phone_verification_codes_deleted": phone_verification_codes_deleted,
+            "mfa_challenges_deleted": mfa_challenges_deleted,
+            "trusted_devices_deleted": trusted_devices_deleted,
+            "exports_cleared": exports_cleared,
+            "sessions_anonymized": sessions_anonymized.len(),
+            "devices_anonymized": devices_anonymized,
+            "memberships_anonymized": memberships_anonymized,
+            "audit_events_anonymized": audit_events_anonymized,
+        });
+
+        models::AuditEvent::record(conn, models::NewAuditEvent {
+            user_uuid: Some(user_uuid),
+            event_type: "user.erased".to_string(),
+            ip_address: None,
+            metadata: receipt.clone(),
+        }).await?;
+
+        Ok::<_, diesel::result::Error>((receipt, sessions_anonymized))
+    }.scope_boxed()).await?;
+
+    cache.forget_sessions(&sessions_anonymized).await;
+    cache.forget_user(user_uuid).await;
+
+    Ok(receipt)
+}
+
+async fn run_job(conn: &mut AsyncPgConnection, cache: &Cache, job_uuid: Uuid) -> Result<(), diesel::result::Error> {
+    let job = models::UserDataJob::mark_running(conn, job_uuid).await?;
+
+    let outcome = match job.job_type {
+        models::UserDataJobTypeEnum::Export => export_user_data(conn, job.user_uuid).await,
+        models::UserDataJobTypeEnum::Erasure => erase_user_data(conn, cache, job.user_uuid).await,
+    };
+
+    match outcome {
+        Ok(result) => models::UserDataJob::mark_completed(conn, job_uuid, result).await?,
+        Err(e) => models::UserDataJob::mark_failed(conn, job_uuid, e.to_string()).await?,
+    };
+
+    Ok(())
+}
+
+/// Starts queued user data jobs.
+pub trait JobRunner: Send + Sync {
+    fn spawn(&self, job_uuid: Uuid);
+}
+
+/// Runs each job on its own task with a pooled connection.
+pub struct PoolJobRunner {
+    database: DbPool,
+    cache: Arc<Cache>,
+}
+
+impl PoolJobRunner {
+    pub fn new(database: DbPool, cache: Arc<Cache>) -> Self {
+        PoolJobRunner { database, cache }
+    }
+}
+
+impl JobRunner for PoolJobRunner {
+    fn spawn(&self, job_uuid: Uuid) {
+        spawn_job(self.database.clone(), self.cache.clone(), job_uuid);
+    }
+}
+
+/// Processes a queued job in the background.
+pub fn spawn_job(database: DbPool, cache: Arc<Cache>, job_uuid: Uuid) {
+    tokio::spawn(async move {
+        let outcome = match database.get().await {
+            Ok(mut conn) => run_job(&mut conn, &cache, job_uuid).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = outcome {
+            println!("Error running user data job {}: {}", job_uuid, e);
+        }
+    });
+}
+
+/// Re-queues jobs that never finished, e.g. because the server was restarted.
+pub async fn resume_unfinished_jobs(conn: &mut AsyncPgConnection, database: &DbPool, cache: &Arc<Cache>) -> Result<(), diesel::result::Error> {
+    let jobs = models::UserDataJob::find_unfinished(conn).await?;
+
+    for job in jobs {
+        spawn_job(database.clone(), cache.clone(), job.job_uuid);
+    }
+
+    Ok(())
+}
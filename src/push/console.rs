@@ -0,0 +1,11 @@
+use super::{PushError, PushNotification, PushSender};
+
+/// Prints notifications to stdout instead of sending them, for development.
+pub struct LogPushSender;
+
+impl PushSender for LogPushSender {
+    fn send(&self, notification: &PushNotification) -> Result<(), PushError> {
+        println!("Push to {}: {} - {} {}", notification.token, notification.title, notification.body, notification.data);
+        Ok(())
+    }
+}
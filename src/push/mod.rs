@@ -0,0 +1,77 @@
+mod console;
+mod webhook;
+
+use std::sync::Arc;
+
+pub use console::*;
+pub use webhook::*;
+
+use crate::config::Config;
+
+/// A notification for one app install.
+#[derive(Debug, Clone)]
+pub struct PushNotification {
+    /// Token the app registered with `RegisterPushToken`.
+    pub token: String,
+    pub title: String,
+    pub body: String,
+    /// Handed to the app as is, e.g. the challenge to answer.
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// The notification may go through later, e.g. the provider was unreachable.
+    Transient(String),
+    /// The app was uninstalled or the token rotated, it should be forgotten.
+    InvalidToken,
+    /// Retrying won't help, e.g. the payload was rejected.
+    Permanent(String),
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Transient(message) => write!(f, "Transient push error: {}", message),
+            PushError::InvalidToken => write!(f, "Push token is no longer valid"),
+            PushError::Permanent(message) => write!(f, "Permanent push error: {}", message),
+        }
+    }
+}
+
+/// A push notification provider (APNs, FCM, ...).
+///
+/// Senders are blocking, run them on the blocking thread pool.
+pub trait PushSender: Send + Sync {
+    fn send(&self, notification: &PushNotification) -> Result<(), PushError>;
+}
+
+/// Builds the provider selected by `PUSH_TRANSPORT`.
+pub fn push_sender_from_config(config: &Config) -> Result<Arc<dyn PushSender>, String> {
+    match config.push_transport.as_str() {
+        "webhook" => Ok(Arc::new(WebhookPushSender::new(config))),
+        "log" => Ok(Arc::new(LogPushSender)),
+        transport => Err(format!("Unsupported push transport '{}'", transport)),
+    }
+}
+
+pub struct PushGateway {
+    pub sender: Arc<dyn PushSender>,
+}
+
+impl PushGateway {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(PushGateway {
+            sender: push_sender_from_config(config)?,
+        })
+    }
+
+    /// Sends a notification on the blocking thread pool.
+    pub async fn send(&self, notification: PushNotification) -> Result<(), PushError> {
+        let sender = self.sender.clone();
+
+        tokio::task::spawn_blocking(move || sender.send(&notification))
+            .await
+            .map_err(|e| PushError::Transient(format!("Push sender panicked: {}", e)))?
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+use serde_json::json;
+
+use super::{PushError, PushNotification, PushSender};
+use crate::config::Config;
+
+/// POSTs every notification as JSON to `PUSH_WEBHOOK_URL`.
+///
+/// Like the SMS webhook, the receiving end relays to APNs or FCM, and a
+/// local stub can stand in for it. It answers 410 Gone for tokens the
+/// provider no longer knows.
+pub struct WebhookPushSender {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+}
+
+impl WebhookPushSender {
+    pub fn new(config: &Config) -> Self {
+        WebhookPushSender {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            url: config.push_webhook_url.clone(),
+            token: config.push_webhook_token.clone(),
+        }
+    }
+}
+
+impl PushSender for WebhookPushSender {
+    fn send(&self, notification: &PushNotification) -> Result<(), PushError> {
+        let mut request = self.agent.post(&self.url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        let payload = json!({
+            "to": notification.token,
+            "title": notification.title,
+            "body": notification.body,
+            "data": notification.data,
+        });
+
+        match request.send_json(payload) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(404 | 410, _)) => Err(PushError::InvalidToken),
+            // Rate limiting and server errors can go away, other client errors won't.
+            Err(ureq::Error::Status(status, response)) if status == 429 || status >= 500 => {
+                Err(PushError::Transient(format!("{} {}", status, response.status_text())))
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                Err(PushError::Permanent(format!("{} {}", status, response.status_text())))
+            }
+            Err(e) => Err(PushError::Transient(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,1936 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use ipnet::IpNet;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::bounces::{Feedback, FeedbackKind, FeedbackOutcome};
+use crate::import::{ImportContext, ImportRowResult, PreparedUser, RejectedUser, RowError};
+use crate::mailer::OutgoingEmail;
+use crate::models;
+use crate::session_policy::{self, LimitAction, SessionLimits};
+use crate::utils::{self, NormalizedEmail, NormalizedPhone};
+
+use super::{
+    DeviceRepository, EmailRepository, MfaRepository, PhoneRepository, RepositoryResult, SessionRepository,
+    UserRepository,
+};
+
+/// Every repository kept in the process, so services can be exercised
+/// without Postgres.
+///
+/// The unique indexes and the foreign keys of the schema are enforced and
+/// fail the same way Postgres does, deleting a user cascades to its emails,
+/// phones, devices, sessions and audit events.
+pub struct MemoryRepository {
+    tables: Mutex<MemoryTables>,
+}
+
+/// Cloned to roll back, which stands in for transactions.
+#[derive(Default, Clone)]
+struct MemoryTables {
+    users: HashMap<Uuid, MemoryUser>,
+    devices: HashMap<Uuid, models::Device>,
+    sessions: HashMap<Uuid, models::Session>,
+    /// Emails and phones are kept in insertion order, which stands in for
+    /// `created_at` when ordering them.
+    emails: Vec<models::Email>,
+    phones: Vec<models::Phone>,
+    trusted_devices: Vec<models::TrustedDevice>,
+    roles: Vec<models::Role>,
+    /// Roles a user has, there are no organizations.
+    user_roles: Vec<(Uuid, Uuid)>,
+    attribute_definitions: Vec<models::AttributeDefinition>,
+    user_data_jobs: Vec<models::UserDataJob>,
+    session_policies: Vec<models::SessionPolicy>,
+    revoked_tokens: HashMap<Uuid, models::RevokedToken>,
+    mfa_challenges: HashMap<Uuid, models::MfaChallenge>,
+    phone_verification_codes: Vec<models::PhoneVerificationCode>,
+    sms_sends: Vec<SmsSend>,
+    email_verification_tokens: Vec<models::EmailVerificationToken>,
+    email_change_requests: Vec<models::EmailChangeRequest>,
+    /// Normalized values of the suppressed addresses.
+    email_suppressions: Vec<String>,
+    /// Queued mail, which is never delivered.
+    email_deliveries: Vec<OutgoingEmail>,
+    audit_events: Vec<models::AuditEvent>,
+}
+
+#[derive(Clone)]
+struct SmsSend {
+    phone_number: String,
+    ip_address: Option<IpNet>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+struct MemoryUser {
+    user: models::User,
+    username_skeleton: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        MemoryRepository {
+            tables: Mutex::new(MemoryTables::default()),
+        }
+    }
+
+    fn tables(&self) -> MutexGuard<'_, MemoryTables> {
+        self.tables.lock().unwrap()
+    }
+
+    /// Runs `write` on the tables, leaving them untouched if it fails.
+    fn transaction<T>(&self, write: impl FnOnce(&mut MemoryTables) -> Result<T, DieselError>) -> RepositoryResult<T> {
+        let mut tables = self.tables();
+        let snapshot = tables.clone();
+
+        write(&mut tables).map_err(|e| {
+            *tables = snapshot;
+            e.into()
+        })
+    }
+
+    /// Adds a session as is, bypassing the concurrency limits `start` applies.
+    pub fn insert_session(&self, session: models::Session) -> RepositoryResult<()> {
+        let mut tables = self.tables();
+        tables.check_user(session.user_uuid, "sessions_user_uuid_fkey")?;
+        tables.sessions.insert(session.session_uuid, session);
+        Ok(())
+    }
+
+    /// Adds a role, which no repository manages yet.
+    pub fn insert_role(&self, role_name: &str) -> models::Role {
+        let now = Some(Utc::now());
+        let role = models::Role {
+            role_uuid: Uuid::new_v4(),
+            role_name: role_name.to_string(),
+            description: None,
+            created_at: now,
+            updated_at: now,
+        };
+
+        self.tables().roles.push(role.clone());
+        role
+    }
+
+    /// Gives a user a role, which no repository does outside registration.
+    pub fn assign_role(&self, user_uuid: Uuid, role_uuid: Uuid) -> RepositoryResult<()> {
+        let mut tables = self.tables();
+        tables.check_user(user_uuid, "user_roles_user_uuid_fkey")?;
+        tables.user_roles.push((user_uuid, role_uuid));
+        Ok(())
+    }
+
+    /// Adds a session policy, which no repository manages yet.
+    pub fn insert_session_policy(&self, new_policy: models::NewSessionPolicy) -> models::SessionPolicy {
+        let now = Utc::now();
+        let policy = models::SessionPolicy {
+            policy_uuid: Uuid::new_v4(),
+            role_uuid: new_policy.role_uuid,
+            org_uuid: new_policy.org_uuid,
+            idle_timeout: new_policy.idle_timeout,
+            sliding_lifetime: new_policy.sliding_lifetime,
+            absolute_lifetime: new_policy.absolute_lifetime,
+            created_at: now,
+            updated_at: now,
+            max_sessions: new_policy.max_sessions,
+            max_sessions_per_device_type: new_policy.max_sessions_per_device_type,
+            limit_action: new_policy.limit_action,
+        };
+
+        self.tables().session_policies.push(policy.clone());
+        policy
+    }
+
+    pub fn audit_events(&self) -> Vec<models::AuditEvent> {
+        self.tables().audit_events.clone()
+    }
+
+    /// The mail queued so far, oldest first.
+    pub fn queued_emails(&self) -> Vec<OutgoingEmail> {
+        self.tables().email_deliveries.clone()
+    }
+}
+
+impl Default for MemoryRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn unique_violation(constraint: &str) -> DieselError {
+    DieselError::DatabaseError(
+        DatabaseErrorKind::UniqueViolation,
+        Box::new(format!("duplicate key value violates unique constraint \"{}\"", constraint)),
+    )
+}
+
+fn foreign_key_violation(constraint: &str) -> DieselError {
+    DieselError::DatabaseError(
+        DatabaseErrorKind::ForeignKeyViolation,
+        Box::new(format!("insert or update violates foreign key constraint \"{}\"", constraint)),
+    )
+}
+
+impl MemoryTables {
+    fn check_user(&self, user_uuid: Uuid, constraint: &str) -> Result<(), DieselError> {
+        match self.users.contains_key(&user_uuid) {
+            true => Ok(()),
+            false => Err(foreign_key_violation(constraint)),
+        }
+    }
+
+    /// Usernames are unique ignoring case, as `users_username_lower_idx`.
+    fn check_username(&self, username: &str, exclude: Option<Uuid>) -> Result<(), DieselError> {
+        let lowered = username.to_lowercase();
+        let taken = self.users.values().any(|stored| {
+            Some(stored.user.user_uuid) != exclude && stored.user.username.to_lowercase() == lowered
+        });
+
+        match taken {
+            true => Err(unique_violation("users_username_lower_idx")),
+            false => Ok(()),
+        }
+    }
+
+    /// Look-alike usernames are refused, as `users_username_skeleton_idx`.
+    fn check_skeleton(&self, skeleton: &str, exclude: Option<Uuid>) -> Result<(), DieselError> {
+        let taken = self.users.values().any(|stored| {
+            Some(stored.user.user_uuid) != exclude && stored.username_skeleton.as_deref() == Some(skeleton)
+        });
+
+        match taken {
+            true => Err(unique_violation("users_username_skeleton_idx")),
+            false => Ok(()),
+        }
+    }
+
+    fn insert_user(&mut self, new_user: models::NewUser) -> Result<models::User, DieselError> {
+        self.check_username(&new_user.username, None)?;
+        self.check_skeleton(&new_user.username_skeleton, None)?;
+
+        let user = models::User {
+            user_uuid: Uuid::new_v4(),
+            username: new_user.username,
+            password_hash: new_user.password_hash,
+            is_verified: false,
+            onboarded: false,
+            status: models::UserStatusEnum::Active,
+            metadata: new_user.metadata,
+            email_update_required: false,
+            mfa_enabled: false,
+        };
+
+        self.users.insert(user.user_uuid, MemoryUser {
+            user: user.clone(),
+            username_skeleton: Some(new_user.username_skeleton),
+            created_at: Utc::now(),
+        });
+
+        Ok(user)
+    }
+
+    /// Adds an email, the user's first one is made primary.
+    fn insert_email(&mut self, user_uuid: Uuid, address: NormalizedEmail) -> Result<models::Email, DieselError> {
+        self.check_user(user_uuid, "emails_user_uuid_fkey")?;
+
+        if self.emails.iter().any(|email| email.value == address.value) {
+            return Err(unique_violation("emails_value_idx"));
+        }
+        if self.emails.iter().any(|email| email.normalized_value == address.normalized) {
+            return Err(unique_violation("emails_normalized_value_idx"));
+        }
+
+        let email = models::Email {
+            email_uuid: Uuid::new_v4(),
+            user_uuid,
+            value: address.value,
+            status: models::EmailStatusEnum::Unverified,
+            is_primary: !self.emails.iter().any(|email| email.user_uuid == user_uuid),
+            is_verified: false,
+            metadata: json!({}),
+            normalized_value: address.normalized,
+        };
+
+        self.emails.push(email.clone());
+        Ok(email)
+    }
+
+    /// Adds a phone, the user's first one is made primary.
+    fn insert_phone(&mut self, user_uuid: Uuid, number: NormalizedPhone) -> Result<models::Phone, DieselError> {
+        self.check_user(user_uuid, "phones_user_uuid_fkey")?;
+
+        // `full_number` is generated from the country code and the number.
+        let full_number = format!("{}{}", number.country_code, number.number);
+        if self.phones.iter().any(|phone| phone.full_number.as_deref() == Some(full_number.as_str())) {
+            return Err(unique_violation("phones_value_idx"));
+        }
+
+        let phone = models::Phone {
+            phone_uuid: Uuid::new_v4(),
+            user_uuid,
+            country_code: number.country_code,
+            number: number.number,
+            full_number: Some(full_number),
+            type_: Some(number.number_type.to_string()),
+            status: models::PhoneStatusEnum::Unverified,
+            is_primary: !self.phones.iter().any(|phone| phone.user_uuid == user_uuid),
+            is_verified: false,
+            metadata: json!({ "region": number.region }),
+        };
+
+        self.phones.push(phone.clone());
+        Ok(phone)
+    }
+
+    /// Assigns roles, skipping the ones the user already has.
+    fn assign_roles(&mut self, user_uuid: Uuid, role_uuids: &[Uuid]) -> Result<(), DieselError> {
+        self.check_user(user_uuid, "user_roles_user_uuid_fkey")?;
+
+        for role_uuid in role_uuids {
+            if !self.roles.iter().any(|role| role.role_uuid == *role_uuid) {
+                return Err(foreign_key_violation("user_roles_role_uuid_fkey"));
+            }
+            if !self.user_roles.contains(&(user_uuid, *role_uuid)) {
+                self.user_roles.push((user_uuid, *role_uuid));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn attribute_value_taken(&self, key: &str, value: &Value, exclude: Option<Uuid>) -> bool {
+        self.users.values().any(|stored| {
+            Some(stored.user.user_uuid) != exclude && stored.user.metadata.get(key) == Some(value)
+        })
+    }
+
+    /// Inserts an imported user the way `ImportContext::insert_user` does.
+    fn import_user(&mut self, definitions: &[models::AttributeDefinition], user: PreparedUser) -> Result<Uuid, RowError> {
+        let confusable = self.users.values()
+            .any(|stored| stored.username_skeleton.as_deref() == Some(user.username_skeleton.as_str()));
+        if confusable {
+            return Err(RowError::Invalid("Username is already taken".to_string()));
+        }
+
+        for definition in definitions.iter().filter(|definition| definition.is_unique) {
+            if let Some(value) = user.attributes.get(&definition.key) {
+                if self.attribute_value_taken(&definition.key, value, None) {
+                    return Err(RowError::Invalid(format!("Attribute '{}' is already in use", definition.key)));
+                }
+            }
+        }
+
+        let created = self.insert_user(models::NewUser {
+            username: user.username,
+            username_skeleton: user.username_skeleton,
+            password_hash: user.password_hash,
+            metadata: Value::Object(user.attributes),
+        })?;
+
+        for email in user.emails {
+            self.insert_email(created.user_uuid, email)?;
+        }
+        for phone in user.phones {
+            self.insert_phone(created.user_uuid, phone)?;
+        }
+        self.assign_roles(created.user_uuid, &user.role_uuids)?;
+
+        Ok(created.user_uuid)
+    }
+
+    fn user_mut(&mut self, user_uuid: Uuid) -> Result<&mut MemoryUser, DieselError> {
+        self.users.get_mut(&user_uuid).ok_or(DieselError::NotFound)
+    }
+
+    fn record_event(&mut self, user_uuid: Option<Uuid>, event_type: &str, metadata: Value) {
+        self.audit_events.push(models::AuditEvent {
+            event_uuid: Uuid::new_v4(),
+            user_uuid,
+            event_type: event_type.to_string(),
+            ip_address: None,
+            metadata,
+            created_at: Utc::now(),
+        });
+    }
+
+    /// Revokes the active sessions matching `filter`, returning them.
+    fn revoke_sessions(&mut self, filter: impl Fn(&models::Session) -> bool) -> Vec<Uuid> {
+        let now = Utc::now();
+        let mut revoked = vec![];
+
+        for session in self.sessions.values_mut() {
+            if session.revoked_at.is_none() && filter(session) {
+                session.is_active = false;
+                session.revoked_at = Some(now);
+                revoked.push(session.session_uuid);
+            }
+        }
+
+        revoked
+    }
+
+    fn with_device(&self, session: &models::Session) -> (models::Session, Option<models::Device>) {
+        let device = session.device_uuid.and_then(|device_uuid| self.devices.get(&device_uuid).cloned());
+        (session.clone(), device)
+    }
+
+    fn record_device_event(&mut self, device: &models::Device, event_type: &str, metadata: Value) {
+        let mut metadata = metadata;
+        metadata["device_id"] = json!(device.device_uuid);
+        self.record_event(device.user_uuid, event_type, metadata);
+    }
+
+    fn device_mut(&mut self, device_uuid: Uuid) -> Result<&mut models::Device, DieselError> {
+        self.devices.get_mut(&device_uuid).ok_or(DieselError::NotFound)
+    }
+
+    /// Revokes the active trusts matching `filter`, returning how many.
+    fn revoke_trusts(&mut self, filter: impl Fn(&models::TrustedDevice) -> bool) -> usize {
+        let now = Utc::now();
+        let mut revoked = 0;
+
+        for trust in self.trusted_devices.iter_mut() {
+            if trust.revoked_at.is_none() && filter(trust) {
+                trust.revoked_at = Some(now);
+                revoked += 1;
+            }
+        }
+
+        revoked
+    }
+
+    fn email_mut(&mut self, email_uuid: Uuid) -> Result<&mut models::Email, DieselError> {
+        self.emails.iter_mut().find(|email| email.email_uuid == email_uuid).ok_or(DieselError::NotFound)
+    }
+
+    fn phone_mut(&mut self, phone_uuid: Uuid) -> Result<&mut models::Phone, DieselError> {
+        self.phones.iter_mut().find(|phone| phone.phone_uuid == phone_uuid).ok_or(DieselError::NotFound)
+    }
+
+    fn record_challenge_event(&mut self, challenge: &models::MfaChallenge, event_type: &str) {
+        self.audit_events.push(models::AuditEvent {
+            event_uuid: Uuid::new_v4(),
+            user_uuid: Some(challenge.user_uuid),
+            event_type: event_type.to_string(),
+            ip_address: Some(challenge.ip_address),
+            metadata: json!({
+                "challenge_id": challenge.challenge_uuid,
+                "device_id": challenge.device_uuid,
+                "factor": challenge.factor,
+                "responder_device_id": challenge.responder_device_uuid,
+            }),
+            created_at: Utc::now(),
+        });
+    }
+
+    /// Pending challenges only, as the `status = 'pending'` filters.
+    fn pending_challenge_mut(&mut self, challenge_uuid: Uuid) -> Option<&mut models::MfaChallenge> {
+        self.mfa_challenges.get_mut(&challenge_uuid)
+            .filter(|challenge| challenge.status == models::MfaChallengeStatusEnum::Pending)
+    }
+
+    fn record_sms_send(&mut self, sms_send: models::NewSmsSend) {
+        self.sms_sends.push(SmsSend {
+            phone_number: sms_send.phone_number,
+            ip_address: sms_send.ip_address,
+            created_at: Utc::now(),
+        });
+    }
+
+    /// Times of the messages matching `filter` since `since`, newest first.
+    fn sms_sent_since(&self, since: DateTime<Utc>, filter: impl Fn(&SmsSend) -> bool) -> Vec<DateTime<Utc>> {
+        let mut sent: Vec<DateTime<Utc>> = self.sms_sends.iter()
+            .filter(|send| send.created_at >= since && filter(send))
+            .map(|send| send.created_at)
+            .collect();
+
+        sent.sort_by(|a, b| b.cmp(a));
+        sent
+    }
+
+    /// Deletes an email, its verifications go with it and the changes it
+    /// was part of lose it.
+    fn remove_email(&mut self, email_uuid: Uuid) -> Result<models::Email, DieselError> {
+        let index = self.emails.iter()
+            .position(|email| email.email_uuid == email_uuid)
+            .ok_or(DieselError::NotFound)?;
+
+        self.email_verification_tokens.retain(|token| token.email_uuid != email_uuid);
+        for request in self.email_change_requests.iter_mut() {
+            if request.old_email_uuid == Some(email_uuid) {
+                request.old_email_uuid = None;
+            }
+            if request.new_email_uuid == Some(email_uuid) {
+                request.new_email_uuid = None;
+            }
+        }
+
+        Ok(self.emails.remove(index))
+    }
+
+    fn set_email_status(&mut self, email_uuid: Uuid, status: models::EmailStatusEnum) -> Result<models::Email, DieselError> {
+        let email = self.email_mut(email_uuid)?;
+        match status {
+            models::EmailStatusEnum::Verified => email.is_verified = true,
+            models::EmailStatusEnum::Unverified => email.is_verified = false,
+            _ => {}
+        }
+        email.status = status;
+        Ok(email.clone())
+    }
+
+    fn set_primary_email(&mut self, email_uuid: Uuid) -> Result<models::Email, DieselError> {
+        let user_uuid = self.email_mut(email_uuid)?.user_uuid;
+
+        for email in self.emails.iter_mut().filter(|email| email.user_uuid == user_uuid) {
+            email.is_primary = email.email_uuid == email_uuid;
+        }
+
+        Ok(self.email_mut(email_uuid)?.clone())
+    }
+
+    fn consume_verifications(&mut self, email_uuid: Uuid) {
+        let now = Utc::now();
+
+        for token in self.email_verification_tokens.iter_mut() {
+            if token.email_uuid == email_uuid && token.consumed_at.is_none() {
+                token.consumed_at = Some(now);
+            }
+        }
+    }
+
+    fn change_mut(&mut self, request_uuid: Uuid) -> Result<&mut models::EmailChangeRequest, DieselError> {
+        self.email_change_requests.iter_mut()
+            .find(|request| request.request_uuid == request_uuid)
+            .ok_or(DieselError::NotFound)
+    }
+
+    fn record_change_event(&mut self, request: &models::EmailChangeRequest, event_type: &str, metadata: Value) {
+        let mut metadata = metadata;
+        metadata["request_id"] = json!(request.request_uuid);
+        self.record_event(Some(request.user_uuid), event_type, metadata);
+    }
+
+    fn consume_verification_codes(&mut self, phone_uuid: Uuid) {
+        let now = Utc::now();
+
+        for code in self.phone_verification_codes.iter_mut() {
+            if code.phone_uuid == phone_uuid && code.consumed_at.is_none() {
+                code.consumed_at = Some(now);
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl UserRepository for MemoryRepository {
+    async fn create(&self, new_user: models::NewUser) -> RepositoryResult<models::User> {
+        Ok(self.tables().insert_user(new_user)?)
+    }
+
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<models::User> {
+        let tables = self.tables();
+        let stored = tables.users.get(&user_uuid).ok_or(DieselError::NotFound)?;
+        Ok(stored.user.clone())
+    }
+
+    async fn find_by_username(&self, username: String) -> RepositoryResult<models::User> {
+        let normalized = utils::normalize_username(&username);
+
+        self.tables().users.values()
+            .find(|stored| stored.user.username.to_lowercase() == normalized)
+            .map(|stored| stored.user.clone())
+            .ok_or(DieselError::NotFound.into())
+    }
+
+    async fn find_confusable(&self, skeleton: &str, exclude: Option<Uuid>) -> RepositoryResult<Option<models::User>> {
+        Ok(self.tables().users.values()
+            .filter(|stored| Some(stored.user.user_uuid) != exclude)
+            .find(|stored| stored.username_skeleton.as_deref() == Some(skeleton))
+            .map(|stored| stored.user.clone()))
+    }
+
+    async fn update_password(&self, user_uuid: Uuid, password_hash: String) -> RepositoryResult<bool> {
+        match self.tables().users.get_mut(&user_uuid) {
+            Some(stored) => {
+                stored.user.password_hash = password_hash;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn update_username(&self, user_uuid: Uuid, username: String, skeleton: String) -> RepositoryResult<models::User> {
+        let mut tables = self.tables();
+        tables.user_mut(user_uuid)?;
+        tables.check_username(&username, Some(user_uuid))?;
+        tables.check_skeleton(&skeleton, Some(user_uuid))?;
+
+        let stored = tables.user_mut(user_uuid)?;
+        stored.user.username = username;
+        stored.username_skeleton = Some(skeleton);
+        Ok(stored.user.clone())
+    }
+
+    async fn set_mfa_enabled(&self, user_uuid: Uuid, enabled: bool) -> RepositoryResult<models::User> {
+        let mut tables = self.tables();
+        let stored = tables.user_mut(user_uuid)?;
+        stored.user.mfa_enabled = enabled;
+        Ok(stored.user.clone())
+    }
+
+    async fn delete(&self, user_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+
+        if tables.users.remove(&user_uuid).is_none() {
+            return Ok(false);
+        }
+
+        // Sessions only point at their device, they outlive it.
+        let email_uuids: Vec<Uuid> = tables.emails.iter()
+            .filter(|email| email.user_uuid == user_uuid)
+            .map(|email| email.email_uuid)
+            .collect();
+        for email_uuid in email_uuids {
+            tables.remove_email(email_uuid)?;
+        }
+        tables.email_change_requests.retain(|request| request.user_uuid != user_uuid);
+        tables.phones.retain(|phone| phone.user_uuid != user_uuid);
+        tables.devices.retain(|_, device| device.user_uuid != Some(user_uuid));
+        let MemoryTables { devices, trusted_devices, .. } = &mut *tables;
+        trusted_devices.retain(|trust| trust.user_uuid != user_uuid && devices.contains_key(&trust.device_uuid));
+        tables.sessions.retain(|_, session| session.user_uuid != user_uuid);
+        tables.user_roles.retain(|(role_user_uuid, _)| *role_user_uuid != user_uuid);
+        tables.user_data_jobs.retain(|job| job.user_uuid != user_uuid);
+        tables.revoked_tokens.retain(|_, token| token.user_uuid != user_uuid);
+        tables.mfa_challenges.retain(|_, challenge| challenge.user_uuid != user_uuid);
+        let MemoryTables { phones, phone_verification_codes, .. } = &mut *tables;
+        phone_verification_codes.retain(|code| phones.iter().any(|phone| phone.phone_uuid == code.phone_uuid));
+        tables.audit_events.retain(|event| event.user_uuid != Some(user_uuid));
+
+        Ok(true)
+    }
+
+    async fn register(
+        &self,
+        new_user: models::NewUser,
+        address: NormalizedEmail,
+        number: Option<NormalizedPhone>,
+        role_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<(models::User, models::Email, Option<models::Phone>)> {
+        self.transaction(|tables| {
+            let user = tables.insert_user(new_user)?;
+            let email = tables.insert_email(user.user_uuid, address)?;
+            let phone = match number {
+                Some(number) => Some(tables.insert_phone(user.user_uuid, number)?),
+                None => None,
+            };
+            tables.assign_roles(user.user_uuid, &role_uuids)?;
+
+            Ok((user, email, phone))
+        })
+    }
+
+    /// Each row is rolled back on its own when it fails, and the whole
+    /// batch on a dry run.
+    async fn import_batch(
+        &self,
+        context: &ImportContext,
+        batch: Vec<(usize, Result<PreparedUser, RejectedUser>)>,
+        dry_run: bool,
+    ) -> RepositoryResult<Vec<ImportRowResult>> {
+        let mut tables = self.tables();
+        let before_batch = tables.clone();
+        let mut results = Vec::with_capacity(batch.len());
+
+        for (row, prepared) in batch {
+            let result = match prepared {
+                Ok(user) => {
+                    let username = user.username.clone();
+                    let before_row = tables.clone();
+
+                    match tables.import_user(context.definitions(), user) {
+                        Ok(user_uuid) => ImportRowResult { row, username, user_uuid: Some(user_uuid), error: None },
+                        Err(error) => {
+                            *tables = before_row;
+                            ImportRowResult { row, username, user_uuid: None, error: Some(error.to_string()) }
+                        }
+                    }
+                }
+                Err(rejected) => ImportRowResult {
+                    row,
+                    username: rejected.username,
+                    user_uuid: None,
+                    error: Some(rejected.error),
+                },
+            };
+
+            results.push(result);
+        }
+
+        if dry_run {
+            *tables = before_batch;
+            for result in results.iter_mut() {
+                result.user_uuid = None;
+            }
+        }
+
+        Ok(results)
+    }
+
+    async fn update_metadata(&self, user_uuid: Uuid, metadata: Value) -> RepositoryResult<models::User> {
+        let mut tables = self.tables();
+        let stored = tables.user_mut(user_uuid)?;
+        stored.user.metadata = metadata;
+        Ok(stored.user.clone())
+    }
+
+    async fn find_by_attribute(&self, key: &str, value: Value, limit: i64, offset: i64) -> RepositoryResult<Vec<models::User>> {
+        let tables = self.tables();
+        let mut users: Vec<&MemoryUser> = tables.users.values()
+            .filter(|stored| stored.user.metadata.get(key) == Some(&value))
+            .collect();
+
+        users.sort_by_key(|stored| stored.created_at);
+        Ok(users.into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|stored| stored.user.clone())
+            .collect())
+    }
+
+    async fn attribute_value_taken(&self, key: &str, value: Value, exclude: Option<Uuid>) -> RepositoryResult<bool> {
+        Ok(self.tables().attribute_value_taken(key, &value, exclude))
+    }
+
+    async fn list_attribute_definitions(&self) -> RepositoryResult<Vec<models::AttributeDefinition>> {
+        let mut definitions = self.tables().attribute_definitions.clone();
+        definitions.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(definitions)
+    }
+
+    async fn find_attribute_definition(&self, key: &str) -> RepositoryResult<models::AttributeDefinition> {
+        self.tables().attribute_definitions.iter()
+            .find(|definition| definition.key == key)
+            .cloned()
+            .ok_or(DieselError::NotFound.into())
+    }
+
+    async fn upsert_attribute_definition(&self, definition: models::NewAttributeDefinition) -> RepositoryResult<models::AttributeDefinition> {
+        let mut tables = self.tables();
+        let now = Some(Utc::now());
+        let existing = tables.attribute_definitions.iter().position(|existing| existing.key == definition.key);
+
+        let (attribute_uuid, created_at) = match existing {
+            Some(index) => {
+                let replaced = tables.attribute_definitions.remove(index);
+                (replaced.attribute_uuid, replaced.created_at)
+            }
+            None => (Uuid::new_v4(), now),
+        };
+
+        let definition = models::AttributeDefinition {
+            attribute_uuid,
+            key: definition.key,
+            attribute_type: definition.attribute_type,
+            description: definition.description,
+            is_required: definition.is_required,
+            is_unique: definition.is_unique,
+            is_indexed: definition.is_indexed,
+            visibility: definition.visibility,
+            enum_values: definition.enum_values,
+            pattern: definition.pattern,
+            created_at,
+            updated_at: now,
+        };
+
+        tables.attribute_definitions.push(definition.clone());
+        Ok(definition)
+    }
+
+    async fn delete_attribute_definition(&self, key: &str) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+        let before = tables.attribute_definitions.len();
+        tables.attribute_definitions.retain(|definition| definition.key != key);
+        Ok(tables.attribute_definitions.len() < before)
+    }
+
+    async fn list_roles(&self) -> RepositoryResult<Vec<models::Role>> {
+        let mut roles = self.tables().roles.clone();
+        roles.sort_by(|a, b| a.role_name.cmp(&b.role_name));
+        Ok(roles)
+    }
+
+    async fn find_roles_by_names(&self, role_names: &[String]) -> RepositoryResult<Vec<models::Role>> {
+        Ok(self.tables().roles.iter()
+            .filter(|role| role_names.contains(&role.role_name))
+            .cloned()
+            .collect())
+    }
+
+    async fn create_data_job(&self, new_job: models::NewUserDataJob) -> RepositoryResult<models::UserDataJob> {
+        let mut tables = self.tables();
+        tables.check_user(new_job.user_uuid, "user_data_jobs_user_uuid_fkey")?;
+
+        let job = models::UserDataJob {
+            job_uuid: Uuid::new_v4(),
+            user_uuid: new_job.user_uuid,
+            job_type: new_job.job_type,
+            status: models::UserDataJobStatusEnum::Pending,
+            result: None,
+            error: None,
+            created_at: Utc::now(),
+            started_at: None,
+            completed_at: None,
+        };
+
+        tables.user_data_jobs.push(job.clone());
+        Ok(job)
+    }
+
+    async fn find_data_job(&self, job_uuid: Uuid) -> RepositoryResult<models::UserDataJob> {
+        self.tables().user_data_jobs.iter()
+            .find(|job| job.job_uuid == job_uuid)
+            .cloned()
+            .ok_or(DieselError::NotFound.into())
+    }
+}
+
+#[tonic::async_trait]
+impl SessionRepository for MemoryRepository {
+    async fn find(&self, session_uuid: Uuid) -> RepositoryResult<models::Session> {
+        let tables = self.tables();
+        let session = tables.sessions.get(&session_uuid).ok_or(DieselError::NotFound)?;
+        Ok(session.clone())
+    }
+
+    async fn find_with_device(&self, session_uuid: Uuid) -> RepositoryResult<(models::Session, Option<models::Device>)> {
+        let tables = self.tables();
+        let session = tables.sessions.get(&session_uuid).ok_or(DieselError::NotFound)?;
+        Ok(tables.with_device(session))
+    }
+
+    async fn find_by_user_with_devices(
+        &self,
+        user_uuid: Uuid,
+        include_inactive: bool,
+        limit: i64,
+        offset: i64,
+    ) -> RepositoryResult<Vec<(models::Session, Option<models::Device>)>> {
+        let tables = self.tables();
+        let now = Utc::now();
+
+        let mut sessions: Vec<&models::Session> = tables.sessions.values()
+            .filter(|session| session.user_uuid == user_uuid)
+            .filter(|session| {
+                include_inactive || (session.is_active && session.revoked_at.is_none() && session.expires_at > now)
+            })
+            .collect();
+
+        // Latest first with missing timestamps last, as `NULLS LAST`.
+        sessions.sort_by(|a, b| {
+            b.last_accessed_at.cmp(&a.last_accessed_at)
+                .then(b.created_at.cmp(&a.created_at))
+                .then(a.session_uuid.cmp(&b.session_uuid))
+        });
+
+        Ok(sessions
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|session| tables.with_device(session))
+            .collect())
+    }
+
+    async fn revoke(&self, session: &models::Session) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+        let session_uuid = session.session_uuid;
+
+        let revoked = !tables.revoke_sessions(|stored| stored.session_uuid == session_uuid).is_empty();
+
+        if revoked {
+            tables.record_event(Some(session.user_uuid), "session.revoked", json!({
+                "session_id": session_uuid,
+                "device_id": session.device_uuid,
+            }));
+        }
+
+        Ok(revoked)
+    }
+
+    async fn revoke_others_for_user(&self, user_uuid: Uuid, kept_session_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut tables = self.tables();
+
+        let sessions_revoked = tables.revoke_sessions(|session| {
+            session.user_uuid == user_uuid && session.session_uuid != kept_session_uuid
+        });
+
+        tables.record_event(Some(user_uuid), "session.others_revoked", json!({
+            "kept_session_id": kept_session_uuid,
+            "sessions_revoked": sessions_revoked.len(),
+        }));
+
+        Ok(sessions_revoked)
+    }
+
+    async fn revoke_for_user_device(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut tables = self.tables();
+
+        let sessions_revoked = tables.revoke_sessions(|session| {
+            session.user_uuid == user_uuid && session.device_uuid == Some(device_uuid)
+        });
+
+        tables.record_event(Some(user_uuid), "session.device_sessions_revoked", json!({
+            "device_id": device_uuid,
+            "sessions_revoked": sessions_revoked.len(),
+        }));
+
+        Ok(sessions_revoked)
+    }
+
+    async fn find_policies(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::SessionPolicy>> {
+        let tables = self.tables();
+
+        Ok(tables.session_policies.iter()
+            .filter(|policy| {
+                tables.user_roles.iter().any(|(role_user_uuid, role_uuid)| {
+                    *role_user_uuid == user_uuid && policy.role_uuid == Some(*role_uuid)
+                })
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn start(
+        &self,
+        new_session: models::NewSession,
+        limits: &SessionLimits,
+        device_type: &models::DeviceTypeEnum,
+        location: Option<Value>,
+    ) -> RepositoryResult<Option<(models::Session, Vec<Uuid>)>> {
+        let mut tables = self.tables();
+        let now = Utc::now();
+        let user_uuid = new_session.user_uuid;
+        tables.check_user(user_uuid, "sessions_user_uuid_fkey")?;
+
+        let mut evicted = vec![];
+        if limits.limits_concurrency() {
+            let sessions: Vec<(models::Session, Option<models::Device>)> = tables.sessions.values()
+                .filter(|session| session.user_uuid == user_uuid)
+                .map(|session| tables.with_device(session))
+                .collect();
+            evicted = session_policy::select_evictions(limits, &sessions, device_type, now);
+        }
+
+        if !evicted.is_empty() {
+            if limits.limit_action == LimitAction::Reject {
+                return Ok(None);
+            }
+
+            tables.revoke_sessions(|session| evicted.contains(&session.session_uuid));
+            for session_uuid in &evicted {
+                if let Some(session) = tables.sessions.get_mut(session_uuid) {
+                    session.metadata["revocation_reason"] = json!(session_policy::EVICTION_REASON);
+                }
+            }
+
+            tables.record_event(Some(user_uuid), "session.evicted", json!({
+                "session_ids": evicted,
+                "reason": session_policy::EVICTION_REASON,
+            }));
+        }
+
+        let device = tables.device_mut(new_session.device_uuid).map_err(|_| foreign_key_violation("sessions_device_uuid_fkey"))?;
+        device.ip_address = new_session.ip_address;
+        device.last_used_at = Some(now);
+        if let Some(location) = location {
+            device.location = Some(location);
+        }
+
+        let session = models::Session {
+            session_uuid: Uuid::new_v4(),
+            user_uuid,
+            device_uuid: Some(new_session.device_uuid),
+            ip_address: new_session.ip_address,
+            metadata: new_session.metadata,
+            last_accessed_at: new_session.last_accessed_at,
+            is_active: true,
+            expires_at: new_session.expires_at,
+            invalidated_at: None,
+            revoked_at: None,
+            created_at: Some(now),
+            idle_timeout: new_session.idle_timeout,
+            sliding_lifetime: new_session.sliding_lifetime,
+            idle_expires_at: new_session.idle_expires_at,
+            absolute_expires_at: new_session.absolute_expires_at,
+        };
+
+        tables.sessions.insert(session.session_uuid, session.clone());
+        Ok(Some((session, evicted)))
+    }
+
+    async fn touch(&self, session_uuid: Uuid, idle_expires_at: Option<DateTime<Utc>>) -> RepositoryResult<()> {
+        if let Some(session) = self.tables().sessions.get_mut(&session_uuid) {
+            session.last_accessed_at = Some(Utc::now());
+            session.idle_expires_at = idle_expires_at;
+        }
+
+        Ok(())
+    }
+
+    async fn renew(
+        &self,
+        session_uuid: Uuid,
+        expires_at: DateTime<Utc>,
+        idle_expires_at: Option<DateTime<Utc>>,
+    ) -> RepositoryResult<models::Session> {
+        let mut tables = self.tables();
+        let session = tables.sessions.get_mut(&session_uuid).ok_or(DieselError::NotFound)?;
+
+        session.expires_at = expires_at;
+        session.idle_expires_at = idle_expires_at;
+        session.last_accessed_at = Some(Utc::now());
+
+        Ok(session.clone())
+    }
+
+    async fn revoke_token(&self, new_token: models::NewRevokedToken) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+
+        if tables.revoked_tokens.contains_key(&new_token.jti) {
+            return Ok(false);
+        }
+
+        tables.record_event(Some(new_token.user_uuid), "token.revoked", json!({
+            "jti": new_token.jti,
+            "session_id": new_token.session_uuid,
+        }));
+
+        tables.revoked_tokens.insert(new_token.jti, models::RevokedToken {
+            jti: new_token.jti,
+            user_uuid: new_token.user_uuid,
+            session_uuid: new_token.session_uuid,
+            expires_at: new_token.expires_at,
+            revoked_at: Utc::now(),
+        });
+
+        Ok(true)
+    }
+
+    async fn is_token_revoked(&self, jti: Uuid) -> RepositoryResult<bool> {
+        Ok(self.tables().revoked_tokens.contains_key(&jti))
+    }
+}
+
+#[tonic::async_trait]
+impl MfaRepository for MemoryRepository {
+    async fn create_challenge(
+        &self,
+        new_challenge: models::NewMfaChallenge,
+        sms_send: Option<models::NewSmsSend>,
+    ) -> RepositoryResult<models::MfaChallenge> {
+        let mut tables = self.tables();
+        tables.check_user(new_challenge.user_uuid, "mfa_challenges_user_uuid_fkey")?;
+
+        let challenge = models::MfaChallenge {
+            challenge_uuid: new_challenge.challenge_uuid,
+            user_uuid: new_challenge.user_uuid,
+            device_uuid: new_challenge.device_uuid,
+            ip_address: new_challenge.ip_address,
+            factor: new_challenge.factor,
+            code_hash: new_challenge.code_hash,
+            attempts: 0,
+            status: models::MfaChallengeStatusEnum::Pending,
+            expires_at: new_challenge.expires_at,
+            completed_at: None,
+            created_at: Utc::now(),
+            responder_device_uuid: None,
+            consumed_at: None,
+        };
+
+        if let Some(sms_send) = sms_send {
+            tables.record_sms_send(sms_send);
+        }
+
+        tables.record_challenge_event(&challenge, "mfa.challenge_started");
+        tables.mfa_challenges.insert(challenge.challenge_uuid, challenge.clone());
+
+        Ok(challenge)
+    }
+
+    async fn find_challenge(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge> {
+        let tables = self.tables();
+        let challenge = tables.mfa_challenges.get(&challenge_uuid).ok_or(DieselError::NotFound)?;
+        Ok(challenge.clone())
+    }
+
+    async fn claim_attempt(&self, challenge_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::MfaChallenge>> {
+        let mut tables = self.tables();
+
+        Ok(tables.pending_challenge_mut(challenge_uuid)
+            .filter(|challenge| challenge.attempts < max_attempts)
+            .map(|challenge| {
+                challenge.attempts += 1;
+                challenge.clone()
+            }))
+    }
+
+    async fn settle(
+        &self,
+        challenge_uuid: Uuid,
+        status: models::MfaChallengeStatusEnum,
+        responder_device_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::MfaChallenge> {
+        let mut tables = self.tables();
+        let challenge = tables.pending_challenge_mut(challenge_uuid).ok_or(DieselError::NotFound)?;
+
+        challenge.status = status;
+        challenge.completed_at = Some(Utc::now());
+        if responder_device_uuid.is_some() {
+            challenge.responder_device_uuid = responder_device_uuid;
+        }
+        let challenge = challenge.clone();
+
+        let event_type = match challenge.status {
+            models::MfaChallengeStatusEnum::Approved => Some("mfa.challenge_approved"),
+            models::MfaChallengeStatusEnum::Denied => Some("mfa.challenge_denied"),
+            _ => None,
+        };
+        if let Some(event_type) = event_type {
+            tables.record_challenge_event(&challenge, event_type);
+        }
+
+        Ok(challenge)
+    }
+
+    async fn consume(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge> {
+        let mut tables = self.tables();
+        let challenge = tables.mfa_challenges.get_mut(&challenge_uuid)
+            .filter(|challenge| challenge.status == models::MfaChallengeStatusEnum::Approved && challenge.consumed_at.is_none())
+            .ok_or(DieselError::NotFound)?;
+
+        challenge.consumed_at = Some(Utc::now());
+        Ok(challenge.clone())
+    }
+}
+
+#[tonic::async_trait]
+impl DeviceRepository for MemoryRepository {
+    async fn create(&self, new_device: models::NewDevice, metadata: Value) -> RepositoryResult<models::Device> {
+        let mut tables = self.tables();
+
+        if let Some(user_uuid) = new_device.user_uuid {
+            tables.check_user(user_uuid, "devices_user_uuid_fkey")?;
+        }
+
+        let now = Some(Utc::now());
+        let device = models::Device {
+            device_uuid: Uuid::new_v4(),
+            device_type: new_device.device_type,
+            os: new_device.os,
+            os_version: new_device.os_version,
+            browser: new_device.browser,
+            browser_version: new_device.browser_version,
+            ip_address: new_device.ip_address,
+            location: new_device.location,
+            status: new_device.status,
+            last_used_at: None,
+            created_at: now,
+            updated_at: now,
+            mac_address: new_device.mac_address,
+            notification_token: None,
+            notification_enabled: Some(true),
+            metadata: json!({}),
+            user_uuid: new_device.user_uuid,
+            client_device_id: new_device.client_device_id,
+            user_agent_hash: new_device.user_agent_hash,
+            ip_prefix: new_device.ip_prefix,
+        };
+
+        tables.devices.insert(device.device_uuid, device.clone());
+        tables.record_device_event(&device, "device.new", metadata);
+        Ok(device)
+    }
+
+    async fn find(&self, device_uuid: Uuid) -> RepositoryResult<models::Device> {
+        Ok(self.tables().device_mut(device_uuid)?.clone())
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Device>> {
+        let mut devices: Vec<models::Device> = self.tables().devices.values()
+            .filter(|device| device.user_uuid == Some(user_uuid))
+            .cloned()
+            .collect();
+
+        devices.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.device_uuid.cmp(&b.device_uuid)));
+
+        Ok(devices
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn find_fingerprint_candidates(
+        &self,
+        client_device_id: Option<&str>,
+        mac_address: Option<&str>,
+        user_agent_hash: &str,
+        ip_prefix: IpNet,
+        limit: i64,
+    ) -> RepositoryResult<Vec<models::Device>> {
+        // Missing signals never match, as comparing with NULL.
+        let shares = |stored: Option<&str>, signal: Option<&str>| stored.is_some() && stored == signal;
+
+        let mut devices: Vec<models::Device> = self.tables().devices.values()
+            .filter(|device| device.status != models::DeviceStatusEnum::Retired)
+            .filter(|device| {
+                shares(device.client_device_id.as_deref(), client_device_id)
+                    || shares(device.mac_address.as_deref(), mac_address)
+                    || (device.user_agent_hash.as_deref() == Some(user_agent_hash) && device.ip_prefix == Some(ip_prefix))
+            })
+            .cloned()
+            .collect();
+
+        // Latest first with missing timestamps last, as `NULLS LAST`.
+        devices.sort_by(|a, b| b.last_used_at.cmp(&a.last_used_at).then(b.created_at.cmp(&a.created_at)));
+        devices.truncate(limit.max(0) as usize);
+        Ok(devices)
+    }
+
+    async fn record_match(
+        &self,
+        device_uuid: Uuid,
+        changes: models::DeviceChangeset,
+        ip_address: IpNet,
+        location: Option<Value>,
+        user_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::Device> {
+        let mut tables = self.tables();
+
+        if let Some(user_uuid) = user_uuid {
+            tables.check_user(user_uuid, "devices_user_uuid_fkey")?;
+        }
+
+        let device = tables.device_mut(device_uuid)?;
+        apply_changes(device, changes);
+        device.ip_address = ip_address;
+        if location.is_some() {
+            device.location = location;
+        }
+        device.last_used_at = Some(Utc::now());
+        if device.user_uuid.is_none() {
+            device.user_uuid = user_uuid;
+        }
+
+        Ok(device.clone())
+    }
+
+    async fn assign_user(&self, device_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+        tables.check_user(user_uuid, "devices_user_uuid_fkey")?;
+
+        match tables.devices.get_mut(&device_uuid) {
+            Some(device) if device.user_uuid.is_none() => {
+                device.user_uuid = Some(user_uuid);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn update(
+        &self,
+        device_uuid: Uuid,
+        changes: Option<models::DeviceChangeset>,
+        status: Option<models::DeviceStatusEnum>,
+        metadata: Value,
+    ) -> RepositoryResult<(models::Device, Vec<Uuid>)> {
+        let mut tables = self.tables();
+        let device = tables.device_mut(device_uuid)?;
+
+        if let Some(changes) = changes {
+            apply_changes(device, changes);
+        }
+
+        let Some(status) = status else {
+            return Ok((device.clone(), vec![]));
+        };
+
+        device.status = status;
+        device.updated_at = Some(Utc::now());
+        let device = device.clone();
+
+        let mut sessions_revoked = vec![];
+        if device.status.is_blocked() {
+            sessions_revoked = tables.revoke_sessions(|session| session.device_uuid == Some(device_uuid));
+            tables.revoke_trusts(|trust| trust.device_uuid == device_uuid);
+        }
+
+        let mut metadata = metadata;
+        metadata["sessions_revoked"] = json!(sessions_revoked.len());
+        tables.record_device_event(&device, "device.status_changed", metadata);
+
+        Ok((device, sessions_revoked))
+    }
+
+    async fn delete(&self, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut tables = self.tables();
+        let device = tables.devices.remove(&device_uuid).ok_or(DieselError::NotFound)?;
+
+        let sessions_revoked = tables.revoke_sessions(|session| session.device_uuid == Some(device_uuid));
+        tables.trusted_devices.retain(|trust| trust.device_uuid != device_uuid);
+
+        tables.record_device_event(&device, "device.deleted", json!({
+            "sessions_revoked": sessions_revoked.len(),
+        }));
+
+        Ok(sessions_revoked)
+    }
+
+    async fn set_notification_token(&self, device_uuid: Uuid, token: String, event_type: &str) -> RepositoryResult<models::Device> {
+        let mut tables = self.tables();
+        tables.device_mut(device_uuid)?;
+
+        let now = Some(Utc::now());
+        for device in tables.devices.values_mut() {
+            if device.device_uuid == device_uuid {
+                device.notification_token = Some(token.clone());
+                device.notification_enabled = Some(true);
+                device.updated_at = now;
+            } else if device.notification_token.as_deref() == Some(token.as_str()) {
+                device.notification_token = None;
+                device.notification_enabled = Some(false);
+                device.updated_at = now;
+            }
+        }
+
+        let device = tables.device_mut(device_uuid)?.clone();
+        tables.record_device_event(&device, event_type, json!({}));
+        Ok(device)
+    }
+
+    async fn clear_notification_token(&self, token: &str) -> RepositoryResult<usize> {
+        let mut tables = self.tables();
+        let mut cleared = 0;
+
+        for device in tables.devices.values_mut() {
+            if device.notification_token.as_deref() == Some(token) {
+                device.notification_token = None;
+                device.notification_enabled = Some(false);
+                device.updated_at = Some(Utc::now());
+                cleared += 1;
+            }
+        }
+
+        Ok(cleared)
+    }
+
+    async fn trust(&self, new_trust: models::NewTrustedDevice) -> RepositoryResult<models::TrustedDevice> {
+        let mut tables = self.tables();
+        tables.check_user(new_trust.user_uuid, "trusted_devices_user_uuid_fkey")?;
+        if !tables.devices.contains_key(&new_trust.device_uuid) {
+            return Err(foreign_key_violation("trusted_devices_device_uuid_fkey").into());
+        }
+
+        tables.revoke_trusts(|trust| trust.user_uuid == new_trust.user_uuid && trust.device_uuid == new_trust.device_uuid);
+
+        let trust = models::TrustedDevice {
+            trust_uuid: new_trust.trust_uuid,
+            user_uuid: new_trust.user_uuid,
+            device_uuid: new_trust.device_uuid,
+            expires_at: new_trust.expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            created_at: Utc::now(),
+        };
+
+        tables.trusted_devices.push(trust.clone());
+        tables.record_event(Some(trust.user_uuid), "device.trusted", json!({
+            "device_id": trust.device_uuid,
+            "trust_id": trust.trust_uuid,
+            "expires_at": trust.expires_at.to_rfc3339(),
+        }));
+
+        Ok(trust)
+    }
+
+    async fn find_active_trust(&self, trust_uuid: Uuid) -> RepositoryResult<Option<models::TrustedDevice>> {
+        let now = Utc::now();
+
+        Ok(self.tables().trusted_devices.iter()
+            .find(|trust| trust.trust_uuid == trust_uuid && trust.revoked_at.is_none() && trust.expires_at > now)
+            .cloned())
+    }
+
+    async fn find_trusted_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<(models::TrustedDevice, models::Device)>> {
+        let tables = self.tables();
+        let now = Utc::now();
+
+        // Newest first, as they were added.
+        Ok(tables.trusted_devices.iter()
+            .rev()
+            .filter(|trust| trust.user_uuid == user_uuid && trust.revoked_at.is_none() && trust.expires_at > now)
+            .filter_map(|trust| tables.devices.get(&trust.device_uuid).map(|device| (trust.clone(), device.clone())))
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn touch_trust(&self, trust_uuid: Uuid) -> RepositoryResult<()> {
+        let mut tables = self.tables();
+
+        if let Some(trust) = tables.trusted_devices.iter_mut().find(|trust| trust.trust_uuid == trust_uuid) {
+            trust.last_used_at = Some(Utc::now());
+        }
+
+        Ok(())
+    }
+
+    async fn revoke_trust(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut tables = self.tables();
+        let revoked = tables.revoke_trusts(|trust| trust.user_uuid == user_uuid && trust.device_uuid == device_uuid) > 0;
+
+        if revoked {
+            tables.record_event(Some(user_uuid), "device.trust_revoked", json!({
+                "device_id": device_uuid,
+            }));
+        }
+
+        Ok(revoked)
+    }
+}
+
+/// Applies the fields a changeset sets, as `Device::update`.
+fn apply_changes(device: &mut models::Device, changes: models::DeviceChangeset) {
+    if changes.notification_token.is_some() {
+        device.notification_token = changes.notification_token;
+    }
+    if changes.notification_enabled.is_some() {
+        device.notification_enabled = changes.notification_enabled;
+    }
+    if changes.os_version.is_some() {
+        device.os_version = changes.os_version;
+    }
+    if changes.browser_version.is_some() {
+        device.browser_version = changes.browser_version;
+    }
+    if changes.mac_address.is_some() {
+        device.mac_address = changes.mac_address;
+    }
+    if changes.client_device_id.is_some() {
+        device.client_device_id = changes.client_device_id;
+    }
+    if changes.user_agent_hash.is_some() {
+        device.user_agent_hash = changes.user_agent_hash;
+    }
+    if changes.ip_prefix.is_some() {
+        device.ip_prefix = changes.ip_prefix;
+    }
+    device.updated_at = Some(Utc::now());
+}
+
+#[tonic::async_trait]
+impl EmailRepository for MemoryRepository {
+    async fn add_to_user(&self, user_uuid: Uuid, address: NormalizedEmail) -> RepositoryResult<models::Email> {
+        Ok(self.tables().insert_email(user_uuid, address)?)
+    }
+
+    async fn find(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        Ok(self.tables().email_mut(email_uuid)?.clone())
+    }
+
+    async fn find_by_value(&self, normalized_value: String) -> RepositoryResult<models::Email> {
+        self.tables().emails.iter()
+            .find(|email| email.normalized_value == normalized_value)
+            .cloned()
+            .ok_or(DieselError::NotFound.into())
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::Email>> {
+        let mut emails: Vec<models::Email> = self.tables().emails.iter()
+            .filter(|email| email.user_uuid == user_uuid)
+            .cloned()
+            .collect();
+
+        emails.sort_by_key(|email| !email.is_primary);
+        Ok(emails)
+    }
+
+    async fn set_primary(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        let mut tables = self.tables();
+        let email = tables.set_primary_email(email_uuid)?;
+
+        if let Ok(stored) = tables.user_mut(email.user_uuid) {
+            stored.user.email_update_required = false;
+        }
+
+        Ok(email)
+    }
+
+    async fn update(
+        &self,
+        email_uuid: Uuid,
+        address: Option<NormalizedEmail>,
+        status: Option<models::EmailStatusEnum>,
+    ) -> RepositoryResult<models::Email> {
+        let mut tables = self.tables();
+        let mut email = tables.email_mut(email_uuid)?.clone();
+
+        if let Some(address) = address {
+            if address.value != email.value {
+                let taken = tables.emails.iter().any(|other| {
+                    other.email_uuid != email_uuid && other.normalized_value == address.normalized
+                });
+                if taken {
+                    return Err(unique_violation("emails_normalized_value_idx").into());
+                }
+
+                let stored = tables.email_mut(email_uuid)?;
+                stored.value = address.value;
+                stored.normalized_value = address.normalized;
+                stored.status = models::EmailStatusEnum::Unverified;
+                stored.is_verified = false;
+                email = stored.clone();
+            }
+        }
+
+        if let Some(status) = status {
+            email = tables.set_email_status(email_uuid, status)?;
+        }
+
+        Ok(email)
+    }
+
+    async fn delete(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        Ok(self.tables().remove_email(email_uuid)?)
+    }
+
+    async fn is_suppressed(&self, normalized_value: &str) -> RepositoryResult<bool> {
+        Ok(self.tables().email_suppressions.iter().any(|suppressed| suppressed == normalized_value))
+    }
+
+    async fn apply_feedback(&self, normalized_value: String, feedback: Feedback) -> RepositoryResult<FeedbackOutcome> {
+        let mut tables = self.tables();
+        let email = tables.emails.iter().find(|email| email.normalized_value == normalized_value).cloned();
+        let (event_type, status, reason) = feedback.kind.effects();
+
+        let suppressed = reason.is_some();
+        if suppressed && !tables.email_suppressions.contains(&normalized_value) {
+            tables.email_suppressions.push(normalized_value);
+        }
+
+        if let Some(email) = &email {
+            if let Some(status) = status {
+                tables.set_email_status(email.email_uuid, status)?;
+            }
+
+            if feedback.kind == FeedbackKind::HardBounce && email.is_primary {
+                tables.user_mut(email.user_uuid)?.user.email_update_required = true;
+            }
+
+            tables.record_event(Some(email.user_uuid), event_type, json!({
+                "email_id": email.email_uuid,
+                "source": feedback.source,
+                "diagnostic": feedback.diagnostic,
+            }));
+        }
+
+        Ok(FeedbackOutcome {
+            recipient: feedback.recipient,
+            kind: feedback.kind,
+            email_uuid: email.map(|email| email.email_uuid),
+            suppressed,
+        })
+    }
+
+    async fn find_verifications_issued_since(&self, email_uuid: Uuid, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        let mut issued: Vec<DateTime<Utc>> = self.tables().email_verification_tokens.iter()
+            .filter(|token| token.email_uuid == email_uuid && token.created_at >= since)
+            .map(|token| token.created_at)
+            .collect();
+
+        issued.sort_by(|a, b| b.cmp(a));
+        Ok(issued)
+    }
+
+    async fn replace_verification(
+        &self,
+        new_token: models::NewEmailVerificationToken,
+        outgoing: Vec<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailVerificationToken> {
+        let mut tables = self.tables();
+        tables.email_mut(new_token.email_uuid).map_err(|_| foreign_key_violation("email_verification_tokens_email_uuid_fkey"))?;
+
+        tables.consume_verifications(new_token.email_uuid);
+
+        let token = models::EmailVerificationToken {
+            token_uuid: new_token.token_uuid,
+            email_uuid: new_token.email_uuid,
+            code_hash: new_token.code_hash,
+            token_hash: new_token.token_hash,
+            attempts: 0,
+            code_expires_at: new_token.code_expires_at,
+            expires_at: new_token.expires_at,
+            consumed_at: None,
+            created_at: Utc::now(),
+        };
+
+        tables.email_verification_tokens.push(token.clone());
+        tables.email_deliveries.extend(outgoing);
+
+        Ok(token)
+    }
+
+    async fn find_latest_verification(&self, email_uuid: Uuid) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        // Tokens are kept in the order they were issued.
+        Ok(self.tables().email_verification_tokens.iter()
+            .rev()
+            .find(|token| token.email_uuid == email_uuid && token.consumed_at.is_none())
+            .cloned())
+    }
+
+    async fn find_verification_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        Ok(self.tables().email_verification_tokens.iter()
+            .find(|token| token.token_hash == token_hash)
+            .cloned())
+    }
+
+    async fn claim_verification_attempt(&self, token_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        let mut tables = self.tables();
+
+        Ok(tables.email_verification_tokens.iter_mut()
+            .find(|token| token.token_uuid == token_uuid && token.consumed_at.is_none() && token.attempts < max_attempts)
+            .map(|token| {
+                token.attempts += 1;
+                token.clone()
+            }))
+    }
+
+    async fn burn_verifications(&self, email_uuid: Uuid) -> RepositoryResult<()> {
+        self.tables().consume_verifications(email_uuid);
+        Ok(())
+    }
+
+    async fn mark_verified(&self, email_uuid: Uuid, method: &str) -> RepositoryResult<models::Email> {
+        let mut tables = self.tables();
+        tables.consume_verifications(email_uuid);
+        let email = tables.set_email_status(email_uuid, models::EmailStatusEnum::Verified)?;
+
+        let stored = tables.user_mut(email.user_uuid)?;
+        let user_verified = !stored.user.is_verified;
+        stored.user.is_verified = true;
+
+        tables.record_event(Some(email.user_uuid), "email.verified", json!({
+            "email_id": email_uuid,
+            "method": method,
+            "user_verified": user_verified,
+        }));
+
+        Ok(email)
+    }
+
+    async fn start_change(&self, new_request: models::NewEmailChangeRequest) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut tables = self.tables();
+        tables.check_user(new_request.user_uuid, "email_change_requests_user_uuid_fkey")?;
+
+        let now = Utc::now();
+        for request in tables.email_change_requests.iter_mut() {
+            if request.user_uuid == new_request.user_uuid && request.status == models::EmailChangeStatusEnum::Pending {
+                request.status = models::EmailChangeStatusEnum::Cancelled;
+                request.cancelled_at = Some(now);
+            }
+        }
+
+        let request = models::EmailChangeRequest {
+            request_uuid: Uuid::new_v4(),
+            user_uuid: new_request.user_uuid,
+            old_email_uuid: new_request.old_email_uuid,
+            new_email_uuid: Some(new_request.new_email_uuid),
+            locale: new_request.locale,
+            status: models::EmailChangeStatusEnum::Pending,
+            cancel_token_hash: new_request.cancel_token_hash,
+            revert_token_hash: None,
+            expires_at: new_request.expires_at,
+            undo_expires_at: None,
+            completed_at: None,
+            cancelled_at: None,
+            reverted_at: None,
+            created_at: now,
+        };
+
+        tables.email_change_requests.push(request.clone());
+        tables.record_change_event(&request, "email.change_requested", json!({
+            "old_email_id": request.old_email_uuid,
+            "new_email_id": request.new_email_uuid,
+        }));
+
+        Ok(request)
+    }
+
+    async fn find_pending_change(&self, new_email_uuid: Uuid) -> RepositoryResult<Option<models::EmailChangeRequest>> {
+        let now = Utc::now();
+
+        Ok(self.tables().email_change_requests.iter()
+            .find(|request| {
+                request.new_email_uuid == Some(new_email_uuid)
+                    && request.status == models::EmailChangeStatusEnum::Pending
+                    && request.expires_at > now
+            })
+            .cloned())
+    }
+
+    async fn find_change_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailChangeRequest>> {
+        Ok(self.tables().email_change_requests.iter()
+            .find(|request| request.cancel_token_hash == token_hash || request.revert_token_hash.as_deref() == Some(token_hash))
+            .cloned())
+    }
+
+    async fn is_undo_pending(&self, email_uuid: Uuid) -> RepositoryResult<bool> {
+        let now = Utc::now();
+
+        Ok(self.tables().email_change_requests.iter().any(|request| {
+            request.old_email_uuid == Some(email_uuid)
+                && request.status == models::EmailChangeStatusEnum::Completed
+                && request.undo_expires_at.is_some_and(|undo_expires_at| undo_expires_at > now)
+        }))
+    }
+
+    async fn complete_change(
+        &self,
+        request_uuid: Uuid,
+        revert_token_hash: String,
+        undo_expires_at: DateTime<Utc>,
+        notice: Option<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut tables = self.tables();
+        let new_email_uuid = tables.change_mut(request_uuid)?.new_email_uuid.ok_or(DieselError::NotFound)?;
+        tables.set_primary_email(new_email_uuid)?;
+
+        let request = tables.change_mut(request_uuid)?;
+        request.status = models::EmailChangeStatusEnum::Completed;
+        request.revert_token_hash = Some(revert_token_hash);
+        request.completed_at = Some(Utc::now());
+        request.undo_expires_at = Some(undo_expires_at);
+        let request = request.clone();
+
+        tables.user_mut(request.user_uuid)?.user.email_update_required = false;
+        tables.email_deliveries.extend(notice);
+
+        tables.record_change_event(&request, "email.change_completed", json!({
+            "old_email_id": request.old_email_uuid,
+            "new_email_id": new_email_uuid,
+        }));
+
+        Ok(request)
+    }
+
+    async fn cancel_change(&self, request_uuid: Uuid) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut tables = self.tables();
+        let request = tables.change_mut(request_uuid)?;
+        request.status = models::EmailChangeStatusEnum::Cancelled;
+        request.cancelled_at = Some(Utc::now());
+        let request = request.clone();
+
+        if let Some(new_email_uuid) = request.new_email_uuid {
+            match tables.email_mut(new_email_uuid).ok().cloned() {
+                Some(email) if !email.is_primary && email.status == models::EmailStatusEnum::Unverified => {
+                    tables.remove_email(new_email_uuid)?;
+                }
+                Some(_) => tables.consume_verifications(new_email_uuid),
+                None => {}
+            }
+        }
+
+        // Deleting the new email drops it from the request.
+        let request = tables.change_mut(request_uuid)?.clone();
+        tables.record_change_event(&request, "email.change_cancelled", json!({}));
+
+        Ok(request)
+    }
+
+    async fn revert_change(&self, request_uuid: Uuid) -> RepositoryResult<(models::EmailChangeRequest, Vec<Uuid>)> {
+        let mut tables = self.tables();
+        let request = tables.change_mut(request_uuid)?.clone();
+        let old_email_uuid = request.old_email_uuid.ok_or(DieselError::NotFound)?;
+        tables.set_primary_email(old_email_uuid)?;
+
+        if let Some(new_email_uuid) = request.new_email_uuid {
+            let _ = tables.remove_email(new_email_uuid);
+        }
+
+        let request = tables.change_mut(request_uuid)?;
+        request.status = models::EmailChangeStatusEnum::Reverted;
+        request.reverted_at = Some(Utc::now());
+        let request = request.clone();
+
+        let user_uuid = request.user_uuid;
+        let sessions_revoked = tables.revoke_sessions(|session| session.user_uuid == user_uuid);
+
+        tables.record_change_event(&request, "email.change_reverted", json!({
+            "old_email_id": old_email_uuid,
+            "sessions_revoked": sessions_revoked.len(),
+        }));
+
+        Ok((request, sessions_revoked))
+    }
+}
+
+#[tonic::async_trait]
+impl PhoneRepository for MemoryRepository {
+    async fn add_to_user(&self, user_uuid: Uuid, number: NormalizedPhone) -> RepositoryResult<models::Phone> {
+        Ok(self.tables().insert_phone(user_uuid, number)?)
+    }
+
+    async fn find(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        Ok(self.tables().phone_mut(phone_uuid)?.clone())
+    }
+
+    async fn find_by_number(&self, full_number: String) -> RepositoryResult<models::Phone> {
+        self.tables().phones.iter()
+            .find(|phone| phone.full_number.as_deref() == Some(full_number.as_str()))
+            .cloned()
+            .ok_or(DieselError::NotFound.into())
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Phone>> {
+        let mut phones: Vec<models::Phone> = self.tables().phones.iter()
+            .filter(|phone| phone.user_uuid == user_uuid)
+            .cloned()
+            .collect();
+
+        phones.sort_by_key(|phone| !phone.is_primary);
+
+        Ok(phones
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn count_for_user(&self, user_uuid: Uuid) -> RepositoryResult<i64> {
+        Ok(self.tables().phones.iter().filter(|phone| phone.user_uuid == user_uuid).count() as i64)
+    }
+
+    async fn update(&self, phone_uuid: Uuid, type_: Option<String>, status: Option<models::PhoneStatusEnum>) -> RepositoryResult<models::Phone> {
+        let mut tables = self.tables();
+        let phone = tables.phone_mut(phone_uuid)?;
+
+        if let Some(type_) = type_ {
+            phone.type_ = Some(type_);
+        }
+
+        if let Some(status) = status {
+            match status {
+                models::PhoneStatusEnum::Verified => phone.is_verified = true,
+                models::PhoneStatusEnum::Unverified => phone.is_verified = false,
+                _ => {}
+            }
+            phone.status = status;
+        }
+
+        Ok(phone.clone())
+    }
+
+    async fn set_primary(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut tables = self.tables();
+        let user_uuid = tables.phone_mut(phone_uuid)?.user_uuid;
+
+        for phone in tables.phones.iter_mut().filter(|phone| phone.user_uuid == user_uuid) {
+            phone.is_primary = phone.phone_uuid == phone_uuid;
+        }
+
+        Ok(tables.phone_mut(phone_uuid)?.clone())
+    }
+
+    async fn delete(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut tables = self.tables();
+        let index = tables.phones.iter()
+            .position(|phone| phone.phone_uuid == phone_uuid)
+            .ok_or(DieselError::NotFound)?;
+
+        tables.phone_verification_codes.retain(|code| code.phone_uuid != phone_uuid);
+        Ok(tables.phones.remove(index))
+    }
+
+    async fn find_sms_sent_to_number_since(&self, phone_number: &str, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        Ok(self.tables().sms_sent_since(since, |send| send.phone_number == phone_number))
+    }
+
+    async fn find_sms_sent_for_ip_since(&self, ip_address: IpNet, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        Ok(self.tables().sms_sent_since(since, |send| send.ip_address == Some(ip_address)))
+    }
+
+    async fn replace_verification_code(
+        &self,
+        new_code: models::NewPhoneVerificationCode,
+        sms_send: models::NewSmsSend,
+    ) -> RepositoryResult<models::PhoneVerificationCode> {
+        let mut tables = self.tables();
+        tables.phone_mut(new_code.phone_uuid).map_err(|_| foreign_key_violation("phone_verification_codes_phone_uuid_fkey"))?;
+
+        tables.consume_verification_codes(new_code.phone_uuid);
+
+        let code = models::PhoneVerificationCode {
+            code_uuid: new_code.code_uuid,
+            phone_uuid: new_code.phone_uuid,
+            code_hash: new_code.code_hash,
+            attempts: 0,
+            expires_at: new_code.expires_at,
+            consumed_at: None,
+            created_at: Utc::now(),
+        };
+
+        tables.phone_verification_codes.push(code.clone());
+        tables.record_sms_send(sms_send);
+
+        Ok(code)
+    }
+
+    async fn find_latest_verification_code(&self, phone_uuid: Uuid) -> RepositoryResult<Option<models::PhoneVerificationCode>> {
+        // Codes are kept in the order they were issued.
+        Ok(self.tables().phone_verification_codes.iter()
+            .rev()
+            .find(|code| code.phone_uuid == phone_uuid && code.consumed_at.is_none())
+            .cloned())
+    }
+
+    async fn claim_verification_attempt(&self, code_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::PhoneVerificationCode>> {
+        let mut tables = self.tables();
+
+        Ok(tables.phone_verification_codes.iter_mut()
+            .find(|code| code.code_uuid == code_uuid && code.consumed_at.is_none() && code.attempts < max_attempts)
+            .map(|code| {
+                code.attempts += 1;
+                code.clone()
+            }))
+    }
+
+    async fn burn_verification_codes(&self, phone_uuid: Uuid) -> RepositoryResult<()> {
+        self.tables().consume_verification_codes(phone_uuid);
+        Ok(())
+    }
+
+    async fn mark_verified(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut tables = self.tables();
+        tables.consume_verification_codes(phone_uuid);
+
+        let phone = tables.phone_mut(phone_uuid)?;
+        phone.status = models::PhoneStatusEnum::Verified;
+        phone.is_verified = true;
+        let phone = phone.clone();
+
+        tables.record_event(Some(phone.user_uuid), "phone.verified", json!({
+            "phone_id": phone_uuid,
+            "method": "code",
+        }));
+
+        Ok(phone)
+    }
+}
@@ -0,0 +1,387 @@
+#[cfg(test)]
+mod memory;
+mod postgres;
+
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use diesel::result::Error as DieselError;
+use ipnet::IpNet;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[cfg(test)]
+pub use self::memory::*;
+pub use self::postgres::*;
+
+use crate::bounces::{Feedback, FeedbackOutcome};
+use crate::import::{ImportContext, ImportRowResult, PreparedUser, RejectedUser};
+use crate::mailer::OutgoingEmail;
+use crate::models;
+use crate::session_policy::SessionLimits;
+use crate::utils::{NormalizedEmail, NormalizedPhone};
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// No connection freed up in time, the call can be retried.
+    Unavailable(String),
+    /// Errors keep diesel's shape whatever the backend, so `NotFound` and
+    /// constraint violations are told apart the same way.
+    Database(DieselError),
+}
+
+impl std::fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepositoryError::Unavailable(message) => write!(f, "Database unavailable: {}", message),
+            RepositoryError::Database(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<DieselError> for RepositoryError {
+    fn from(error: DieselError) -> Self {
+        RepositoryError::Database(error)
+    }
+}
+
+pub type RepositoryResult<T> = Result<T, RepositoryError>;
+
+/// Every repository, for the services that need most of them.
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub sessions: Arc<dyn SessionRepository>,
+    pub mfa: Arc<dyn MfaRepository>,
+    pub devices: Arc<dyn DeviceRepository>,
+    pub emails: Arc<dyn EmailRepository>,
+    pub phones: Arc<dyn PhoneRepository>,
+}
+
+impl Repositories {
+    pub fn new<R>(repository: Arc<R>) -> Self
+    where
+        R: UserRepository + SessionRepository + MfaRepository + DeviceRepository + EmailRepository + PhoneRepository + 'static,
+    {
+        Repositories {
+            users: repository.clone(),
+            sessions: repository.clone(),
+            mfa: repository.clone(),
+            devices: repository.clone(),
+            emails: repository.clone(),
+            phones: repository,
+        }
+    }
+}
+
+/// Users, the rows every other repository hangs off, with the roles and
+/// attribute definitions they refer to and their data jobs.
+#[tonic::async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn create(&self, new_user: models::NewUser) -> RepositoryResult<models::User>;
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<models::User>;
+    /// Looks a user up by username, ignoring case and compatibility variants.
+    async fn find_by_username(&self, username: String) -> RepositoryResult<models::User>;
+    /// Finds a user other than `exclude` whose username has the given skeleton.
+    async fn find_confusable(&self, skeleton: &str, exclude: Option<Uuid>) -> RepositoryResult<Option<models::User>>;
+    async fn update_password(&self, user_uuid: Uuid, password_hash: String) -> RepositoryResult<bool>;
+    async fn update_username(&self, user_uuid: Uuid, username: String, skeleton: String) -> RepositoryResult<models::User>;
+    async fn set_mfa_enabled(&self, user_uuid: Uuid, enabled: bool) -> RepositoryResult<models::User>;
+    /// Deletes a user with everything that belongs to it.
+    async fn delete(&self, user_uuid: Uuid) -> RepositoryResult<bool>;
+    /// Creates a user with its primary email, primary phone and roles, all
+    /// or nothing.
+    async fn register(
+        &self,
+        new_user: models::NewUser,
+        address: NormalizedEmail,
+        number: Option<NormalizedPhone>,
+        role_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<(models::User, models::Email, Option<models::Phone>)>;
+    /// Inserts a batch of imported users, see `ImportContext::insert_batch`.
+    async fn import_batch(
+        &self,
+        context: &ImportContext,
+        batch: Vec<(usize, Result<PreparedUser, RejectedUser>)>,
+        dry_run: bool,
+    ) -> RepositoryResult<Vec<ImportRowResult>>;
+    async fn update_metadata(&self, user_uuid: Uuid, metadata: Value) -> RepositoryResult<models::User>;
+    /// Users whose custom attribute `key` is `value`, oldest first.
+    async fn find_by_attribute(&self, key: &str, value: Value, limit: i64, offset: i64) -> RepositoryResult<Vec<models::User>>;
+    /// Whether a user other than `exclude` already uses `value` for `key`.
+    async fn attribute_value_taken(&self, key: &str, value: Value, exclude: Option<Uuid>) -> RepositoryResult<bool>;
+    /// Attribute definitions ordered by key.
+    async fn list_attribute_definitions(&self) -> RepositoryResult<Vec<models::AttributeDefinition>>;
+    async fn find_attribute_definition(&self, key: &str) -> RepositoryResult<models::AttributeDefinition>;
+    /// Creates the definition, replacing any with the same key.
+    async fn upsert_attribute_definition(&self, definition: models::NewAttributeDefinition) -> RepositoryResult<models::AttributeDefinition>;
+    async fn delete_attribute_definition(&self, key: &str) -> RepositoryResult<bool>;
+    /// Roles ordered by name.
+    async fn list_roles(&self) -> RepositoryResult<Vec<models::Role>>;
+    async fn find_roles_by_names(&self, role_names: &[String]) -> RepositoryResult<Vec<models::Role>>;
+    async fn create_data_job(&self, new_job: models::NewUserDataJob) -> RepositoryResult<models::UserDataJob>;
+    async fn find_data_job(&self, job_uuid: Uuid) -> RepositoryResult<models::UserDataJob>;
+}
+
+/// Sessions with the devices they were started from.
+///
+/// Revocations are recorded as audit events in the same transaction.
+#[tonic::async_trait]
+pub trait SessionRepository: Send + Sync {
+    async fn find(&self, session_uuid: Uuid) -> RepositoryResult<models::Session>;
+    async fn find_with_device(&self, session_uuid: Uuid) -> RepositoryResult<(models::Session, Option<models::Device>)>;
+    /// Sessions of a user, most recently used first. Revoked and expired
+    /// sessions are left out unless `include_inactive`.
+    async fn find_by_user_with_devices(
+        &self,
+        user_uuid: Uuid,
+        include_inactive: bool,
+        limit: i64,
+        offset: i64,
+    ) -> RepositoryResult<Vec<(models::Session, Option<models::Device>)>>;
+    /// Revokes a session, returns whether it wasn't already revoked.
+    async fn revoke(&self, session: &models::Session) -> RepositoryResult<bool>;
+    /// Revokes every active session of the user but one, returning them.
+    async fn revoke_others_for_user(&self, user_uuid: Uuid, kept_session_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+    /// Revokes the active sessions of the user on one device, returning them.
+    async fn revoke_for_user_device(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+    /// Session policies of the user's roles and organizations.
+    async fn find_policies(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::SessionPolicy>>;
+    /// Starts a session if the concurrency limits admit it, evicting the
+    /// oldest sessions over them unless the limits say to reject it, see
+    /// `session_policy::select_evictions`. The device records the sign-in.
+    ///
+    /// Returns the session with the evicted ones, `None` if it was rejected.
+    async fn start(
+        &self,
+        new_session: models::NewSession,
+        limits: &SessionLimits,
+        device_type: &models::DeviceTypeEnum,
+        location: Option<Value>,
+    ) -> RepositoryResult<Option<(models::Session, Vec<Uuid>)>>;
+    /// Records a use of the session, pushing back its idle deadline.
+    async fn touch(&self, session_uuid: Uuid, idle_expires_at: Option<DateTime<Utc>>) -> RepositoryResult<()>;
+    /// Extends a session on refresh.
+    async fn renew(
+        &self,
+        session_uuid: Uuid,
+        expires_at: DateTime<Utc>,
+        idle_expires_at: Option<DateTime<Utc>>,
+    ) -> RepositoryResult<models::Session>;
+    /// Revokes an access token, recorded as `token.revoked`. Returns whether
+    /// it wasn't already revoked.
+    async fn revoke_token(&self, new_token: models::NewRevokedToken) -> RepositoryResult<bool>;
+    async fn is_token_revoked(&self, jti: Uuid) -> RepositoryResult<bool>;
+}
+
+/// Second factor challenges of sign-ins.
+///
+/// Starting, approving and denying a challenge are recorded as audit events
+/// in the same transaction.
+#[tonic::async_trait]
+pub trait MfaRepository: Send + Sync {
+    /// Starts a challenge, logging the text message carrying its code if any.
+    async fn create_challenge(
+        &self,
+        new_challenge: models::NewMfaChallenge,
+        sms_send: Option<models::NewSmsSend>,
+    ) -> RepositoryResult<models::MfaChallenge>;
+    async fn find_challenge(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge>;
+    /// Counts an attempt against a pending challenge, `None` once it was
+    /// settled or `max_attempts` were made.
+    async fn claim_attempt(&self, challenge_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::MfaChallenge>>;
+    /// Settles a pending challenge, with the device that answered a push
+    /// challenge. `NotFound` if it was already settled.
+    async fn settle(
+        &self,
+        challenge_uuid: Uuid,
+        status: models::MfaChallengeStatusEnum,
+        responder_device_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::MfaChallenge>;
+    /// Marks an approved challenge as used by its sign-in, `NotFound` if it
+    /// isn't approved or was already used.
+    async fn consume(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge>;
+}
+
+/// Devices, the push tokens they registered and the trust users gave them.
+///
+/// Changes are recorded as audit events in the same transaction.
+#[tonic::async_trait]
+pub trait DeviceRepository: Send + Sync {
+    /// Registers a device, recorded as `device.new` with `metadata`.
+    async fn create(&self, new_device: models::NewDevice, metadata: Value) -> RepositoryResult<models::Device>;
+    async fn find(&self, device_uuid: Uuid) -> RepositoryResult<models::Device>;
+    /// A page of the user's devices, oldest first.
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Device>>;
+    /// Devices sharing at least one fingerprint signal, most recently used
+    /// first, see `Device::find_fingerprint_candidates`.
+    async fn find_fingerprint_candidates(
+        &self,
+        client_device_id: Option<&str>,
+        mac_address: Option<&str>,
+        user_agent_hash: &str,
+        ip_prefix: IpNet,
+        limit: i64,
+    ) -> RepositoryResult<Vec<models::Device>>;
+    /// Records that a registration matched the device: its signals and last
+    /// known address are refreshed, and the user becomes its owner if it
+    /// had none.
+    async fn record_match(
+        &self,
+        device_uuid: Uuid,
+        changes: models::DeviceChangeset,
+        ip_address: IpNet,
+        location: Option<Value>,
+        user_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::Device>;
+    /// Makes the user the owner of a device that has none yet.
+    async fn assign_user(&self, device_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool>;
+    /// Changes the push settings and the status of a device at once.
+    ///
+    /// A status change is recorded as `device.status_changed` with
+    /// `metadata`. Blocking the device revokes its sessions, returned, and
+    /// every trust given to it.
+    async fn update(
+        &self,
+        device_uuid: Uuid,
+        changes: Option<models::DeviceChangeset>,
+        status: Option<models::DeviceStatusEnum>,
+        metadata: Value,
+    ) -> RepositoryResult<(models::Device, Vec<Uuid>)>;
+    /// Deletes a device, revoking its sessions, returned.
+    async fn delete(&self, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>>;
+    /// Sets the token push notifications are sent to, taking it away from
+    /// any other device, recorded as `event_type`.
+    async fn set_notification_token(&self, device_uuid: Uuid, token: String, event_type: &str) -> RepositoryResult<models::Device>;
+    /// Forgets a token the push provider reported as no longer valid.
+    async fn clear_notification_token(&self, token: &str) -> RepositoryResult<usize>;
+    /// Trusts a device for a user, replacing any earlier trust of that pair,
+    /// recorded as `device.trusted`.
+    async fn trust(&self, new_trust: models::NewTrustedDevice) -> RepositoryResult<models::TrustedDevice>;
+    /// Finds a trust that is neither revoked nor expired.
+    async fn find_active_trust(&self, trust_uuid: Uuid) -> RepositoryResult<Option<models::TrustedDevice>>;
+    /// Active trusts of a user with their devices, newest first.
+    async fn find_trusted_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<(models::TrustedDevice, models::Device)>>;
+    async fn touch_trust(&self, trust_uuid: Uuid) -> RepositoryResult<()>;
+    /// Revokes the trust a user gave to a device, recorded as
+    /// `device.trust_revoked` if there was one.
+    async fn revoke_trust(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<bool>;
+}
+
+/// Emails with their verifications, changes and the mail queued for them.
+///
+/// Queued mail is only sent once the change it belongs to is committed.
+#[tonic::async_trait]
+pub trait EmailRepository: Send + Sync {
+    /// Adds an unverified email to a user, it becomes their primary email
+    /// if it is their first.
+    async fn add_to_user(&self, user_uuid: Uuid, address: NormalizedEmail) -> RepositoryResult<models::Email>;
+    async fn find(&self, email_uuid: Uuid) -> RepositoryResult<models::Email>;
+    /// Looks an email up by its normalized value, see `utils::EmailPolicy`.
+    async fn find_by_value(&self, normalized_value: String) -> RepositoryResult<models::Email>;
+    /// The emails of a user, primary first.
+    async fn find_by_user(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::Email>>;
+    /// Makes an email the primary one of its user, who no longer has to
+    /// update their email.
+    async fn set_primary(&self, email_uuid: Uuid) -> RepositoryResult<models::Email>;
+    /// Replaces the address of an email, which has to be verified again, and
+    /// changes its status, either can be left as is.
+    async fn update(
+        &self,
+        email_uuid: Uuid,
+        address: Option<NormalizedEmail>,
+        status: Option<models::EmailStatusEnum>,
+    ) -> RepositoryResult<models::Email>;
+    async fn delete(&self, email_uuid: Uuid) -> RepositoryResult<models::Email>;
+    /// Whether the address bounced or complained, see `bounces`.
+    async fn is_suppressed(&self, normalized_value: &str) -> RepositoryResult<bool>;
+    /// Records a bounce or complaint against the email with the normalized
+    /// value, suppressing the address if the feedback calls for it.
+    async fn apply_feedback(&self, normalized_value: String, feedback: Feedback) -> RepositoryResult<FeedbackOutcome>;
+    /// Times verifications were issued for an email since `since`, newest first.
+    async fn find_verifications_issued_since(&self, email_uuid: Uuid, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>>;
+    /// Issues a verification, superseding the outstanding ones of the email,
+    /// and queues the emails carrying it.
+    async fn replace_verification(
+        &self,
+        new_token: models::NewEmailVerificationToken,
+        outgoing: Vec<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailVerificationToken>;
+    /// The most recently issued verification of an email that wasn't used yet.
+    async fn find_latest_verification(&self, email_uuid: Uuid) -> RepositoryResult<Option<models::EmailVerificationToken>>;
+    async fn find_verification_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailVerificationToken>>;
+    /// Counts an attempt against an unused verification, `None` once
+    /// `max_attempts` were made.
+    async fn claim_verification_attempt(&self, token_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::EmailVerificationToken>>;
+    /// Marks every outstanding verification of an email as used.
+    async fn burn_verifications(&self, email_uuid: Uuid) -> RepositoryResult<()>;
+    /// Marks an email verified, using up its verifications, and verifies
+    /// its user on their first verified email, recorded as `email.verified`
+    /// with `method`.
+    async fn mark_verified(&self, email_uuid: Uuid, method: &str) -> RepositoryResult<models::Email>;
+    /// Starts an email change, cancelling the pending one of the user if
+    /// any, recorded as `email.change_requested`.
+    async fn start_change(&self, new_request: models::NewEmailChangeRequest) -> RepositoryResult<models::EmailChangeRequest>;
+    /// The unexpired pending change waiting for `new_email_uuid` to be verified.
+    async fn find_pending_change(&self, new_email_uuid: Uuid) -> RepositoryResult<Option<models::EmailChangeRequest>>;
+    /// Finds a change by the hash of either token sent to the old address.
+    async fn find_change_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailChangeRequest>>;
+    /// Whether `email_uuid` is the old address of a change that can still be undone.
+    async fn is_undo_pending(&self, email_uuid: Uuid) -> RepositoryResult<bool>;
+    /// Makes the new email of a change primary and queues `notice` to the
+    /// old address, recorded as `email.change_completed`.
+    async fn complete_change(
+        &self,
+        request_uuid: Uuid,
+        revert_token_hash: String,
+        undo_expires_at: DateTime<Utc>,
+        notice: Option<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailChangeRequest>;
+    /// Cancels a pending change, dropping the new address unless it got
+    /// verified some other way, recorded as `email.change_cancelled`.
+    async fn cancel_change(&self, request_uuid: Uuid) -> RepositoryResult<models::EmailChangeRequest>;
+    /// Undoes a completed change: the old address is primary again, the new
+    /// one is deleted and every session of the user is revoked, recorded as
+    /// `email.change_reverted`. `NotFound` if the old address is gone.
+    ///
+    /// Returns the change with the sessions revoked.
+    async fn revert_change(&self, request_uuid: Uuid) -> RepositoryResult<(models::EmailChangeRequest, Vec<Uuid>)>;
+}
+
+#[tonic::async_trait]
+pub trait PhoneRepository: Send + Sync {
+    /// Adds an unverified phone to a user, it becomes their primary phone
+    /// if it is their first.
+    async fn add_to_user(&self, user_uuid: Uuid, number: NormalizedPhone) -> RepositoryResult<models::Phone>;
+    async fn find(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone>;
+    /// Looks a phone up by its E.164 number, see `utils::normalize_phone`.
+    async fn find_by_number(&self, full_number: String) -> RepositoryResult<models::Phone>;
+    /// A page of the user's phones, primary first, then oldest first.
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Phone>>;
+    async fn count_for_user(&self, user_uuid: Uuid) -> RepositoryResult<i64>;
+    /// Changes the type and the status of a phone at once, either can be left as is.
+    async fn update(&self, phone_uuid: Uuid, type_: Option<String>, status: Option<models::PhoneStatusEnum>) -> RepositoryResult<models::Phone>;
+    /// Makes a phone its user's primary phone, demoting the previous one.
+    async fn set_primary(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone>;
+    async fn delete(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone>;
+    /// Times of the text messages sent to a number since `since`, newest first.
+    async fn find_sms_sent_to_number_since(&self, phone_number: &str, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>>;
+    /// Times of the text messages requested from an IP since `since`, newest first.
+    async fn find_sms_sent_for_ip_since(&self, ip_address: IpNet, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>>;
+    /// Issues a verification code, superseding the outstanding ones of the
+    /// phone, and logs the text message carrying it.
+    async fn replace_verification_code(
+        &self,
+        new_code: models::NewPhoneVerificationCode,
+        sms_send: models::NewSmsSend,
+    ) -> RepositoryResult<models::PhoneVerificationCode>;
+    /// The most recently issued code of a phone that wasn't used yet.
+    async fn find_latest_verification_code(&self, phone_uuid: Uuid) -> RepositoryResult<Option<models::PhoneVerificationCode>>;
+    /// Counts an attempt against an unused code, `None` once `max_attempts`
+    /// were made.
+    async fn claim_verification_attempt(&self, code_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::PhoneVerificationCode>>;
+    /// Marks every outstanding code of a phone as used.
+    async fn burn_verification_codes(&self, phone_uuid: Uuid) -> RepositoryResult<()>;
+    /// Marks a phone verified with a code, which is used up, recorded as
+    /// `phone.verified`.
+    async fn mark_verified(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone>;
+}
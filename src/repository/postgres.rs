@@ -0,0 +1,1085 @@
+use chrono::{DateTime, Utc};
+use diesel::OptionalExtension;
+use diesel::result::Error as DieselError;
+use diesel_async::AsyncConnection;
+use diesel_async::pooled_connection::deadpool::PoolError;
+use diesel_async::scoped_futures::ScopedFutureExt;
+use ipnet::IpNet;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::bounces::{Feedback, FeedbackKind, FeedbackOutcome};
+use crate::db::{DbConnection, DbPool};
+use crate::import::{ImportContext, ImportRowResult, PreparedUser, RejectedUser};
+use crate::mailer::{self, OutgoingEmail};
+use crate::models;
+use crate::session_policy::{self, LimitAction, SessionLimits};
+use crate::utils::{NormalizedEmail, NormalizedPhone};
+
+use super::{DeviceRepository, EmailRepository, MfaRepository, PhoneRepository, RepositoryError, RepositoryResult, SessionRepository, UserRepository};
+
+impl From<PoolError> for RepositoryError {
+    fn from(error: PoolError) -> Self {
+        RepositoryError::Unavailable(error.to_string())
+    }
+}
+
+/// Every repository on top of the models, one pooled connection per call.
+#[derive(Clone)]
+pub struct PgRepository {
+    database: DbPool,
+}
+
+impl PgRepository {
+    pub fn new(database: DbPool) -> Self {
+        PgRepository { database }
+    }
+
+    async fn conn(&self) -> RepositoryResult<DbConnection> {
+        Ok(self.database.get().await?)
+    }
+}
+
+#[tonic::async_trait]
+impl UserRepository for PgRepository {
+    async fn create(&self, new_user: models::NewUser) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::create(&mut conn, new_user).await?)
+    }
+
+    async fn find(&self, user_uuid: Uuid) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::find_user_uuid(&mut conn, user_uuid).await?)
+    }
+
+    async fn find_by_username(&self, username: String) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::find_by_username(&mut conn, username).await?)
+    }
+
+    async fn find_confusable(&self, skeleton: &str, exclude: Option<Uuid>) -> RepositoryResult<Option<models::User>> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::find_confusable(&mut conn, skeleton, exclude).await?)
+    }
+
+    async fn update_password(&self, user_uuid: Uuid, password_hash: String) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::update_password(&mut conn, user_uuid, password_hash).await?)
+    }
+
+    async fn update_username(&self, user_uuid: Uuid, username: String, skeleton: String) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::update_username(&mut conn, user_uuid, username, skeleton).await?)
+    }
+
+    async fn set_mfa_enabled(&self, user_uuid: Uuid, enabled: bool) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::set_mfa_enabled(&mut conn, user_uuid, enabled).await?)
+    }
+
+    /// Foreign keys cascade the delete to the rest.
+    async fn delete(&self, user_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::delete_user(&mut conn, user_uuid).await?)
+    }
+
+    async fn register(
+        &self,
+        new_user: models::NewUser,
+        address: NormalizedEmail,
+        number: Option<NormalizedPhone>,
+        role_uuids: Vec<Uuid>,
+    ) -> RepositoryResult<(models::User, models::Email, Option<models::Phone>)> {
+        let mut conn = self.conn().await?;
+        let registered = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let user = models::User::create(conn, new_user).await?;
+
+            let email = models::Email::create(conn, models::NewEmail {
+                user_uuid: user.user_uuid,
+                value: address.value,
+                normalized_value: address.normalized,
+                status: models::EmailStatusEnum::Unverified,
+                is_primary: true,
+                metadata: json!({}),
+            }).await?;
+
+            let phone = match number {
+                Some(number) => Some(models::Phone::create(conn, models::NewPhone {
+                    user_uuid: user.user_uuid,
+                    country_code: number.country_code,
+                    number: number.number,
+                    type_: number.number_type.to_string(),
+                    status: models::PhoneStatusEnum::Unverified,
+                    is_primary: true,
+                    metadata: json!({ "region": number.region }),
+                }).await?),
+                None => None,
+            };
+
+            models::Role::assign_to_user(conn, user.user_uuid, &role_uuids).await?;
+
+            Ok((user, email, phone))
+        }.scope_boxed()).await?;
+
+        Ok(registered)
+    }
+
+    async fn import_batch(
+        &self,
+        context: &ImportContext,
+        batch: Vec<(usize, Result<PreparedUser, RejectedUser>)>,
+        dry_run: bool,
+    ) -> RepositoryResult<Vec<ImportRowResult>> {
+        let mut conn = self.conn().await?;
+        Ok(context.insert_batch(&mut conn, batch, dry_run).await?)
+    }
+
+    async fn update_metadata(&self, user_uuid: Uuid, metadata: Value) -> RepositoryResult<models::User> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::update_metadata(&mut conn, user_uuid, metadata).await?)
+    }
+
+    async fn find_by_attribute(&self, key: &str, value: Value, limit: i64, offset: i64) -> RepositoryResult<Vec<models::User>> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::find_by_attribute(&mut conn, key, value, limit, offset).await?)
+    }
+
+    async fn attribute_value_taken(&self, key: &str, value: Value, exclude: Option<Uuid>) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::User::attribute_value_taken(&mut conn, key, value, exclude).await?)
+    }
+
+    async fn list_attribute_definitions(&self) -> RepositoryResult<Vec<models::AttributeDefinition>> {
+        let mut conn = self.conn().await?;
+        Ok(models::AttributeDefinition::list(&mut conn).await?)
+    }
+
+    async fn find_attribute_definition(&self, key: &str) -> RepositoryResult<models::AttributeDefinition> {
+        let mut conn = self.conn().await?;
+        Ok(models::AttributeDefinition::find_by_key(&mut conn, key).await?)
+    }
+
+    async fn upsert_attribute_definition(&self, definition: models::NewAttributeDefinition) -> RepositoryResult<models::AttributeDefinition> {
+        let mut conn = self.conn().await?;
+        Ok(models::AttributeDefinition::upsert(&mut conn, definition).await?)
+    }
+
+    async fn delete_attribute_definition(&self, key: &str) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::AttributeDefinition::delete_by_key(&mut conn, key).await?)
+    }
+
+    async fn list_roles(&self) -> RepositoryResult<Vec<models::Role>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Role::list(&mut conn).await?)
+    }
+
+    async fn find_roles_by_names(&self, role_names: &[String]) -> RepositoryResult<Vec<models::Role>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Role::find_by_names(&mut conn, role_names).await?)
+    }
+
+    async fn create_data_job(&self, new_job: models::NewUserDataJob) -> RepositoryResult<models::UserDataJob> {
+        let mut conn = self.conn().await?;
+        Ok(models::UserDataJob::create(&mut conn, new_job).await?)
+    }
+
+    async fn find_data_job(&self, job_uuid: Uuid) -> RepositoryResult<models::UserDataJob> {
+        let mut conn = self.conn().await?;
+        Ok(models::UserDataJob::find_by_uuid(&mut conn, job_uuid).await?)
+    }
+}
+
+fn audit_event(user_uuid: Option<Uuid>, event_type: &str, metadata: Value) -> models::NewAuditEvent {
+    models::NewAuditEvent {
+        user_uuid,
+        event_type: event_type.to_string(),
+        ip_address: None,
+        metadata,
+    }
+}
+
+#[tonic::async_trait]
+impl SessionRepository for PgRepository {
+    async fn find(&self, session_uuid: Uuid) -> RepositoryResult<models::Session> {
+        let mut conn = self.conn().await?;
+        Ok(models::Session::find_by_uuid(&mut conn, session_uuid).await?)
+    }
+
+    async fn find_with_device(&self, session_uuid: Uuid) -> RepositoryResult<(models::Session, Option<models::Device>)> {
+        let mut conn = self.conn().await?;
+        Ok(models::Session::find_with_device(&mut conn, session_uuid).await?)
+    }
+
+    async fn find_by_user_with_devices(
+        &self,
+        user_uuid: Uuid,
+        include_inactive: bool,
+        limit: i64,
+        offset: i64,
+    ) -> RepositoryResult<Vec<(models::Session, Option<models::Device>)>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Session::find_by_user_with_devices(&mut conn, user_uuid, include_inactive, limit, offset).await?)
+    }
+
+    async fn revoke(&self, session: &models::Session) -> RepositoryResult<bool> {
+        let (session_uuid, user_uuid, device_uuid) = (session.session_uuid, session.user_uuid, session.device_uuid);
+
+        let mut conn = self.conn().await?;
+
+        let revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let revoked = models::Session::revoke(conn, session_uuid).await? > 0;
+
+            if revoked {
+                models::AuditEvent::record(conn, audit_event(Some(user_uuid), "session.revoked", json!({
+                    "session_id": session_uuid,
+                    "device_id": device_uuid,
+                }))).await?;
+            }
+
+            Ok(revoked)
+        }.scope_boxed()).await?;
+
+        Ok(revoked)
+    }
+
+    async fn revoke_others_for_user(&self, user_uuid: Uuid, kept_session_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut conn = self.conn().await?;
+        let sessions_revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let sessions_revoked = models::Session::revoke_others_for_user(conn, user_uuid, kept_session_uuid).await?;
+
+            models::AuditEvent::record(conn, audit_event(Some(user_uuid), "session.others_revoked", json!({
+                "kept_session_id": kept_session_uuid,
+                "sessions_revoked": sessions_revoked.len(),
+            }))).await?;
+
+            Ok(sessions_revoked)
+        }.scope_boxed()).await?;
+
+        Ok(sessions_revoked)
+    }
+
+    async fn revoke_for_user_device(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut conn = self.conn().await?;
+        let sessions_revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let sessions_revoked = models::Session::revoke_for_user_device(conn, user_uuid, device_uuid).await?;
+
+            models::AuditEvent::record(conn, audit_event(Some(user_uuid), "session.device_sessions_revoked", json!({
+                "device_id": device_uuid,
+                "sessions_revoked": sessions_revoked.len(),
+            }))).await?;
+
+            Ok(sessions_revoked)
+        }.scope_boxed()).await?;
+
+        Ok(sessions_revoked)
+    }
+
+    async fn find_policies(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::SessionPolicy>> {
+        let mut conn = self.conn().await?;
+        Ok(models::SessionPolicy::find_for_user(&mut conn, user_uuid).await?)
+    }
+
+    /// The user row stays locked until the session is created, so parallel
+    /// logins are counted one after the other.
+    async fn start(
+        &self,
+        new_session: models::NewSession,
+        limits: &SessionLimits,
+        device_type: &models::DeviceTypeEnum,
+        location: Option<Value>,
+    ) -> RepositoryResult<Option<(models::Session, Vec<Uuid>)>> {
+        let mut conn = self.conn().await?;
+        let started = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let (user_uuid, device_uuid) = (new_session.user_uuid, new_session.device_uuid);
+            let mut evicted = vec![];
+
+            if limits.limits_concurrency() {
+                models::User::lock(conn, user_uuid).await?;
+
+                let sessions = models::Session::find_by_user_with_devices(conn, user_uuid, false, session_policy::MAX_COUNTED_SESSIONS, 0).await?;
+                evicted = session_policy::select_evictions(limits, &sessions, device_type, Utc::now());
+            }
+
+            if !evicted.is_empty() {
+                if limits.limit_action == LimitAction::Reject {
+                    return Ok(None);
+                }
+
+                models::Session::revoke_with_reason(conn, &evicted, session_policy::EVICTION_REASON).await?;
+                models::AuditEvent::record(conn, audit_event(Some(user_uuid), "session.evicted", json!({
+                    "session_ids": evicted,
+                    "reason": session_policy::EVICTION_REASON,
+                }))).await?;
+            }
+
+            models::Device::record_use(conn, device_uuid, new_session.ip_address, location).await?;
+            let session = models::Session::create(conn, new_session).await?;
+
+            Ok(Some((session, evicted)))
+        }.scope_boxed()).await?;
+
+        Ok(started)
+    }
+
+    async fn touch(&self, session_uuid: Uuid, idle_expires_at: Option<DateTime<Utc>>) -> RepositoryResult<()> {
+        let mut conn = self.conn().await?;
+        models::Session::touch(&mut conn, session_uuid, idle_expires_at).await?;
+        Ok(())
+    }
+
+    async fn renew(
+        &self,
+        session_uuid: Uuid,
+        expires_at: DateTime<Utc>,
+        idle_expires_at: Option<DateTime<Utc>>,
+    ) -> RepositoryResult<models::Session> {
+        let mut conn = self.conn().await?;
+        Ok(models::Session::renew(&mut conn, session_uuid, expires_at, idle_expires_at).await?)
+    }
+
+    async fn revoke_token(&self, new_token: models::NewRevokedToken) -> RepositoryResult<bool> {
+        let (jti, user_uuid, session_uuid) = (new_token.jti, new_token.user_uuid, new_token.session_uuid);
+
+        let mut conn = self.conn().await?;
+        let revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let revoked = models::RevokedToken::create(conn, new_token).await? > 0;
+
+            if revoked {
+                models::AuditEvent::record(conn, audit_event(Some(user_uuid), "token.revoked", json!({
+                    "jti": jti,
+                    "session_id": session_uuid,
+                }))).await?;
+            }
+
+            Ok(revoked)
+        }.scope_boxed()).await?;
+
+        Ok(revoked)
+    }
+
+    async fn is_token_revoked(&self, jti: Uuid) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::RevokedToken::is_revoked(&mut conn, jti).await?)
+    }
+}
+
+fn challenge_event(challenge: &models::MfaChallenge, event_type: &str) -> models::NewAuditEvent {
+    models::NewAuditEvent {
+        user_uuid: Some(challenge.user_uuid),
+        event_type: event_type.to_string(),
+        ip_address: Some(challenge.ip_address),
+        metadata: json!({
+            "challenge_id": challenge.challenge_uuid,
+            "device_id": challenge.device_uuid,
+            "factor": challenge.factor,
+            "responder_device_id": challenge.responder_device_uuid,
+        }),
+    }
+}
+
+#[tonic::async_trait]
+impl MfaRepository for PgRepository {
+    async fn create_challenge(
+        &self,
+        new_challenge: models::NewMfaChallenge,
+        sms_send: Option<models::NewSmsSend>,
+    ) -> RepositoryResult<models::MfaChallenge> {
+        let mut conn = self.conn().await?;
+        let challenge = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let challenge = models::MfaChallenge::create(conn, new_challenge).await?;
+
+            if let Some(sms_send) = sms_send {
+                models::SmsSend::record(conn, sms_send).await?;
+            }
+
+            models::AuditEvent::record(conn, challenge_event(&challenge, "mfa.challenge_started")).await?;
+            Ok(challenge)
+        }.scope_boxed()).await?;
+
+        Ok(challenge)
+    }
+
+    async fn find_challenge(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge> {
+        let mut conn = self.conn().await?;
+        Ok(models::MfaChallenge::find_by_uuid(&mut conn, challenge_uuid).await?)
+    }
+
+    async fn claim_attempt(&self, challenge_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::MfaChallenge>> {
+        let mut conn = self.conn().await?;
+        Ok(models::MfaChallenge::claim_attempt(&mut conn, challenge_uuid, max_attempts).await?)
+    }
+
+    async fn settle(
+        &self,
+        challenge_uuid: Uuid,
+        status: models::MfaChallengeStatusEnum,
+        responder_device_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::MfaChallenge> {
+        let mut conn = self.conn().await?;
+        let challenge = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let challenge = match responder_device_uuid {
+                Some(responder_device_uuid) => models::MfaChallenge::respond(conn, challenge_uuid, status, responder_device_uuid).await?,
+                None => models::MfaChallenge::complete(conn, challenge_uuid, status).await?,
+            };
+
+            let event_type = match challenge.status {
+                models::MfaChallengeStatusEnum::Approved => Some("mfa.challenge_approved"),
+                models::MfaChallengeStatusEnum::Denied => Some("mfa.challenge_denied"),
+                _ => None,
+            };
+            if let Some(event_type) = event_type {
+                models::AuditEvent::record(conn, challenge_event(&challenge, event_type)).await?;
+            }
+
+            Ok(challenge)
+        }.scope_boxed()).await?;
+
+        Ok(challenge)
+    }
+
+    async fn consume(&self, challenge_uuid: Uuid) -> RepositoryResult<models::MfaChallenge> {
+        let mut conn = self.conn().await?;
+        Ok(models::MfaChallenge::consume(&mut conn, challenge_uuid).await?)
+    }
+}
+
+/// Device events carry the device, whoever owns it.
+fn device_event(device: &models::Device, event_type: &str, metadata: Value) -> models::NewAuditEvent {
+    let mut metadata = metadata;
+    metadata["device_id"] = json!(device.device_uuid);
+    audit_event(device.user_uuid, event_type, metadata)
+}
+
+#[tonic::async_trait]
+impl DeviceRepository for PgRepository {
+    async fn create(&self, new_device: models::NewDevice, metadata: Value) -> RepositoryResult<models::Device> {
+        let mut conn = self.conn().await?;
+        let device = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let device = models::Device::create(conn, new_device).await?;
+            models::AuditEvent::record(conn, device_event(&device, "device.new", metadata)).await?;
+            Ok(device)
+        }.scope_boxed()).await?;
+
+        Ok(device)
+    }
+
+    async fn find(&self, device_uuid: Uuid) -> RepositoryResult<models::Device> {
+        let mut conn = self.conn().await?;
+        Ok(models::Device::find_by_uuid(&mut conn, device_uuid).await?)
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Device>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Device::find_by_user(&mut conn, user_uuid, limit, offset).await?)
+    }
+
+    async fn find_fingerprint_candidates(
+        &self,
+        client_device_id: Option<&str>,
+        mac_address: Option<&str>,
+        user_agent_hash: &str,
+        ip_prefix: IpNet,
+        limit: i64,
+    ) -> RepositoryResult<Vec<models::Device>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Device::find_fingerprint_candidates(&mut conn, client_device_id, mac_address, user_agent_hash, ip_prefix, limit).await?)
+    }
+
+    async fn record_match(
+        &self,
+        device_uuid: Uuid,
+        changes: models::DeviceChangeset,
+        ip_address: IpNet,
+        location: Option<Value>,
+        user_uuid: Option<Uuid>,
+    ) -> RepositoryResult<models::Device> {
+        let mut conn = self.conn().await?;
+        let device = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::Device::update(conn, device_uuid, changes).await?;
+            models::Device::record_use(conn, device_uuid, ip_address, location).await?;
+            if let Some(user_uuid) = user_uuid {
+                models::Device::assign_user(conn, device_uuid, user_uuid).await?;
+            }
+
+            models::Device::find_by_uuid(conn, device_uuid).await
+        }.scope_boxed()).await?;
+
+        Ok(device)
+    }
+
+    async fn assign_user(&self, device_uuid: Uuid, user_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::Device::assign_user(&mut conn, device_uuid, user_uuid).await? > 0)
+    }
+
+    async fn update(
+        &self,
+        device_uuid: Uuid,
+        changes: Option<models::DeviceChangeset>,
+        status: Option<models::DeviceStatusEnum>,
+        metadata: Value,
+    ) -> RepositoryResult<(models::Device, Vec<Uuid>)> {
+        let mut conn = self.conn().await?;
+        let updated = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let mut device = match changes {
+                Some(changes) => models::Device::update(conn, device_uuid, changes).await?,
+                None => models::Device::find_by_uuid(conn, device_uuid).await?,
+            };
+            let mut sessions_revoked = vec![];
+
+            if let Some(status) = status {
+                device = models::Device::update_status(conn, device_uuid, status).await?;
+
+                if device.status.is_blocked() {
+                    sessions_revoked = models::Session::revoke_all_for_device(conn, device_uuid).await?;
+                    models::TrustedDevice::revoke_all_for_device(conn, device_uuid).await?;
+                }
+
+                let mut metadata = metadata;
+                metadata["sessions_revoked"] = json!(sessions_revoked.len());
+                models::AuditEvent::record(conn, device_event(&device, "device.status_changed", metadata)).await?;
+            }
+
+            Ok((device, sessions_revoked))
+        }.scope_boxed()).await?;
+
+        Ok(updated)
+    }
+
+    async fn delete(&self, device_uuid: Uuid) -> RepositoryResult<Vec<Uuid>> {
+        let mut conn = self.conn().await?;
+        let sessions_revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let sessions_revoked = models::Session::revoke_all_for_device(conn, device_uuid).await?;
+            let device = models::Device::delete(conn, device_uuid).await?;
+
+            models::AuditEvent::record(conn, device_event(&device, "device.deleted", json!({
+                "sessions_revoked": sessions_revoked.len(),
+            }))).await?;
+
+            Ok(sessions_revoked)
+        }.scope_boxed()).await?;
+
+        Ok(sessions_revoked)
+    }
+
+    async fn set_notification_token(&self, device_uuid: Uuid, token: String, event_type: &str) -> RepositoryResult<models::Device> {
+        let mut conn = self.conn().await?;
+        let device = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let device = models::Device::set_notification_token(conn, device_uuid, token).await?;
+            models::AuditEvent::record(conn, device_event(&device, event_type, json!({}))).await?;
+            Ok(device)
+        }.scope_boxed()).await?;
+
+        Ok(device)
+    }
+
+    async fn clear_notification_token(&self, token: &str) -> RepositoryResult<usize> {
+        let mut conn = self.conn().await?;
+        Ok(models::Device::clear_notification_token(&mut conn, token).await?)
+    }
+
+    async fn trust(&self, new_trust: models::NewTrustedDevice) -> RepositoryResult<models::TrustedDevice> {
+        let mut conn = self.conn().await?;
+        let trust = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::TrustedDevice::revoke_for_user_device(conn, new_trust.user_uuid, new_trust.device_uuid).await?;
+            let trust = models::TrustedDevice::create(conn, new_trust).await?;
+
+            models::AuditEvent::record(conn, audit_event(Some(trust.user_uuid), "device.trusted", json!({
+                "device_id": trust.device_uuid,
+                "trust_id": trust.trust_uuid,
+                "expires_at": trust.expires_at.to_rfc3339(),
+            }))).await?;
+
+            Ok(trust)
+        }.scope_boxed()).await?;
+
+        Ok(trust)
+    }
+
+    async fn find_active_trust(&self, trust_uuid: Uuid) -> RepositoryResult<Option<models::TrustedDevice>> {
+        let mut conn = self.conn().await?;
+        Ok(models::TrustedDevice::find_active(&mut conn, trust_uuid).await?)
+    }
+
+    async fn find_trusted_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<(models::TrustedDevice, models::Device)>> {
+        let mut conn = self.conn().await?;
+        Ok(models::TrustedDevice::find_active_by_user(&mut conn, user_uuid, limit, offset).await?)
+    }
+
+    async fn touch_trust(&self, trust_uuid: Uuid) -> RepositoryResult<()> {
+        let mut conn = self.conn().await?;
+        models::TrustedDevice::touch(&mut conn, trust_uuid).await?;
+        Ok(())
+    }
+
+    async fn revoke_trust(&self, user_uuid: Uuid, device_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        let revoked = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let revoked = models::TrustedDevice::revoke_for_user_device(conn, user_uuid, device_uuid).await? > 0;
+
+            if revoked {
+                models::AuditEvent::record(conn, audit_event(Some(user_uuid), "device.trust_revoked", json!({
+                    "device_id": device_uuid,
+                }))).await?;
+            }
+
+            Ok(revoked)
+        }.scope_boxed()).await?;
+
+        Ok(revoked)
+    }
+}
+
+/// An email change event, carrying the request it belongs to.
+fn change_event(request: &models::EmailChangeRequest, event_type: &str, metadata: Value) -> models::NewAuditEvent {
+    let mut metadata = metadata;
+    metadata["request_id"] = json!(request.request_uuid);
+    audit_event(Some(request.user_uuid), event_type, metadata)
+}
+
+#[tonic::async_trait]
+impl EmailRepository for PgRepository {
+    async fn add_to_user(&self, user_uuid: Uuid, address: NormalizedEmail) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        let email = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let make_primary = models::Email::find_by_user(conn, user_uuid).await?.is_empty();
+
+            let email = models::Email::create(conn, models::NewEmail {
+                user_uuid,
+                metadata: json!({}),
+                value: address.value,
+                normalized_value: address.normalized,
+                status: models::EmailStatusEnum::Unverified,
+                is_primary: false,
+            }).await?;
+
+            if make_primary {
+                return models::Email::set_primary(conn, email.email_uuid).await;
+            }
+
+            Ok(email)
+        }.scope_boxed()).await?;
+
+        Ok(email)
+    }
+
+    async fn find(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        Ok(models::Email::find_by_uuid(&mut conn, email_uuid).await?)
+    }
+
+    async fn find_by_value(&self, normalized_value: String) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        Ok(models::Email::find_by_value(&mut conn, normalized_value).await?)
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid) -> RepositoryResult<Vec<models::Email>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Email::find_by_user(&mut conn, user_uuid).await?)
+    }
+
+    async fn set_primary(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        let email = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let email = models::Email::set_primary(conn, email_uuid).await?;
+            models::User::set_email_update_required(conn, email.user_uuid, false).await?;
+            Ok(email)
+        }.scope_boxed()).await?;
+
+        Ok(email)
+    }
+
+    async fn update(
+        &self,
+        email_uuid: Uuid,
+        address: Option<NormalizedEmail>,
+        status: Option<models::EmailStatusEnum>,
+    ) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        let email = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let mut email = models::Email::find_by_uuid(conn, email_uuid).await?;
+
+            if let Some(address) = address {
+                if address.value != email.value {
+                    email = models::Email::update_value(conn, email_uuid, address.value, address.normalized).await?;
+                }
+            }
+
+            if let Some(status) = status {
+                email = models::Email::update_status(conn, email_uuid, status).await?;
+            }
+
+            Ok(email)
+        }.scope_boxed()).await?;
+
+        Ok(email)
+    }
+
+    async fn delete(&self, email_uuid: Uuid) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        Ok(models::Email::delete(&mut conn, email_uuid).await?)
+    }
+
+    async fn is_suppressed(&self, normalized_value: &str) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailSuppression::is_suppressed(&mut conn, normalized_value).await?)
+    }
+
+    async fn apply_feedback(&self, normalized_value: String, feedback: Feedback) -> RepositoryResult<FeedbackOutcome> {
+        let mut conn = self.conn().await?;
+        let outcome = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let email = models::Email::find_by_value(conn, normalized_value.clone()).await.optional()?;
+            let (event_type, status, reason) = feedback.kind.effects();
+
+            let suppressed = reason.is_some();
+            if let Some(reason) = reason {
+                models::EmailSuppression::suppress(conn, models::NewEmailSuppression {
+                    normalized_value,
+                    reason,
+                    source: feedback.source.to_string(),
+                    diagnostic: feedback.diagnostic.clone(),
+                }).await?;
+            }
+
+            if let Some(email) = &email {
+                if let Some(status) = status {
+                    models::Email::update_status(conn, email.email_uuid, status).await?;
+                }
+
+                if feedback.kind == FeedbackKind::HardBounce && email.is_primary {
+                    models::User::set_email_update_required(conn, email.user_uuid, true).await?;
+                }
+
+                models::AuditEvent::record(conn, audit_event(Some(email.user_uuid), event_type, json!({
+                    "email_id": email.email_uuid,
+                    "source": feedback.source,
+                    "diagnostic": feedback.diagnostic,
+                }))).await?;
+            }
+
+            Ok(FeedbackOutcome {
+                recipient: feedback.recipient,
+                kind: feedback.kind,
+                email_uuid: email.map(|email| email.email_uuid),
+                suppressed,
+            })
+        }.scope_boxed()).await?;
+
+        Ok(outcome)
+    }
+
+    async fn find_verifications_issued_since(&self, email_uuid: Uuid, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        let mut conn = self.conn().await?;
+        let issued = models::EmailVerificationToken::find_issued_since(&mut conn, email_uuid, since).await?;
+        Ok(issued.into_iter().map(|token| token.created_at).collect())
+    }
+
+    async fn replace_verification(
+        &self,
+        new_token: models::NewEmailVerificationToken,
+        outgoing: Vec<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailVerificationToken> {
+        let mut conn = self.conn().await?;
+        let token = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::EmailVerificationToken::consume_all_for_email(conn, new_token.email_uuid).await?;
+            let token = models::EmailVerificationToken::create(conn, new_token).await?;
+
+            for email in outgoing {
+                mailer::enqueue(conn, email).await?;
+            }
+
+            Ok(token)
+        }.scope_boxed()).await?;
+
+        Ok(token)
+    }
+
+    async fn find_latest_verification(&self, email_uuid: Uuid) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailVerificationToken::find_latest_active(&mut conn, email_uuid).await?)
+    }
+
+    async fn find_verification_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailVerificationToken::find_by_token_hash(&mut conn, token_hash).await?)
+    }
+
+    async fn claim_verification_attempt(&self, token_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::EmailVerificationToken>> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailVerificationToken::claim_attempt(&mut conn, token_uuid, max_attempts).await?)
+    }
+
+    async fn burn_verifications(&self, email_uuid: Uuid) -> RepositoryResult<()> {
+        let mut conn = self.conn().await?;
+        models::EmailVerificationToken::consume_all_for_email(&mut conn, email_uuid).await?;
+        Ok(())
+    }
+
+    async fn mark_verified(&self, email_uuid: Uuid, method: &str) -> RepositoryResult<models::Email> {
+        let mut conn = self.conn().await?;
+        let email = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::EmailVerificationToken::consume_all_for_email(conn, email_uuid).await?;
+            let email = models::Email::update_status(conn, email_uuid, models::EmailStatusEnum::Verified).await?;
+            let user_verified = models::User::mark_verified(conn, email.user_uuid).await?;
+
+            models::AuditEvent::record(conn, audit_event(Some(email.user_uuid), "email.verified", json!({
+                "email_id": email_uuid,
+                "method": method,
+                "user_verified": user_verified,
+            }))).await?;
+
+            Ok(email)
+        }.scope_boxed()).await?;
+
+        Ok(email)
+    }
+
+    async fn start_change(&self, new_request: models::NewEmailChangeRequest) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut conn = self.conn().await?;
+        let request = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::EmailChangeRequest::cancel_pending_for_user(conn, new_request.user_uuid).await?;
+            let request = models::EmailChangeRequest::create(conn, new_request).await?;
+
+            models::AuditEvent::record(conn, change_event(&request, "email.change_requested", json!({
+                "old_email_id": request.old_email_uuid,
+                "new_email_id": request.new_email_uuid,
+            }))).await?;
+
+            Ok(request)
+        }.scope_boxed()).await?;
+
+        Ok(request)
+    }
+
+    async fn find_pending_change(&self, new_email_uuid: Uuid) -> RepositoryResult<Option<models::EmailChangeRequest>> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailChangeRequest::find_pending_for_email(&mut conn, new_email_uuid).await?)
+    }
+
+    async fn find_change_by_token_hash(&self, token_hash: &str) -> RepositoryResult<Option<models::EmailChangeRequest>> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailChangeRequest::find_by_token_hash(&mut conn, token_hash).await?)
+    }
+
+    async fn is_undo_pending(&self, email_uuid: Uuid) -> RepositoryResult<bool> {
+        let mut conn = self.conn().await?;
+        Ok(models::EmailChangeRequest::is_undo_pending_for_old_email(&mut conn, email_uuid).await?)
+    }
+
+    async fn complete_change(
+        &self,
+        request_uuid: Uuid,
+        revert_token_hash: String,
+        undo_expires_at: DateTime<Utc>,
+        notice: Option<OutgoingEmail>,
+    ) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut conn = self.conn().await?;
+        let request = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let request = models::EmailChangeRequest::mark_completed(conn, request_uuid, revert_token_hash, undo_expires_at).await?;
+            let new_email_uuid = request.new_email_uuid.ok_or(DieselError::NotFound)?;
+
+            models::Email::set_primary(conn, new_email_uuid).await?;
+            models::User::set_email_update_required(conn, request.user_uuid, false).await?;
+
+            if let Some(notice) = notice {
+                mailer::enqueue(conn, notice).await?;
+            }
+
+            models::AuditEvent::record(conn, change_event(&request, "email.change_completed", json!({
+                "old_email_id": request.old_email_uuid,
+                "new_email_id": new_email_uuid,
+            }))).await?;
+
+            Ok(request)
+        }.scope_boxed()).await?;
+
+        Ok(request)
+    }
+
+    async fn cancel_change(&self, request_uuid: Uuid) -> RepositoryResult<models::EmailChangeRequest> {
+        let mut conn = self.conn().await?;
+        let request = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let request = models::EmailChangeRequest::mark_cancelled(conn, request_uuid).await?;
+
+            // The address was only added for the change, unless it got verified some other way.
+            if let Some(new_email_uuid) = request.new_email_uuid {
+                match models::Email::find_by_uuid(conn, new_email_uuid).await.optional()? {
+                    Some(email) if !email.is_primary && email.status == models::EmailStatusEnum::Unverified => {
+                        models::Email::delete(conn, new_email_uuid).await?;
+                    }
+                    Some(_) => {
+                        models::EmailVerificationToken::consume_all_for_email(conn, new_email_uuid).await?;
+                    }
+                    None => {}
+                }
+            }
+
+            models::AuditEvent::record(conn, change_event(&request, "email.change_cancelled", json!({}))).await?;
+
+            Ok(request)
+        }.scope_boxed()).await?;
+
+        Ok(request)
+    }
+
+    async fn revert_change(&self, request_uuid: Uuid) -> RepositoryResult<(models::EmailChangeRequest, Vec<Uuid>)> {
+        let mut conn = self.conn().await?;
+        let reverted = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let request = models::EmailChangeRequest::mark_reverted(conn, request_uuid).await?;
+            let old_email_uuid = request.old_email_uuid.ok_or(DieselError::NotFound)?;
+            models::Email::set_primary(conn, old_email_uuid).await?;
+
+            if let Some(new_email_uuid) = request.new_email_uuid {
+                models::Email::delete(conn, new_email_uuid).await.optional()?;
+            }
+
+            let sessions_revoked = models::Session::revoke_all_for_user(conn, request.user_uuid).await?;
+
+            models::AuditEvent::record(conn, change_event(&request, "email.change_reverted", json!({
+                "old_email_id": old_email_uuid,
+                "sessions_revoked": sessions_revoked.len(),
+            }))).await?;
+
+            Ok((request, sessions_revoked))
+        }.scope_boxed()).await?;
+
+        Ok(reverted)
+    }
+}
+
+#[tonic::async_trait]
+impl PhoneRepository for PgRepository {
+    async fn add_to_user(&self, user_uuid: Uuid, number: NormalizedPhone) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        let phone = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let make_primary = models::Phone::count_for_user(conn, user_uuid).await? == 0;
+
+            let phone = models::Phone::create(conn, models::NewPhone {
+                user_uuid,
+                country_code: number.country_code,
+                number: number.number,
+                type_: number.number_type.to_string(),
+                status: models::PhoneStatusEnum::Unverified,
+                is_primary: false,
+                metadata: json!({ "region": number.region }),
+            }).await?;
+
+            if make_primary {
+                return models::Phone::set_primary(conn, phone.phone_uuid).await;
+            }
+
+            Ok(phone)
+        }.scope_boxed()).await?;
+
+        Ok(phone)
+    }
+
+    async fn find(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::find_by_uuid(&mut conn, phone_uuid).await?)
+    }
+
+    async fn find_by_number(&self, full_number: String) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::find_by_number(&mut conn, full_number).await?)
+    }
+
+    async fn find_by_user(&self, user_uuid: Uuid, limit: i64, offset: i64) -> RepositoryResult<Vec<models::Phone>> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::find_by_user(&mut conn, user_uuid, limit, offset).await?)
+    }
+
+    async fn count_for_user(&self, user_uuid: Uuid) -> RepositoryResult<i64> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::count_for_user(&mut conn, user_uuid).await?)
+    }
+
+    async fn update(&self, phone_uuid: Uuid, type_: Option<String>, status: Option<models::PhoneStatusEnum>) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        let phone = conn.transaction::<_, DieselError, _>(|conn| async move {
+            let mut phone = models::Phone::find_by_uuid(conn, phone_uuid).await?;
+
+            if let Some(type_) = type_ {
+                phone = models::Phone::update_type(conn, phone_uuid, type_).await?;
+            }
+
+            if let Some(status) = status {
+                phone = models::Phone::update_status(conn, phone_uuid, status).await?;
+            }
+
+            Ok(phone)
+        }.scope_boxed()).await?;
+
+        Ok(phone)
+    }
+
+    async fn set_primary(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::set_primary(&mut conn, phone_uuid).await?)
+    }
+
+    async fn delete(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        Ok(models::Phone::delete(&mut conn, phone_uuid).await?)
+    }
+
+    async fn find_sms_sent_to_number_since(&self, phone_number: &str, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        let mut conn = self.conn().await?;
+        Ok(models::SmsSend::find_sent_to_number_since(&mut conn, phone_number, since).await?)
+    }
+
+    async fn find_sms_sent_for_ip_since(&self, ip_address: IpNet, since: DateTime<Utc>) -> RepositoryResult<Vec<DateTime<Utc>>> {
+        let mut conn = self.conn().await?;
+        Ok(models::SmsSend::find_sent_for_ip_since(&mut conn, ip_address, since).await?)
+    }
+
+    async fn replace_verification_code(
+        &self,
+        new_code: models::NewPhoneVerificationCode,
+        sms_send: models::NewSmsSend,
+    ) -> RepositoryResult<models::PhoneVerificationCode> {
+        let mut conn = self.conn().await?;
+        let code = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::PhoneVerificationCode::consume_all_for_phone(conn, new_code.phone_uuid).await?;
+            let code = models::PhoneVerificationCode::create(conn, new_code).await?;
+            models::SmsSend::record(conn, sms_send).await?;
+            Ok(code)
+        }.scope_boxed()).await?;
+
+        Ok(code)
+    }
+
+    async fn find_latest_verification_code(&self, phone_uuid: Uuid) -> RepositoryResult<Option<models::PhoneVerificationCode>> {
+        let mut conn = self.conn().await?;
+        Ok(models::PhoneVerificationCode::find_latest_active(&mut conn, phone_uuid).await?)
+    }
+
+    async fn claim_verification_attempt(&self, code_uuid: Uuid, max_attempts: i32) -> RepositoryResult<Option<models::PhoneVerificationCode>> {
+        let mut conn = self.conn().await?;
+        Ok(models::PhoneVerificationCode::claim_attempt(&mut conn, code_uuid, max_attempts).await?)
+    }
+
+    async fn burn_verification_codes(&self, phone_uuid: Uuid) -> RepositoryResult<()> {
+        let mut conn = self.conn().await?;
+        models::PhoneVerificationCode::consume_all_for_phone(&mut conn, phone_uuid).await?;
+        Ok(())
+    }
+
+    async fn mark_verified(&self, phone_uuid: Uuid) -> RepositoryResult<models::Phone> {
+        let mut conn = self.conn().await?;
+        let phone = conn.transaction::<_, DieselError, _>(|conn| async move {
+            models::PhoneVerificationCode::consume_all_for_phone(conn, phone_uuid).await?;
+            let phone = models::Phone::update_status(conn, phone_uuid, models::PhoneStatusEnum::Verified).await?;
+
+            models::AuditEvent::record(conn, audit_event(Some(phone.user_uuid), "phone.verified", json!({
+                "phone_id": phone_uuid,
+                "method": "code",
+            }))).await?;
+
+            Ok(phone)
+        }.scope_boxed()).await?;
+
+        Ok(phone)
+    }
+}
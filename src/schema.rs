@@ -1,6 +1,14 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "attribute_type_enum"))]
+    pub struct AttributeTypeEnum;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "attribute_visibility_enum"))]
+    pub struct AttributeVisibilityEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "device_status_enum"))]
     pub struct DeviceStatusEnum;
@@ -9,10 +17,22 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "device_type_enum"))]
     pub struct DeviceTypeEnum;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "email_change_status_enum"))]
+    pub struct EmailChangeStatusEnum;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "email_delivery_status_enum"))]
+    pub struct EmailDeliveryStatusEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "email_status_enum"))]
     pub struct EmailStatusEnum;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "email_suppression_reason_enum"))]
+    pub struct EmailSuppressionReasonEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "membership_invitation_status_enum"))]
     pub struct MembershipInvitationStatusEnum;
@@ -21,6 +41,10 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "membership_status_enum"))]
     pub struct MembershipStatusEnum;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "mfa_challenge_status_enum"))]
+    pub struct MfaChallengeStatusEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "os_enum"))]
     pub struct OsEnum;
@@ -29,11 +53,31 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "phone_status_enum"))]
     pub struct PhoneStatusEnum;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "user_data_job_status_enum"))]
+    pub struct UserDataJobStatusEnum;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "user_data_job_type_enum"))]
+    pub struct UserDataJobTypeEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "user_status_enum"))]
     pub struct UserStatusEnum;
 }
 
+diesel::table! {
+    audit_events (event_uuid) {
+        event_uuid -> Uuid,
+        user_uuid -> Nullable<Uuid>,
+        #[max_length = 100]
+        event_type -> Varchar,
+        ip_address -> Nullable<Inet>,
+        metadata -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::DeviceTypeEnum;
@@ -61,6 +105,88 @@ diesel::table! {
         notification_token -> Nullable<Text>,
         notification_enabled -> Nullable<Bool>,
         metadata -> Jsonb,
+        user_uuid -> Nullable<Uuid>,
+        #[max_length = 128]
+        client_device_id -> Nullable<Varchar>,
+        #[max_length = 64]
+        user_agent_hash -> Nullable<Varchar>,
+        ip_prefix -> Nullable<Inet>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::EmailChangeStatusEnum;
+
+    email_change_requests (request_uuid) {
+        request_uuid -> Uuid,
+        user_uuid -> Uuid,
+        old_email_uuid -> Nullable<Uuid>,
+        new_email_uuid -> Nullable<Uuid>,
+        #[max_length = 35]
+        locale -> Varchar,
+        status -> EmailChangeStatusEnum,
+        #[max_length = 64]
+        cancel_token_hash -> Varchar,
+        #[max_length = 64]
+        revert_token_hash -> Nullable<Varchar>,
+        expires_at -> Timestamptz,
+        undo_expires_at -> Nullable<Timestamptz>,
+        completed_at -> Nullable<Timestamptz>,
+        cancelled_at -> Nullable<Timestamptz>,
+        reverted_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::EmailDeliveryStatusEnum;
+
+    email_deliveries (delivery_uuid) {
+        delivery_uuid -> Uuid,
+        #[max_length = 255]
+        recipient -> Varchar,
+        subject -> Text,
+        text_body -> Text,
+        html_body -> Text,
+        status -> EmailDeliveryStatusEnum,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        sent_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::EmailSuppressionReasonEnum;
+
+    email_suppressions (normalized_value) {
+        #[max_length = 255]
+        normalized_value -> Varchar,
+        reason -> EmailSuppressionReasonEnum,
+        #[max_length = 50]
+        source -> Varchar,
+        diagnostic -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (token_uuid) {
+        token_uuid -> Uuid,
+        email_uuid -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        attempts -> Int4,
+        code_expires_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        consumed_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
     }
 }
 
@@ -81,6 +207,8 @@ diesel::table! {
         metadata -> Jsonb,
         created_at -> Nullable<Timestamptz>,
         updated_at -> Nullable<Timestamptz>,
+        #[max_length = 255]
+        normalized_value -> Varchar,
     }
 }
 
@@ -104,6 +232,29 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::MfaChallengeStatusEnum;
+
+    mfa_challenges (challenge_uuid) {
+        challenge_uuid -> Uuid,
+        user_uuid -> Uuid,
+        device_uuid -> Uuid,
+        ip_address -> Inet,
+        #[max_length = 20]
+        factor -> Varchar,
+        #[max_length = 64]
+        code_hash -> Nullable<Varchar>,
+        attempts -> Int4,
+        status -> MfaChallengeStatusEnum,
+        expires_at -> Timestamptz,
+        completed_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        responder_device_uuid -> Nullable<Uuid>,
+        consumed_at -> Nullable<Timestamptz>,
+    }
+}
+
 diesel::table! {
     organizations (org_uuid) {
         org_uuid -> Uuid,
@@ -124,6 +275,19 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    phone_verification_codes (code_uuid) {
+        code_uuid -> Uuid,
+        phone_uuid -> Uuid,
+        #[max_length = 64]
+        code_hash -> Varchar,
+        attempts -> Int4,
+        expires_at -> Timestamptz,
+        consumed_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::PhoneStatusEnum;
@@ -151,6 +315,16 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    revoked_tokens (jti) {
+        jti -> Uuid,
+        user_uuid -> Uuid,
+        session_uuid -> Nullable<Uuid>,
+        expires_at -> Timestamptz,
+        revoked_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     role_permissions (role_uuid, permission_uuid) {
         role_uuid -> Uuid,
@@ -170,6 +344,23 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    session_policies (policy_uuid) {
+        policy_uuid -> Uuid,
+        role_uuid -> Nullable<Uuid>,
+        org_uuid -> Nullable<Uuid>,
+        idle_timeout -> Nullable<Int4>,
+        sliding_lifetime -> Nullable<Int4>,
+        absolute_lifetime -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        max_sessions -> Nullable<Int4>,
+        max_sessions_per_device_type -> Nullable<Int4>,
+        #[max_length = 20]
+        limit_action -> Nullable<Varchar>,
+    }
+}
+
 diesel::table! {
     sessions (session_uuid) {
         session_uuid -> Uuid,
@@ -183,6 +374,74 @@ diesel::table! {
         invalidated_at -> Nullable<Timestamptz>,
         revoked_at -> Nullable<Timestamptz>,
         created_at -> Nullable<Timestamptz>,
+        idle_timeout -> Nullable<Int4>,
+        sliding_lifetime -> Nullable<Int4>,
+        idle_expires_at -> Nullable<Timestamptz>,
+        absolute_expires_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    sms_sends (send_uuid) {
+        send_uuid -> Uuid,
+        #[max_length = 30]
+        phone_number -> Varchar,
+        ip_address -> Nullable<Inet>,
+        #[max_length = 50]
+        purpose -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    trusted_devices (trust_uuid) {
+        trust_uuid -> Uuid,
+        user_uuid -> Uuid,
+        device_uuid -> Uuid,
+        expires_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+        revoked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::AttributeTypeEnum;
+    use super::sql_types::AttributeVisibilityEnum;
+
+    user_attribute_definitions (attribute_uuid) {
+        attribute_uuid -> Uuid,
+        #[max_length = 64]
+        key -> Varchar,
+        attribute_type -> AttributeTypeEnum,
+        description -> Nullable<Text>,
+        is_required -> Bool,
+        is_unique -> Bool,
+        is_indexed -> Bool,
+        visibility -> AttributeVisibilityEnum,
+        enum_values -> Nullable<Jsonb>,
+        pattern -> Nullable<Text>,
+        created_at -> Nullable<Timestamptz>,
+        updated_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::UserDataJobTypeEnum;
+    use super::sql_types::UserDataJobStatusEnum;
+
+    user_data_jobs (job_uuid) {
+        job_uuid -> Uuid,
+        user_uuid -> Uuid,
+        job_type -> UserDataJobTypeEnum,
+        status -> UserDataJobStatusEnum,
+        result -> Nullable<Jsonb>,
+        error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        completed_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -211,30 +470,59 @@ diesel::table! {
         archived_at -> Nullable<Timestamp>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        #[max_length = 255]
+        username_skeleton -> Nullable<Varchar>,
+        email_update_required -> Bool,
+        mfa_enabled -> Bool,
     }
 }
 
+diesel::joinable!(audit_events -> users (user_uuid));
+diesel::joinable!(devices -> users (user_uuid));
+diesel::joinable!(email_change_requests -> users (user_uuid));
+diesel::joinable!(email_verification_tokens -> emails (email_uuid));
 diesel::joinable!(emails -> users (user_uuid));
 diesel::joinable!(membership -> organizations (org_uuid));
 diesel::joinable!(membership -> roles (role_uuid));
 diesel::joinable!(membership -> users (user_uuid));
+diesel::joinable!(mfa_challenges -> users (user_uuid));
+diesel::joinable!(phone_verification_codes -> phones (phone_uuid));
 diesel::joinable!(phones -> users (user_uuid));
+diesel::joinable!(revoked_tokens -> users (user_uuid));
 diesel::joinable!(role_permissions -> permissions (permission_uuid));
 diesel::joinable!(role_permissions -> roles (role_uuid));
+diesel::joinable!(session_policies -> organizations (org_uuid));
+diesel::joinable!(session_policies -> roles (role_uuid));
 diesel::joinable!(sessions -> users (user_uuid));
+diesel::joinable!(trusted_devices -> devices (device_uuid));
+diesel::joinable!(trusted_devices -> users (user_uuid));
+diesel::joinable!(user_data_jobs -> users (user_uuid));
 diesel::joinable!(user_roles -> roles (role_uuid));
 diesel::joinable!(user_roles -> users (user_uuid));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    audit_events,
     devices,
+    email_change_requests,
+    email_deliveries,
+    email_suppressions,
+    email_verification_tokens,
     emails,
     membership,
+    mfa_challenges,
     organizations,
     permissions,
+    phone_verification_codes,
     phones,
+    revoked_tokens,
     role_permissions,
     roles,
+    session_policies,
     sessions,
+    sms_sends,
+    trusted_devices,
+    user_attribute_definitions,
+    user_data_jobs,
     user_roles,
     users,
 );
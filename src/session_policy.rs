@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::models;
+use crate::repository::{RepositoryResult, SessionRepository};
+
+/// Uses closer together than this don't update `last_accessed_at`, so
+/// busy clients don't write on every request.
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+/// Live sessions looked at when enforcing concurrency limits.
+pub const MAX_COUNTED_SESSIONS: i64 = 1000;
+
+/// Stored in the metadata of sessions evicted to make room for a new one.
+pub const EVICTION_REASON: &str = "session_limit";
+
+/// What happens to a login that would go over a concurrency limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitAction {
+    Reject,
+    EvictOldest,
+}
+
+impl std::str::FromStr for LimitAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(LimitAction::Reject),
+            "evict_oldest" => Ok(LimitAction::EvictOldest),
+            value => Err(format!("Unknown session limit action '{}', expected 'reject' or 'evict_oldest'", value)),
+        }
+    }
+}
+
+/// Lifetimes of a user's sessions, in seconds, and how many they can have
+/// at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionLimits {
+    pub idle_timeout: Option<i32>,
+    pub sliding_lifetime: i32,
+    pub absolute_lifetime: i32,
+    pub max_sessions: Option<i32>,
+    pub max_sessions_per_device_type: Option<i32>,
+    pub limit_action: LimitAction,
+}
+
+/// When a session started now ends.
+pub struct SessionDeadlines {
+    pub expires_at: DateTime<Utc>,
+    pub idle_expires_at: Option<DateTime<Utc>>,
+    pub absolute_expires_at: DateTime<Utc>,
+}
+
+/// Clamps a non-negative config value (durations in seconds, session counts)
+/// into the `INTEGER` columns it is stored in.
+fn clamp_i32(value: i64) -> i32 {
+    value.clamp(0, i32::MAX as i64) as i32
+}
+
+fn min_limit(limit: Option<i32>, other: Option<i32>) -> Option<i32> {
+    match (limit, other) {
+        (Some(limit), Some(other)) => Some(limit.min(other)),
+        (limit, other) => limit.or(other),
+    }
+}
+
+impl SessionLimits {
+    pub fn from_config(config: &Config) -> Self {
+        SessionLimits {
+            idle_timeout: Some(clamp_i32(config.session_idle_timeout)).filter(|timeout| *timeout > 0),
+            sliding_lifetime: clamp_i32(config.session_sliding_lifetime),
+            absolute_lifetime: clamp_i32(config.session_absolute_lifetime),
+            max_sessions: Some(clamp_i32(config.session_max_per_user)).filter(|max| *max > 0),
+            max_sessions_per_device_type: Some(clamp_i32(config.session_max_per_device_type)).filter(|max| *max > 0),
+            // Checked at startup.
+            limit_action: config.session_limit_action.parse().unwrap_or(LimitAction::EvictOldest),
+        }
+    }
+
+    /// Tightens the limits with a role or organization policy. A user
+    /// subject to several policies gets the strictest value of each, and
+    /// rejection wins over eviction.
+    fn restrict(self, policy: &models::SessionPolicy) -> Self {
+        let limit_action = match policy.limit_action.as_deref().map(str::parse) {
+            Some(Ok(LimitAction::Reject)) => LimitAction::Reject,
+            _ => self.limit_action,
+        };
+
+        SessionLimits {
+            idle_timeout: min_limit(self.idle_timeout, policy.idle_timeout),
+            sliding_lifetime: policy.sliding_lifetime.map_or(self.sliding_lifetime, |lifetime| lifetime.min(self.sliding_lifetime)),
+            absolute_lifetime: policy.absolute_lifetime.map_or(self.absolute_lifetime, |lifetime| lifetime.min(self.absolute_lifetime)),
+            max_sessions: min_limit(self.max_sessions, policy.max_sessions),
+            max_sessions_per_device_type: min_limit(self.max_sessions_per_device_type, policy.max_sessions_per_device_type),
+            limit_action,
+        }
+    }
+
+    /// Whether the user can only have so many sessions at once.
+    pub fn limits_concurrency(&self) -> bool {
+        self.max_sessions.is_some() || self.max_sessions_per_device_type.is_some()
+    }
+
+    pub fn deadlines(&self, now: DateTime<Utc>) -> SessionDeadlines {
+        let absolute_expires_at = now + Duration::seconds(self.absolute_lifetime as i64);
+
+        SessionDeadlines {
+            expires_at: (now + Duration::seconds(self.sliding_lifetime as i64)).min(absolute_expires_at),
+            idle_expires_at: self.idle_timeout.map(|timeout| now + Duration::seconds(timeout as i64)),
+            absolute_expires_at,
+        }
+    }
+}
+
+/// The limits of sessions the user starts: the configured defaults,
+/// tightened by the policies of their roles and organizations.
+pub async fn resolve_limits(
+    sessions: &dyn SessionRepository,
+    config: &Config,
+    user_uuid: Uuid,
+) -> RepositoryResult<SessionLimits> {
+    let policies = sessions.find_policies(user_uuid).await?;
+
+    Ok(policies
+        .iter()
+        .fold(SessionLimits::from_config(config), |limits, policy| limits.restrict(policy)))
+}
+
+/// Whether the session can still be used: neither revoked, invalidated,
+/// expired, idle for too long nor past its absolute lifetime.
+///
+/// The reaper eventually marks dead sessions inactive, this doesn't wait for it.
+pub fn is_live(session: &models::Session, now: DateTime<Utc>) -> bool {
+    session.is_active
+        && session.revoked_at.is_none()
+        && session.invalidated_at.is_none()
+        && session.expires_at > now
+        && session.idle_expires_at.is_none_or(|at| at > now)
+        && session.absolute_expires_at.is_none_or(|at| at > now)
+}
+
+/// When a live session ends if it isn't used or refreshed before.
+pub fn live_until(session: &models::Session) -> DateTime<Utc> {
+    [session.idle_expires_at, session.absolute_expires_at]
+        .into_iter()
+        .flatten()
+        .fold(session.expires_at, DateTime::min)
+}
+
+/// The sessions to evict for a new session of the user on a device of
+/// `device_type` to fit under the concurrency limits, oldest first.
+/// `sessions` are the user's sessions with their devices.
+///
+/// The per device type limit is applied first, sessions it evicts also
+/// make room under the per user limit. If the limits say to reject the
+/// login rather than evict, any session returned means rejection.
+pub fn select_evictions(
+    limits: &SessionLimits,
+    sessions: &[(models::Session, Option<models::Device>)],
+    device_type: &models::DeviceTypeEnum,
+    now: DateTime<Utc>,
+) -> Vec<Uuid> {
+    let mut sessions: Vec<&(models::Session, Option<models::Device>)> = sessions
+        .iter()
+        .filter(|(session, _)| is_live(session, now))
+        .collect();
+    sessions.sort_by_key(|(session, _)| session.created_at);
+
+    let mut evicted: Vec<Uuid> = vec![];
+
+    if let Some(max) = limits.max_sessions_per_device_type {
+        let same_type: Vec<Uuid> = sessions
+            .iter()
+            .filter(|(_, device)| device.as_ref().is_some_and(|device| &device.device_type == device_type))
+            .map(|(session, _)| session.session_uuid)
+            .collect();
+        let excess = (same_type.len() + 1).saturating_sub(max as usize);
+        evicted.extend(same_type.into_iter().take(excess));
+    }
+
+    if let Some(max) = limits.max_sessions {
+        let remaining: Vec<Uuid> = sessions
+            .iter()
+            .map(|(session, _)| session.session_uuid)
+            .filter(|session_uuid| !evicted.contains(session_uuid))
+            .collect();
+        let excess = (remaining.len() + 1).saturating_sub(max as usize);
+        evicted.extend(remaining.into_iter().take(excess));
+    }
+
+    evicted
+}
+
+/// Records that the session was used, e.g. to validate an access token.
+pub async fn touch(sessions: &dyn SessionRepository, session: &models::Session) -> RepositoryResult<()> {
+    let now = Utc::now();
+
+    let recently_touched = session.last_accessed_at
+        .is_some_and(|at| now - at < Duration::seconds(TOUCH_INTERVAL_SECONDS));
+    if recently_touched {
+        return Ok(());
+    }
+
+    let idle_expires_at = session.idle_timeout.map(|timeout| now + Duration::seconds(timeout as i64));
+    sessions.touch(session.session_uuid, idle_expires_at).await
+}
+
+/// Slides the expiry of a live session on refresh, never past its absolute
+/// lifetime. Sessions started without a sliding lifetime keep their expiry.
+pub async fn renew(sessions: &dyn SessionRepository, session: &models::Session) -> RepositoryResult<models::Session> {
+    let now = Utc::now();
+
+    let expires_at = match session.sliding_lifetime {
+        Some(lifetime) => {
+            let slid = now + Duration::seconds(lifetime as i64);
+            session.absolute_expires_at.map_or(slid, |absolute| slid.min(absolute))
+        }
+        None => session.expires_at,
+    };
+    let idle_expires_at = session.idle_timeout.map(|timeout| now + Duration::seconds(timeout as i64));
+
+    sessions.renew(session.session_uuid, expires_at, idle_expires_at).await
+}
+
+/// Marks stale sessions inactive in the background for as long as the
+/// server runs, so listings and audits don't show them as signed in. They
+/// are dropped from the cache too.
+pub fn spawn_session_reaper(database: DbPool, cache: Arc<Cache>, config: &Config) {
+    let interval = StdDuration::from_secs(config.session_reaper_interval.max(1));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            let outcome = match database.get().await {
+                Ok(mut conn) => models::Session::invalidate_stale(&mut conn).await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            match outcome {
+                Ok(invalidated) if invalidated.is_empty() => {}
+                Ok(invalidated) => {
+                    cache.forget_sessions(&invalidated).await;
+                    println!("Invalidated {} stale sessions", invalidated.len());
+                }
+                Err(e) => println!("Error invalidating stale sessions: {}", e),
+            }
+        }
+    });
+}
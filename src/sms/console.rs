@@ -0,0 +1,11 @@
+use super::{OutgoingSms, SmsError, SmsSender};
+
+/// Prints messages to stdout instead of sending them, for development.
+pub struct LogSmsSender;
+
+impl SmsSender for LogSmsSender {
+    fn send(&self, sms: &OutgoingSms) -> Result<(), SmsError> {
+        println!("SMS to {}: {}", sms.to, sms.body);
+        Ok(())
+    }
+}
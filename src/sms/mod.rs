@@ -0,0 +1,81 @@
+mod console;
+mod policy;
+mod templates;
+mod webhook;
+
+use std::sync::Arc;
+
+pub use console::*;
+pub use policy::*;
+pub use templates::*;
+pub use webhook::*;
+
+use crate::config::Config;
+
+/// A rendered text message, ready to be handed to a provider.
+#[derive(Debug, Clone)]
+pub struct OutgoingSms {
+    /// Recipient in E.164 form.
+    pub to: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum SmsError {
+    /// The message may go through later, e.g. the provider was unreachable.
+    Transient(String),
+    /// Retrying won't help, e.g. the provider rejected the number.
+    Permanent(String),
+}
+
+impl std::fmt::Display for SmsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmsError::Transient(message) => write!(f, "Transient SMS error: {}", message),
+            SmsError::Permanent(message) => write!(f, "Permanent SMS error: {}", message),
+        }
+    }
+}
+
+/// An outbound SMS provider.
+///
+/// Senders are blocking, run them on the blocking thread pool.
+pub trait SmsSender: Send + Sync {
+    fn send(&self, sms: &OutgoingSms) -> Result<(), SmsError>;
+}
+
+/// Builds the provider selected by `SMS_TRANSPORT`.
+pub fn sms_sender_from_config(config: &Config) -> Result<Arc<dyn SmsSender>, String> {
+    match config.sms_transport.as_str() {
+        "webhook" => Ok(Arc::new(WebhookSmsSender::new(config))),
+        "log" => Ok(Arc::new(LogSmsSender)),
+        transport => Err(format!("Unsupported SMS transport '{}'", transport)),
+    }
+}
+
+/// Everything needed to send a text: the provider, the templates and the
+/// country policy.
+pub struct SmsGateway {
+    pub sender: Arc<dyn SmsSender>,
+    pub templates: SmsTemplates,
+    pub policy: SmsPolicy,
+}
+
+impl SmsGateway {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(SmsGateway {
+            sender: sms_sender_from_config(config)?,
+            templates: SmsTemplates::load(&config.sms_templates_dir, &config.sms_default_locale)?,
+            policy: SmsPolicy::from_config(config)?,
+        })
+    }
+
+    /// Sends a message on the blocking thread pool.
+    pub async fn send(&self, sms: OutgoingSms) -> Result<(), SmsError> {
+        let sender = self.sender.clone();
+
+        tokio::task::spawn_blocking(move || sender.send(&sms))
+            .await
+            .map_err(|e| SmsError::Transient(format!("SMS sender panicked: {}", e)))?
+    }
+}
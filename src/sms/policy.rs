@@ -0,0 +1,127 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::config::Config;
+
+/// Which countries SMS may be sent to.
+///
+/// A region is allowed when it isn't denied, is in the allow list (if there
+/// is one) and doesn't cost more than the maximum cost per message.
+pub struct SmsPolicy {
+    pub allowed_countries: HashSet<String>,
+    pub denied_countries: HashSet<String>,
+    pub costs: HashMap<String, f64>,
+    pub default_cost: Option<f64>,
+    pub max_cost: Option<f64>,
+}
+
+fn parse_regions(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(|region| region.trim().to_uppercase())
+        .filter(|region| !region.is_empty())
+        .collect()
+}
+
+impl SmsPolicy {
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let mut costs = HashMap::new();
+
+        for pair in config.sms_country_costs.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (region, cost) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid SMS_COUNTRY_COSTS entry '{}', expected REGION:cost", pair))?;
+            let cost = cost
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("Invalid cost in SMS_COUNTRY_COSTS entry '{}'", pair))?;
+
+            costs.insert(region.trim().to_uppercase(), cost);
+        }
+
+        Ok(SmsPolicy {
+            allowed_countries: parse_regions(&config.sms_allowed_countries),
+            denied_countries: parse_regions(&config.sms_denied_countries),
+            costs,
+            default_cost: config.sms_default_cost,
+            max_cost: config.sms_max_cost,
+        })
+    }
+
+    /// Checks whether SMS can be sent to numbers of `region`.
+    ///
+    /// Numbers that don't map to a single region (e.g. shared calling codes)
+    /// are only accepted when no allow list or cost limit applies.
+    pub fn check(&self, region: Option<&str>) -> Result<(), String> {
+        let Some(region) = region.map(str::to_uppercase) else {
+            if self.allowed_countries.is_empty() && self.max_cost.is_none() {
+                return Ok(());
+            }
+            return Err("SMS can't be sent to numbers without a known country".to_string());
+        };
+
+        if self.denied_countries.contains(&region) {
+            return Err(format!("SMS can't be sent to {}", region));
+        }
+
+        if !self.allowed_countries.is_empty() && !self.allowed_countries.contains(&region) {
+            return Err(format!("SMS can't be sent to {}", region));
+        }
+
+        if let Some(max_cost) = self.max_cost {
+            match self.costs.get(&region).copied().or(self.default_cost) {
+                Some(cost) if cost <= max_cost => {}
+                Some(_) => return Err(format!("SMS to {} cost more than allowed", region)),
+                None => return Err(format!("SMS cost to {} is unknown", region)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn policy(overrides: &[(&str, &str)]) -> SmsPolicy {
+        SmsPolicy::from_config(&testing::config(overrides)).unwrap()
+    }
+
+    #[test]
+    fn every_country_is_allowed_by_default() {
+        let policy = policy(&[]);
+        assert!(policy.check(Some("GB")).is_ok());
+        assert!(policy.check(None).is_ok());
+    }
+
+    #[test]
+    fn denied_countries_win_over_the_allow_list() {
+        let policy = policy(&[("SMS_ALLOWED_COUNTRIES", "gb, us"), ("SMS_DENIED_COUNTRIES", "US")]);
+
+        assert!(policy.check(Some("gb")).is_ok());
+        assert_eq!(policy.check(Some("US")).unwrap_err(), "SMS can't be sent to US");
+        assert_eq!(policy.check(Some("FR")).unwrap_err(), "SMS can't be sent to FR");
+        assert!(policy.check(None).is_err());
+    }
+
+    #[test]
+    fn countries_costing_more_than_the_maximum_are_refused() {
+        let policy = policy(&[
+            ("SMS_COUNTRY_COSTS", "US:0.008, GB:0.04"),
+            ("SMS_MAX_COST", "0.02"),
+        ]);
+
+        assert!(policy.check(Some("US")).is_ok());
+        assert_eq!(policy.check(Some("GB")).unwrap_err(), "SMS to GB cost more than allowed");
+        assert_eq!(policy.check(Some("FR")).unwrap_err(), "SMS cost to FR is unknown");
+
+        let policy = self::policy(&[("SMS_MAX_COST", "0.02"), ("SMS_DEFAULT_COST", "0.01")]);
+        assert!(policy.check(Some("FR")).is_ok());
+    }
+
+    #[test]
+    fn malformed_costs_are_rejected() {
+        let config = testing::config(&[("SMS_COUNTRY_COSTS", "US=0.008")]);
+        assert!(SmsPolicy::from_config(&config).is_err());
+    }
+}
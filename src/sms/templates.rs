@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::mailer::substitute;
+
+/// Templates the server sends, they must exist for the default locale.
+pub const REQUIRED_SMS_TEMPLATES: &[&str] = &["verify_phone", "mfa_code"];
+
+/// Per-locale message bodies loaded from `<dir>/<locale>/<name>.txt`.
+///
+/// Lookups fall back from `fr-CA` to `fr` and then to the default locale,
+/// like email templates.
+pub struct SmsTemplates {
+    templates: HashMap<(String, String), String>,
+    default_locale: String,
+}
+
+impl SmsTemplates {
+    pub fn load(dir: &str, default_locale: &str) -> Result<Self, String> {
+        let mut templates = HashMap::new();
+        let locales = fs::read_dir(dir).map_err(|e| format!("Error reading SMS templates from {}: {}", dir, e))?;
+
+        for locale in locales {
+            let locale = locale.map_err(|e| e.to_string())?.path();
+            if !locale.is_dir() {
+                continue;
+            }
+
+            let locale_name = locale.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+
+            for file in fs::read_dir(&locale).map_err(|e| e.to_string())? {
+                let path = file.map_err(|e| e.to_string())?.path();
+                let Some(name) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".txt")) else {
+                    continue;
+                };
+
+                let body = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+                templates.insert((locale_name.clone(), name.to_string()), body.trim().to_string());
+            }
+        }
+
+        let default_locale = default_locale.to_lowercase();
+
+        for name in REQUIRED_SMS_TEMPLATES {
+            if !templates.contains_key(&(default_locale.clone(), name.to_string())) {
+                return Err(format!("SMS template '{}' is missing for locale '{}'", name, default_locale));
+            }
+        }
+
+        Ok(SmsTemplates { templates, default_locale })
+    }
+
+    /// Renders a template.
+    ///
+    /// Only `REQUIRED_SMS_TEMPLATES` are sure to exist, any other template
+    /// may be missing for every locale.
+    pub fn render(&self, name: &str, locale: &str, variables: &[(&str, &str)]) -> Result<String, String> {
+        let locale = locale.to_lowercase().replace('_', "-");
+        let language = locale.split('-').next().unwrap_or_default().to_string();
+
+        let template = [locale, language, self.default_locale.clone()]
+            .into_iter()
+            .find_map(|locale| self.templates.get(&(locale, name.to_string())))
+            .ok_or_else(|| format!("SMS template '{}' is missing", name))?;
+
+        let variables: Vec<(&str, String)> = variables.iter().map(|(name, value)| (*name, value.to_string())).collect();
+        Ok(substitute(template, &variables))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texts_are_rendered_in_the_closest_locale() {
+        let templates = SmsTemplates::load("templates/sms", "en").unwrap();
+        let variables = [("code", "123456"), ("minutes", "10")];
+
+        assert_eq!(
+            templates.render("verify_phone", "fr-BE", &variables).unwrap(),
+            "Votre code de vérification est 123456. Il expire dans 10 minutes.",
+        );
+        assert!(templates.render("verify_phone", "ja", &variables).unwrap().starts_with("Your verification code is 123456."));
+        assert_eq!(templates.render("welcome", "en", &[]).unwrap_err(), "SMS template 'welcome' is missing");
+    }
+}
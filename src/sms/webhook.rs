@@ -0,0 +1,46 @@
+use std::time::Duration;
+use serde_json::json;
+
+use super::{OutgoingSms, SmsError, SmsSender};
+use crate::config::Config;
+
+/// POSTs every message as JSON to `SMS_WEBHOOK_URL`.
+///
+/// The receiving end is expected to relay it to a real provider, which keeps
+/// provider specifics out of the server and lets a local stub stand in for it.
+pub struct WebhookSmsSender {
+    agent: ureq::Agent,
+    url: String,
+    token: Option<String>,
+}
+
+impl WebhookSmsSender {
+    pub fn new(config: &Config) -> Self {
+        WebhookSmsSender {
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(10)).build(),
+            url: config.sms_webhook_url.clone(),
+            token: config.sms_webhook_token.clone(),
+        }
+    }
+}
+
+impl SmsSender for WebhookSmsSender {
+    fn send(&self, sms: &OutgoingSms) -> Result<(), SmsError> {
+        let mut request = self.agent.post(&self.url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        match request.send_json(json!({ "to": sms.to, "body": sms.body })) {
+            Ok(_) => Ok(()),
+            // Rate limiting and server errors can go away, other client errors won't.
+            Err(ureq::Error::Status(status, response)) if status == 429 || status >= 500 => {
+                Err(SmsError::Transient(format!("{} {}", status, response.status_text())))
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                Err(SmsError::Permanent(format!("{} {}", status, response.status_text())))
+            }
+            Err(e) => Err(SmsError::Transient(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Fixtures shared by the unit tests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use envconfig::Envconfig;
+use chrono::{Duration, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::mailer::Mailer;
+use crate::models;
+use crate::privacy::JobRunner;
+use crate::push::{PushError, PushGateway, PushNotification, PushSender};
+use crate::repository::{DeviceRepository, EmailRepository, MemoryRepository, PhoneRepository, UserRepository};
+use crate::sms::{OutgoingSms, SmsError, SmsGateway, SmsPolicy, SmsSender, SmsTemplates};
+use crate::utils::EmailPolicy;
+
+/// The default configuration with `overrides`, without GeoIP databases.
+pub fn config(overrides: &[(&str, &str)]) -> Config {
+    let mut env: HashMap<String, String> = HashMap::from([
+        ("DATABASE_URL".to_string(), "postgres://localhost/ingot_test".to_string()),
+        ("GEOIP_CITY_DATABASE".to_string(), "".to_string()),
+        ("GEOIP_ASN_DATABASE".to_string(), "".to_string()),
+    ]);
+
+    for (key, value) in overrides {
+        env.insert(key.to_string(), value.to_string());
+    }
+
+    Config::init_from_hashmap(&env).unwrap()
+}
+
+/// An in-process cache.
+pub fn cache(config: &Config) -> Arc<Cache> {
+    Arc::new(Cache::new(config).unwrap())
+}
+
+pub async fn create_user(repository: &MemoryRepository, username: &str) -> models::User {
+    UserRepository::create(repository, models::NewUser {
+        username: username.to_string(),
+        username_skeleton: crate::utils::username_skeleton(username),
+        password_hash: crate::utils::hash_new_password("correct horse battery staple").unwrap(),
+        metadata: json!({}),
+    }).await.unwrap()
+}
+
+/// A live session of the user on the device, used a minute ago.
+pub fn session(user_uuid: Uuid, device_uuid: Uuid) -> models::Session {
+    let now = Utc::now();
+
+    models::Session {
+        session_uuid: Uuid::new_v4(),
+        user_uuid,
+        device_uuid: Some(device_uuid),
+        ip_address: "203.0.113.7/32".parse().unwrap(),
+        metadata: json!({}),
+        last_accessed_at: Some(now - Duration::minutes(1)),
+        is_active: true,
+        expires_at: now + Duration::days(30),
+        invalidated_at: None,
+        revoked_at: None,
+        created_at: Some(now - Duration::hours(1)),
+        idle_timeout: None,
+        sliding_lifetime: None,
+        idle_expires_at: None,
+        absolute_expires_at: None,
+    }
+}
+
+/// A desktop device of the user, registered from `203.0.113.7`.
+pub async fn create_device(repository: &MemoryRepository, user_uuid: Option<Uuid>) -> models::Device {
+    DeviceRepository::create(repository, models::NewDevice {
+        user_uuid,
+        device_type: models::DeviceTypeEnum::Desktop,
+        os: models::OsEnum::Linux,
+        os_version: None,
+        browser: None,
+        browser_version: None,
+        ip_address: "203.0.113.7/32".parse().unwrap(),
+        location: None,
+        status: models::DeviceStatusEnum::Active,
+        mac_address: None,
+        client_device_id: Some(Uuid::new_v4().to_string()),
+        user_agent_hash: None,
+        ip_prefix: None,
+    }, json!({})).await.unwrap()
+}
+
+/// A phone of the user, verified unless `verified` is false.
+pub async fn create_phone(repository: &MemoryRepository, user_uuid: Uuid, number: &str, verified: bool) -> models::Phone {
+    let phone = PhoneRepository::add_to_user(repository, user_uuid, crate::utils::normalize_full_phone(number).unwrap()).await.unwrap();
+
+    match verified {
+        true => PhoneRepository::update(repository, phone.phone_uuid, None, Some(models::PhoneStatusEnum::Verified)).await.unwrap(),
+        false => phone,
+    }
+}
+
+/// An SMS provider that keeps what it is handed.
+#[derive(Default)]
+pub struct StubSmsSender {
+    sent: Mutex<Vec<OutgoingSms>>,
+}
+
+impl StubSmsSender {
+    pub fn sent(&self) -> Vec<OutgoingSms> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl SmsSender for StubSmsSender {
+    fn send(&self, sms: &OutgoingSms) -> Result<(), SmsError> {
+        self.sent.lock().unwrap().push(sms.clone());
+        Ok(())
+    }
+}
+
+/// A gateway with the shipped templates, sending to the stub.
+pub fn sms_gateway(config: &Config) -> (Arc<SmsGateway>, Arc<StubSmsSender>) {
+    let sender = Arc::new(StubSmsSender::default());
+    let gateway = SmsGateway {
+        sender: sender.clone(),
+        templates: SmsTemplates::load(&config.sms_templates_dir, &config.sms_default_locale).unwrap(),
+        policy: SmsPolicy::from_config(config).unwrap(),
+    };
+
+    (Arc::new(gateway), sender)
+}
+
+/// The six digit code in a text message or an email.
+pub fn code_in(text: &str) -> String {
+    text
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|word| word.len() == 6)
+        .unwrap()
+        .to_string()
+}
+
+/// The token of the link in an email.
+pub fn token_in(text: &str) -> String {
+    let (_, rest) = text.split_once("token=").unwrap();
+    rest.split(|c: char| c.is_whitespace() || c == '"' || c == '&').next().unwrap().to_string()
+}
+
+/// A mailer with the shipped templates.
+pub fn mailer(config: &Config) -> Arc<Mailer> {
+    Arc::new(Mailer::new(config).unwrap())
+}
+
+/// An email of the user, verified unless `verified` is false.
+pub async fn create_email(repository: &MemoryRepository, user_uuid: Uuid, address: &str, verified: bool) -> models::Email {
+    let address = EmailPolicy::load(None, false).unwrap().normalize(address).unwrap();
+    let email = EmailRepository::add_to_user(repository, user_uuid, address).await.unwrap();
+
+    match verified {
+        true => EmailRepository::update(repository, email.email_uuid, None, Some(models::EmailStatusEnum::Verified)).await.unwrap(),
+        false => email,
+    }
+}
+
+/// A push provider that keeps what it is handed, and refuses the tokens
+/// marked invalid.
+#[derive(Default)]
+pub struct StubPushSender {
+    sent: Mutex<Vec<PushNotification>>,
+    invalid_tokens: Mutex<Vec<String>>,
+}
+
+impl StubPushSender {
+    pub fn sent(&self) -> Vec<PushNotification> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Makes the provider answer that the app behind `token` is gone.
+    pub fn invalidate(&self, token: &str) {
+        self.invalid_tokens.lock().unwrap().push(token.to_string());
+    }
+}
+
+impl PushSender for StubPushSender {
+    fn send(&self, notification: &PushNotification) -> Result<(), PushError> {
+        if self.invalid_tokens.lock().unwrap().contains(&notification.token) {
+            return Err(PushError::InvalidToken);
+        }
+
+        self.sent.lock().unwrap().push(notification.clone());
+        Ok(())
+    }
+}
+
+pub fn push_gateway() -> (Arc<PushGateway>, Arc<StubPushSender>) {
+    let sender = Arc::new(StubPushSender::default());
+    (Arc::new(PushGateway { sender: sender.clone() }), sender)
+}
+
+/// A job runner that keeps the jobs it is handed instead of running them.
+#[derive(Default)]
+pub struct StubJobRunner {
+    spawned: Mutex<Vec<Uuid>>,
+}
+
+impl StubJobRunner {
+    pub fn spawned(&self) -> Vec<Uuid> {
+        self.spawned.lock().unwrap().clone()
+    }
+}
+
+impl JobRunner for StubJobRunner {
+    fn spawn(&self, job_uuid: Uuid) {
+        self.spawned.lock().unwrap().push(job_uuid);
+    }
+}
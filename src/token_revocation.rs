@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+use chrono::{DateTime, Utc};
+use diesel::result::Error as DieselError;
+use uuid::Uuid;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::db::{DbConnection, DbPool};
+use crate::models;
+use crate::repository::{RepositoryResult, SessionRepository};
+
+/// An access token to revoke before it expires.
+pub struct TokenRevocation {
+    pub jti: Uuid,
+    pub user_uuid: Uuid,
+    pub session_uuid: Option<Uuid>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Revokes an access token: it is recorded in Postgres, then added to the
+/// cached deny-list. Returns whether it wasn't already revoked.
+///
+/// If the cache can't be written to, the deny-list stops being trusted
+/// until it is rebuilt, and tokens are checked against Postgres meanwhile:
+/// see `Cache::is_token_denied`.
+pub async fn revoke_token(
+    sessions: &dyn SessionRepository,
+    cache: &Cache,
+    revocation: TokenRevocation,
+) -> RepositoryResult<bool> {
+    let revoked = sessions.revoke_token(models::NewRevokedToken {
+        jti: revocation.jti,
+        user_uuid: revocation.user_uuid,
+        session_uuid: revocation.session_uuid,
+        expires_at: revocation.expires_at,
+    }).await?;
+
+    cache.deny_token(revocation.jti, revocation.expires_at).await;
+
+    Ok(revoked)
+}
+
+/// Rebuilds the cached deny-list from Postgres whenever it can't be trusted,
+/// starting with the empty one at startup, and forgets revoked tokens once
+/// they have expired.
+pub fn spawn_deny_list_sync(database: DbPool, cache: Arc<Cache>, config: &Config) {
+    let interval = StdDuration::from_secs(config.cache_sync_interval.max(1));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            let mut conn = match database.get().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    println!("Error rebuilding the token deny-list: {}", e);
+                    continue;
+                }
+            };
+
+            match sync_deny_list(&mut conn, &cache).await {
+                Ok(Some(denied)) => println!("Rebuilt the token deny-list with {} tokens", denied),
+                Ok(None) => {}
+                Err(e) => println!("Error rebuilding the token deny-list: {}", e),
+            }
+        }
+    });
+}
+
+/// Returns how many tokens the deny-list was rebuilt with, `None` if it
+/// didn't need to be or couldn't be.
+async fn sync_deny_list(conn: &mut DbConnection, cache: &Cache) -> Result<Option<usize>, DieselError> {
+    models::RevokedToken::delete_expired(conn).await?;
+
+    let Some(sync_point) = cache.deny_list_sync_point() else {
+        return Ok(None);
+    };
+
+    let tokens = models::RevokedToken::find_unexpired(conn).await?;
+    Ok(cache.restore_deny_list(sync_point, &tokens).await.then_some(tokens.len()))
+}
@@ -0,0 +1,129 @@
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::models;
+use crate::repository::{DeviceRepository, RepositoryError, RepositoryResult};
+
+const TRUST_ISSUER: &str = "ingot-device-trust";
+
+/// Claims of a "remember this device" token. The token is only accepted
+/// with the device it was issued to, and while its `trusted_devices` row
+/// (`jti`) is active.
+#[derive(Debug, Serialize, Deserialize)]
+struct DeviceTrustClaims {
+    iss: String,
+    sub: Uuid,
+    did: Uuid,
+    jti: Uuid,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug)]
+pub enum TrustError {
+    /// The trust token couldn't be signed.
+    Token(jsonwebtoken::errors::Error),
+    Database(RepositoryError),
+}
+
+impl From<RepositoryError> for TrustError {
+    fn from(error: RepositoryError) -> Self {
+        TrustError::Database(error)
+    }
+}
+
+impl std::fmt::Display for TrustError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrustError::Token(e) => write!(f, "Error signing trust token: {}", e),
+            TrustError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+pub struct IssuedTrust {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Trusts a device for a user after they passed a second factor on it,
+/// replacing any earlier trust of that pair.
+///
+/// Returns `None` when no `DEVICE_TRUST_SECRET` is configured.
+pub async fn trust_device(
+    devices: &dyn DeviceRepository,
+    config: &Config,
+    user_uuid: Uuid,
+    device_uuid: Uuid,
+) -> Result<Option<IssuedTrust>, TrustError> {
+    let Some(secret) = config.device_trust_secret.as_deref() else {
+        return Ok(None);
+    };
+
+    let trust = devices.trust(models::NewTrustedDevice {
+        trust_uuid: Uuid::new_v4(),
+        user_uuid,
+        device_uuid,
+        expires_at: Utc::now() + Duration::seconds(config.device_trust_ttl),
+    }).await?;
+
+    let claims = DeviceTrustClaims {
+        iss: TRUST_ISSUER.to_string(),
+        sub: user_uuid,
+        did: device_uuid,
+        jti: trust.trust_uuid,
+        iat: trust.created_at.timestamp(),
+        exp: trust.expires_at.timestamp(),
+    };
+
+    let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(secret.as_bytes()))
+        .map_err(TrustError::Token)?;
+
+    Ok(Some(IssuedTrust {
+        token,
+        expires_at: trust.expires_at,
+    }))
+}
+
+/// Whether `token` lets the user skip the second factor on this device.
+///
+/// Anything wrong with the token (signature, expiry, another user or
+/// device, revoked trust, blocked device) just means no.
+pub async fn is_device_trusted(
+    devices: &dyn DeviceRepository,
+    config: &Config,
+    token: &str,
+    user_uuid: Uuid,
+    device: &models::Device,
+) -> RepositoryResult<bool> {
+    let Some(secret) = config.device_trust_secret.as_deref() else {
+        return Ok(false);
+    };
+
+    if token.is_empty() || device.status.is_blocked() {
+        return Ok(false);
+    }
+
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[TRUST_ISSUER]);
+
+    let claims = match decode::<DeviceTrustClaims>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation) {
+        Ok(data) => data.claims,
+        Err(_) => return Ok(false),
+    };
+
+    if claims.sub != user_uuid || claims.did != device.device_uuid {
+        return Ok(false);
+    }
+
+    match devices.find_active_trust(claims.jti).await? {
+        Some(trust) if trust.user_uuid == user_uuid && trust.device_uuid == device.device_uuid => {
+            devices.touch_trust(trust.trust_uuid).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
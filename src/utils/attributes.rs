@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use regex::Regex;
+use serde_json::{Map, Number, Value};
+
+use crate::models::{AttributeDefinition, AttributeTypeEnum, AttributeVisibilityEnum};
+
+/// Parses a raw attribute value into the JSON type `attribute_type`.
+///
+/// # Returns
+/// * `Ok(Value)` - The typed value.
+/// * `Err(String)` - A human readable reason the value was rejected.
+pub fn parse_attribute_value(key: &str, attribute_type: &AttributeTypeEnum, raw: &str) -> Result<Value, String> {
+    match attribute_type {
+        AttributeTypeEnum::String => Ok(Value::String(raw.to_string())),
+        AttributeTypeEnum::Integer => raw
+            .parse::<i64>()
+            .map(Value::from)
+            .map_err(|_| format!("Attribute '{}' must be an integer", key)),
+        AttributeTypeEnum::Number => raw
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| format!("Attribute '{}' must be a number", key)),
+        AttributeTypeEnum::Boolean => raw
+            .parse::<bool>()
+            .map(Value::Bool)
+            .map_err(|_| format!("Attribute '{}' must be true or false", key)),
+    }
+}
+
+/// Converts a raw attribute value into the JSON type declared by its definition
+/// and checks it against the enum and pattern constraints.
+///
+/// # Returns
+/// * `Ok(Value)` - The typed value, ready to be stored in `users.metadata`.
+/// * `Err(String)` - A human readable reason the value was rejected.
+pub fn coerce_attribute_value(definition: &AttributeDefinition, raw: &str) -> Result<Value, String> {
+    let value = parse_attribute_value(&definition.key, &definition.attribute_type, raw)?;
+
+    if let Some(Value::Array(allowed)) = &definition.enum_values {
+        if !allowed.contains(&value) {
+            return Err(format!("Attribute '{}' must be one of the allowed values", definition.key));
+        }
+    }
+
+    if let Some(pattern) = &definition.pattern {
+        let regex = Regex::new(pattern)
+            .map_err(|_| format!("Attribute '{}' has an invalid pattern", definition.key))?;
+
+        if !regex.is_match(raw) {
+            return Err(format!("Attribute '{}' does not match the required pattern", definition.key));
+        }
+    }
+
+    Ok(value)
+}
+
+/// Validates and types a set of raw attribute values.
+///
+/// Attributes without a definition are only accepted from administrators, and
+/// admin-only attributes can't be written from the user view.
+pub fn coerce_attributes(
+    definitions: &[AttributeDefinition],
+    values: &HashMap<String, String>,
+    admin: bool,
+) -> Result<Map<String, Value>, String> {
+    let mut attributes = Map::new();
+
+    for (key, raw) in values {
+        let value = match definitions.iter().find(|definition| &definition.key == key) {
+            Some(definition) => {
+                if !admin && definition.visibility == AttributeVisibilityEnum::Admin {
+                    return Err(format!("Attribute '{}' can only be changed by an administrator", key));
+                }
+                coerce_attribute_value(definition, raw)?
+            }
+            None if admin => Value::String(raw.clone()),
+            None => return Err(format!("Unknown attribute '{}'", key)),
+        };
+
+        attributes.insert(key.clone(), value);
+    }
+
+    Ok(attributes)
+}
+
+/// Ensures every required attribute has a value.
+pub fn check_required_attributes(
+    definitions: &[AttributeDefinition],
+    attributes: &Map<String, Value>,
+) -> Result<(), String> {
+    let missing: Vec<&str> = definitions
+        .iter()
+        .filter(|definition| definition.is_required && !attributes.contains_key(&definition.key))
+        .map(|definition| definition.key.as_str())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format!("Missing required attributes: {}", missing.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Returns the attributes of `metadata` that are visible to the requested audience.
+///
+/// Administrators see everything; users only see attributes explicitly
+/// defined as user visible.
+pub fn visible_attributes(
+    definitions: &[AttributeDefinition],
+    metadata: &Value,
+    admin: bool,
+) -> Value {
+    let Some(object) = metadata.as_object() else {
+        return Value::Object(Map::new());
+    };
+
+    if admin {
+        return metadata.clone();
+    }
+
+    let visible = object
+        .iter()
+        .filter(|(key, _)| {
+            definitions.iter().any(|definition| {
+                &definition.key == *key && definition.visibility == AttributeVisibilityEnum::User
+            })
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    Value::Object(visible)
+}
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use std::fs;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::AsyncPgConnection;
+use email_address::{EmailAddress, Options};
+
+use crate::models;
+
+/// Domains whose addresses ignore dots and `+tags` in the local part.
+const GMAIL_DOMAINS: &[&str] = &["gmail.com", "googlemail.com"];
+
+/// How email addresses are validated and normalized.
+pub struct EmailPolicy {
+    /// Fold Gmail addresses (`j.doe+news@googlemail.com` -> `jdoe@gmail.com`)
+    /// when checking uniqueness.
+    pub fold_gmail: bool,
+    /// Blocked (e.g. disposable) domains, in their ASCII form.
+    pub blocked_domains: HashSet<String>,
+}
+
+/// A validated address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedEmail {
+    /// The address as stored and displayed, with a lowercased (Unicode) domain.
+    pub value: String,
+    /// The key used for uniqueness checks and lookups.
+    pub normalized: String,
+}
+
+impl EmailPolicy {
+    /// Loads the blocked domains from a file with one domain per line, `#` starts a comment.
+    pub fn load(blocked_domains_file: Option<&str>, fold_gmail: bool) -> Result<Self, String> {
+        let mut blocked_domains = HashSet::new();
+
+        if let Some(path) = blocked_domains_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Error reading blocked domains from {}: {}", path, e))?;
+
+            for line in contents.lines() {
+                let domain = line.split('#').next().unwrap_or_default().trim();
+                if domain.is_empty() {
+                    continue;
+                }
+
+                let domain = idna::domain_to_ascii(domain)
+                    .map_err(|_| format!("Invalid blocked domain '{}' in {}", domain, path))?;
+                blocked_domains.insert(domain);
+            }
+        }
+
+        Ok(EmailPolicy { fold_gmail, blocked_domains })
+    }
+
+    /// Checks the domain and every parent domain against the block list.
+    fn is_blocked(&self, ascii_domain: &str) -> bool {
+        let mut domain = ascii_domain;
+
+        loop {
+            if self.blocked_domains.contains(domain) {
+                return true;
+            }
+
+            match domain.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => domain = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Validates an address and computes its stored and normalized forms.
+    ///
+    /// Internationalized domains are converted with IDNA, the local part is
+    /// kept as entered but compared case-insensitively.
+    ///
+    /// # Returns
+    /// * `Ok(NormalizedEmail)` - The address is acceptable.
+    /// * `Err(String)` - A human readable reason the address was rejected.
+    pub fn normalize(&self, email: &str) -> Result<NormalizedEmail, String> {
+        self.parse(email, true)
+    }
+
+    /// Returns the key to look an existing address up by.
+    ///
+    /// Unlike `normalize` this doesn't apply the block list, so addresses
+    /// stored before a domain was blocked can still be found.
+    pub fn lookup_key(&self, email: &str) -> String {
+        self.parse(email, false)
+            .map(|email| email.normalized)
+            .unwrap_or_else(|_| email.trim().to_lowercase())
+    }
+
+    fn parse(&self, email: &str, check_blocked: bool) -> Result<NormalizedEmail, String> {
+        let email = email.trim();
+
+        if email.is_empty() {
+            return Err("Email is required".to_string());
+        }
+
+        let (local, domain) = email
+            .rsplit_once('@')
+            .ok_or_else(|| "Email must contain an '@'".to_string())?;
+
+        let ascii_domain = idna::domain_to_ascii(domain)
+            .map_err(|_| format!("Email domain '{}' is invalid", domain))?;
+
+        let options = Options::default()
+            .with_required_tld()
+            .without_domain_literal()
+            .without_display_text();
+
+        EmailAddress::parse_with_options(&format!("{}@{}", local, ascii_domain), options)
+            .map_err(|e| format!("Email is invalid: {}", e))?;
+
+        if check_blocked && self.is_blocked(&ascii_domain) {
+            return Err(format!("Email addresses from '{}' are not allowed", domain.to_lowercase()));
+        }
+
+        let (unicode_domain, _) = idna::domain_to_unicode(&ascii_domain);
+
+        let mut normalized_local = local.to_lowercase();
+        let mut normalized_domain = ascii_domain;
+
+        if self.fold_gmail && GMAIL_DOMAINS.contains(&normalized_domain.as_str()) {
+            let without_tag = normalized_local.split('+').next().unwrap_or_default();
+            normalized_local = without_tag.replace('.', "");
+            normalized_domain = GMAIL_DOMAINS[0].to_string();
+        }
+
+        Ok(NormalizedEmail {
+            value: format!("{}@{}", local, unicode_domain),
+            normalized: format!("{}@{}", normalized_local, normalized_domain),
+        })
+    }
+}
+
+/// Re-keys the addresses whose key doesn't follow `policy`, e.g. those the
+/// migration keyed by their lowercased value, or all of them after Gmail
+/// folding was turned on or off.
+///
+/// An address whose new key is already taken keeps the old one, the two
+/// accounts have to be merged by hand.
+pub async fn backfill_email_keys(conn: &mut AsyncPgConnection, policy: &EmailPolicy) -> Result<usize, DieselError> {
+    let emails = models::Email::find_keys(conn).await?;
+    let mut backfilled = 0;
+
+    for (email_uuid, value, normalized_value) in emails {
+        let key = policy.lookup_key(&value);
+        if key == normalized_value {
+            continue;
+        }
+
+        match models::Email::set_normalized_value(conn, email_uuid, key).await {
+            Ok(_) => backfilled += 1,
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                println!("Email {} is the same address as another one, keeping its key {}", email_uuid, normalized_value);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(backfilled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(fold_gmail: bool, blocked_domains: &[&str]) -> EmailPolicy {
+        EmailPolicy {
+            fold_gmail,
+            blocked_domains: policy_domains(blocked_domains),
+        }
+    }
+
+    fn policy_domains(domains: &[&str]) -> HashSet<String> {
+        domains.iter().map(|domain| domain.to_string()).collect()
+    }
+
+    #[test]
+    fn internationalized_domains_are_keyed_in_ascii() {
+        let email = policy(false, &[]).normalize(" Jürgen@Bücher.DE ").unwrap();
+
+        assert_eq!(email.value, "Jürgen@bücher.de");
+        assert_eq!(email.normalized, "jürgen@xn--bcher-kva.de");
+        assert_eq!(policy(false, &[]).lookup_key("jürgen@xn--bcher-kva.de"), email.normalized);
+    }
+
+    #[test]
+    fn gmail_addresses_are_folded_when_enabled() {
+        let folded = policy(true, &[]).normalize("J.Doe+news@GoogleMail.com").unwrap();
+        assert_eq!(folded.value, "J.Doe+news@googlemail.com");
+        assert_eq!(folded.normalized, "jdoe@gmail.com");
+        assert_eq!(policy(true, &[]).lookup_key("jdoe@gmail.com"), "jdoe@gmail.com");
+
+        // Other providers treat dots and tags as part of the address.
+        assert_eq!(policy(true, &[]).normalize("j.doe+news@example.com").unwrap().normalized, "j.doe+news@example.com");
+        assert_eq!(policy(false, &[]).normalize("J.Doe+news@gmail.com").unwrap().normalized, "j.doe+news@gmail.com");
+    }
+
+    #[test]
+    fn blocked_domains_are_loaded_in_ascii() {
+        let path = std::env::temp_dir().join(format!("blocked-domains-{}.txt", uuid::Uuid::new_v4()));
+        fs::write(&path, "# disposable\nmailinator.com  # and its subdomains\n\nBücher.de\n").unwrap();
+
+        let policy = EmailPolicy::load(path.to_str(), false).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(policy.blocked_domains, policy_domains(&["mailinator.com", "xn--bcher-kva.de"]));
+    }
+
+    #[test]
+    fn blocked_domains_cover_their_subdomains() {
+        let policy = policy(false, &["mailinator.com", "xn--bcher-kva.de"]);
+
+        assert!(policy.normalize("someone@mailinator.com").is_err());
+        assert!(policy.normalize("someone@eu.mx.Mailinator.com").is_err());
+        assert!(policy.normalize("someone@bücher.de").is_err());
+        assert!(policy.normalize("someone@notmailinator.com").is_ok());
+        assert!(policy.normalize("someone@mailinator.com.example").is_ok());
+
+        // Addresses stored before the domain was blocked can still be found.
+        assert_eq!(policy.lookup_key("Someone@Mailinator.com"), "someone@mailinator.com");
+    }
+}
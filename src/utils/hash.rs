@@ -1,4 +1,4 @@
-use argon2::{Argon2, PasswordHasher, PasswordHash, PasswordVerifier, password_hash::Salt};
+use argon2::{Argon2, PasswordHasher, PasswordHash, PasswordVerifier, password_hash::{rand_core::OsRng, Salt, SaltString}};
 
 pub fn hash_password(password: &str, salt: Salt) -> Result<String, argon2::password_hash::Error> {
     // Set up the Argon2 configuration
@@ -12,6 +12,23 @@ pub fn hash_password(password: &str, salt: Salt) -> Result<String, argon2::passw
     Ok(hash.to_string())
 }
 
+/// Hashes a new plaintext password for storage, with a random salt so
+/// identical passwords don't share a hash.
+pub fn hash_new_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    hash_password(password, salt.as_salt())
+}
+
+/// Checks whether `hashed_password` is a PHC string that `verify_password` can check.
+///
+/// Used when importing pre-hashed passwords from another system.
+pub fn is_supported_password_hash(hashed_password: &str) -> bool {
+    match PasswordHash::new(hashed_password) {
+        Ok(parsed_hash) => argon2::Algorithm::try_from(parsed_hash.algorithm).is_ok(),
+        Err(_) => false,
+    }
+}
+
 /// Verifies a plaintext password against a hashed password.
 ///
 /// # Arguments
@@ -30,4 +47,9 @@ pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, ar
 
     // Verify the password against the hashed password
     Ok(argon2.verify_password(password.as_bytes(), &parsed_hash).is_ok())
-}
\ No newline at end of file
+}
+/// Compares without short-circuiting so a secret can't be guessed byte
+/// by byte from response times.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,2 +1,14 @@
+mod attributes;
+mod email;
 mod hash;
+mod phone;
+mod user_agent;
+mod username;
+mod validation;
+pub use attributes::*;
+pub use email::*;
 pub use hash::*;
+pub use phone::*;
+pub use user_agent::*;
+pub use username::*;
+pub use validation::*;
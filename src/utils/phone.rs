@@ -0,0 +1,99 @@
+use phonenumber::country;
+use phonenumber::{Mode, PhoneNumber, Type};
+
+/// A validated phone number, split the way the `phones` table stores it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedPhone {
+    /// Calling code with a leading `+`, e.g. `+44`.
+    pub country_code: String,
+    /// National significant number, digits only.
+    pub number: String,
+    /// The full number in E.164 form, e.g. `+442071838750`.
+    pub e164: String,
+    /// `mobile`, `landline`, `voip`, etc., see `phone_type_name`.
+    pub number_type: &'static str,
+    /// ISO 3166 region the number belongs to, if it maps to a single one.
+    pub region: Option<String>,
+}
+
+/// Name stored in `phones.type` for a detected number type.
+pub fn phone_type_name(number_type: Type) -> &'static str {
+    match number_type {
+        Type::Mobile => "mobile",
+        Type::FixedLine => "landline",
+        Type::FixedLineOrMobile => "landline_or_mobile",
+        Type::Voip => "voip",
+        Type::TollFree => "toll_free",
+        Type::PremiumRate => "premium_rate",
+        Type::SharedCost => "shared_cost",
+        Type::PersonalNumber => "personal",
+        Type::Pager => "pager",
+        Type::Uan => "uan",
+        Type::Voicemail => "voicemail",
+        Type::Emergency | Type::ShortCode | Type::StandardRate | Type::Carrier | Type::NoInternational => "other",
+        Type::Unknown => "unknown",
+    }
+}
+
+fn normalized(number: PhoneNumber) -> Result<NormalizedPhone, String> {
+    if !number.is_valid() {
+        return Err("Phone number is not valid for its region".to_string());
+    }
+
+    let country_code = format!("+{}", number.code().value());
+    let e164 = number.format().mode(Mode::E164).to_string();
+    let national = e164
+        .strip_prefix(&country_code)
+        .ok_or("Phone number can't be split from its country code")?
+        .to_string();
+
+    Ok(NormalizedPhone {
+        country_code,
+        number: national,
+        number_type: phone_type_name(number.number_type(&phonenumber::metadata::DATABASE)),
+        region: number.country().id().map(|id| id.as_ref().to_string()),
+        e164,
+    })
+}
+
+/// Parses a number given as a country and a national (or international) number.
+///
+/// `country_code` is either a calling code (`+44`, `44`) or an ISO 3166
+/// region (`GB`), which also lets national prefixes like a leading `0` be
+/// stripped. A `number` starting with `+` is parsed on its own.
+pub fn normalize_phone(country_code: &str, number: &str) -> Result<NormalizedPhone, String> {
+    let country_code = country_code.trim();
+    let number = number.trim();
+
+    if number.is_empty() {
+        return Err("Phone number is required".to_string());
+    }
+
+    let parsed = if number.starts_with('+') || country_code.is_empty() {
+        phonenumber::parse(None, number)
+    } else if country_code.chars().all(|c| c.is_ascii_alphabetic()) {
+        let region: country::Id = country_code
+            .to_uppercase()
+            .parse()
+            .map_err(|_| format!("Unknown country '{}'", country_code))?;
+        phonenumber::parse(Some(region), number)
+    } else {
+        let calling_code = country_code.trim_start_matches('+');
+        if calling_code.is_empty() || !calling_code.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("Invalid country code '{}'", country_code));
+        }
+        phonenumber::parse(None, format!("+{} {}", calling_code, number))
+    };
+
+    normalized(parsed.map_err(|e| format!("Invalid phone number: {}", e))?)
+}
+
+/// Parses a full number in international form, e.g. `+44 20 7183 8750`.
+pub fn normalize_full_phone(full_number: &str) -> Result<NormalizedPhone, String> {
+    let full_number = full_number.trim();
+    if !full_number.starts_with('+') {
+        return Err("Phone number must start with + and the country code".to_string());
+    }
+
+    normalize_phone("", full_number)
+}
@@ -0,0 +1,217 @@
+use uaparser::{Parser, UserAgentParser};
+
+use crate::models::{DeviceTypeEnum, OsEnum};
+
+/// What a user agent says about the device it comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub device_type: DeviceTypeEnum,
+    pub os: OsEnum,
+    pub os_version: Option<String>,
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+}
+
+/// Classifies user agents with the uap-core regexes, falling back to woothee
+/// for whatever they don't recognize.
+///
+/// Building the uaparser regexes is expensive, load this once and share it.
+pub struct UserAgentClassifier {
+    parser: UserAgentParser,
+    fallback: woothee::parser::Parser,
+}
+
+/// Joins the version parts that are present, e.g. `17.4` or `120.0.6099`.
+fn join_version<'a>(parts: impl IntoIterator<Item = Option<&'a str>>) -> Option<String> {
+    let parts: Vec<&str> = parts
+        .into_iter()
+        .map_while(|part| part.filter(|part| !part.is_empty()))
+        .collect();
+
+    (!parts.is_empty()).then(|| parts.join("."))
+}
+
+fn known(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty() && *value != "Other" && *value != woothee::woothee::VALUE_UNKNOWN)
+}
+
+/// Cuts a value to the length of the column it's stored in.
+fn truncated(value: String, max_length: usize) -> String {
+    match value.char_indices().nth(max_length) {
+        Some((index, _)) => value[..index].to_string(),
+        None => value,
+    }
+}
+
+/// Device types only visible in the raw user agent, checked before the
+/// generic mobile/desktop classification.
+fn device_type_from_agent(agent: &str) -> Option<DeviceTypeEnum> {
+    let agent = agent.to_lowercase();
+    let contains = |needles: &[&str]| needles.iter().any(|needle| agent.contains(needle));
+
+    if contains(&["smart-tv", "smarttv", "googletv", "appletv", "crkey", "hbbtv", "roku", "aftb", "aftt", "web0s"]) {
+        Some(DeviceTypeEnum::SmartTv)
+    } else if contains(&["playstation", "xbox", "nintendo"]) {
+        Some(DeviceTypeEnum::GamingConsole)
+    } else if contains(&["oculus", "quest", "pico"]) {
+        Some(DeviceTypeEnum::VrHeadset)
+    } else if contains(&["kindle", "kobo", "nook"]) && !contains(&["silk"]) {
+        Some(DeviceTypeEnum::EReader)
+    } else if contains(&["watch os", "watchos", "wear os", "smartwatch"]) {
+        Some(DeviceTypeEnum::Wearable)
+    } else if contains(&["tesla", "android automotive"]) {
+        Some(DeviceTypeEnum::Car)
+    } else if contains(&["ipad", "tablet", "silk", "playbook"]) || (agent.contains("android") && !agent.contains("mobile")) {
+        Some(DeviceTypeEnum::Tablet)
+    } else {
+        None
+    }
+}
+
+impl UserAgentClassifier {
+    pub fn load(regexes_file: &str) -> Result<Self, String> {
+        let parser = UserAgentParser::builder()
+            .build_from_yaml(regexes_file)
+            .map_err(|e| format!("Error loading user agent regexes from {}: {:?}", regexes_file, e))?;
+
+        Ok(UserAgentClassifier {
+            parser,
+            fallback: woothee::parser::Parser::new(),
+        })
+    }
+
+    pub fn classify(&self, agent: &str) -> ClientInfo {
+        let client = self.parser.parse(agent);
+        let fallback = self.fallback.parse(agent);
+
+        let (browser, browser_version) = match known(&client.user_agent.family) {
+            Some(family) => (
+                Some(family.to_string()),
+                join_version([
+                    client.user_agent.major.as_deref(),
+                    client.user_agent.minor.as_deref(),
+                    client.user_agent.patch.as_deref(),
+                ]),
+            ),
+            None => (
+                fallback.as_ref().and_then(|result| known(result.name)).map(str::to_string),
+                fallback.as_ref().and_then(|result| known(result.version)).map(str::to_string),
+            ),
+        };
+
+        let (os, os_version) = match known(&client.os.family) {
+            Some(family) => (
+                OsEnum::from_device_family(family),
+                join_version([
+                    client.os.major.as_deref(),
+                    client.os.minor.as_deref(),
+                    client.os.patch.as_deref(),
+                ]),
+            ),
+            None => match &fallback {
+                Some(result) => (
+                    OsEnum::from_device_family(result.os),
+                    known(&result.os_version).map(str::to_string),
+                ),
+                None => (OsEnum::Other, None),
+            },
+        };
+
+        let device_type = device_type_from_agent(agent)
+            .or_else(|| known(&client.device.family)
+                .map(DeviceTypeEnum::from_device_family)
+                .filter(|device_type| *device_type != DeviceTypeEnum::Other))
+            .unwrap_or_else(|| match fallback.as_ref().map(|result| result.category) {
+                Some("pc") => DeviceTypeEnum::Desktop,
+                Some("smartphone") | Some("mobilephone") => DeviceTypeEnum::Mobile,
+                _ => match os {
+                    OsEnum::Windows | OsEnum::Macos | OsEnum::Linux | OsEnum::ChromeOs | OsEnum::Freebsd => DeviceTypeEnum::Desktop,
+                    OsEnum::Android | OsEnum::Ios | OsEnum::FireOs | OsEnum::Tizen => DeviceTypeEnum::Mobile,
+                    OsEnum::Watchos => DeviceTypeEnum::Wearable,
+                    OsEnum::Webos => DeviceTypeEnum::SmartTv,
+                    OsEnum::Other => DeviceTypeEnum::Other,
+                },
+            });
+
+        ClientInfo {
+            device_type,
+            os,
+            os_version: os_version.map(|version| truncated(version, 50)),
+            browser: browser.map(|browser| truncated(browser, 100)),
+            browser_version: browser_version.map(|version| truncated(version, 50)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classifier() -> UserAgentClassifier {
+        UserAgentClassifier::load("config/regexes.yaml").unwrap()
+    }
+
+    #[test]
+    fn parser_os_families_map_to_their_variant() {
+        // Lowercasing the family and then matching capitalized names used to
+        // turn every one of these into `Other`.
+        for (family, os) in [
+            ("Windows", OsEnum::Windows),
+            ("Windows 10", OsEnum::Windows),
+            ("Mac OS X", OsEnum::Macos),
+            ("iOS", OsEnum::Ios),
+            ("Android", OsEnum::Android),
+            ("Chrome OS", OsEnum::ChromeOs),
+            ("Ubuntu", OsEnum::Linux),
+            ("Windows Phone", OsEnum::Other),
+        ] {
+            assert_eq!(OsEnum::from_device_family(family), os, "{}", family);
+        }
+    }
+
+    #[test]
+    fn user_agents_are_classified_with_their_versions() {
+        let classifier = classifier();
+        let cases = [
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.130 Safari/537.36",
+                OsEnum::Windows, DeviceTypeEnum::Desktop, "Chrome", "120.0.6099",
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+                OsEnum::Macos, DeviceTypeEnum::Desktop, "Safari", "17.4",
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+                OsEnum::Ios, DeviceTypeEnum::Mobile, "Safari", "17.4",
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.6099.144 Mobile Safari/537.36",
+                OsEnum::Android, DeviceTypeEnum::Mobile, "Chrome", "120.0.6099",
+            ),
+            (
+                "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                OsEnum::ChromeOs, DeviceTypeEnum::Desktop, "Chrome", "120.0.0",
+            ),
+            (
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0",
+                OsEnum::Linux, DeviceTypeEnum::Desktop, "Firefox", "121.0",
+            ),
+        ];
+
+        for (agent, os, device_type, browser, browser_version) in cases {
+            let info = classifier.classify(agent);
+            assert_eq!(info.os, os, "{}", agent);
+            assert_eq!(info.device_type, device_type, "{}", agent);
+            assert_eq!(info.browser.as_deref(), Some(browser), "{}", agent);
+            assert_eq!(info.browser_version.as_deref(), Some(browser_version), "{}", agent);
+        }
+    }
+
+    #[test]
+    fn os_versions_come_from_the_os_not_the_browser() {
+        let info = classifier().classify("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15");
+
+        assert_eq!(info.os_version.as_deref(), Some("10.15.7"));
+    }
+}
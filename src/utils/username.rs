@@ -0,0 +1,127 @@
+use caseless::default_case_fold_str;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel_async::AsyncPgConnection;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+use super::validate_username;
+use crate::models;
+
+/// Normalizes a username for storage and lookup (NFKC + case folding).
+///
+/// Usernames that render the same way and only differ by case or by
+/// compatibility characters (e.g. full-width letters) normalize to the
+/// same value.
+pub fn normalize_username(username: &str) -> String {
+    let normalized: String = username.trim().nfkc().collect();
+    default_case_fold_str(&normalized).nfkc().collect()
+}
+
+/// Returns the confusable skeleton of a normalized username (UTS #39).
+///
+/// Two usernames with the same skeleton look alike, e.g. `paypal` and
+/// `pаypal` with a Cyrillic `а`.
+pub fn username_skeleton(username: &str) -> String {
+    skeleton(username).collect()
+}
+
+/// Unique indexes that keep a username, or a look-alike of it, from being used twice.
+const USERNAME_CONSTRAINTS: &[&str] = &["users_username_lower_idx", "users_username_skeleton_idx"];
+
+/// Checks whether a write failed because the username, or one that looks
+/// like it, was taken in the meantime.
+pub fn is_username_conflict(error: &DieselError) -> bool {
+    match error {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+            let constraint = info.constraint_name().unwrap_or_else(|| info.message());
+            USERNAME_CONSTRAINTS.iter().any(|name| constraint.contains(name))
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether a normalized username is, or looks like, a reserved name.
+pub fn is_reserved_username(username: &str, reserved: &[String]) -> bool {
+    let username_skeleton = username_skeleton(username);
+
+    reserved
+        .iter()
+        .any(|name| name == username || self::username_skeleton(name) == username_skeleton)
+}
+
+/// Normalizes and validates a username chosen by a user.
+///
+/// # Returns
+/// * `Ok(String)` - The normalized username, ready to be stored.
+/// * `Err(&str)` - A human readable reason the username was rejected.
+pub fn canonicalize_username(username: &str, reserved: &[String]) -> Result<String, &'static str> {
+    let username = normalize_username(username);
+
+    validate_username(&username)?;
+
+    if is_reserved_username(&username, reserved) {
+        return Err("Username is reserved");
+    }
+
+    Ok(username)
+}
+
+/// Computes the skeleton of users created before skeletons were tracked.
+///
+/// A user that looks like an older one keeps no skeleton, the unique index
+/// only allows one of them to have it.
+pub async fn backfill_username_skeletons(conn: &mut AsyncPgConnection) -> Result<usize, diesel::result::Error> {
+    let users = models::User::find_missing_skeletons(conn).await?;
+    let mut backfilled = 0;
+
+    for (user_uuid, username) in &users {
+        match models::User::set_skeleton(conn, *user_uuid, username_skeleton(&normalize_username(username))).await {
+            Ok(_) => backfilled += 1,
+            Err(e) if is_username_conflict(&e) => {
+                println!("Username of user {} looks like another one, leaving it without a skeleton", user_uuid);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(backfilled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserved() -> Vec<String> {
+        vec!["admin".to_string(), "support".to_string()]
+    }
+
+    #[test]
+    fn usernames_are_normalized_and_case_folded() {
+        assert_eq!(normalize_username(" Alice "), "alice");
+        assert_eq!(normalize_username("ＡＬＩＣＥ"), "alice");
+        assert_eq!(normalize_username("Straße"), "strasse");
+        assert_eq!(canonicalize_username("Ｂｏｂ_２", &[]).unwrap(), "bob_2");
+    }
+
+    #[test]
+    fn look_alike_usernames_share_a_skeleton() {
+        assert_eq!(username_skeleton("paypal"), username_skeleton("pаypal"));
+        assert_eq!(username_skeleton("rnallory"), username_skeleton("mallory"));
+        assert_ne!(username_skeleton("alice"), username_skeleton("alicia"));
+    }
+
+    #[test]
+    fn reserved_names_and_their_look_alikes_are_refused() {
+        assert_eq!(canonicalize_username("Admin", &reserved()).err(), Some("Username is reserved"));
+        assert_eq!(canonicalize_username("SUPPORT", &reserved()).err(), Some("Username is reserved"));
+        assert!(is_reserved_username("adrnin", &reserved()));
+        assert!(!is_reserved_username("admins", &reserved()));
+        assert_eq!(canonicalize_username("administrator", &reserved()).unwrap(), "administrator");
+    }
+
+    #[test]
+    fn only_ascii_usernames_are_accepted() {
+        assert!(canonicalize_username("pаypal", &[]).is_err());
+        assert!(canonicalize_username("zoë", &[]).is_err());
+    }
+}
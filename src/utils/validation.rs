@@ -0,0 +1,57 @@
+use regex::Regex;
+
+/// Validates a username against the account naming rules.
+///
+/// # Returns
+/// * `Ok(())` - If the username is acceptable.
+/// * `Err(&str)` - A human readable reason the username was rejected.
+pub fn validate_username(username: &str) -> Result<(), &'static str> {
+    let username_regex = Regex::new(r"^[a-zA-Z0-9_]+$").unwrap();
+
+    if !username_regex.is_match(username) {
+        return Err("Username must contain only letters, numbers, and underscores");
+    }
+
+    if username.len() < 3 {
+        return Err("Username must be at least 3 characters");
+    }
+
+    if username.len() > 32 {
+        return Err("Username must be less than 32 characters");
+    }
+
+    Ok(())
+}
+
+/// Validates a plaintext password against the password policy.
+///
+/// # Returns
+/// * `Ok(())` - If the password is acceptable.
+/// * `Err(&str)` - A human readable reason the password was rejected.
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.len() < 8 {
+        return Err("Password must be at least 8 characters");
+    }
+
+    if password.len() > 64 {
+        return Err("Password must be less than 64 characters");
+    }
+
+    if !Regex::new(r"[A-Z]").unwrap().is_match(password) {
+        return Err("Password must contain at least one uppercase letter");
+    }
+
+    if !Regex::new(r"[a-z]").unwrap().is_match(password) {
+        return Err("Password must contain at least one lowercase letter");
+    }
+
+    if !Regex::new(r"[0-9]").unwrap().is_match(password) {
+        return Err("Password must contain at least one number");
+    }
+
+    if !Regex::new(r"[!@#$%^&*(),.?:{}|<>]").unwrap().is_match(password) {
+        return Err("Password must contain at least one special character");
+    }
+
+    Ok(())
+}
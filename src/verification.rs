@@ -0,0 +1,273 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use diesel::result::Error as DieselError;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::mailer::{Mailer, OutgoingEmail};
+use crate::models;
+use crate::repository::{EmailRepository, RepositoryError};
+
+/// A freshly issued verification, the only time the plain code and token exist.
+pub struct IssuedVerification {
+    pub email: models::Email,
+    pub code: String,
+    pub token: String,
+    pub code_expires_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub enum VerificationError {
+    EmailNotFound,
+    AlreadyVerified,
+    /// The email is bounced, flagged as spam or blocked.
+    NotVerifiable,
+    Throttled { retry_at: DateTime<Utc> },
+    /// The address hard-bounced or complained, nothing is sent to it anymore.
+    Suppressed,
+    /// The code or token is wrong, expired, used or burned.
+    Invalid,
+    /// The email carrying the verification couldn't be rendered.
+    Template(String),
+    Database(RepositoryError),
+}
+
+impl From<RepositoryError> for VerificationError {
+    fn from(error: RepositoryError) -> Self {
+        VerificationError::Database(error)
+    }
+}
+
+impl std::fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerificationError::EmailNotFound => write!(f, "Email not found"),
+            VerificationError::AlreadyVerified => write!(f, "Email is already verified"),
+            VerificationError::NotVerifiable => write!(f, "Email can't be verified in its current status"),
+            VerificationError::Throttled { retry_at } => write!(f, "Too many verification requests, retry after {}", retry_at.to_rfc3339()),
+            VerificationError::Suppressed => write!(f, "Email bounced or was reported as spam, no more emails are sent to it"),
+            VerificationError::Invalid => write!(f, "Invalid or expired verification code"),
+            VerificationError::Template(e) => write!(f, "Error rendering email: {}", e),
+            VerificationError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+pub(crate) fn hash_secret(salt: &Uuid, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(secret.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Generates a random URL-safe token for links.
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn check_verifiable(email: &models::Email) -> Result<(), VerificationError> {
+    match email.status {
+        models::EmailStatusEnum::Unverified => Ok(()),
+        models::EmailStatusEnum::Verified => Err(VerificationError::AlreadyVerified),
+        _ => Err(VerificationError::NotVerifiable),
+    }
+}
+
+fn email_not_found(error: RepositoryError) -> VerificationError {
+    match error {
+        RepositoryError::Database(DieselError::NotFound) => VerificationError::EmailNotFound,
+        e => VerificationError::Database(e),
+    }
+}
+
+/// Checks the per-email limits: one message per resend interval and a fixed
+/// number of messages per hour.
+async fn check_issue_limits(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    email: &models::Email,
+) -> Result<(), VerificationError> {
+    check_verifiable(email)?;
+
+    let now = Utc::now();
+    let issued = emails.find_verifications_issued_since(email.email_uuid, now - Duration::hours(1)).await?;
+
+    if let Some(latest) = issued.first() {
+        let resend_at = *latest + Duration::seconds(config.email_verification_resend_interval);
+        if resend_at > now {
+            return Err(VerificationError::Throttled { retry_at: resend_at });
+        }
+    }
+
+    if issued.len() >= config.email_verification_max_per_hour {
+        if let Some(oldest) = issued.last() {
+            return Err(VerificationError::Throttled { retry_at: *oldest + Duration::hours(1) });
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a verification email may be sent to `email`, which is
+/// throttled as issuing is and refused for suppressed addresses.
+pub(crate) async fn check_sendable(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    email: &models::Email,
+) -> Result<(), VerificationError> {
+    check_issue_limits(emails, config, email).await?;
+
+    if emails.is_suppressed(&email.normalized_value).await? {
+        return Err(VerificationError::Suppressed);
+    }
+
+    Ok(())
+}
+
+/// Stores a new code and link token for an email, superseding older ones,
+/// and queues `outgoing` with them.
+async fn issue(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    email: models::Email,
+    outgoing: impl FnOnce(&IssuedVerification) -> Result<Vec<OutgoingEmail>, VerificationError>,
+) -> Result<IssuedVerification, VerificationError> {
+    let now = Utc::now();
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let token = generate_token();
+
+    let token_uuid = Uuid::new_v4();
+    let new_token = models::NewEmailVerificationToken {
+        token_uuid,
+        email_uuid: email.email_uuid,
+        code_hash: hash_secret(&token_uuid, &code),
+        token_hash: hash_token(&token),
+        code_expires_at: now + Duration::seconds(config.email_verification_code_ttl),
+        expires_at: now + Duration::seconds(config.email_verification_link_ttl),
+    };
+
+    let issued = IssuedVerification {
+        email,
+        code,
+        token,
+        code_expires_at: new_token.code_expires_at,
+        expires_at: new_token.expires_at,
+    };
+
+    emails.replace_verification(new_token, outgoing(&issued)?).await?;
+    Ok(issued)
+}
+
+/// Issues a new code and link token for an email, superseding older ones.
+///
+/// Issuing is throttled per email: one message per resend interval and a
+/// fixed number of messages per hour.
+pub async fn issue_email_verification(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    email_uuid: Uuid,
+) -> Result<IssuedVerification, VerificationError> {
+    let email = emails.find(email_uuid).await.map_err(email_not_found)?;
+    check_issue_limits(emails, config, &email).await?;
+
+    issue(emails, config, email, |_| Ok(vec![])).await
+}
+
+/// Issues a new verification for an email that passed `check_sendable`,
+/// queueing the email carrying it along with `also`.
+///
+/// Everything is stored at once, so a failed request never leaves a token
+/// nobody received.
+pub(crate) async fn issue_and_send(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    mailer: &Mailer,
+    email: models::Email,
+    locale: &str,
+    also: Vec<OutgoingEmail>,
+) -> Result<IssuedVerification, VerificationError> {
+    issue(emails, config, email, |issued| {
+        let verification = mailer.render_verification(&issued.email.value, locale, &issued.code, &issued.token)
+            .map_err(VerificationError::Template)?;
+
+        let mut outgoing = vec![verification];
+        outgoing.extend(also);
+        Ok(outgoing)
+    }).await
+}
+
+/// Issues a new verification and queues the email carrying it.
+pub async fn send_email_verification(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    mailer: &Mailer,
+    email_uuid: Uuid,
+    locale: &str,
+) -> Result<IssuedVerification, VerificationError> {
+    let email = emails.find(email_uuid).await.map_err(email_not_found)?;
+    check_sendable(emails, config, &email).await?;
+
+    issue_and_send(emails, config, mailer, email, locale, vec![]).await
+}
+
+/// Verifies an email with the short code that was sent to it.
+///
+/// Every try counts as an attempt, once the limit is reached the code is
+/// burned and a new one has to be requested.
+pub async fn verify_email_code(
+    emails: &dyn EmailRepository,
+    config: &Config,
+    normalized_value: &str,
+    code: &str,
+) -> Result<models::Email, VerificationError> {
+    let email = emails.find_by_value(normalized_value.to_string()).await.map_err(email_not_found)?;
+    check_verifiable(&email)?;
+
+    let token = emails.find_latest_verification(email.email_uuid).await?
+        .ok_or(VerificationError::Invalid)?;
+
+    if token.code_expires_at < Utc::now() {
+        return Err(VerificationError::Invalid);
+    }
+
+    // The attempt is counted before the code is compared, so parallel guesses
+    // can't all slip under the limit.
+    let token = emails.claim_verification_attempt(token.token_uuid, config.email_verification_max_attempts).await?
+        .ok_or(VerificationError::Invalid)?;
+
+    if hash_secret(&token.token_uuid, code.trim()) != token.code_hash {
+        if token.attempts >= config.email_verification_max_attempts {
+            emails.burn_verifications(email.email_uuid).await?;
+        }
+        return Err(VerificationError::Invalid);
+    }
+
+    Ok(emails.mark_verified(email.email_uuid, "code").await?)
+}
+
+/// Verifies an email with the token embedded in a verification link.
+pub async fn verify_email_token(
+    emails: &dyn EmailRepository,
+    token: &str,
+) -> Result<models::Email, VerificationError> {
+    let token = emails.find_verification_by_token_hash(&hash_token(token.trim())).await?
+        .ok_or(VerificationError::Invalid)?;
+
+    if token.consumed_at.is_some() || token.expires_at < Utc::now() {
+        return Err(VerificationError::Invalid);
+    }
+
+    let email = emails.find(token.email_uuid).await?;
+    check_verifiable(&email)?;
+
+    Ok(emails.mark_verified(email.email_uuid, "link").await?)
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::json;
+
+use crate::bounces;
+use crate::repository::{EmailRepository, RepositoryError};
+use crate::utils::{self, EmailPolicy};
+
+/// Header carrying the webhook secret, `?token=` works for providers that
+/// can't set headers.
+const TOKEN_HEADER: &str = "x-webhook-token";
+
+#[derive(Clone)]
+struct WebhookState {
+    emails: Arc<dyn EmailRepository>,
+    email_policy: Arc<EmailPolicy>,
+    secret: Arc<String>,
+}
+
+async fn email_feedback(
+    State(state): State<WebhookState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let token = headers
+        .get(TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| query.get("token").map(String::as_str))
+        .unwrap_or_default();
+
+    if state.secret.is_empty() || !utils::constant_time_eq(token.as_bytes(), state.secret.as_bytes()) {
+        return (StatusCode::UNAUTHORIZED, Json(json!({ "error": "Invalid webhook token" }))).into_response();
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    match bounces::ingest_feedback(state.emails.as_ref(), &state.email_policy, &content_type, &body).await {
+        Ok(Ok(outcomes)) => (StatusCode::OK, Json(json!({ "results": outcomes }))).into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, Json(json!({ "error": e }))).into_response(),
+        Err(RepositoryError::Unavailable(e)) => {
+            println!("Error ingesting email feedback: {}", e);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+        Err(e) => {
+            println!("Error ingesting email feedback: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Serves the bounce and complaint webhook next to the gRPC server.
+pub fn spawn_webhook_server(
+    emails: Arc<dyn EmailRepository>,
+    email_policy: Arc<EmailPolicy>,
+    secret: String,
+    addr: SocketAddr,
+) {
+    let app = Router::new()
+        .route("/webhooks/email-feedback", post(email_feedback))
+        .with_state(WebhookState {
+            emails,
+            email_policy,
+            secret: Arc::new(secret),
+        });
+
+    tokio::spawn(async move {
+        println!("Webhooks listening on {}", addr);
+
+        if let Err(e) = axum::Server::bind(&addr).serve(app.into_make_service()).await {
+            println!("Webhook server stopped: {}", e);
+        }
+    });
+}